@@ -130,6 +130,14 @@ impl Cbmd {
         &self.title
     }
 
+    pub fn get_intro_words(&self) -> &str {
+        &self.intro_words
+    }
+
+    pub fn get_path(&self) -> &str {
+        &self.path
+    }
+
     pub fn serialize(&self) -> Vec<u8> {
         let title_len = self.title.len();
         let words_len = self.intro_words.len();
@@ -175,9 +183,7 @@ fn mm_dd_yyyy_since_epoch(date: &str) -> u64 {
     let date = date.trim();
 
     let month_day_year = date.split("/")
-        .map(|num| num.parse::<i32>())
-        .filter(|res| res.is_ok())
-        .map(|u| u.unwrap())
+        .flat_map(|num| num.parse::<i32>())
         .collect::<Vec<i32>>();
 
     assert!(month_day_year.len() == 3);
@@ -92,7 +92,7 @@ pub fn tag_to_iter_tag(tag: Tag) -> Vec<IterTag> {
 
             for child in children {
                 let iter_tag = tag_to_iter_tag(child);
-                tags.extend(iter_tag.into_iter());
+                tags.extend(iter_tag);
             }
             tags
         }
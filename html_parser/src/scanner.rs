@@ -282,7 +282,7 @@ impl Token {
         self.character_pos
     }
 
-    pub fn get_str_representation<'a>(&'a self, source: &'a str) -> &str {
+    pub fn get_str_representation<'a>(&'a self, source: &'a str) -> &'a str {
         &source[self.lexeme_start..self.lexeme_end]
     }
 
@@ -171,12 +171,9 @@ impl Tag {
         for (name, value) in self.get_attributes() {
             add_tabs(depth+2, &mut text);
             text.push_str(name);
-            match value {
-                Some(val) => {
-                    let attribute_text = format!("={},", val);
-                    text.push_str(&attribute_text);
-                },
-                None => {},
+            if let Some(val) = value {
+                let attribute_text = format!("={},", val);
+                text.push_str(&attribute_text);
             }
             text.push('\n');
         }
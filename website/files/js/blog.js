@@ -41,7 +41,7 @@ addEventListener("resize", (e) => {
 get_posts();
 
 async function get_posts() {
-    const res = await fetch(`/api/recentBlogPosts?skip=${skip}&max=10`);
+    const res = await fetch(`/api/recentBlogPosts?skip=${skip}&max=10`, { headers: { "Accept": "application/octet-stream" } });
 
     if (!res.ok) {
         console.log(res);
@@ -96,7 +96,7 @@ function post_list_error(res) {
 }
 
 async function search() {
-    const res = await fetch(`/api/searchBlog?title=${searchBar.value}`);
+    const res = await fetch(`/api/searchBlog?title=${searchBar.value}`, { headers: { "Accept": "application/octet-stream" } });
     innerResults.innerHTML = '';
     if (!res.ok) {
         return search_error(res);
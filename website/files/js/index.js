@@ -4,7 +4,7 @@ const miniBlogError = document.getElementById("mini-card-error");
 const noContent = document.getElementById("no-content");
 
 async function fetch2posts() {
-    const res = await fetch("/api/recentBlogPosts?max=2");
+    const res = await fetch("/api/recentBlogPosts?max=2", { headers: { "Accept": "application/octet-stream" } });
 
     if (!res.ok) {
         return mini_card_error(res);
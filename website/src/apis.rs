@@ -1,13 +1,18 @@
 use std::fmt::Debug;
 use std::{collections::HashMap, time::Instant, net::IpAddr};
-use std::sync::RwLock;
-use crate::types::{Response, Request};
+use std::sync::{Arc, RwLock};
+use crate::types::{Response, Request, HTTPType};
+use crate::sse::EventStream;
+use crate::websocket::WebSocket;
 
 type InnerApi = Box<dyn Fn(Request) -> Response + Send + Sync + 'static>;
+// gets the upgrade request and the socket, the connection closes when this returns
+type InnerWebSocket = Box<dyn Fn(Request, &mut WebSocket) + Send + Sync + 'static>;
 
 
 pub struct Api {
     inner: InnerApi,
+    methods: Vec<HTTPType>,
     limit_count: usize,
     seconds_till_refresh: u32,
 }
@@ -15,6 +20,7 @@ pub struct Api {
 impl Debug for Api {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         f.debug_struct("Api")
+            .field("methods", &self.methods)
             .field("limit_count", &self.limit_count)
             .field("seconds_till_refresh", &self.seconds_till_refresh)
             .finish()
@@ -26,6 +32,73 @@ impl Api {
         (self.inner)(req)
     }
 
+    // HEAD comes for free with GET and OPTIONS is always answered by the server
+    pub fn allows(&self, method: HTTPType) -> bool {
+        match method {
+            HTTPType::Options => true,
+            HTTPType::Head => self.methods.contains(&HTTPType::Get),
+            method => self.methods.contains(&method),
+        }
+    }
+
+    // formatted for the Allow header
+    pub fn get_allowed_methods(&self) -> String {
+        let mut methods = self.methods.iter()
+            .map(HTTPType::to_string)
+            .collect::<Vec<String>>();
+
+        if self.methods.contains(&HTTPType::Get) {
+            methods.push(HTTPType::Head.to_string());
+        }
+        methods.push(HTTPType::Options.to_string());
+
+        methods.join(", ")
+    }
+
+    fn get_limit_and_refresh(&self) -> (usize, u32) {
+        (self.limit_count, self.seconds_till_refresh)
+    }
+}
+
+// an endpoint that holds the connection open and swaps messages instead of answering once
+pub struct WebSocketApi {
+    inner: InnerWebSocket,
+    limit_count: usize,
+    seconds_till_refresh: u32,
+}
+
+impl Debug for WebSocketApi {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("WebSocketApi")
+            .field("limit_count", &self.limit_count)
+            .field("seconds_till_refresh", &self.seconds_till_refresh)
+            .finish()
+    }
+}
+
+impl WebSocketApi {
+    pub fn run(&self, req: Request, socket: &mut WebSocket) {
+        (self.inner)(req, socket)
+    }
+
+    fn get_limit_and_refresh(&self) -> (usize, u32) {
+        (self.limit_count, self.seconds_till_refresh)
+    }
+}
+
+// a feed of Server-Sent Events, GETs to it subscribe
+#[derive(Debug)]
+pub struct EventStreamApi {
+    events: Arc<EventStream>,
+    limit_count: usize,
+    seconds_till_refresh: u32,
+}
+
+impl EventStreamApi {
+    pub fn events(&self) -> &Arc<EventStream> {
+        &self.events
+    }
+
     fn get_limit_and_refresh(&self) -> (usize, u32) {
         (self.limit_count, self.seconds_till_refresh)
     }
@@ -34,20 +107,31 @@ impl Api {
 #[derive(Debug)]
 pub struct ApiRegister {
     apis: HashMap<String, Api>,
+    websockets: HashMap<String, WebSocketApi>,
+    event_streams: HashMap<String, EventStreamApi>,
     users: RwLock<HashMap<IpAddr, User>>,
 }
 
+impl Default for ApiRegister {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
 impl ApiRegister {
     pub fn new() -> Self {
         Self {
             apis: HashMap::new(),
+            websockets: HashMap::new(),
+            event_streams: HashMap::new(),
             users: RwLock::new(HashMap::new()),
         }
     }
 
-    pub fn register_api(&mut self, path: &str, inner_api: InnerApi, limit: usize, refresh_timer: u32) {
+    pub fn register_api(&mut self, path: &str, methods: &[HTTPType], inner_api: InnerApi, limit: usize, refresh_timer: u32) {
         let api = Api {
             inner: inner_api,
+            methods: methods.to_vec(),
             limit_count: limit,
             seconds_till_refresh: refresh_timer,
         };
@@ -58,54 +142,85 @@ impl ApiRegister {
         self.apis.get(path)
     }
 
+    // the limit counts upgrades, not the messages sent after
+    pub fn register_websocket(&mut self, path: &str, inner_socket: InnerWebSocket, limit: usize, refresh_timer: u32) {
+        let socket = WebSocketApi {
+            inner: inner_socket,
+            limit_count: limit,
+            seconds_till_refresh: refresh_timer,
+        };
+        self.websockets.insert(path.into(), socket);
+    }
+
+    pub fn get_websocket(&self, path: &str) -> Option<&WebSocketApi> {
+        self.websockets.get(path)
+    }
+
+    // keep a clone of the Arc to publish events, the limit counts subscribes
+    pub fn register_event_stream(&mut self, path: &str, events: Arc<EventStream>, limit: usize, refresh_timer: u32) {
+        let stream = EventStreamApi {
+            events,
+            limit_count: limit,
+            seconds_till_refresh: refresh_timer,
+        };
+        self.event_streams.insert(path.into(), stream);
+    }
+
+    pub fn get_event_stream(&self, path: &str) -> Option<&EventStreamApi> {
+        self.event_streams.get(path)
+    }
+
     pub fn user_exists(&self, ip: &IpAddr) -> bool {
         let reader = self.users.read().unwrap();
         reader.contains_key(ip)
     }
 
+    // users that dont exist yet get added here too, under the same lock so the cleaner
+    // cant take them out between adding and using them
     pub fn check_limit(&self, ip: &IpAddr, api_path: &str) -> bool {
         let mut writer = self.users.write().unwrap();
-        writer.get_mut(ip).unwrap().check_limit(api_path)
+        writer.entry(*ip).or_insert_with(|| self.new_user()).check_limit(api_path)
     }
 
     pub fn add_request(&self, api_path: &str, user_ip: IpAddr) {
         let mut writer = self.users.write().unwrap();
-        writer.get_mut(&user_ip).unwrap().add_request(api_path);
+        writer.entry(user_ip).or_insert_with(|| self.new_user()).add_request(api_path);
     }
 
     pub fn add_gloabal_request(&self, user_ip: IpAddr) {
         let mut writer = self.users.write().unwrap();
-        writer.get_mut(&user_ip).unwrap().add_gloabal_request();
+        writer.entry(user_ip).or_insert_with(|| self.new_user()).add_gloabal_request();
     }
 
     pub fn add_user(&self, user_ip: IpAddr) {
+        let user = self.new_user();
+        let mut inserter = self.users.write().unwrap();
+        inserter.insert(user_ip, user);
+    }
+
+    // a user with a limiter for every api, websocket and event stream
+    fn new_user(&self) -> User {
+        let sockets = self.websockets.iter()
+            .map(|(k, v)| (k, v.get_limit_and_refresh()));
+        let streams = self.event_streams.iter()
+            .map(|(k, v)| (k, v.get_limit_and_refresh()));
         let limits = self.apis.iter()
-            .map(|(k, v)| {
-                let (limit, refresh) = v.get_limit_and_refresh();
-                (limit,refresh, k.as_str())
-            })
+            .map(|(k, v)| (k, v.get_limit_and_refresh()))
+            .chain(sockets)
+            .chain(streams)
+            .map(|(k, (limit, refresh))| (limit, refresh, k.as_str()))
             .map(|tup| (RateLimiter::new(tup.0, tup.1), tup.2))
             .collect::<Vec<(RateLimiter, &str)>>();
 
         let mut user = User::new();
         user.add_many(limits);
-        let mut inserter = self.users.write().unwrap();
-        inserter.insert(user_ip, user);
+        user
     }
 
+    // done under one lock so a user that made a request since it was looked at doesnt get removed
     pub fn clean_recent_requests(&self) {
-        let reader = self.users.read().unwrap();
-        let keys_to_remove = reader.iter()
-            .filter(|(_, user)| user.get_recent_request_count() == 0)
-            .map(|(key, _)| *key)
-            .collect::<Vec<IpAddr>>();
-
-        drop(reader);
-        let mut inserter = self.users.write().unwrap();
-
-        for key in keys_to_remove {
-            inserter.remove(&key);
-        }
+        let mut writer = self.users.write().unwrap();
+        writer.retain(|_, user| user.get_recent_request_count() != 0);
     }
 }
 
@@ -143,8 +258,8 @@ impl User {
     }
 
     pub fn get_recent_request_count(&self) -> usize {
-        self.limits.iter()
-            .map(|(_, limiter)| limiter.get_recent_request_count())
+        self.limits.values()
+            .map(|limiter| limiter.get_recent_request_count())
             .sum()
     }
 
@@ -154,7 +269,9 @@ impl User {
 
     pub fn add_request(&mut self, api_path: &str) {
         self.limits.get_mut("global").unwrap().add_request(Instant::now());
-        self.limits.get_mut(api_path).unwrap().add_request(Instant::now());
+        if let Some(limiter) = self.limits.get_mut(api_path) {
+            limiter.add_request(Instant::now());
+        }
     }
 }
 
@@ -185,16 +302,13 @@ impl RateLimiter {
 
 
     pub fn check_limit(&mut self) -> bool {
-        match self.lockdown_time {
-            Some(time) => {
-                if time.elapsed().as_secs() > self.seconds_till_refresh as u64 {
-                    self.lockdown_time = None;
-                } else {
-                    // on lockdown no more requets !
-                    return false;
-                }
+        if let Some(time) = self.lockdown_time {
+            if time.elapsed().as_secs() > self.seconds_till_refresh as u64 {
+                self.lockdown_time = None;
+            } else {
+                // on lockdown no more requets !
+                return false;
             }
-            None => {},
         }
 
 
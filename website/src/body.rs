@@ -0,0 +1,275 @@
+use std::io::{BufRead, Read};
+use std::time::Instant;
+use crate::headers::{Headers, trim_line_ending};
+use crate::limits::{Deadline, RequestLimits};
+use crate::types::HTTPError;
+
+// a chunk size line is a few hex digits, anything this long is someone messing with us
+pub(crate) const MAX_CHUNK_LINE: u64 = 4096;
+
+// how the end of a request body is found, RFC 9112 section 6.3
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BodyLength {
+    Empty,
+    Fixed(usize),
+    Chunked,
+}
+
+impl BodyLength {
+    pub fn from_headers(headers: &Headers) -> Result<Self, HTTPError> {
+        let content_length = get_content_length(headers)?;
+
+        if !headers.contains("Transfer-Encoding") {
+            return match content_length {
+                Some(0) | None => Ok(Self::Empty),
+                Some(length) => Ok(Self::Fixed(length)),
+            };
+        }
+
+        // sending both is how request smuggling works so it gets rejected outright
+        if content_length.is_some() {
+            return Err(HTTPError::InvalidTransferEncoding);
+        }
+
+        let codings = headers.get_list("Transfer-Encoding")
+            .map(|coding| coding.split(';').next().unwrap_or("").trim())
+            .collect::<Vec<&str>>();
+
+        // chunked has to be the last coding or there is no way to find the end of the body
+        match codings.last() {
+            Some(last) if last.eq_ignore_ascii_case("chunked") => {},
+            _ => return Err(HTTPError::InvalidTransferEncoding),
+        }
+
+        let other_codings = &codings[..codings.len() - 1];
+        if other_codings.iter().any(|coding| coding.eq_ignore_ascii_case("chunked")) {
+            return Err(HTTPError::InvalidTransferEncoding);
+        }
+        // we dont decompress anything so any other coding is a 501
+        if !other_codings.is_empty() {
+            return Err(HTTPError::UnsupportedTransferEncoding);
+        }
+
+        Ok(Self::Chunked)
+    }
+}
+
+// reads the whole body off the reader, chunked bodies come back decoded with their trailer fields
+// the client gets body_timeout from here to finish sending it
+pub fn read_body<R: BufRead + Deadline>(reader: &mut R, length: BodyLength, limits: &RequestLimits) -> Result<(Vec<u8>, Headers), HTTPError> {
+    if length != BodyLength::Empty {
+        reader.set_deadline(Some(Instant::now() + limits.body_timeout));
+    }
+
+    match length {
+        BodyLength::Empty => Ok((Vec::new(), Headers::new())),
+        // checked before reading anything so a huge Content-Length never gets allocated
+        BodyLength::Fixed(length) if length > limits.max_body_size => Err(HTTPError::ContentTooLarge),
+        BodyLength::Fixed(length) => {
+            let mut content = Vec::new();
+            match reader.take(length as u64).read_to_end(&mut content) {
+                Ok(read) if read == length => Ok((content, Headers::new())),
+                Ok(_) => Err(HTTPError::InvalidContent),
+                Err(e) => Err(HTTPError::from_io(&e, HTTPError::InvalidContent)),
+            }
+        },
+        BodyLength::Chunked => read_chunked(reader, limits),
+    }
+}
+
+// chunked-body = *chunk last-chunk trailer-section CRLF
+fn read_chunked<R: BufRead>(reader: &mut R, limits: &RequestLimits) -> Result<(Vec<u8>, Headers), HTTPError> {
+    let mut content = Vec::new();
+    let mut line = Vec::new();
+
+    loop {
+        read_chunk_line(reader, &mut line)?;
+        let size = parse_chunk_size(&line)?;
+
+        if size == 0 {
+            break;
+        }
+        // the sizes add up as we go so this is only known one chunk at a time
+        if size > limits.max_body_size - content.len() {
+            return Err(HTTPError::ContentTooLarge);
+        }
+
+        match reader.take(size as u64).read_to_end(&mut content) {
+            Ok(read) if read == size => {},
+            Ok(_) => return Err(HTTPError::InvalidChunk),
+            Err(e) => return Err(HTTPError::from_io(&e, HTTPError::InvalidChunk)),
+        }
+
+        // every chunk ends with its own line ending
+        read_chunk_line(reader, &mut line)?;
+        if !matches!(trim_line_ending(&line), Ok([])) {
+            return Err(HTTPError::InvalidChunk);
+        }
+    }
+
+    // the trailer section looks just like a header and ends the same way
+    let trailers = match Headers::read_from(reader, limits.max_header_size) {
+        Ok(trailers) => trailers,
+        Err(HTTPError::InvalidHeader) => return Err(HTTPError::InvalidChunk),
+        Err(e) => return Err(e),
+    };
+
+    Ok((content, trailers))
+}
+
+fn read_chunk_line<R: BufRead>(reader: &mut R, line: &mut Vec<u8>) -> Result<(), HTTPError> {
+    line.clear();
+    match reader.by_ref().take(MAX_CHUNK_LINE).read_until(b'\n', line) {
+        Ok(_) => Ok(()),
+        Err(e) => Err(HTTPError::from_io(&e, HTTPError::InvalidChunk)),
+    }
+}
+
+// chunk-size [ chunk-ext ] CRLF, the extensions dont mean anything to us so they get skipped
+fn parse_chunk_size(line: &[u8]) -> Result<usize, HTTPError> {
+    let line = match trim_line_ending(line) {
+        Ok(line) => line,
+        Err(_) => return Err(HTTPError::InvalidChunk),
+    };
+
+    let size_end = line.iter().position(|c| !c.is_ascii_hexdigit()).unwrap_or(line.len());
+    let (size, extensions) = line.split_at(size_end);
+    if size.is_empty() {
+        return Err(HTTPError::InvalidChunk);
+    }
+
+    let extensions = extensions.trim_ascii_start();
+    if !extensions.is_empty() && extensions[0] != b';' {
+        return Err(HTTPError::InvalidChunk);
+    }
+
+    // only hex digits so its fine as a str, too many of them overflows and fails here
+    let size = String::from_utf8_lossy(size);
+    match usize::from_str_radix(&size, 16) {
+        Ok(size) => Ok(size),
+        Err(_) => Err(HTTPError::InvalidChunk),
+    }
+}
+
+// repeats are fine as long as they all agree, anything else is an error
+fn get_content_length(headers: &Headers) -> Result<Option<usize>, HTTPError> {
+    let mut content_length = None;
+    for value in headers.get_list("Content-Length") {
+        if !value.bytes().all(|c| c.is_ascii_digit()) {
+            return Err(HTTPError::InvalidContentLength);
+        }
+        let length = match value.parse::<usize>() {
+            Ok(length) => length,
+            Err(_) => return Err(HTTPError::InvalidContentLength),
+        };
+        match content_length {
+            Some(previous) if previous != length => return Err(HTTPError::InvalidContentLength),
+            _ => content_length = Some(length),
+        }
+    }
+
+    Ok(content_length)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn decode(body: &[u8]) -> Result<(Vec<u8>, Headers), HTTPError> {
+        let mut reader = body;
+        read_chunked(&mut reader, &RequestLimits::default())
+    }
+
+    fn headers(list: &[(&str, &str)]) -> Headers {
+        let mut headers = Headers::new();
+        for (name, value) in list {
+            headers.insert(name, value);
+        }
+        headers
+    }
+
+    #[test]
+    fn chunks_are_put_back_together() {
+        let (content, trailers) = decode(b"5\r\nhello\r\n1;name=value\r\n \r\nA\r\nbig world!\r\n0\r\n\r\n").unwrap();
+        assert_eq!(content, b"hello big world!");
+        assert!(trailers.get("anything").is_none());
+
+        // bare LFs are let through like they are in the header
+        let (content, _) = decode(b"3\nabc\n0\n\n").unwrap();
+        assert_eq!(content, b"abc");
+
+        let (content, _) = decode(b"0\r\n\r\n").unwrap();
+        assert!(content.is_empty());
+    }
+
+    #[test]
+    fn trailers_come_back_with_the_body() {
+        let (content, trailers) = decode(b"2\r\nhi\r\n0\r\nChecksum: abc\r\nExpires: never\r\n\r\n").unwrap();
+        assert_eq!(content, b"hi");
+        assert_eq!(trailers.get("checksum"), Some("abc"));
+        assert_eq!(trailers.get("Expires"), Some("never"));
+    }
+
+    #[test]
+    fn nothing_after_the_body_is_read() {
+        let mut reader: &[u8] = b"2\r\nhi\r\n0\r\n\r\nGET / HTTP/1.1\r\n";
+        read_chunked(&mut reader, &RequestLimits::default()).unwrap();
+        assert_eq!(reader, b"GET / HTTP/1.1\r\n");
+    }
+
+    #[test]
+    fn bad_chunks() {
+        for body in [
+            // no size, not hex, junk after the size
+            &b"\r\nhi\r\n0\r\n\r\n"[..],
+            b"zz\r\nhi\r\n0\r\n\r\n",
+            b"2 x\r\nhi\r\n0\r\n\r\n",
+            // more data than the size said and less
+            b"2\r\nhello\r\n0\r\n\r\n",
+            b"5\r\nhi\r\n",
+            // a size that doesnt fit in a usize
+            b"fffffffffffffffffffff\r\n",
+            // the stream ending before the last chunk or in the middle of the trailers
+            b"2\r\nhi\r\n",
+            b"2\r\nhi\r\n0\r\nChecksum: abc\r\n",
+            b"2\r\nhi\r\n0\r\nnot a field\r\n\r\n",
+        ] {
+            assert!(matches!(decode(body), Err(HTTPError::InvalidChunk)), "{:?}", String::from_utf8_lossy(body));
+        }
+    }
+
+    #[test]
+    fn chunks_past_the_limit() {
+        let limits = RequestLimits {
+            max_body_size: 8,
+            ..RequestLimits::default()
+        };
+        let mut reader: &[u8] = b"8\r\n12345678\r\n0\r\n\r\n";
+        assert!(read_chunked(&mut reader, &limits).is_ok());
+
+        // only the total is too big, neither chunk is on its own
+        let mut reader: &[u8] = b"5\r\n12345\r\n5\r\n67890\r\n0\r\n\r\n";
+        assert!(matches!(read_chunked(&mut reader, &limits), Err(HTTPError::ContentTooLarge)));
+
+        // a size line that never ends
+        let mut body = b"1".to_vec();
+        body.extend(vec![b'0'; MAX_CHUNK_LINE as usize]);
+        assert!(matches!(decode(&body), Err(HTTPError::InvalidChunk)));
+    }
+
+    #[test]
+    fn body_length() {
+        assert!(matches!(BodyLength::from_headers(&headers(&[])), Ok(BodyLength::Empty)));
+        assert!(matches!(BodyLength::from_headers(&headers(&[("Content-Length", "0")])), Ok(BodyLength::Empty)));
+        assert!(matches!(BodyLength::from_headers(&headers(&[("Content-Length", "12")])), Ok(BodyLength::Fixed(12))));
+        assert!(matches!(BodyLength::from_headers(&headers(&[("Content-Length", "12"), ("Content-Length", "12")])), Ok(BodyLength::Fixed(12))));
+        assert!(matches!(BodyLength::from_headers(&headers(&[("Transfer-Encoding", "Chunked")])), Ok(BodyLength::Chunked)));
+
+        assert!(matches!(BodyLength::from_headers(&headers(&[("Content-Length", "12"), ("Content-Length", "13")])), Err(HTTPError::InvalidContentLength)));
+        assert!(matches!(BodyLength::from_headers(&headers(&[("Content-Length", "+12")])), Err(HTTPError::InvalidContentLength)));
+        assert!(matches!(BodyLength::from_headers(&headers(&[("Content-Length", "5"), ("Transfer-Encoding", "chunked")])), Err(HTTPError::InvalidTransferEncoding)));
+        assert!(matches!(BodyLength::from_headers(&headers(&[("Transfer-Encoding", "chunked, gzip")])), Err(HTTPError::InvalidTransferEncoding)));
+        assert!(matches!(BodyLength::from_headers(&headers(&[("Transfer-Encoding", "chunked, chunked")])), Err(HTTPError::InvalidTransferEncoding)));
+        assert!(matches!(BodyLength::from_headers(&headers(&[("Transfer-Encoding", "gzip, chunked")])), Err(HTTPError::UnsupportedTransferEncoding)));
+    }
+}
@@ -0,0 +1,278 @@
+use std::net::IpAddr;
+use std::str::FromStr;
+use crate::headers::Headers;
+
+// an address block like 10.0.0.0/8 or ::1/128, a bare address is a block of one
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Cidr {
+    address: IpAddr,
+    prefix: u8,
+}
+
+impl Cidr {
+    pub fn contains(&self, ip: IpAddr) -> bool {
+        match (self.address, ip.to_canonical()) {
+            (IpAddr::V4(network), IpAddr::V4(ip)) => {
+                let mask = u32::MAX.checked_shl(32 - self.prefix as u32).unwrap_or(0);
+                u32::from(network) & mask == u32::from(ip) & mask
+            },
+            (IpAddr::V6(network), IpAddr::V6(ip)) => {
+                let mask = u128::MAX.checked_shl(128 - self.prefix as u32).unwrap_or(0);
+                u128::from(network) & mask == u128::from(ip) & mask
+            },
+            _ => false,
+        }
+    }
+}
+
+impl FromStr for Cidr {
+    type Err = String;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let s = s.trim();
+        let (address, prefix) = match s.split_once('/') {
+            Some((address, prefix)) => (address, Some(prefix)),
+            None => (s, None),
+        };
+
+        let address = match IpAddr::from_str(address) {
+            Ok(address) => address.to_canonical(),
+            Err(_) => return Err(format!("{s} is not an ip address")),
+        };
+        let max = if address.is_ipv4() { 32 } else { 128 };
+
+        let prefix = match prefix.map(str::parse::<u8>) {
+            None => max,
+            Some(Ok(prefix)) if prefix <= max => prefix,
+            Some(_) => return Err(format!("{s} has an invalid prefix length")),
+        };
+
+        Ok(Self {
+            address,
+            prefix,
+        })
+    }
+}
+
+// the proxies allowed to tell us who the client is
+// a forwarding header from anyone else is ignored as it could just be made up
+#[derive(Debug, Clone, Default)]
+pub struct TrustedProxies {
+    ranges: Vec<Cidr>,
+}
+
+impl TrustedProxies {
+    pub fn new(ranges: Vec<Cidr>) -> Self {
+        Self {
+            ranges,
+        }
+    }
+
+    // a comma seperated list like the TRUSTED_PROXIES env var
+    pub fn parse(list: &str) -> Result<Self, String> {
+        let ranges = list.split(',')
+            .filter(|range| !range.trim().is_empty())
+            .map(Cidr::from_str)
+            .collect::<Result<Vec<Cidr>, String>>()?;
+
+        Ok(Self::new(ranges))
+    }
+
+    // a proxy on the same machine
+    pub fn loopback() -> Self {
+        Self::parse("127.0.0.0/8, ::1").unwrap_or_default()
+    }
+
+    pub fn is_trusted(&self, ip: IpAddr) -> bool {
+        self.ranges.iter().any(|range| range.contains(ip))
+    }
+}
+
+// works out the real client starting from whoever opened the connection
+// every proxy appends the address it got the request from so the list is walked from the right
+// untill an address that isnt one of our proxies shows up, thats the client
+// Forwarded (RFC 7239) is used over X-Forwarded-For when both are sent
+pub fn resolve_client_ip(peer: IpAddr, headers: &Headers, trusted: &TrustedProxies) -> IpAddr {
+    let peer = peer.to_canonical();
+    if !trusted.is_trusted(peer) {
+        return peer;
+    }
+
+    let hops = if headers.contains("Forwarded") {
+        forwarded_for(headers)
+    } else {
+        headers.get_list("X-Forwarded-For")
+            .map(parse_node)
+            .collect()
+    };
+
+    let mut client = peer;
+    for hop in hops.into_iter().rev() {
+        match hop {
+            Some(ip) => {
+                client = ip;
+                if !trusted.is_trusted(ip) {
+                    break;
+                }
+            },
+            // unknown or obfuscated, the last proxy we trust is as close as we can get
+            None => break,
+        }
+    }
+
+    client
+}
+
+// Forwarded: for=192.0.2.60;proto=http, for="[2001:db8:cafe::17]:4711"
+fn forwarded_for(headers: &Headers) -> Vec<Option<IpAddr>> {
+    let mut hops = Vec::new();
+    for value in headers.get_all("Forwarded") {
+        for element in split_outside_quotes(value, ',') {
+            let node = split_outside_quotes(element, ';').into_iter()
+                .filter_map(|pair| pair.split_once('='))
+                .find(|(key, _)| key.trim().eq_ignore_ascii_case("for"))
+                .and_then(|(_, node)| parse_node(node.trim().trim_matches('"')));
+            // a hop that didnt say who it was for cant be looked past
+            hops.push(node);
+        }
+    }
+    hops
+}
+
+// quoted values can hold the seperators so a plain split isnt good enough
+fn split_outside_quotes(text: &str, seperator: char) -> Vec<&str> {
+    let mut pieces = Vec::new();
+    let mut in_quotes = false;
+    let mut start = 0;
+    for (i, c) in text.char_indices() {
+        match c {
+            '"' => in_quotes = !in_quotes,
+            c if c == seperator && !in_quotes => {
+                pieces.push(text[start..i].trim());
+                start = i + c.len_utf8();
+            },
+            _ => {},
+        }
+    }
+    pieces.push(text[start..].trim());
+    pieces.retain(|piece| !piece.is_empty());
+    pieces
+}
+
+// 192.0.2.60, 192.0.2.60:80, 2001:db8::1, [2001:db8::1]:4711
+// "unknown" and obfuscated names like _hidden dont give us an address
+fn parse_node(node: &str) -> Option<IpAddr> {
+    let node = node.trim();
+    if let Ok(ip) = IpAddr::from_str(node) {
+        return Some(ip.to_canonical());
+    }
+
+    let host = match node.strip_prefix('[') {
+        Some(bracketed) => bracketed.split_once(']')?.0,
+        None => node.rsplit_once(':')?.0,
+    };
+
+    IpAddr::from_str(host).ok().map(|ip| ip.to_canonical())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn ip(text: &str) -> IpAddr {
+        text.parse().unwrap()
+    }
+
+    fn cidr(text: &str) -> Cidr {
+        text.parse().unwrap()
+    }
+
+    fn resolve(peer: &str, headers: &[(&str, &str)], trusted: &str) -> IpAddr {
+        let mut map = Headers::new();
+        for (name, value) in headers {
+            map.insert(name, value);
+        }
+        resolve_client_ip(ip(peer), &map, &TrustedProxies::parse(trusted).unwrap())
+    }
+
+    #[test]
+    fn cidr_ranges() {
+        assert!(cidr("10.0.0.0/8").contains(ip("10.255.0.1")));
+        assert!(!cidr("10.0.0.0/8").contains(ip("11.0.0.1")));
+        assert!(cidr("192.0.2.7").contains(ip("192.0.2.7")));
+        assert!(!cidr("192.0.2.7/32").contains(ip("192.0.2.8")));
+        assert!(cidr("0.0.0.0/0").contains(ip("203.0.113.9")));
+        assert!(cidr("2001:db8::/32").contains(ip("2001:db8:1::5")));
+        assert!(!cidr("2001:db8::/32").contains(ip("2001:db9::5")));
+        assert!(cidr("::/0").contains(ip("::1")));
+        // v4 ranges dont hold v6 addresses and the other way round
+        assert!(!cidr("0.0.0.0/0").contains(ip("::1")));
+        assert!(!cidr("::/0").contains(ip("127.0.0.1")));
+    }
+
+    #[test]
+    fn ipv4_mapped_addresses() {
+        // both ways round they are just the v4 address
+        assert!(cidr("127.0.0.0/8").contains(ip("::ffff:127.0.0.1")));
+        assert!(cidr("::ffff:10.0.0.1").contains(ip("10.0.0.1")));
+        assert_eq!(resolve("::ffff:203.0.113.9", &[], ""), ip("203.0.113.9"));
+        assert_eq!(resolve("::ffff:127.0.0.1", &[("X-Forwarded-For", "203.0.113.9")], "127.0.0.1"), ip("203.0.113.9"));
+    }
+
+    #[test]
+    fn invalid_cidrs() {
+        for text in ["", "10.0.0", "10.0.0.0/33", "::/129", "10.0.0.0/", "10.0.0.0/-1", "10.0.0.0/8/8", "localhost", "10.0.0.0 /8x"] {
+            assert!(text.parse::<Cidr>().is_err(), "{text}");
+        }
+        assert!(TrustedProxies::parse("10.0.0.0/8, nope").is_err());
+        assert!(TrustedProxies::parse(" 10.0.0.0/8 ,, ::1 ,").is_ok());
+    }
+
+    #[test]
+    fn untrusted_peers_cant_spoof() {
+        let headers = [("X-Forwarded-For", "1.1.1.1"), ("Forwarded", "for=2.2.2.2")];
+        assert_eq!(resolve("203.0.113.9", &headers, "127.0.0.0/8"), ip("203.0.113.9"));
+        assert_eq!(resolve("203.0.113.9", &headers, ""), ip("203.0.113.9"));
+    }
+
+    #[test]
+    fn x_forwarded_for_is_walked_from_the_right() {
+        let trusted = "127.0.0.0/8, 10.0.0.0/8";
+        // the client made up the first one, our proxies added the rest
+        let headers = [("X-Forwarded-For", "6.6.6.6, 203.0.113.9, 10.0.0.2"), ("X-Forwarded-For", "10.0.0.1")];
+        assert_eq!(resolve("127.0.0.1", &headers, trusted), ip("203.0.113.9"));
+
+        // every hop is trusted so the furthest one is the best there is
+        assert_eq!(resolve("127.0.0.1", &[("X-Forwarded-For", "10.0.0.2, 10.0.0.1")], trusted), ip("10.0.0.2"));
+        // ports are dropped
+        assert_eq!(resolve("127.0.0.1", &[("X-Forwarded-For", "203.0.113.9:5000")], trusted), ip("203.0.113.9"));
+        // junk stops the walk at the last proxy we trust
+        assert_eq!(resolve("127.0.0.1", &[("X-Forwarded-For", "203.0.113.9, garbage, 10.0.0.1")], trusted), ip("10.0.0.1"));
+        assert_eq!(resolve("127.0.0.1", &[], trusted), ip("127.0.0.1"));
+    }
+
+    #[test]
+    fn forwarded_wins_over_x_forwarded_for() {
+        let trusted = "127.0.0.1";
+        let headers = [
+            ("X-Forwarded-For", "1.1.1.1"),
+            ("Forwarded", "for=6.6.6.6, for=\"[2001:db8:cafe::17]:4711\";proto=https;by=127.0.0.1"),
+        ];
+        assert_eq!(resolve("127.0.0.1", &headers, trusted), ip("2001:db8:cafe::17"));
+
+        let headers = [("Forwarded", "For=\"198.51.100.4:80\"")];
+        assert_eq!(resolve("127.0.0.1", &headers, trusted), ip("198.51.100.4"));
+        // a quoted comma doesnt split the element
+        let headers = [("Forwarded", "for=203.0.113.9;host=\"a,b\"")];
+        assert_eq!(resolve("127.0.0.1", &headers, trusted), ip("203.0.113.9"));
+    }
+
+    #[test]
+    fn forwarded_unknown_and_hidden_hops() {
+        let trusted = "127.0.0.1, 10.0.0.0/8";
+        // cant see past them so the proxy that said so is as far as it goes
+        assert_eq!(resolve("127.0.0.1", &[("Forwarded", "for=203.0.113.9, for=unknown, for=10.0.0.1")], trusted), ip("10.0.0.1"));
+        assert_eq!(resolve("127.0.0.1", &[("Forwarded", "for=_hidden")], trusted), ip("127.0.0.1"));
+        // an element without for counts as one of those too
+        assert_eq!(resolve("127.0.0.1", &[("Forwarded", "for=203.0.113.9, proto=https")], trusted), ip("127.0.0.1"));
+    }
+}
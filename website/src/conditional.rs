@@ -0,0 +1,204 @@
+use std::fs::Metadata;
+use std::time::{SystemTime, UNIX_EPOCH};
+use crate::headers::Headers;
+use crate::types::parse_http_date;
+
+// a strong etag built from the size and modified time so the file never has to be read to make one
+// the nanoseconds are in there so two saves in the same second still get different tags
+pub fn make_etag(metadata: &Metadata) -> Option<String> {
+    let modified = metadata.modified().ok()?;
+    let since_epoch = modified.duration_since(UNIX_EPOCH).ok()?;
+    Some(format!("\"{:x}-{:x}.{:x}\"", metadata.len(), since_epoch.as_secs(), since_epoch.subsec_nanos()))
+}
+
+// checks If-None-Match and If-Modified-Since for a GET or HEAD, RFC 9110 section 13.2.2
+// If-Modified-Since is only looked at when there is no If-None-Match
+pub fn is_not_modified(headers: &Headers, etag: &str, last_modified: Option<SystemTime>) -> bool {
+    if headers.contains("If-None-Match") {
+        return headers.get_all("If-None-Match")
+            .any(|value| etag_list_matches(value, etag, false));
+    }
+
+    let since = match headers.get("If-Modified-Since").and_then(parse_http_date) {
+        Some(since) => since,
+        // a date we cant read is ignored like the header was never sent
+        None => return false,
+    };
+
+    match last_modified {
+        // http dates only go down to the second
+        Some(modified) => truncate_to_seconds(modified) <= since,
+        None => false,
+    }
+}
+
+// a Range is only used if If-Range still matches what we have, otherwise the whole file is sent
+// this needs a strong match, a weak etag or a date that isnt exactly Last-Modified wont do
+pub fn if_range_matches(headers: &Headers, etag: Option<&str>, last_modified: Option<SystemTime>) -> bool {
+    let value = match headers.get("If-Range") {
+        Some(value) => value.trim(),
+        None => return true,
+    };
+
+    if value.starts_with('"') || value.starts_with("W/") {
+        return match etag {
+            Some(etag) => etag_list_matches(value, etag, true),
+            None => false,
+        };
+    }
+
+    match (parse_http_date(value), last_modified) {
+        (Some(date), Some(modified)) => truncate_to_seconds(modified) == date,
+        _ => false,
+    }
+}
+
+// compares an If-None-Match or If-Match style list against our etag
+// weak comparison ignores the W/ on both sides, strong comparison fails on any weak tag
+pub fn etag_list_matches(list: &str, etag: &str, strong: bool) -> bool {
+    if list.trim() == "*" {
+        return true;
+    }
+
+    let (etag_weak, etag_opaque) = split_etag(etag);
+    parse_etag_list(list).into_iter()
+        .any(|(weak, opaque)| {
+            if strong && (weak || etag_weak) {
+                return false;
+            }
+            opaque == etag_opaque
+        })
+}
+
+fn split_etag(etag: &str) -> (bool, &str) {
+    match etag.strip_prefix("W/") {
+        Some(opaque) => (true, opaque),
+        None => (false, etag),
+    }
+}
+
+// entity-tag = [ "W/" ] DQUOTE *etagc DQUOTE
+// commas are allowed inside the quotes so splitting on them isnt enough
+fn parse_etag_list(list: &str) -> Vec<(bool, &str)> {
+    let mut tags = Vec::new();
+    let mut rest = list;
+
+    loop {
+        rest = rest.trim_start_matches([',', ' ', '\t']);
+        if rest.is_empty() {
+            return tags;
+        }
+
+        let weak = match rest.strip_prefix("W/") {
+            Some(stripped) => {
+                rest = stripped;
+                true
+            },
+            None => false,
+        };
+
+        if !rest.starts_with('"') {
+            // not a tag, give up on whatever is left
+            return tags;
+        }
+        let end = match rest[1..].find('"') {
+            Some(end) => end + 2,
+            None => return tags,
+        };
+
+        tags.push((weak, &rest[..end]));
+        rest = &rest[end..];
+    }
+}
+
+fn truncate_to_seconds(time: SystemTime) -> SystemTime {
+    match time.duration_since(UNIX_EPOCH) {
+        Ok(since_epoch) => UNIX_EPOCH + std::time::Duration::from_secs(since_epoch.as_secs()),
+        Err(_) => time,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+
+    const ETAG: &str = "\"abc-1\"";
+
+    fn headers(fields: &[(&str, &str)]) -> Headers {
+        let mut headers = Headers::new();
+        for (name, value) in fields {
+            headers.insert(name, value);
+        }
+        headers
+    }
+
+    // Sun, 06 Nov 1994 08:49:37 GMT and a bit
+    fn modified() -> SystemTime {
+        UNIX_EPOCH + Duration::from_secs(784111777) + Duration::from_millis(500)
+    }
+
+    #[test]
+    fn etag_lists() {
+        assert!(etag_list_matches("\"abc-1\"", ETAG, true));
+        assert!(etag_list_matches("\"x\", \"abc-1\"", ETAG, false));
+        assert!(etag_list_matches(" * ", ETAG, true));
+        assert!(!etag_list_matches("\"abc\"", ETAG, false));
+        // unquoted isnt a tag
+        assert!(!etag_list_matches("abc-1", ETAG, false));
+        // commas can be inside the quotes
+        assert!(etag_list_matches("\"a,b\", \"c\"", "\"a,b\"", true));
+        assert!(!etag_list_matches("\"a,b\"", "\"a\"", false));
+        // junk stops the list
+        assert!(!etag_list_matches("junk, \"abc-1\"", ETAG, false));
+        assert!(!etag_list_matches("\"open", "\"open\"", false));
+    }
+
+    #[test]
+    fn weak_and_strong_comparison() {
+        assert!(etag_list_matches("W/\"abc-1\"", ETAG, false));
+        assert!(etag_list_matches("\"abc-1\"", "W/\"abc-1\"", false));
+        assert!(!etag_list_matches("W/\"abc-1\"", ETAG, true));
+        assert!(!etag_list_matches("\"abc-1\"", "W/\"abc-1\"", true));
+    }
+
+    #[test]
+    fn not_modified() {
+        assert!(!is_not_modified(&headers(&[]), ETAG, Some(modified())));
+        assert!(is_not_modified(&headers(&[("If-None-Match", "W/\"abc-1\"")]), ETAG, None));
+        assert!(is_not_modified(&headers(&[("If-None-Match", "\"x\""), ("If-None-Match", "\"abc-1\"")]), ETAG, None));
+        assert!(is_not_modified(&headers(&[("If-None-Match", "*")]), ETAG, None));
+
+        // only whole seconds get compared
+        assert!(is_not_modified(&headers(&[("If-Modified-Since", "Sun, 06 Nov 1994 08:49:37 GMT")]), ETAG, Some(modified())));
+        assert!(!is_not_modified(&headers(&[("If-Modified-Since", "Sun, 06 Nov 1994 08:49:36 GMT")]), ETAG, Some(modified())));
+        assert!(!is_not_modified(&headers(&[("If-Modified-Since", "Sun, 06 Nov 1994 08:49:37 GMT")]), ETAG, None));
+        assert!(!is_not_modified(&headers(&[("If-Modified-Since", "whenever")]), ETAG, Some(modified())));
+    }
+
+    #[test]
+    fn if_none_match_wins() {
+        // a date that would match is ignored when the tag doesnt
+        let stale = headers(&[("If-None-Match", "\"old\""), ("If-Modified-Since", "Sun, 06 Nov 1994 08:49:37 GMT")]);
+        assert!(!is_not_modified(&stale, ETAG, Some(modified())));
+        // and the other way round
+        let fresh = headers(&[("If-None-Match", ETAG), ("If-Modified-Since", "Sat, 01 Jan 1994 00:00:00 GMT")]);
+        assert!(is_not_modified(&fresh, ETAG, Some(modified())));
+    }
+
+    #[test]
+    fn if_range() {
+        assert!(if_range_matches(&headers(&[]), Some(ETAG), Some(modified())));
+        assert!(if_range_matches(&headers(&[("If-Range", ETAG)]), Some(ETAG), None));
+        assert!(!if_range_matches(&headers(&[("If-Range", "\"other\"")]), Some(ETAG), None));
+        // has to be a strong match
+        assert!(!if_range_matches(&headers(&[("If-Range", "W/\"abc-1\"")]), Some(ETAG), None));
+        assert!(!if_range_matches(&headers(&[("If-Range", ETAG)]), None, None));
+
+        // dates have to be exactly Last-Modified
+        assert!(if_range_matches(&headers(&[("If-Range", "Sun, 06 Nov 1994 08:49:37 GMT")]), Some(ETAG), Some(modified())));
+        assert!(!if_range_matches(&headers(&[("If-Range", "Sun, 06 Nov 1994 08:49:38 GMT")]), Some(ETAG), Some(modified())));
+        assert!(!if_range_matches(&headers(&[("If-Range", "Sun, 06 Nov 1994 08:49:37 GMT")]), Some(ETAG), None));
+        assert!(!if_range_matches(&headers(&[("If-Range", "garbage")]), Some(ETAG), Some(modified())));
+    }
+}
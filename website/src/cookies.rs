@@ -0,0 +1,414 @@
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use openssl::base64;
+use openssl::hash::MessageDigest;
+use openssl::memcmp;
+use openssl::pkey::PKey;
+use openssl::rand::rand_bytes;
+use openssl::sign::Signer;
+use openssl::symm::{decrypt_aead, encrypt_aead, Cipher};
+use crate::headers::Headers;
+use crate::types::{is_token_char, turn_system_time_to_http_date};
+
+// cookies, RFC 6265
+// the client sends them all in Cookie headers as name=value pairs seperated by "; "
+// and the server sets them one Set-Cookie header at a time
+
+// every cookie in the Cookie headers in the order they were sent
+// when a name shows up twice the first one is the one with the most specific path
+pub fn parse(headers: &Headers) -> Vec<(String, String)> {
+    headers.get_all("Cookie")
+        .flat_map(|header| header.split(';'))
+        .filter_map(|pair| {
+            let (name, value) = pair.trim().split_once('=')?;
+            let name = name.trim();
+            if name.is_empty() {
+                return None;
+            }
+            let value = value.trim();
+            // quotes around the value arent part of it
+            let value = value.strip_prefix('"').and_then(|v| v.strip_suffix('"')).unwrap_or(value);
+            Some((name.to_string(), value.to_string()))
+        })
+        .collect()
+}
+
+pub fn get(headers: &Headers, name: &str) -> Option<String> {
+    parse(headers).into_iter()
+        .find(|(cookie, _)| cookie == name)
+        .map(|(_, value)| value)
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SameSite {
+    Strict,
+    Lax,
+    None,
+}
+
+impl std::fmt::Display for SameSite {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Strict => write!(f, "Strict"),
+            Self::Lax => write!(f, "Lax"),
+            Self::None => write!(f, "None"),
+        }
+    }
+}
+
+// one Set-Cookie header, built up like
+//    SetCookie::new("theme", "dark")
+//        .path("/")
+//        .max_age(Duration::from_secs(60 * 60 * 24 * 365))
+//        .same_site(SameSite::Lax)
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SetCookie {
+    name: String,
+    value: String,
+    expires: Option<SystemTime>,
+    max_age: Option<Duration>,
+    domain: Option<String>,
+    path: Option<String>,
+    secure: bool,
+    http_only: bool,
+    same_site: Option<SameSite>,
+}
+
+impl SetCookie {
+    pub fn new(name: &str, value: &str) -> Self {
+        Self {
+            name: name.to_string(),
+            value: value.to_string(),
+            expires: None,
+            max_age: None,
+            domain: None,
+            path: None,
+            secure: false,
+            http_only: false,
+            same_site: None,
+        }
+    }
+
+    // tells the browser to throw the cookie away, the path and domain have to match the ones it was set with
+    pub fn removal(name: &str) -> Self {
+        Self::new(name, "")
+            .expires(UNIX_EPOCH)
+            .max_age(Duration::ZERO)
+    }
+
+    pub fn expires(mut self, expires: SystemTime) -> Self {
+        self.expires = Some(expires);
+        self
+    }
+
+    // wins over Expires in every browser that understands it
+    pub fn max_age(mut self, max_age: Duration) -> Self {
+        self.max_age = Some(max_age);
+        self
+    }
+
+    pub fn domain(mut self, domain: &str) -> Self {
+        self.domain = Some(domain.to_string());
+        self
+    }
+
+    pub fn path(mut self, path: &str) -> Self {
+        self.path = Some(path.to_string());
+        self
+    }
+
+    pub fn secure(mut self, secure: bool) -> Self {
+        self.secure = secure;
+        self
+    }
+
+    pub fn http_only(mut self, http_only: bool) -> Self {
+        self.http_only = http_only;
+        self
+    }
+
+    // browsers drop SameSite=None cookies that arent Secure so that turns Secure on as well
+    pub fn same_site(mut self, same_site: SameSite) -> Self {
+        self.same_site = Some(same_site);
+        if same_site == SameSite::None {
+            self.secure = true;
+        }
+        self
+    }
+
+    pub fn get_name(&self) -> &str {
+        &self.name
+    }
+
+    pub fn get_value(&self) -> &str {
+        &self.value
+    }
+
+    // a name or value that would break the header or get cut short by the browser
+    pub fn is_valid(&self) -> bool {
+        let attribute_ok = |attribute: &Option<String>| attribute.as_ref()
+            .is_none_or(|value| !value.is_empty() && value.bytes().all(|c| !c.is_ascii_control() && c != b';'));
+
+        !self.name.is_empty()
+            && self.name.bytes().all(is_token_char)
+            && self.value.bytes().all(is_cookie_octet)
+            && attribute_ok(&self.domain)
+            && attribute_ok(&self.path)
+    }
+}
+
+impl std::fmt::Display for SetCookie {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}={}", self.name, self.value)?;
+        if let Some(expires) = self.expires {
+            write!(f, "; Expires={}", turn_system_time_to_http_date(expires))?;
+        }
+        if let Some(max_age) = self.max_age {
+            write!(f, "; Max-Age={}", max_age.as_secs())?;
+        }
+        if let Some(domain) = &self.domain {
+            write!(f, "; Domain={}", domain)?;
+        }
+        if let Some(path) = &self.path {
+            write!(f, "; Path={}", path)?;
+        }
+        if self.secure {
+            write!(f, "; Secure")?;
+        }
+        if self.http_only {
+            write!(f, "; HttpOnly")?;
+        }
+        if let Some(same_site) = self.same_site {
+            write!(f, "; SameSite={}", same_site)?;
+        }
+        Ok(())
+    }
+}
+
+// what can go in a cookie value without quoting, no spaces, quotes, commas, semicolons or backslashes
+fn is_cookie_octet(c: u8) -> bool {
+    matches!(c, 0x21 | 0x23..=0x2b | 0x2d..=0x3a | 0x3c..=0x5b | 0x5d..=0x7e)
+}
+
+// the server secret for cookies the client shouldnt be able to change (signed) or read (encrypted)
+// the same secret has to be used every time the server starts or every old cookie stops working
+pub struct CookieKey {
+    signing: Vec<u8>,
+    encryption: Vec<u8>,
+}
+
+impl std::fmt::Debug for CookieKey {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "CookieKey(..)")
+    }
+}
+
+impl CookieKey {
+    // the secret should be at least 32 random bytes, the two keys are worked out from it
+    // so a signed cookie can never be passed off as an encrypted one
+    pub fn new(secret: &[u8]) -> Result<Self, String> {
+        if secret.len() < 32 {
+            return Err(String::from("the cookie secret needs to be at least 32 bytes"));
+        }
+
+        Ok(Self {
+            signing: hmac(secret, b"bottomless-site cookie signing").map_err(|e| e.to_string())?,
+            encryption: hmac(secret, b"bottomless-site cookie encryption").map_err(|e| e.to_string())?,
+        })
+    }
+
+    // a random key, cookies made with it stop working when the server restarts
+    pub fn generate() -> Result<Self, String> {
+        let mut secret = [0; 32];
+        rand_bytes(&mut secret).map_err(|e| e.to_string())?;
+        Self::new(&secret)
+    }
+
+    // the value stays readable and gets the signature added on the end
+    pub fn sign(&self, mut cookie: SetCookie) -> SetCookie {
+        let signature = match hmac(&self.signing, format!("{}={}", cookie.name, cookie.value).as_bytes()) {
+            Ok(signature) => signature,
+            // an invalid cookie never gets sent so a broken signature cant get out either
+            Err(_) => return SetCookie::new("", ""),
+        };
+        cookie.value = format!("{}.{}", cookie.value, base64::encode_block(&signature));
+        cookie
+    }
+
+    // the value from a signed cookie, None if it was changed or was signed with a different key
+    // the name is part of the signature so a value cant be moved over to another cookie
+    pub fn verify(&self, name: &str, value: &str) -> Option<String> {
+        let (value, signature) = value.rsplit_once('.')?;
+        let signature = base64::decode_block(signature).ok()?;
+        let expected = hmac(&self.signing, format!("{}={}", name, value).as_bytes()).ok()?;
+
+        if signature.len() != expected.len() || !memcmp::eq(&signature, &expected) {
+            return None;
+        }
+        Some(value.to_string())
+    }
+
+    // the value gets replaced with AES-256-GCM of it, the name is checked along with it like verify
+    pub fn encrypt(&self, mut cookie: SetCookie) -> SetCookie {
+        let mut nonce = [0; 12];
+        let mut tag = [0; 16];
+        let sealed = rand_bytes(&mut nonce)
+            .and_then(|_| encrypt_aead(Cipher::aes_256_gcm(), &self.encryption, Some(&nonce), cookie.name.as_bytes(), cookie.value.as_bytes(), &mut tag));
+
+        let ciphertext = match sealed {
+            Ok(ciphertext) => ciphertext,
+            Err(_) => return SetCookie::new("", ""),
+        };
+        cookie.value = base64::encode_block(&[&nonce[..], &ciphertext, &tag].concat());
+        cookie
+    }
+
+    pub fn decrypt(&self, name: &str, value: &str) -> Option<String> {
+        let sealed = base64::decode_block(value).ok()?;
+        if sealed.len() < 12 + 16 {
+            return None;
+        }
+        let (nonce, rest) = sealed.split_at(12);
+        let (ciphertext, tag) = rest.split_at(rest.len() - 16);
+
+        let plain = decrypt_aead(Cipher::aes_256_gcm(), &self.encryption, Some(nonce), name.as_bytes(), ciphertext, tag).ok()?;
+        String::from_utf8(plain).ok()
+    }
+}
+
+fn hmac(key: &[u8], data: &[u8]) -> Result<Vec<u8>, openssl::error::ErrorStack> {
+    let key = PKey::hmac(key)?;
+    let mut signer = Signer::new(MessageDigest::sha256(), &key)?;
+    signer.update(data)?;
+    signer.sign_to_vec()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn key() -> CookieKey {
+        CookieKey::new(&[7; 32]).unwrap()
+    }
+
+    #[test]
+    fn parsing_the_cookie_header() {
+        let mut headers = Headers::new();
+        headers.insert("Cookie", "theme=dark; session=\"abc\"; =nameless; broken");
+        headers.insert("Cookie", "theme=light");
+
+        assert_eq!(parse(&headers), [
+            (String::from("theme"), String::from("dark")),
+            (String::from("session"), String::from("abc")),
+            (String::from("theme"), String::from("light")),
+        ]);
+        assert_eq!(get(&headers, "theme").as_deref(), Some("dark"));
+        assert_eq!(get(&headers, "missing"), None);
+    }
+
+    #[test]
+    fn set_cookie_rendering() {
+        let cookie = SetCookie::new("theme", "dark")
+            .path("/")
+            .domain("example.com")
+            .max_age(Duration::from_secs(3600))
+            .http_only(true)
+            .same_site(SameSite::Lax);
+        assert_eq!(cookie.to_string(), "theme=dark; Max-Age=3600; Domain=example.com; Path=/; HttpOnly; SameSite=Lax");
+
+        // SameSite=None has to be Secure too
+        assert_eq!(SetCookie::new("a", "b").same_site(SameSite::None).to_string(), "a=b; Secure; SameSite=None");
+        assert_eq!(SetCookie::removal("theme").to_string(), "theme=; Expires=Thu, 01 Jan 1970 00:00:00 GMT; Max-Age=0");
+    }
+
+    #[test]
+    fn set_cookie_validity() {
+        assert!(SetCookie::new("theme", "dark").is_valid());
+        assert!(!SetCookie::new("", "dark").is_valid());
+        assert!(!SetCookie::new("the me", "dark").is_valid());
+        assert!(!SetCookie::new("theme", "da;rk").is_valid());
+        assert!(!SetCookie::new("theme", "da rk").is_valid());
+        assert!(!SetCookie::new("theme", "dark").path("/a;b").is_valid());
+        assert!(!SetCookie::new("theme", "dark").domain("").is_valid());
+    }
+
+    #[test]
+    fn short_secrets_are_refused() {
+        assert!(CookieKey::new(&[7; 31]).is_err());
+    }
+
+    #[test]
+    fn signed_round_trip() {
+        let key = key();
+        let cookie = key.sign(SetCookie::new("user", "alice"));
+        assert!(cookie.is_valid());
+        assert!(cookie.get_value().starts_with("alice."));
+        assert_eq!(key.verify("user", cookie.get_value()).as_deref(), Some("alice"));
+    }
+
+    #[test]
+    fn signed_tampering_is_caught() {
+        let key = key();
+        let value = key.sign(SetCookie::new("user", "alice")).get_value().to_string();
+        let (_, signature) = value.rsplit_once('.').unwrap();
+
+        // a changed value with the old signature
+        assert_eq!(key.verify("user", &format!("mallory.{}", signature)), None);
+        // a changed signature
+        let mut bad = base64::decode_block(signature).unwrap();
+        bad[0] ^= 1;
+        assert_eq!(key.verify("user", &format!("alice.{}", base64::encode_block(&bad))), None);
+        // moved to another cookie
+        assert_eq!(key.verify("admin", &value), None);
+        // signed with another key
+        assert_eq!(CookieKey::new(&[8; 32]).unwrap().verify("user", &value), None);
+    }
+
+    #[test]
+    fn signed_garbage_doesnt_panic() {
+        let key = key();
+        for value in ["", "alice", "alice.", "alice.!!!not base64", "alice.YWJj", "."] {
+            assert_eq!(key.verify("user", value), None, "{value}");
+        }
+    }
+
+    #[test]
+    fn encrypted_round_trip() {
+        let key = key();
+        let cookie = key.encrypt(SetCookie::new("user", "alice"));
+        assert!(cookie.is_valid());
+        assert!(!cookie.get_value().contains("alice"));
+        assert_eq!(key.decrypt("user", cookie.get_value()).as_deref(), Some("alice"));
+
+        // a fresh nonce every time
+        let again = key.encrypt(SetCookie::new("user", "alice"));
+        assert_ne!(cookie.get_value(), again.get_value());
+    }
+
+    #[test]
+    fn encrypted_tampering_is_caught() {
+        let key = key();
+        let value = key.encrypt(SetCookie::new("user", "alice")).get_value().to_string();
+        let sealed = base64::decode_block(&value).unwrap();
+
+        // a flipped bit in the ciphertext and in the tag
+        for i in [12, sealed.len() - 1] {
+            let mut bad = sealed.clone();
+            bad[i] ^= 1;
+            assert_eq!(key.decrypt("user", &base64::encode_block(&bad)), None);
+        }
+        // the name is the associated data so it cant be moved to another cookie
+        assert_eq!(key.decrypt("admin", &value), None);
+        assert_eq!(CookieKey::new(&[8; 32]).unwrap().decrypt("user", &value), None);
+    }
+
+    #[test]
+    fn encrypted_garbage_doesnt_panic() {
+        let key = key();
+        let value = key.encrypt(SetCookie::new("user", "alice")).get_value().to_string();
+        let short = base64::encode_block(&[0; 27]);
+
+        for value in ["", "!!!not base64", &short, &value[..value.len() - 4]] {
+            assert_eq!(key.decrypt("user", value), None, "{value}");
+        }
+    }
+}
@@ -0,0 +1,115 @@
+use std::fs;
+use std::path::PathBuf;
+use std::time::SystemTime;
+use openssl::rand::rand_bytes;
+use crate::headers::Headers;
+use crate::json::Json;
+use crate::negotiate::negotiate;
+use crate::types::{ContentType, Response, reason_phrase, turn_system_time_to_http_date};
+
+// turns the bare plain text error responses into something nicer for whoever is going to read it,
+// an html page for browsers and plain text or JSON for anything calling the apis
+
+// for when the templates folder is missing or empty
+const FALLBACK_TEMPLATE: &str = "<!DOCTYPE html>\n<html lang=\"en\">\n<head>\n    <meta charset=\"UTF-8\">\n    <title>{{status}} {{reason}}</title>\n</head>\n<body>\n    <h1>{{status}}</h1>\n    <h2>{{reason}}</h2>\n    <p>{{message}}</p>\n    <p>request id: {{request_id}}</p>\n</body>\n</html>\n";
+
+// the first one is used when the client doesnt say or nothing it asks for fits
+const PAGE_FORMATS: &[ContentType] = &[ContentType::Html, ContentType::PlainText, ContentType::Json];
+const API_FORMATS: &[ContentType] = &[ContentType::PlainText, ContentType::Json, ContentType::Html];
+
+// templates are read from dir every time so they can be edited without a restart
+// {code}.html is used for that status and error.html for everything else,
+// {{status}}, {{reason}}, {{message}} and {{request_id}} get filled in
+#[derive(Debug, Clone)]
+pub struct ErrorPages {
+    dir: PathBuf,
+}
+
+impl ErrorPages {
+    pub fn new(dir: impl Into<PathBuf>) -> Self {
+        Self {
+            dir: dir.into(),
+        }
+    }
+
+    // has to be worked out before the request is handed to whatever answers it
+    // use an empty Headers when the request couldnt even be read
+    pub fn pick_format(headers: &Headers, path: &str) -> ContentType {
+        let offered = if path.starts_with("/api/") {
+            API_FORMATS
+        } else {
+            PAGE_FORMATS
+        };
+        negotiate(headers, offered).unwrap_or(offered[0])
+    }
+
+    // only touches error responses that still have the plain text body they were made with,
+    // anything a handler already gave a real body is sent as is
+    pub fn render(&self, mut response: Response, format: ContentType) -> Response {
+        let code = response.get_code();
+        if code < 400 || !matches!(response.get_content_type(), ContentType::PlainText) {
+            return response;
+        }
+        let message = match response.get_data() {
+            Some(data) => String::from_utf8_lossy(data).trim().to_string(),
+            None => return response,
+        };
+
+        let request_id = new_request_id();
+        let reason = reason_phrase(code);
+        println!("Error: {} {} ({}) sent with request id {}, occured at: {}", code, reason, message, request_id, turn_system_time_to_http_date(SystemTime::now()));
+
+        let data = match format {
+            ContentType::Html => {
+                let template = self.load_template(code);
+                fill_template(&template, code, reason, &message, &request_id).into_bytes()
+            },
+            ContentType::Json => Json::Object(vec![
+                (String::from("status"), Json::from(code as u64)),
+                (String::from("reason"), Json::from(reason)),
+                (String::from("message"), Json::from(message)),
+                (String::from("requestId"), Json::from(request_id.as_str())),
+            ]).to_string().into_bytes(),
+            _ => format!("{}\nrequest id: {}\n", message, request_id).into_bytes(),
+        };
+
+        response.set_data(format, data);
+        response.set_header("X-Request-Id", &request_id);
+        response.set_header("Vary", "Accept");
+        response
+    }
+
+    fn load_template(&self, code: u16) -> String {
+        fs::read_to_string(self.dir.join(format!("{}.html", code)))
+            .or_else(|_| fs::read_to_string(self.dir.join("error.html")))
+            .unwrap_or_else(|_| FALLBACK_TEMPLATE.to_string())
+    }
+}
+
+fn fill_template(template: &str, code: u16, reason: &str, message: &str, request_id: &str) -> String {
+    template
+        .replace("{{status}}", &code.to_string())
+        .replace("{{reason}}", &escape_html(reason))
+        .replace("{{message}}", &escape_html(message))
+        .replace("{{request_id}}", &escape_html(request_id))
+}
+
+// the message can have bits of the request in it so it cant go in the page as is
+fn escape_html(text: &str) -> String {
+    text.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+        .replace('\'', "&#39;")
+}
+
+// 16 hex digits, enough to find the log line for an error someone reports
+fn new_request_id() -> String {
+    let mut id = [0_u8; 8];
+    if rand_bytes(&mut id).is_err() {
+        // not having a random id isnt worth failing the response over
+        let nanos = SystemTime::now().duration_since(SystemTime::UNIX_EPOCH).map(|time| time.as_nanos()).unwrap_or(0);
+        return format!("{:016x}", nanos as u64);
+    }
+    id.iter().map(|byte| format!("{:02x}", byte)).collect()
+}
@@ -0,0 +1,295 @@
+use crate::headers::Headers;
+use crate::mime::{MediaType, parse_params};
+use crate::types::{HTTPError, percent_decode};
+
+// the two ways a browser sends an html form,
+// application/x-www-form-urlencoded which looks like a query string and
+// multipart/form-data which is needed as soon as a file is in it, RFC 7578
+
+// the header of a single part, these are a couple of short lines so anything bigger is junk
+const MAX_PART_HEADER: usize = 8 * 1024;
+
+// the fields of a form in the order they were sent, a name can show up more than once
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct Form {
+    fields: Vec<(String, String)>,
+}
+
+impl Form {
+    // name=value&name=value, + is a space like in a query string
+    pub fn from_urlencoded(data: &[u8]) -> Result<Self, HTTPError> {
+        let text = match std::str::from_utf8(data) {
+            Ok(text) => text,
+            Err(_) => return Err(HTTPError::InvalidForm),
+        };
+
+        let mut fields = Vec::new();
+        for field in text.split('&').filter(|field| !field.is_empty()) {
+            let (name, value) = field.split_once('=').unwrap_or((field, ""));
+            let name = percent_decode(name, true).map_err(|_| HTTPError::InvalidForm)?;
+            let value = percent_decode(value, true).map_err(|_| HTTPError::InvalidForm)?;
+            fields.push((name, value));
+        }
+
+        Ok(Self { fields })
+    }
+
+    pub fn from_fields(fields: &[(&str, &str)]) -> Self {
+        Self {
+            fields: fields.iter().map(|(name, value)| (name.to_string(), value.to_string())).collect(),
+        }
+    }
+
+    // the first value sent for the name
+    pub fn get(&self, name: &str) -> Option<&str> {
+        self.fields.iter()
+            .find(|(key, _)| key == name)
+            .map(|(_, value)| value.as_str())
+    }
+
+    // every value sent for the name in order
+    pub fn get_all<'a>(&'a self, name: &'a str) -> impl Iterator<Item = &'a str> + 'a {
+        self.fields.iter()
+            .filter(move |(key, _)| key == name)
+            .map(|(_, value)| value.as_str())
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = (&str, &str)> {
+        self.fields.iter().map(|(key, value)| (key.as_str(), value.as_str()))
+    }
+}
+
+// one part of a multipart/form-data body, a plain field or an uploaded file
+#[derive(Debug, Clone)]
+pub struct Part {
+    name: String,
+    filename: Option<String>,
+    content_type: Option<MediaType>,
+    headers: Headers,
+    data: Vec<u8>,
+}
+
+impl Part {
+    pub fn get_name(&self) -> &str {
+        &self.name
+    }
+
+    // only there for file uploads, this is whatever the client said so dont put it in a path as is
+    pub fn get_filename(&self) -> Option<&str> {
+        self.filename.as_deref()
+    }
+
+    // None when the client didnt say, which means text/plain
+    pub fn get_content_type(&self) -> Option<&MediaType> {
+        self.content_type.as_ref()
+    }
+
+    pub fn headers(&self) -> &Headers {
+        &self.headers
+    }
+
+    pub fn get_data(&self) -> &[u8] {
+        &self.data
+    }
+
+    pub fn is_file(&self) -> bool {
+        self.filename.is_some()
+    }
+
+    // the data as text, None if it isnt utf-8
+    pub fn text(&self) -> Option<&str> {
+        std::str::from_utf8(&self.data).ok()
+    }
+}
+
+#[derive(Debug, Default, Clone)]
+pub struct Multipart {
+    parts: Vec<Part>,
+}
+
+impl Multipart {
+    // the boundary comes from the Content-Type the body was sent with
+    pub fn parse(data: &[u8], media_type: &MediaType) -> Result<Self, HTTPError> {
+        let boundary = match media_type.boundary() {
+            // RFC 2046 caps it at 70 characters
+            Some(boundary) if !boundary.is_empty() && boundary.len() <= 70 => boundary,
+            _ => return Err(HTTPError::InvalidForm),
+        };
+        let delimiter = format!("--{}", boundary).into_bytes();
+        // every delimiter but the first starts on a new line
+        let next_delimiter = [b"\r\n", &delimiter[..]].concat();
+
+        // anything before the first delimiter is a preamble and gets ignored
+        let mut rest = if data.starts_with(&delimiter) {
+            &data[delimiter.len()..]
+        } else {
+            match find(data, &next_delimiter) {
+                Some(at) => &data[at + next_delimiter.len()..],
+                None => return Err(HTTPError::InvalidForm),
+            }
+        };
+
+        let mut parts = Vec::new();
+        loop {
+            // -- after a delimiter means that was the last one, anything after it is an epilogue
+            if rest.starts_with(b"--") {
+                return Ok(Self { parts });
+            }
+            rest = skip_line_end(rest)?;
+
+            let end = match find(rest, &next_delimiter) {
+                Some(end) => end,
+                None => return Err(HTTPError::InvalidForm),
+            };
+            parts.push(parse_part(&rest[..end])?);
+            rest = &rest[end + next_delimiter.len()..];
+        }
+    }
+
+    pub fn parts(&self) -> &[Part] {
+        &self.parts
+    }
+
+    // the first part sent with the name
+    pub fn get(&self, name: &str) -> Option<&Part> {
+        self.parts.iter().find(|part| part.name == name)
+    }
+
+    // the value of a plain field as text
+    pub fn get_text(&self, name: &str) -> Option<&str> {
+        self.get(name).filter(|part| !part.is_file()).and_then(Part::text)
+    }
+
+    pub fn files(&self) -> impl Iterator<Item = &Part> {
+        self.parts.iter().filter(|part| part.is_file())
+    }
+}
+
+// a part is a header like a request has, a blank line and then the data
+fn parse_part(part: &[u8]) -> Result<Part, HTTPError> {
+    let mut reader = part;
+    let headers = Headers::read_from(&mut reader, MAX_PART_HEADER).map_err(|_| HTTPError::InvalidForm)?;
+    let data = reader.to_vec();
+
+    // Content-Disposition: form-data; name="field"; filename="cat.png"
+    let disposition = headers.get("Content-Disposition").ok_or(HTTPError::InvalidForm)?;
+    let (kind, params) = disposition.split_once(';').unwrap_or((disposition, ""));
+    if !kind.trim().eq_ignore_ascii_case("form-data") {
+        return Err(HTTPError::InvalidForm);
+    }
+    let params = parse_params(params).map_err(|_| HTTPError::InvalidForm)?;
+    let param = |name: &str| params.iter().find(|(key, _)| key == name).map(|(_, value)| value.clone());
+
+    let name = param("name").ok_or(HTTPError::InvalidForm)?;
+    let filename = param("filename");
+    let content_type = match headers.get("Content-Type") {
+        Some(value) => Some(value.parse::<MediaType>().map_err(|_| HTTPError::InvalidForm)?),
+        None => None,
+    };
+
+    Ok(Part {
+        name,
+        filename,
+        content_type,
+        headers,
+        data,
+    })
+}
+
+// the rest of the delimiter line, some clients leave whitespace after the boundary
+fn skip_line_end(data: &[u8]) -> Result<&[u8], HTTPError> {
+    let mut i = 0;
+    while i < data.len() && (data[i] == b' ' || data[i] == b'\t') {
+        i += 1;
+    }
+    match &data[i..] {
+        [b'\r', b'\n', rest @ ..] => Ok(rest),
+        [b'\n', rest @ ..] => Ok(rest),
+        _ => Err(HTTPError::InvalidForm),
+    }
+}
+
+fn find(haystack: &[u8], needle: &[u8]) -> Option<usize> {
+    haystack.windows(needle.len()).position(|window| window == needle)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn multipart(body: &str) -> Result<Multipart, HTTPError> {
+        Multipart::parse(body.as_bytes(), &"multipart/form-data; boundary=XyZ".parse().unwrap())
+    }
+
+    #[test]
+    fn urlencoded() {
+        let form = Form::from_urlencoded(b"name=J%C3%B6rg+Smith&empty=&flag&&name=a%2Bb%26c%3D").unwrap();
+        assert_eq!(form.get("name"), Some("Jörg Smith"));
+        assert_eq!(form.get_all("name").collect::<Vec<&str>>(), ["Jörg Smith", "a+b&c="]);
+        assert_eq!(form.get("empty"), Some(""));
+        assert_eq!(form.get("flag"), Some(""));
+        assert_eq!(form.iter().count(), 4);
+
+        // broken escapes and escapes that arent utf-8
+        for data in [&b"a=%"[..], b"a=%2", b"a=%zz", b"a=%ff", b"a=\xff"] {
+            assert!(matches!(Form::from_urlencoded(data), Err(HTTPError::InvalidForm)), "{data:?}");
+        }
+    }
+
+    #[test]
+    fn multipart_fields_and_files() {
+        let form = multipart(concat!(
+            "preamble\r\n",
+            "--XyZ\r\n",
+            "Content-Disposition: form-data; name=\"title\"\r\n\r\n",
+            "hello\r\n",
+            "--XyZ  \r\n",
+            "Content-Disposition: form-data; name=\"upload\"; filename=\"a;b.txt\"\r\n",
+            "Content-Type: text/plain\r\n\r\n",
+            "line one\r\nXyZ --XyZ\r\n-XyZ\r\n--Xy\r\n",
+            "--XyZ--\r\n",
+            "epilogue",
+        )).unwrap();
+
+        assert_eq!(form.parts().len(), 2);
+        assert_eq!(form.get_text("title"), Some("hello"));
+        let file = form.files().next().unwrap();
+        assert_eq!(file.get_name(), "upload");
+        assert_eq!(file.get_filename(), Some("a;b.txt"));
+        assert_eq!(file.get_content_type().map(MediaType::essence).as_deref(), Some("text/plain"));
+        // the boundary only counts at the start of a line after --, anything else like it stays in the data
+        assert_eq!(file.get_data(), b"line one\r\nXyZ --XyZ\r\n-XyZ\r\n--Xy");
+        assert_eq!(form.get_text("upload"), None);
+    }
+
+    #[test]
+    fn multipart_broken() {
+        for body in [
+            // no boundary at all
+            "Content-Disposition: form-data; name=\"a\"\r\n\r\nvalue",
+            // never closed
+            "--XyZ\r\nContent-Disposition: form-data; name=\"a\"\r\n\r\nvalue",
+            "--XyZ\r\nContent-Disposition: form-data; name=\"a\"\r\n\r\nvalue\r\n--Xy",
+            // junk after the boundary
+            "--XyZjunk\r\nContent-Disposition: form-data; name=\"a\"\r\n\r\nvalue\r\n--XyZ--",
+            // no Content-Disposition, the wrong one or one without a name
+            "--XyZ\r\nContent-Type: text/plain\r\n\r\nvalue\r\n--XyZ--",
+            "--XyZ\r\nContent-Disposition: attachment; name=\"a\"\r\n\r\nvalue\r\n--XyZ--",
+            "--XyZ\r\nContent-Disposition: form-data; filename=\"a\"\r\n\r\nvalue\r\n--XyZ--",
+        ] {
+            assert!(matches!(multipart(body), Err(HTTPError::InvalidForm)), "{body:?}");
+        }
+
+        // the boundary has to be there and fit in 70 characters
+        let long = format!("multipart/form-data; boundary={}", "a".repeat(71));
+        for media_type in ["multipart/form-data", "multipart/form-data; boundary=\"\"", &long] {
+            let media_type = media_type.parse().unwrap();
+            assert!(matches!(Multipart::parse(b"--a--", &media_type), Err(HTTPError::InvalidForm)), "{media_type}");
+        }
+    }
+
+    #[test]
+    fn multipart_empty() {
+        assert!(multipart("--XyZ--\r\n").unwrap().parts().is_empty());
+    }
+}
@@ -0,0 +1,258 @@
+use std::io::{BufRead, Read};
+use crate::types::{HTTPError, is_token_char};
+
+// all the header fields from a request in the order they came in
+// names are matched without caring about case and a name can show up more than once
+#[derive(Debug, Default, Clone)]
+pub struct Headers {
+    fields: Vec<(String, String)>,
+}
+
+impl Headers {
+    pub fn new() -> Self {
+        Self {
+            fields: Vec::new(),
+        }
+    }
+
+    // reads field lines one at a time untill the empty line that ends the header
+    // lines can end in either CRLF or a bare LF
+    // more than max_size bytes all together is a HeaderTooLarge
+    pub fn read_from<R: BufRead>(reader: &mut R, max_size: usize) -> Result<Self, HTTPError> {
+        let mut headers = Self::new();
+        let mut line = Vec::new();
+        let mut remaining = max_size;
+
+        loop {
+            line.clear();
+            if remaining == 0 {
+                return Err(HTTPError::HeaderTooLarge);
+            }
+            match reader.by_ref().take(remaining as u64).read_until(b'\n', &mut line) {
+                Ok(0) => return Err(HTTPError::InvalidHeader), // hung up before the header ended
+                // ran out of room before the line ended
+                Ok(read) if read == remaining && !line.ends_with(b"\n") => return Err(HTTPError::HeaderTooLarge),
+                Ok(read) => remaining -= read,
+                Err(e) => return Err(HTTPError::from_io(&e, HTTPError::InvalidHeader)),
+            }
+
+            let line = trim_line_ending(&line)?;
+            if line.is_empty() {
+                return Ok(headers);
+            }
+
+            // obs-fold, a line starting with whitespace that continues the last value
+            // RFC 9112 lets us swap it for a space or turn the request down, a proxy in front
+            // that reads it the other way could see different headers than we do so its a 400
+            if line[0] == b' ' || line[0] == b'\t' {
+                return Err(HTTPError::InvalidHeader);
+            }
+
+            let (name, value) = parse_field_line(line)?;
+            headers.fields.push((name, value));
+        }
+    }
+
+    pub fn insert(&mut self, name: &str, value: &str) {
+        self.fields.push((name.to_owned(), value.to_owned()));
+    }
+
+    // replaces every value for the name with this one
+    pub fn set(&mut self, name: &str, value: &str) {
+        self.remove(name);
+        self.insert(name, value);
+    }
+
+    pub fn remove(&mut self, name: &str) {
+        self.fields.retain(|(key, _)| !key.eq_ignore_ascii_case(name));
+    }
+
+    // the first value sent for the name
+    pub fn get(&self, name: &str) -> Option<&str> {
+        self.fields.iter()
+            .find(|(key, _)| key.eq_ignore_ascii_case(name))
+            .map(|(_, value)| value.as_str())
+    }
+
+    // every value sent for the name in order
+    pub fn get_all<'a>(&'a self, name: &'a str) -> impl Iterator<Item = &'a str> + 'a {
+        self.fields.iter()
+            .filter(move |(key, _)| key.eq_ignore_ascii_case(name))
+            .map(|(_, value)| value.as_str())
+    }
+
+    // repeated fields joined into one comma seperated list like RFC 9110 allows
+    // this is wrong for Set-Cookie but clients dont send that one
+    pub fn get_combined(&self, name: &str) -> Option<String> {
+        let values = self.get_all(name).collect::<Vec<&str>>();
+        if values.is_empty() {
+            None
+        } else {
+            Some(values.join(", "))
+        }
+    }
+
+    // the comma seperated elements of every value sent for the name
+    pub fn get_list<'a>(&'a self, name: &'a str) -> impl Iterator<Item = &'a str> + 'a {
+        self.get_all(name)
+            .flat_map(|value| value.split(','))
+            .map(str::trim)
+            .filter(|element| !element.is_empty())
+    }
+
+    // checks a comma seperated header like Connection for a token
+    pub fn has_token(&self, name: &str, token: &str) -> bool {
+        self.get_list(name).any(|element| element.eq_ignore_ascii_case(token))
+    }
+
+    pub fn contains(&self, name: &str) -> bool {
+        self.get(name).is_some()
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = (&str, &str)> {
+        self.fields.iter().map(|(key, value)| (key.as_str(), value.as_str()))
+    }
+
+    pub fn len(&self) -> usize {
+        self.fields.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.fields.is_empty()
+    }
+}
+
+// removes the CRLF or LF at the end of a line
+pub(crate) fn trim_line_ending(line: &[u8]) -> Result<&[u8], HTTPError> {
+    let line = match line.strip_suffix(b"\n") {
+        Some(line) => line,
+        // read_until only stops early at the end of the stream
+        None => return Err(HTTPError::InvalidHeader),
+    };
+    Ok(line.strip_suffix(b"\r").unwrap_or(line))
+}
+
+// field-line = field-name ":" OWS field-value OWS
+fn parse_field_line(line: &[u8]) -> Result<(String, String), HTTPError> {
+    let colon = match line.iter().position(|c| *c == b':') {
+        Some(i) => i,
+        None => return Err(HTTPError::InvalidHeader),
+    };
+
+    let name = &line[..colon];
+    // this also rejects whitespace between the name and the colon which RFC 9112 says we must
+    if name.is_empty() || !name.iter().copied().all(is_token_char) {
+        return Err(HTTPError::InvalidHeader);
+    }
+    // only token chars so this cant fail
+    let name = String::from_utf8_lossy(name).into_owned();
+
+    let value = parse_value(&line[colon + 1..])?;
+
+    Ok((name, value))
+}
+
+// strips the optional whitespace around a value and checks what is left
+fn parse_value(value: &[u8]) -> Result<String, HTTPError> {
+    let is_ows = |c: &u8| *c == b' ' || *c == b'\t';
+    let start = value.iter().position(|c| !is_ows(c)).unwrap_or(value.len());
+    let end = value.iter().rposition(|c| !is_ows(c)).map_or(start, |i| i + 1);
+
+    let mut cleaned = Vec::with_capacity(end - start);
+    for c in &value[start..end] {
+        match c {
+            // a bare CR is replaced with a space like RFC 9112 allows
+            b'\r' => cleaned.push(b' '),
+            b'\0' | b'\n' => return Err(HTTPError::InvalidHeader),
+            c if c.is_ascii_control() && *c != b'\t' => return Err(HTTPError::InvalidHeader),
+            c => cleaned.push(*c),
+        }
+    }
+
+    // obs-text isnt utf-8 but there is no point failing the whole request over it
+    Ok(String::from_utf8_lossy(&cleaned).into_owned())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn read(text: &str) -> Result<Headers, HTTPError> {
+        Headers::read_from(&mut text.as_bytes(), 1024)
+    }
+
+    #[test]
+    fn field_lines() {
+        let headers = read("Host: example.com\r\nX-Empty:\r\nBare-Lf:\t a\tb \nAccept: text/html\r\n\r\nbody").unwrap();
+        assert_eq!(headers.len(), 4);
+        assert_eq!(headers.get("host"), Some("example.com"));
+        assert_eq!(headers.get("X-EMPTY"), Some(""));
+        // whitespace around the value goes, inside it stays
+        assert_eq!(headers.get("bare-lf"), Some("a\tb"));
+        assert_eq!(read("A: 1\n\n").unwrap().get("a"), Some("1"));
+        // a bare CR in a value is a space
+        assert_eq!(read("A: 1\r2\r\n\r\n").unwrap().get("a"), Some("1 2"));
+    }
+
+    #[test]
+    fn broken_field_lines() {
+        for text in [
+            // whitespace before the colon
+            "Host : example.com\r\n\r\n",
+            "Host\t: example.com\r\n\r\n",
+            // obs-fold and whitespace before the first field
+            "X-Long: one\r\n two\r\n\r\n",
+            "X-Long: one\r\n\ttwo\r\n\r\n",
+            " Host: example.com\r\n\r\n",
+            // no colon, no name, a name that isnt a token and control characters
+            "Host\r\n\r\n",
+            ": value\r\n\r\n",
+            "Ho(st: example.com\r\n\r\n",
+            "A: nul\0\r\n\r\n",
+            "A: bell\x07\r\n\r\n",
+            // ended before the blank line
+            "Host: example.com\r\n",
+            "Host: example.com",
+        ] {
+            assert!(matches!(read(text), Err(HTTPError::InvalidHeader)), "{text:?}");
+        }
+        assert_eq!(HTTPError::InvalidHeader.get_code(), 400);
+    }
+
+    #[test]
+    fn size_limit() {
+        // the limit counts every line including the blank one at the end
+        let text = "A: 1234\r\n\r\n";
+        assert!(Headers::read_from(&mut text.as_bytes(), text.len()).is_ok());
+        assert!(matches!(Headers::read_from(&mut text.as_bytes(), text.len() - 1), Err(HTTPError::HeaderTooLarge)));
+
+        let long = format!("A: {}\r\n\r\n", "x".repeat(2000));
+        assert!(matches!(read(&long), Err(HTTPError::HeaderTooLarge)));
+        let many = "A: 1\r\n".repeat(300) + "\r\n";
+        assert!(matches!(read(&many), Err(HTTPError::HeaderTooLarge)));
+    }
+
+    #[test]
+    fn repeated_fields_and_lists() {
+        let headers = read("Accept: text/html, ,application/json\r\naccept: */*;q=0.1\r\nConnection: keep-alive, Upgrade\r\n\r\n").unwrap();
+        assert_eq!(headers.get("Accept"), Some("text/html, ,application/json"));
+        assert_eq!(headers.get_all("ACCEPT").count(), 2);
+        assert_eq!(headers.get_combined("accept").as_deref(), Some("text/html, ,application/json, */*;q=0.1"));
+        assert_eq!(headers.get_list("accept").collect::<Vec<&str>>(), ["text/html", "application/json", "*/*;q=0.1"]);
+        assert!(headers.has_token("connection", "upgrade"));
+        assert!(!headers.has_token("connection", "close"));
+        assert_eq!(headers.get_combined("missing"), None);
+    }
+
+    #[test]
+    fn editing() {
+        let mut headers = Headers::new();
+        headers.insert("Vary", "Accept");
+        headers.insert("vary", "Origin");
+        headers.set("VARY", "*");
+        assert_eq!(headers.get_all("Vary").collect::<Vec<&str>>(), ["*"]);
+        headers.remove("vary");
+        assert!(headers.is_empty());
+        assert!(!headers.contains("Vary"));
+    }
+}
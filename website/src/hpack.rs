@@ -0,0 +1,630 @@
+use std::collections::VecDeque;
+use std::sync::OnceLock;
+
+// HPACK, the header compression HTTP/2 uses, RFC 7541
+
+// every entry costs this much on top of its name and value when working out the table size
+const ENTRY_OVERHEAD: usize = 32;
+
+// RFC 7541 appendix A, index 1 is the first entry
+const STATIC_TABLE: [(&str, &str); 61] = [
+    (":authority", ""),
+    (":method", "GET"),
+    (":method", "POST"),
+    (":path", "/"),
+    (":path", "/index.html"),
+    (":scheme", "http"),
+    (":scheme", "https"),
+    (":status", "200"),
+    (":status", "204"),
+    (":status", "206"),
+    (":status", "304"),
+    (":status", "400"),
+    (":status", "404"),
+    (":status", "500"),
+    ("accept-charset", ""),
+    ("accept-encoding", "gzip, deflate"),
+    ("accept-language", ""),
+    ("accept-ranges", ""),
+    ("accept", ""),
+    ("access-control-allow-origin", ""),
+    ("age", ""),
+    ("allow", ""),
+    ("authorization", ""),
+    ("cache-control", ""),
+    ("content-disposition", ""),
+    ("content-encoding", ""),
+    ("content-language", ""),
+    ("content-length", ""),
+    ("content-location", ""),
+    ("content-range", ""),
+    ("content-type", ""),
+    ("cookie", ""),
+    ("date", ""),
+    ("etag", ""),
+    ("expect", ""),
+    ("expires", ""),
+    ("from", ""),
+    ("host", ""),
+    ("if-match", ""),
+    ("if-modified-since", ""),
+    ("if-none-match", ""),
+    ("if-range", ""),
+    ("if-unmodified-since", ""),
+    ("last-modified", ""),
+    ("link", ""),
+    ("location", ""),
+    ("max-forwards", ""),
+    ("proxy-authenticate", ""),
+    ("proxy-authorization", ""),
+    ("range", ""),
+    ("referer", ""),
+    ("refresh", ""),
+    ("retry-after", ""),
+    ("server", ""),
+    ("set-cookie", ""),
+    ("strict-transport-security", ""),
+    ("transfer-encoding", ""),
+    ("user-agent", ""),
+    ("vary", ""),
+    ("via", ""),
+    ("www-authenticate", ""),
+];
+
+// the code and its length in bits for every byte plus EOS at the end, RFC 7541 appendix B
+const HUFFMAN_CODES: [(u32, u8); 257] = [
+    (0x1ff8, 13), (0x7fffd8, 23), (0xfffffe2, 28), (0xfffffe3, 28), (0xfffffe4, 28),
+    (0xfffffe5, 28), (0xfffffe6, 28), (0xfffffe7, 28), (0xfffffe8, 28), (0xffffea, 24),
+    (0x3ffffffc, 30), (0xfffffe9, 28), (0xfffffea, 28), (0x3ffffffd, 30), (0xfffffeb, 28),
+    (0xfffffec, 28), (0xfffffed, 28), (0xfffffee, 28), (0xfffffef, 28), (0xffffff0, 28),
+    (0xffffff1, 28), (0xffffff2, 28), (0x3ffffffe, 30), (0xffffff3, 28), (0xffffff4, 28),
+    (0xffffff5, 28), (0xffffff6, 28), (0xffffff7, 28), (0xffffff8, 28), (0xffffff9, 28),
+    (0xffffffa, 28), (0xffffffb, 28), (0x14, 6), (0x3f8, 10), (0x3f9, 10), (0xffa, 12),
+    (0x1ff9, 13), (0x15, 6), (0xf8, 8), (0x7fa, 11), (0x3fa, 10), (0x3fb, 10), (0xf9, 8),
+    (0x7fb, 11), (0xfa, 8), (0x16, 6), (0x17, 6), (0x18, 6), (0x0, 5), (0x1, 5), (0x2, 5),
+    (0x19, 6), (0x1a, 6), (0x1b, 6), (0x1c, 6), (0x1d, 6), (0x1e, 6), (0x1f, 6), (0x5c, 7),
+    (0xfb, 8), (0x7ffc, 15), (0x20, 6), (0xffb, 12), (0x3fc, 10), (0x1ffa, 13), (0x21, 6),
+    (0x5d, 7), (0x5e, 7), (0x5f, 7), (0x60, 7), (0x61, 7), (0x62, 7), (0x63, 7), (0x64, 7),
+    (0x65, 7), (0x66, 7), (0x67, 7), (0x68, 7), (0x69, 7), (0x6a, 7), (0x6b, 7), (0x6c, 7),
+    (0x6d, 7), (0x6e, 7), (0x6f, 7), (0x70, 7), (0x71, 7), (0x72, 7), (0xfc, 8), (0x73, 7),
+    (0xfd, 8), (0x1ffb, 13), (0x7fff0, 19), (0x1ffc, 13), (0x3ffc, 14), (0x22, 6), (0x7ffd, 15),
+    (0x3, 5), (0x23, 6), (0x4, 5), (0x24, 6), (0x5, 5), (0x25, 6), (0x26, 6), (0x27, 6), (0x6, 5),
+    (0x74, 7), (0x75, 7), (0x28, 6), (0x29, 6), (0x2a, 6), (0x7, 5), (0x2b, 6), (0x76, 7),
+    (0x2c, 6), (0x8, 5), (0x9, 5), (0x2d, 6), (0x77, 7), (0x78, 7), (0x79, 7), (0x7a, 7),
+    (0x7b, 7), (0x7ffe, 15), (0x7fc, 11), (0x3ffd, 14), (0x1ffd, 13), (0xffffffc, 28),
+    (0xfffe6, 20), (0x3fffd2, 22), (0xfffe7, 20), (0xfffe8, 20), (0x3fffd3, 22), (0x3fffd4, 22),
+    (0x3fffd5, 22), (0x7fffd9, 23), (0x3fffd6, 22), (0x7fffda, 23), (0x7fffdb, 23), (0x7fffdc, 23),
+    (0x7fffdd, 23), (0x7fffde, 23), (0xffffeb, 24), (0x7fffdf, 23), (0xffffec, 24), (0xffffed, 24),
+    (0x3fffd7, 22), (0x7fffe0, 23), (0xffffee, 24), (0x7fffe1, 23), (0x7fffe2, 23), (0x7fffe3, 23),
+    (0x7fffe4, 23), (0x1fffdc, 21), (0x3fffd8, 22), (0x7fffe5, 23), (0x3fffd9, 22), (0x7fffe6, 23),
+    (0x7fffe7, 23), (0xffffef, 24), (0x3fffda, 22), (0x1fffdd, 21), (0xfffe9, 20), (0x3fffdb, 22),
+    (0x3fffdc, 22), (0x7fffe8, 23), (0x7fffe9, 23), (0x1fffde, 21), (0x7fffea, 23), (0x3fffdd, 22),
+    (0x3fffde, 22), (0xfffff0, 24), (0x1fffdf, 21), (0x3fffdf, 22), (0x7fffeb, 23), (0x7fffec, 23),
+    (0x1fffe0, 21), (0x1fffe1, 21), (0x3fffe0, 22), (0x1fffe2, 21), (0x7fffed, 23), (0x3fffe1, 22),
+    (0x7fffee, 23), (0x7fffef, 23), (0xfffea, 20), (0x3fffe2, 22), (0x3fffe3, 22), (0x3fffe4, 22),
+    (0x7ffff0, 23), (0x3fffe5, 22), (0x3fffe6, 22), (0x7ffff1, 23), (0x3ffffe0, 26),
+    (0x3ffffe1, 26), (0xfffeb, 20), (0x7fff1, 19), (0x3fffe7, 22), (0x7ffff2, 23), (0x3fffe8, 22),
+    (0x1ffffec, 25), (0x3ffffe2, 26), (0x3ffffe3, 26), (0x3ffffe4, 26), (0x7ffffde, 27),
+    (0x7ffffdf, 27), (0x3ffffe5, 26), (0xfffff1, 24), (0x1ffffed, 25), (0x7fff2, 19),
+    (0x1fffe3, 21), (0x3ffffe6, 26), (0x7ffffe0, 27), (0x7ffffe1, 27), (0x3ffffe7, 26),
+    (0x7ffffe2, 27), (0xfffff2, 24), (0x1fffe4, 21), (0x1fffe5, 21), (0x3ffffe8, 26),
+    (0x3ffffe9, 26), (0xffffffd, 28), (0x7ffffe3, 27), (0x7ffffe4, 27), (0x7ffffe5, 27),
+    (0xfffec, 20), (0xfffff3, 24), (0xfffed, 20), (0x1fffe6, 21), (0x3fffe9, 22), (0x1fffe7, 21),
+    (0x1fffe8, 21), (0x7ffff3, 23), (0x3fffea, 22), (0x3fffeb, 22), (0x1ffffee, 25),
+    (0x1ffffef, 25), (0xfffff4, 24), (0xfffff5, 24), (0x3ffffea, 26), (0x7ffff4, 23),
+    (0x3ffffeb, 26), (0x7ffffe6, 27), (0x3ffffec, 26), (0x3ffffed, 26), (0x7ffffe7, 27),
+    (0x7ffffe8, 27), (0x7ffffe9, 27), (0x7ffffea, 27), (0x7ffffeb, 27), (0xffffffe, 28),
+    (0x7ffffec, 27), (0x7ffffed, 27), (0x7ffffee, 27), (0x7ffffef, 27), (0x7fffff0, 27),
+    (0x3ffffee, 26), (0x3fffffff, 30),];
+const EOS: u16 = 256;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum HpackError {
+    InvalidInteger,
+    InvalidIndex,
+    InvalidString,
+    InvalidHuffman,
+    InvalidTableSize,
+    TooLarge,
+}
+
+impl std::fmt::Display for HpackError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::InvalidInteger => writeln!(f, "Header block integer was cut off or too big"),
+            Self::InvalidIndex => writeln!(f, "Header block used an index that isnt in the table"),
+            Self::InvalidString => writeln!(f, "Header block string was cut off"),
+            Self::InvalidHuffman => writeln!(f, "Header block had invalid huffman code"),
+            Self::InvalidTableSize => writeln!(f, "Header block asked for a bigger table than allowed"),
+            Self::TooLarge => writeln!(f, "Header block decoded to more than the header size limit"),
+        }
+    }
+}
+
+// turns header blocks back into fields, one of these lives as long as the connection
+// as the dynamic table carries over from one block to the next
+#[derive(Debug)]
+pub struct Decoder {
+    table: VecDeque<(String, String)>,
+    size: usize,
+    max_size: usize,
+    // what we told the client in SETTINGS_HEADER_TABLE_SIZE, it can only ask for less than this
+    limit: usize,
+    // what we told the client in SETTINGS_MAX_HEADER_LIST_SIZE, a small block that points at the same
+    // big table entry over and over would decode to far more than this without it
+    max_list_size: usize,
+}
+
+impl Decoder {
+    pub fn new(limit: usize, max_list_size: usize) -> Self {
+        Self {
+            table: VecDeque::new(),
+            size: 0,
+            max_size: limit,
+            limit,
+            max_list_size,
+        }
+    }
+
+    // field names come back the way they were sent, HTTP/2 only allows lowercase ones anyway
+    // stops as soon as the fields add up to more than max_list_size, the table is out of sync after that
+    // so the connection cant be used anymore
+    pub fn decode(&mut self, block: &[u8]) -> Result<Vec<(String, String)>, HpackError> {
+        let mut fields = Vec::new();
+        let mut list_size = 0;
+        let mut rest = block;
+
+        while let Some(&first) = rest.first() {
+            let field = if first & 0x80 != 0 {
+                // indexed field, the whole thing is already in a table
+                let (index, after) = decode_integer(rest, 7)?;
+                rest = after;
+                self.get(index)?
+            } else if first & 0x40 != 0 {
+                // literal that gets added to the dynamic table
+                let (field, after) = self.decode_literal(rest, 6)?;
+                rest = after;
+                self.insert(field.clone());
+                field
+            } else if first & 0x20 != 0 {
+                // table size update, these can only come before any fields
+                let (size, after) = decode_integer(rest, 5)?;
+                rest = after;
+                if !fields.is_empty() || size > self.limit {
+                    return Err(HpackError::InvalidTableSize);
+                }
+                self.max_size = size;
+                self.evict();
+                continue;
+            } else {
+                // literal without indexing or never indexed, neither touch the table
+                let (field, after) = self.decode_literal(rest, 4)?;
+                rest = after;
+                field
+            };
+
+            // counted the same way as SETTINGS_MAX_HEADER_LIST_SIZE
+            list_size += field.0.len() + field.1.len() + ENTRY_OVERHEAD;
+            if list_size > self.max_list_size {
+                return Err(HpackError::TooLarge);
+            }
+            fields.push(field);
+        }
+
+        Ok(fields)
+    }
+
+    fn decode_literal<'a>(&self, block: &'a [u8], prefix: u8) -> Result<((String, String), &'a [u8]), HpackError> {
+        let (index, rest) = decode_integer(block, prefix)?;
+        let (name, rest) = if index == 0 {
+            decode_string(rest)?
+        } else {
+            (self.get(index)?.0, rest)
+        };
+        let (value, rest) = decode_string(rest)?;
+        Ok(((name, value), rest))
+    }
+
+    fn get(&self, index: usize) -> Result<(String, String), HpackError> {
+        match index {
+            0 => Err(HpackError::InvalidIndex),
+            1..=61 => {
+                let (name, value) = STATIC_TABLE[index - 1];
+                Ok((name.to_owned(), value.to_owned()))
+            },
+            _ => self.table.get(index - 62).cloned().ok_or(HpackError::InvalidIndex),
+        }
+    }
+
+    fn insert(&mut self, field: (String, String)) {
+        let size = field.0.len() + field.1.len() + ENTRY_OVERHEAD;
+        // an entry bigger than the whole table just empties it
+        if size > self.max_size {
+            self.table.clear();
+            self.size = 0;
+            return;
+        }
+        self.size += size;
+        self.table.push_front(field);
+        self.evict();
+    }
+
+    fn evict(&mut self) {
+        while self.size > self.max_size {
+            match self.table.pop_back() {
+                Some((name, value)) => self.size -= name.len() + value.len() + ENTRY_OVERHEAD,
+                None => break,
+            }
+        }
+    }
+}
+
+// the encoder never adds anything to the dynamic table so there is no state to keep
+// fields are either a full match from the static table or a literal, names use the static table when they can
+pub fn encode(fields: &[(String, String)]) -> Vec<u8> {
+    let mut block = Vec::new();
+    for (name, value) in fields {
+        let exact = STATIC_TABLE.iter().position(|(n, v)| n == name && v == value);
+        let named = STATIC_TABLE.iter().position(|(n, _)| n == name);
+        match (exact, named) {
+            (Some(index), _) => encode_integer(&mut block, index + 1, 7, 0x80),
+            (None, Some(index)) => {
+                encode_integer(&mut block, index + 1, 4, 0x00);
+                encode_string(&mut block, value);
+            },
+            (None, None) => {
+                block.push(0x00);
+                encode_string(&mut block, name);
+                encode_string(&mut block, value);
+            },
+        }
+    }
+    block
+}
+
+// RFC 7541 section 5.1, the first byte has `prefix` bits for the number and the rest go in 7 bit pieces after it
+fn decode_integer(block: &[u8], prefix: u8) -> Result<(usize, &[u8]), HpackError> {
+    let max_prefix = (1usize << prefix) - 1;
+    let (first, mut rest) = match block.split_first() {
+        Some((first, rest)) => (*first as usize & max_prefix, rest),
+        None => return Err(HpackError::InvalidInteger),
+    };
+    if first < max_prefix {
+        return Ok((first, rest));
+    }
+
+    let mut value = max_prefix;
+    let mut shift = 0;
+    loop {
+        let (byte, after) = match rest.split_first() {
+            Some((byte, after)) => (*byte, after),
+            None => return Err(HpackError::InvalidInteger),
+        };
+        rest = after;
+        // nothing we care about is anywhere near this big, it stops the shift from overflowing
+        if shift > 28 {
+            return Err(HpackError::InvalidInteger);
+        }
+        value += ((byte & 0x7f) as usize) << shift;
+        shift += 7;
+        if byte & 0x80 == 0 {
+            return Ok((value, rest));
+        }
+    }
+}
+
+fn encode_integer(block: &mut Vec<u8>, value: usize, prefix: u8, flags: u8) {
+    let max_prefix = (1usize << prefix) - 1;
+    if value < max_prefix {
+        block.push(flags | value as u8);
+        return;
+    }
+
+    block.push(flags | max_prefix as u8);
+    let mut value = value - max_prefix;
+    while value >= 0x80 {
+        block.push((value & 0x7f) as u8 | 0x80);
+        value >>= 7;
+    }
+    block.push(value as u8);
+}
+
+fn decode_string(block: &[u8]) -> Result<(String, &[u8]), HpackError> {
+    let huffman = match block.first() {
+        Some(first) => first & 0x80 != 0,
+        None => return Err(HpackError::InvalidString),
+    };
+    let (len, rest) = decode_integer(block, 7)?;
+    if len > rest.len() {
+        return Err(HpackError::InvalidString);
+    }
+    let (raw, rest) = rest.split_at(len);
+
+    let bytes = if huffman {
+        huffman_decode(raw)?
+    } else {
+        raw.to_vec()
+    };
+    Ok((String::from_utf8_lossy(&bytes).into_owned(), rest))
+}
+
+// strings always go out as they are, huffman would save a few bytes but isnt worth it here
+fn encode_string(block: &mut Vec<u8>, text: &str) {
+    encode_integer(block, text.len(), 7, 0x00);
+    block.extend_from_slice(text.as_bytes());
+}
+
+// each node has a child for a 0 bit and a 1 bit, a leaf holds the symbol instead
+#[derive(Debug, Clone, Copy)]
+enum HuffmanNode {
+    Branch([Option<usize>; 2]),
+    Leaf(u16),
+}
+
+fn huffman_tree() -> &'static [HuffmanNode] {
+    static TREE: OnceLock<Vec<HuffmanNode>> = OnceLock::new();
+    TREE.get_or_init(|| {
+        let mut tree = vec![HuffmanNode::Branch([None, None])];
+        for (symbol, (code, len)) in HUFFMAN_CODES.iter().enumerate() {
+            let mut node = 0;
+            for i in (0..*len).rev() {
+                let bit = ((code >> i) & 1) as usize;
+                let next = match tree[node] {
+                    HuffmanNode::Branch(children) => children[bit],
+                    HuffmanNode::Leaf(_) => unreachable!("huffman codes are prefix free"),
+                };
+                node = match next {
+                    Some(next) => next,
+                    None => {
+                        let created = tree.len();
+                        let leaf = i == 0;
+                        tree.push(if leaf { HuffmanNode::Leaf(symbol as u16) } else { HuffmanNode::Branch([None, None]) });
+                        if let HuffmanNode::Branch(children) = &mut tree[node] {
+                            children[bit] = Some(created);
+                        }
+                        created
+                    },
+                };
+            }
+        }
+        tree
+    })
+}
+
+// RFC 7541 section 5.2, the end is padded with the start of EOS which is all 1s
+fn huffman_decode(raw: &[u8]) -> Result<Vec<u8>, HpackError> {
+    let tree = huffman_tree();
+    let mut decoded = Vec::with_capacity(raw.len() * 8 / 5);
+    let mut node = 0;
+    // bits read since the last symbol, anything left at the end has to be padding
+    let mut pending_bits = 0;
+    let mut pending_all_ones = true;
+
+    for byte in raw {
+        for i in (0..8).rev() {
+            let bit = ((byte >> i) & 1) as usize;
+            pending_bits += 1;
+            pending_all_ones &= bit == 1;
+
+            node = match tree[node] {
+                HuffmanNode::Branch(children) => children[bit].ok_or(HpackError::InvalidHuffman)?,
+                HuffmanNode::Leaf(_) => return Err(HpackError::InvalidHuffman),
+            };
+
+            if let HuffmanNode::Leaf(symbol) = tree[node] {
+                if symbol == EOS {
+                    return Err(HpackError::InvalidHuffman);
+                }
+                decoded.push(symbol as u8);
+                node = 0;
+                pending_bits = 0;
+                pending_all_ones = true;
+            }
+        }
+    }
+
+    if pending_bits > 7 || !pending_all_ones {
+        return Err(HpackError::InvalidHuffman);
+    }
+    Ok(decoded)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // the examples in RFC 7541 are written as hex with spaces in between
+    fn hex(text: &str) -> Vec<u8> {
+        let digits: Vec<u8> = text.bytes().filter(|c| !c.is_ascii_whitespace()).collect();
+        digits.chunks(2)
+            .map(|pair| u8::from_str_radix(std::str::from_utf8(pair).unwrap(), 16).unwrap())
+            .collect()
+    }
+
+    fn fields(list: &[(&str, &str)]) -> Vec<(String, String)> {
+        list.iter().map(|(name, value)| (name.to_string(), value.to_string())).collect()
+    }
+
+    // C.1
+    #[test]
+    fn integers() {
+        let mut block = Vec::new();
+        encode_integer(&mut block, 10, 5, 0);
+        assert_eq!(block, [0x0a]);
+        assert_eq!(decode_integer(&block, 5).unwrap().0, 10);
+
+        let mut block = Vec::new();
+        encode_integer(&mut block, 1337, 5, 0);
+        assert_eq!(block, [0x1f, 0x9a, 0x0a]);
+        assert_eq!(decode_integer(&block, 5).unwrap().0, 1337);
+
+        let mut block = Vec::new();
+        encode_integer(&mut block, 42, 8, 0);
+        assert_eq!(block, [0x2a]);
+        assert_eq!(decode_integer(&block, 8).unwrap().0, 42);
+
+        // cut off half way through and far too big
+        assert_eq!(decode_integer(&[0x1f, 0x9a], 5), Err(HpackError::InvalidInteger));
+        assert_eq!(decode_integer(&[0x1f, 0xff, 0xff, 0xff, 0xff, 0xff, 0x01], 5), Err(HpackError::InvalidInteger));
+    }
+
+    // C.2
+    #[test]
+    fn literal_fields() {
+        let mut decoder = Decoder::new(4096, usize::MAX);
+        let block = hex("400a 6375 7374 6f6d 2d6b 6579 0d63 7573 746f 6d2d 6865 6164 6572");
+        assert_eq!(decoder.decode(&block).unwrap(), fields(&[("custom-key", "custom-header")]));
+        assert_eq!(decoder.size, 55);
+
+        let mut decoder = Decoder::new(4096, usize::MAX);
+        let block = hex("040c 2f73 616d 706c 652f 7061 7468");
+        assert_eq!(decoder.decode(&block).unwrap(), fields(&[(":path", "/sample/path")]));
+        assert!(decoder.table.is_empty());
+
+        let mut decoder = Decoder::new(4096, usize::MAX);
+        let block = hex("1008 7061 7373 776f 7264 0673 6563 7265 74");
+        assert_eq!(decoder.decode(&block).unwrap(), fields(&[("password", "secret")]));
+        assert!(decoder.table.is_empty());
+
+        let mut decoder = Decoder::new(4096, usize::MAX);
+        assert_eq!(decoder.decode(&hex("82")).unwrap(), fields(&[(":method", "GET")]));
+        assert!(decoder.table.is_empty());
+    }
+
+    // C.3 and C.4 are the same three requests, the second one with huffman strings
+    fn check_requests(blocks: [&str; 3]) {
+        let mut decoder = Decoder::new(4096, usize::MAX);
+
+        assert_eq!(decoder.decode(&hex(blocks[0])).unwrap(), fields(&[
+            (":method", "GET"), (":scheme", "http"), (":path", "/"), (":authority", "www.example.com"),
+        ]));
+        assert_eq!(decoder.size, 57);
+
+        assert_eq!(decoder.decode(&hex(blocks[1])).unwrap(), fields(&[
+            (":method", "GET"), (":scheme", "http"), (":path", "/"), (":authority", "www.example.com"),
+            ("cache-control", "no-cache"),
+        ]));
+        assert_eq!(decoder.size, 110);
+
+        assert_eq!(decoder.decode(&hex(blocks[2])).unwrap(), fields(&[
+            (":method", "GET"), (":scheme", "https"), (":path", "/index.html"), (":authority", "www.example.com"),
+            ("custom-key", "custom-value"),
+        ]));
+        assert_eq!(decoder.size, 164);
+        assert_eq!(Vec::from(decoder.table.clone()), fields(&[
+            ("custom-key", "custom-value"), ("cache-control", "no-cache"), (":authority", "www.example.com"),
+        ]));
+    }
+
+    #[test]
+    fn requests_without_huffman() {
+        check_requests([
+            "8286 8441 0f77 7777 2e65 7861 6d70 6c65 2e63 6f6d",
+            "8286 84be 5808 6e6f 2d63 6163 6865",
+            "8287 85bf 400a 6375 7374 6f6d 2d6b 6579 0c63 7573 746f 6d2d 7661 6c75 65",
+        ]);
+    }
+
+    #[test]
+    fn requests_with_huffman() {
+        check_requests([
+            "8286 8441 8cf1 e3c2 e5f2 3a6b a0ab 90f4 ff",
+            "8286 84be 5886 a8eb 1064 9cbf",
+            "8287 85bf 4088 25a8 49e9 5ba9 7d7f 8925 a849 e95b b8e8 b4bf",
+        ]);
+    }
+
+    // C.5, a 256 byte table so the older entries get evicted
+    #[test]
+    fn responses_with_eviction() {
+        let mut decoder = Decoder::new(256, usize::MAX);
+
+        let block = hex("4803 3330 3258 0770 7269 7661 7465 611d 4d6f 6e2c 2032 3120 4f63 7420 3230 3133
+            2032 303a 3133 3a32 3120 474d 546e 1768 7474 7073 3a2f 2f77 7777 2e65 7861 6d70 6c65 2e63 6f6d");
+        assert_eq!(decoder.decode(&block).unwrap(), fields(&[
+            (":status", "302"), ("cache-control", "private"), ("date", "Mon, 21 Oct 2013 20:13:21 GMT"),
+            ("location", "https://www.example.com"),
+        ]));
+        assert_eq!(decoder.size, 222);
+
+        assert_eq!(decoder.decode(&hex("4803 3330 37c1 c0bf")).unwrap(), fields(&[
+            (":status", "307"), ("cache-control", "private"), ("date", "Mon, 21 Oct 2013 20:13:21 GMT"),
+            ("location", "https://www.example.com"),
+        ]));
+        assert_eq!(decoder.size, 222);
+        assert_eq!(decoder.table[0], (String::from(":status"), String::from("307")));
+        assert!(!decoder.table.iter().any(|(_, value)| value == "302"));
+
+        let block = hex("88c1 611d 4d6f 6e2c 2032 3120 4f63 7420 3230 3133 2032 303a 3133 3a32 3220 474d
+            54c0 5a04 677a 6970 7738 666f 6f3d 4153 444a 4b48 514b 425a 584f 5157 454f 5049
+            5541 5851 5745 4f49 553b 206d 6178 2d61 6765 3d33 3630 303b 2076 6572 7369 6f6e 3d31");
+        assert_eq!(decoder.decode(&block).unwrap(), fields(&[
+            (":status", "200"), ("cache-control", "private"), ("date", "Mon, 21 Oct 2013 20:13:22 GMT"),
+            ("location", "https://www.example.com"), ("content-encoding", "gzip"),
+            ("set-cookie", "foo=ASDJKHQKBZXOQWEOPIUAXQWEOIU; max-age=3600; version=1"),
+        ]));
+        assert_eq!(decoder.size, 215);
+        assert_eq!(decoder.table.len(), 3);
+    }
+
+    #[test]
+    fn table_size_updates() {
+        let mut decoder = Decoder::new(4096, usize::MAX);
+        decoder.decode(&hex("400a 6375 7374 6f6d 2d6b 6579 0d63 7573 746f 6d2d 6865 6164 6572")).unwrap();
+
+        // shrinking to nothing empties the table
+        assert!(decoder.decode(&[0x20]).unwrap().is_empty());
+        assert!(decoder.table.is_empty());
+        assert_eq!(decoder.decode(&[0xbe]), Err(HpackError::InvalidIndex));
+
+        // more than we said in our settings, or after a field
+        let mut decoder = Decoder::new(4096, usize::MAX);
+        let mut block = Vec::new();
+        encode_integer(&mut block, 4097, 5, 0x20);
+        assert_eq!(decoder.decode(&block), Err(HpackError::InvalidTableSize));
+        assert_eq!(decoder.decode(&[0x82, 0x20]), Err(HpackError::InvalidTableSize));
+    }
+
+    #[test]
+    fn repeated_index_bomb() {
+        // one big entry in the table and then a byte per copy of it
+        let mut block = vec![0x40];
+        encode_string(&mut block, "x-big");
+        encode_string(&mut block, &"a".repeat(4000));
+        block.extend(vec![0xbe; 1000]);
+
+        let mut decoder = Decoder::new(4096, 16 * 1024);
+        assert_eq!(decoder.decode(&block), Err(HpackError::TooLarge));
+
+        // right on the limit is still fine
+        let size = "x-big".len() + 4000 + ENTRY_OVERHEAD;
+        let mut decoder = Decoder::new(4096, size * 4);
+        assert_eq!(decoder.decode(&block[..block.len() - 997]).unwrap().len(), 4);
+        let mut decoder = Decoder::new(4096, size * 4);
+        assert_eq!(decoder.decode(&block[..block.len() - 996]), Err(HpackError::TooLarge));
+    }
+
+    #[test]
+    fn bad_blocks() {
+        let mut decoder = Decoder::new(4096, usize::MAX);
+        assert_eq!(decoder.decode(&[0x80]), Err(HpackError::InvalidIndex));
+        assert_eq!(decoder.decode(&[0xbe]), Err(HpackError::InvalidIndex));
+        // says 10 bytes but only has 3
+        assert_eq!(decoder.decode(&hex("400a 6375 73")), Err(HpackError::InvalidString));
+        // padding thats longer than 7 bits and padding that isnt all 1s
+        assert_eq!(huffman_decode(&[0xff]), Err(HpackError::InvalidHuffman));
+        assert_eq!(huffman_decode(&[0x00]), Err(HpackError::InvalidHuffman));
+    }
+
+    #[test]
+    fn encoded_fields_decode_the_same() {
+        let sent = fields(&[
+            (":status", "200"), (":status", "418"), ("content-type", "text/html"),
+            ("x-request-id", "a".repeat(200).as_str()),
+        ]);
+        let block = encode(&sent);
+        // :status 200 is in the static table so it only takes one byte
+        assert_eq!(block[0], 0x88);
+        assert_eq!(Decoder::new(4096, usize::MAX).decode(&block).unwrap(), sent);
+    }
+}
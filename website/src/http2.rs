@@ -0,0 +1,1137 @@
+use std::collections::{HashMap, VecDeque};
+use std::io::{self, BufReader, ErrorKind, Read, Write};
+use std::net::IpAddr;
+use std::str::FromStr;
+use std::time::SystemTime;
+use crate::client_ip::TrustedProxies;
+use crate::headers::Headers;
+use crate::hpack::{self, Decoder, HpackError};
+use crate::limits::RequestLimits;
+use crate::types::{
+    HTTPError, HTTPRequestLine, HTTPType, HTTPVersion,
+    Request, Response, turn_system_time_to_http_date,
+};
+
+// HTTP/2, RFC 9113
+// every request is its own stream on the one connection so a page full of small files
+// doesnt have to wait on one connection per file. Requests are handled in the order they finish
+// arriving and the response bodies get sent a frame at a time taking turns so a big file
+// doesnt hold up the small ones behind it
+
+// what a client sends first, h2c with prior knowledge is spotted by this instead of a request line
+pub const PREFACE: &[u8] = b"PRI * HTTP/2.0\r\n\r\nSM\r\n\r\n";
+
+// frame types
+const DATA: u8 = 0x0;
+const HEADERS: u8 = 0x1;
+const PRIORITY: u8 = 0x2;
+const RST_STREAM: u8 = 0x3;
+const SETTINGS: u8 = 0x4;
+const PUSH_PROMISE: u8 = 0x5;
+const PING: u8 = 0x6;
+const GOAWAY: u8 = 0x7;
+const WINDOW_UPDATE: u8 = 0x8;
+const CONTINUATION: u8 = 0x9;
+
+// frame flags, ACK and END_STREAM share a bit but never the same frame type
+const END_STREAM: u8 = 0x1;
+const ACK: u8 = 0x1;
+const END_HEADERS: u8 = 0x4;
+const PADDED: u8 = 0x8;
+const PRIORITY_FLAG: u8 = 0x20;
+
+// settings
+const SETTINGS_ENABLE_PUSH: u16 = 0x2;
+const SETTINGS_MAX_CONCURRENT_STREAMS: u16 = 0x3;
+const SETTINGS_INITIAL_WINDOW_SIZE: u16 = 0x4;
+const SETTINGS_MAX_FRAME_SIZE: u16 = 0x5;
+const SETTINGS_MAX_HEADER_LIST_SIZE: u16 = 0x6;
+
+const DEFAULT_WINDOW: i64 = 65_535;
+const MAX_WINDOW: i64 = (1 << 31) - 1;
+// the biggest frame we take, its also the default so it never has to be sent
+const MAX_FRAME_SIZE: usize = 16_384;
+const MAX_STREAMS: usize = 100;
+const HEADER_TABLE_SIZE: usize = 4096;
+// how many max_body_size bodies one connection can have waiting in memory between all its streams,
+// past that the stream the data was for is refused and the client can try it again later
+const MAX_BUFFERED_BODIES: usize = 2;
+
+// the connection specific fields HTTP/2 doesnt allow, RFC 9113 section 8.2.2
+const CONNECTION_FIELDS: [&str; 5] = ["connection", "keep-alive", "proxy-connection", "transfer-encoding", "upgrade"];
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum ErrorCode {
+    NoError = 0x0,
+    ProtocolError = 0x1,
+    InternalError = 0x2,
+    FlowControlError = 0x3,
+    StreamClosed = 0x5,
+    FrameSizeError = 0x6,
+    RefusedStream = 0x7,
+    CompressionError = 0x9,
+    EnhanceYourCalm = 0xb,
+}
+
+// anything that ends the whole connection, errors that only kill one stream are sent as RST_STREAM instead
+#[derive(Debug)]
+enum Http2Error {
+    Io(io::Error),
+    Connection(ErrorCode),
+}
+
+impl From<io::Error> for Http2Error {
+    fn from(error: io::Error) -> Self {
+        Self::Io(error)
+    }
+}
+
+#[derive(Debug)]
+struct Frame {
+    kind: u8,
+    flags: u8,
+    stream: u32,
+    payload: Vec<u8>,
+}
+
+// a request thats still coming in
+#[derive(Debug)]
+struct Incoming {
+    method: String,
+    path: String,
+    headers: Headers,
+    body: Vec<u8>,
+    // how much we can send on it, WINDOW_UPDATE can show up before the response exists
+    window: i64,
+}
+
+// a response body thats still going out
+struct Outgoing {
+    stream: u32,
+    window: i64,
+    body: Box<dyn Read + Send>,
+    remaining: Option<u64>,
+    // set when the request was turned down before its body was all in,
+    // RST_STREAM after the response tells the client to stop sending the rest
+    reset_when_done: bool,
+}
+
+// a HEADERS frame waiting on its CONTINUATION frames
+#[derive(Debug)]
+struct HeaderBlock {
+    stream: u32,
+    end_stream: bool,
+    block: Vec<u8>,
+}
+
+struct Connection<'a, S: Read + Write, F: FnMut(Request) -> Response> {
+    reader: &'a mut BufReader<S>,
+    handler: F,
+    peer: IpAddr,
+    trusted_proxies: &'a TrustedProxies,
+    limits: &'a RequestLimits,
+    decoder: Decoder,
+    incoming: HashMap<u32, Incoming>,
+    outgoing: VecDeque<Outgoing>,
+    header_block: Option<HeaderBlock>,
+    // the connection flow control window for what we send
+    send_window: i64,
+    // the clients SETTINGS_INITIAL_WINDOW_SIZE, every new stream starts with this much
+    initial_window: i64,
+    max_frame_size: usize,
+    last_stream: u32,
+    got_settings: bool,
+    going_away: bool,
+}
+
+// serves every stream on the connection untill the client hangs up, goes quiet or breaks the protocol
+// the preface is read here so the reader should be right at the start of the connection
+pub fn serve_connection<S, F>(reader: &mut BufReader<S>, peer: IpAddr, trusted_proxies: &TrustedProxies, limits: &RequestLimits, handler: F) -> io::Result<()>
+where
+    S: Read + Write,
+    F: FnMut(Request) -> Response,
+{
+    let mut preface = [0; PREFACE.len()];
+    reader.read_exact(&mut preface)?;
+    if preface != PREFACE {
+        return Err(io::Error::new(ErrorKind::InvalidData, "invalid HTTP/2 connection preface"));
+    }
+
+    let mut connection = Connection {
+        reader,
+        handler,
+        peer,
+        trusted_proxies,
+        limits,
+        decoder: Decoder::new(HEADER_TABLE_SIZE, limits.max_header_size),
+        incoming: HashMap::new(),
+        outgoing: VecDeque::new(),
+        header_block: None,
+        send_window: DEFAULT_WINDOW,
+        initial_window: DEFAULT_WINDOW,
+        max_frame_size: MAX_FRAME_SIZE,
+        last_stream: 0,
+        got_settings: false,
+        going_away: false,
+    };
+
+    let result = connection.run();
+    let code = match result {
+        Ok(()) => ErrorCode::NoError,
+        // a timeout just means the client is done with us
+        Err(Http2Error::Io(e)) if matches!(e.kind(), ErrorKind::WouldBlock | ErrorKind::TimedOut) => ErrorCode::NoError,
+        Err(Http2Error::Io(e)) if e.kind() == ErrorKind::UnexpectedEof => return Ok(()),
+        Err(Http2Error::Io(e)) => return Err(e),
+        Err(Http2Error::Connection(code)) => code,
+    };
+
+    // the client could already be gone so this failing doesnt matter
+    let _ = connection.send_goaway(code);
+    Ok(())
+}
+
+impl<S: Read + Write, F: FnMut(Request) -> Response> Connection<'_, S, F> {
+    fn run(&mut self) -> Result<(), Http2Error> {
+        let mut settings = Vec::new();
+        for (id, value) in [
+            (SETTINGS_ENABLE_PUSH, 0),
+            (SETTINGS_MAX_CONCURRENT_STREAMS, MAX_STREAMS as u32),
+            (SETTINGS_MAX_HEADER_LIST_SIZE, self.limits.max_header_size as u32),
+        ] {
+            settings.extend_from_slice(&id.to_be_bytes());
+            settings.extend_from_slice(&value.to_be_bytes());
+        }
+        self.write_frame(SETTINGS, 0, 0, &settings)?;
+
+        loop {
+            self.send_data()?;
+
+            if self.going_away && self.incoming.is_empty() && self.outgoing.is_empty() {
+                return Ok(());
+            }
+
+            let frame = match self.read_frame()? {
+                Some(frame) => frame,
+                None => return Ok(()),
+            };
+            self.handle_frame(frame)?;
+        }
+    }
+
+    // sends body frames while there isnt anything waiting to be read, one frame per stream at a time
+    // stops when every stream is out of flow control window so the next WINDOW_UPDATE can be read
+    fn send_data(&mut self) -> Result<(), Http2Error> {
+        while !self.outgoing.is_empty() && self.reader.buffer().is_empty() {
+            let mut sent = false;
+            for _ in 0..self.outgoing.len() {
+                let mut outgoing = match self.outgoing.pop_front() {
+                    Some(outgoing) => outgoing,
+                    None => break,
+                };
+
+                let size = self.send_window.min(outgoing.window).min(self.max_frame_size as i64);
+                if size <= 0 {
+                    self.outgoing.push_back(outgoing);
+                    continue;
+                }
+                let size = match outgoing.remaining {
+                    Some(remaining) => (size as u64).min(remaining) as usize,
+                    None => size as usize,
+                };
+
+                let mut chunk = vec![0; size];
+                let read = match read_up_to(&mut outgoing.body, &mut chunk) {
+                    Ok(read) => read,
+                    Err(e) => {
+                        println!("Error: {}, occured at: {}", e, turn_system_time_to_http_date(SystemTime::now()));
+                        self.send_rst(outgoing.stream, ErrorCode::InternalError)?;
+                        sent = true;
+                        break;
+                    },
+                };
+                chunk.truncate(read);
+
+                let remaining = outgoing.remaining.map(|remaining| remaining - read as u64);
+                // a reader without a length is only done once it runs dry
+                let finished = remaining == Some(0) || read < size;
+                if read < size && remaining.is_some_and(|remaining| remaining > 0) {
+                    // the file got shorter than the Content-Length we already sent
+                    self.send_rst(outgoing.stream, ErrorCode::InternalError)?;
+                    sent = true;
+                    break;
+                }
+
+                let flags = if finished { END_STREAM } else { 0 };
+                self.write_frame(DATA, flags, outgoing.stream, &chunk)?;
+                self.send_window -= read as i64;
+                outgoing.window -= read as i64;
+                outgoing.remaining = remaining;
+
+                if !finished {
+                    self.outgoing.push_back(outgoing);
+                } else if outgoing.reset_when_done {
+                    self.send_rst(outgoing.stream, ErrorCode::NoError)?;
+                }
+                sent = true;
+                break;
+            }
+
+            if !sent {
+                return Ok(());
+            }
+        }
+        Ok(())
+    }
+
+    fn read_frame(&mut self) -> Result<Option<Frame>, Http2Error> {
+        let mut header = [0; 9];
+        match self.reader.read_exact(&mut header) {
+            Ok(()) => {},
+            Err(e) if e.kind() == ErrorKind::UnexpectedEof => return Ok(None),
+            Err(e) => return Err(e.into()),
+        }
+
+        let len = u32::from_be_bytes([0, header[0], header[1], header[2]]) as usize;
+        if len > MAX_FRAME_SIZE {
+            return Err(Http2Error::Connection(ErrorCode::FrameSizeError));
+        }
+        let stream = u32::from_be_bytes([header[5], header[6], header[7], header[8]]) & 0x7fff_ffff;
+
+        let mut payload = vec![0; len];
+        self.reader.read_exact(&mut payload)?;
+
+        Ok(Some(Frame {
+            kind: header[3],
+            flags: header[4],
+            stream,
+            payload,
+        }))
+    }
+
+    fn handle_frame(&mut self, frame: Frame) -> Result<(), Http2Error> {
+        // the first thing the client sends after the preface has to be its settings
+        if !self.got_settings && frame.kind != SETTINGS {
+            return Err(Http2Error::Connection(ErrorCode::ProtocolError));
+        }
+        // nothing else can come between a HEADERS frame and the end of its CONTINUATION frames
+        if let Some(block) = &self.header_block {
+            if frame.kind != CONTINUATION || frame.stream != block.stream {
+                return Err(Http2Error::Connection(ErrorCode::ProtocolError));
+            }
+        }
+
+        match frame.kind {
+            DATA => self.on_data(frame),
+            HEADERS => self.on_headers(frame),
+            CONTINUATION => self.on_continuation(frame),
+            PRIORITY => {
+                if frame.stream == 0 {
+                    return Err(Http2Error::Connection(ErrorCode::ProtocolError));
+                }
+                // priorities dont change anything here
+                if frame.payload.len() != 5 {
+                    self.reset_stream(frame.stream, ErrorCode::FrameSizeError)?;
+                }
+                Ok(())
+            },
+            RST_STREAM => {
+                if frame.stream == 0 || frame.stream > self.last_stream {
+                    return Err(Http2Error::Connection(ErrorCode::ProtocolError));
+                }
+                if frame.payload.len() != 4 {
+                    return Err(Http2Error::Connection(ErrorCode::FrameSizeError));
+                }
+                self.incoming.remove(&frame.stream);
+                self.outgoing.retain(|outgoing| outgoing.stream != frame.stream);
+                Ok(())
+            },
+            SETTINGS => self.on_settings(frame),
+            // clients cant push
+            PUSH_PROMISE => Err(Http2Error::Connection(ErrorCode::ProtocolError)),
+            PING => {
+                if frame.stream != 0 {
+                    return Err(Http2Error::Connection(ErrorCode::ProtocolError));
+                }
+                if frame.payload.len() != 8 {
+                    return Err(Http2Error::Connection(ErrorCode::FrameSizeError));
+                }
+                if frame.flags & ACK == 0 {
+                    self.write_frame(PING, ACK, 0, &frame.payload)?;
+                }
+                Ok(())
+            },
+            GOAWAY => {
+                if frame.stream != 0 {
+                    return Err(Http2Error::Connection(ErrorCode::ProtocolError));
+                }
+                // whatever already started still gets finished
+                self.going_away = true;
+                Ok(())
+            },
+            WINDOW_UPDATE => self.on_window_update(frame),
+            // unknown frame types have to be ignored
+            _ => Ok(()),
+        }
+    }
+
+    fn on_data(&mut self, frame: Frame) -> Result<(), Http2Error> {
+        if frame.stream == 0 {
+            return Err(Http2Error::Connection(ErrorCode::ProtocolError));
+        }
+
+        // the whole frame counts against the connection window even if the stream is gone,
+        // it gets handed straight back as the body is held in memory anyway
+        let len = frame.payload.len();
+        if len > 0 {
+            self.write_frame(WINDOW_UPDATE, 0, 0, &(len as u32).to_be_bytes())?;
+        }
+        let data = strip_padding(&frame.payload, frame.flags)?;
+        let end_stream = frame.flags & END_STREAM != 0;
+
+        let incoming = match self.incoming.get_mut(&frame.stream) {
+            Some(incoming) => incoming,
+            None if frame.stream > self.last_stream => return Err(Http2Error::Connection(ErrorCode::ProtocolError)),
+            // frames that were already on the way when we reset the stream
+            None => return Ok(()),
+        };
+
+        if data.len() > self.limits.max_body_size - incoming.body.len() {
+            return self.reject(frame.stream, HTTPError::ContentTooLarge);
+        }
+        // the window is handed straight back so flow control never stops a client sending,
+        // this is what stops lots of streams each sending a body just under the limit
+        let buffered = self.incoming.values().map(|incoming| incoming.body.len()).sum::<usize>();
+        if buffered + data.len() > self.limits.max_body_size.saturating_mul(MAX_BUFFERED_BODIES) {
+            return self.reset_stream(frame.stream, ErrorCode::RefusedStream);
+        }
+        let incoming = match self.incoming.get_mut(&frame.stream) {
+            Some(incoming) => incoming,
+            None => return Ok(()),
+        };
+        incoming.body.extend_from_slice(data);
+
+        if end_stream {
+            return self.finish_request(frame.stream);
+        }
+        if len > 0 {
+            self.write_frame(WINDOW_UPDATE, 0, frame.stream, &(len as u32).to_be_bytes())?;
+        }
+        Ok(())
+    }
+
+    fn on_headers(&mut self, frame: Frame) -> Result<(), Http2Error> {
+        // streams the client opens always have odd ids
+        if frame.stream == 0 || frame.stream.is_multiple_of(2) {
+            return Err(Http2Error::Connection(ErrorCode::ProtocolError));
+        }
+
+        let mut block = strip_padding(&frame.payload, frame.flags)?;
+        if frame.flags & PRIORITY_FLAG != 0 {
+            if block.len() < 5 {
+                return Err(Http2Error::Connection(ErrorCode::FrameSizeError));
+            }
+            block = &block[5..];
+        }
+
+        // a second HEADERS on a stream thats still open is its trailers
+        if !self.incoming.contains_key(&frame.stream) {
+            if frame.stream <= self.last_stream {
+                return Err(Http2Error::Connection(ErrorCode::StreamClosed));
+            }
+            self.last_stream = frame.stream;
+        }
+
+        self.header_block = Some(HeaderBlock {
+            stream: frame.stream,
+            end_stream: frame.flags & END_STREAM != 0,
+            block: block.to_vec(),
+        });
+
+        if frame.flags & END_HEADERS != 0 {
+            return self.finish_headers();
+        }
+        Ok(())
+    }
+
+    fn on_continuation(&mut self, frame: Frame) -> Result<(), Http2Error> {
+        let block = match &mut self.header_block {
+            Some(block) => block,
+            None => return Err(Http2Error::Connection(ErrorCode::ProtocolError)),
+        };
+
+        // endless CONTINUATION frames would just fill up memory
+        if block.block.len() + frame.payload.len() > self.limits.max_header_size {
+            return Err(Http2Error::Connection(ErrorCode::EnhanceYourCalm));
+        }
+        block.block.extend_from_slice(&frame.payload);
+
+        if frame.flags & END_HEADERS != 0 {
+            return self.finish_headers();
+        }
+        Ok(())
+    }
+
+    fn finish_headers(&mut self) -> Result<(), Http2Error> {
+        let HeaderBlock { stream, end_stream, block } = match self.header_block.take() {
+            Some(block) => block,
+            None => return Ok(()),
+        };
+
+        // this has to happen even for streams that get refused or the table would get out of sync
+        let fields = match self.decoder.decode(&block) {
+            Ok(fields) => fields,
+            // more than we said we take in SETTINGS_MAX_HEADER_LIST_SIZE, decoding stopped part way
+            // so the table cant be trusted anymore and the whole connection has to go
+            Err(HpackError::TooLarge) => return Err(Http2Error::Connection(ErrorCode::EnhanceYourCalm)),
+            Err(_) => return Err(Http2Error::Connection(ErrorCode::CompressionError)),
+        };
+
+        if self.incoming.contains_key(&stream) {
+            // trailers have to end the stream, nothing here uses them so they get dropped
+            if !end_stream {
+                return self.reset_stream(stream, ErrorCode::ProtocolError);
+            }
+            return self.finish_request(stream);
+        }
+
+        if self.going_away || self.incoming.len() + self.outgoing.len() >= MAX_STREAMS {
+            return self.send_rst(stream, ErrorCode::RefusedStream);
+        }
+
+        let incoming = match parse_fields(fields) {
+            Some((method, path, headers)) => Incoming {
+                method,
+                path,
+                headers,
+                body: Vec::new(),
+                window: self.initial_window,
+            },
+            None => return self.send_rst(stream, ErrorCode::ProtocolError),
+        };
+        // no point waiting for a body thats already too big going by its Content-Length
+        let too_long = incoming.headers.get("content-length")
+            .and_then(|length| length.parse::<usize>().ok())
+            .is_some_and(|length| length > self.limits.max_body_size);
+        self.incoming.insert(stream, incoming);
+
+        if too_long {
+            return self.reject(stream, HTTPError::ContentTooLarge);
+        }
+        if end_stream {
+            return self.finish_request(stream);
+        }
+        Ok(())
+    }
+
+    // the whole request is in so its handled just like an HTTP/1.1 one
+    fn finish_request(&mut self, stream: u32) -> Result<(), Http2Error> {
+        let Incoming { method, path, headers, body, window } = match self.incoming.remove(&stream) {
+            Some(incoming) => incoming,
+            None => return Ok(()),
+        };
+
+        // a Content-Length that doesnt match the DATA frames makes the request malformed
+        if let Some(length) = headers.get("content-length") {
+            if length.parse::<usize>().ok() != Some(body.len()) {
+                return self.send_rst(stream, ErrorCode::ProtocolError);
+            }
+        }
+
+        let head_only = method == "HEAD";
+        let request = HTTPType::from_str(&method)
+            .and_then(|kind| HTTPRequestLine::new(kind, &path, HTTPVersion::Http2))
+            .and_then(|line| Request::from_parts(line, headers, body, self.peer, self.trusted_proxies));
+
+        let response = match request {
+            Ok(request) => (self.handler)(request),
+            Err(e) => {
+                println!("Error: {}, occured at: {}", e, turn_system_time_to_http_date(SystemTime::now()));
+                Response::from_error(e)
+            },
+        };
+
+        self.send_response(stream, response, head_only, window)
+    }
+
+    // window is how much the client has said we can send on the stream so far
+    fn send_response(&mut self, stream: u32, response: Response, head_only: bool, window: i64) -> Result<(), Http2Error> {
+        let (code, fields, body) = response.into_parts();
+
+        let mut h2_fields = vec![(String::from(":status"), code.to_string())];
+        for (name, value) in fields {
+            let name = name.to_ascii_lowercase();
+            if !CONNECTION_FIELDS.contains(&name.as_str()) {
+                h2_fields.push((name, value));
+            }
+        }
+
+        let end_stream = head_only || body.is_empty();
+        let block = hpack::encode(&h2_fields);
+        let mut pieces = block.chunks(self.max_frame_size).peekable();
+        let mut kind = HEADERS;
+        let mut flags = if end_stream { END_STREAM } else { 0 };
+        // an empty block still needs its one HEADERS frame
+        if block.is_empty() {
+            self.write_frame(HEADERS, flags | END_HEADERS, stream, &[])?;
+        }
+        while let Some(piece) = pieces.next() {
+            if pieces.peek().is_none() {
+                flags |= END_HEADERS;
+            }
+            self.write_frame(kind, flags, stream, piece)?;
+            kind = CONTINUATION;
+            flags = 0;
+        }
+
+        if end_stream {
+            return Ok(());
+        }
+
+        let remaining = body.len();
+        let body = match body.into_reader() {
+            Ok(body) => body,
+            Err(e) => {
+                println!("Error: {}, occured at: {}", e, turn_system_time_to_http_date(SystemTime::now()));
+                return self.send_rst(stream, ErrorCode::InternalError);
+            },
+        };
+
+        self.outgoing.push_back(Outgoing {
+            stream,
+            window,
+            body,
+            remaining,
+            reset_when_done: false,
+        });
+        Ok(())
+    }
+
+    // answers a request that wont be handled with its error, the rest of its body gets ignored
+    fn reject(&mut self, stream: u32, error: HTTPError) -> Result<(), Http2Error> {
+        let window = match self.incoming.remove(&stream) {
+            Some(incoming) => incoming.window,
+            None => self.initial_window,
+        };
+        self.send_response(stream, Response::from_error(error), false, window)?;
+
+        match self.outgoing.iter_mut().find(|outgoing| outgoing.stream == stream) {
+            Some(outgoing) => outgoing.reset_when_done = true,
+            None => self.send_rst(stream, ErrorCode::NoError)?,
+        }
+        Ok(())
+    }
+
+    fn on_settings(&mut self, frame: Frame) -> Result<(), Http2Error> {
+        if frame.stream != 0 {
+            return Err(Http2Error::Connection(ErrorCode::ProtocolError));
+        }
+        if frame.flags & ACK != 0 {
+            if !frame.payload.is_empty() {
+                return Err(Http2Error::Connection(ErrorCode::FrameSizeError));
+            }
+            return Ok(());
+        }
+        if !frame.payload.len().is_multiple_of(6) {
+            return Err(Http2Error::Connection(ErrorCode::FrameSizeError));
+        }
+
+        for setting in frame.payload.chunks(6) {
+            let id = u16::from_be_bytes([setting[0], setting[1]]);
+            let value = u32::from_be_bytes([setting[2], setting[3], setting[4], setting[5]]);
+            match id {
+                SETTINGS_ENABLE_PUSH if value > 1 => return Err(Http2Error::Connection(ErrorCode::ProtocolError)),
+                SETTINGS_INITIAL_WINDOW_SIZE => {
+                    let value = value as i64;
+                    if value > MAX_WINDOW {
+                        return Err(Http2Error::Connection(ErrorCode::FlowControlError));
+                    }
+                    // changes every stream window by the difference, even ones that are already open
+                    let delta = value - self.initial_window;
+                    self.initial_window = value;
+                    for incoming in self.incoming.values_mut() {
+                        incoming.window += delta;
+                    }
+                    for outgoing in self.outgoing.iter_mut() {
+                        outgoing.window += delta;
+                        if outgoing.window > MAX_WINDOW {
+                            return Err(Http2Error::Connection(ErrorCode::FlowControlError));
+                        }
+                    }
+                },
+                SETTINGS_MAX_FRAME_SIZE => {
+                    if !(16_384..=16_777_215).contains(&value) {
+                        return Err(Http2Error::Connection(ErrorCode::ProtocolError));
+                    }
+                    self.max_frame_size = value as usize;
+                },
+                // the table size only matters to an encoder that uses the table, ours doesnt
+                _ => {},
+            }
+        }
+
+        self.got_settings = true;
+        self.write_frame(SETTINGS, ACK, 0, &[])?;
+        Ok(())
+    }
+
+    fn on_window_update(&mut self, frame: Frame) -> Result<(), Http2Error> {
+        if frame.payload.len() != 4 {
+            return Err(Http2Error::Connection(ErrorCode::FrameSizeError));
+        }
+        let increment = (u32::from_be_bytes([frame.payload[0], frame.payload[1], frame.payload[2], frame.payload[3]]) & 0x7fff_ffff) as i64;
+
+        if frame.stream == 0 {
+            if increment == 0 {
+                return Err(Http2Error::Connection(ErrorCode::ProtocolError));
+            }
+            self.send_window += increment;
+            if self.send_window > MAX_WINDOW {
+                return Err(Http2Error::Connection(ErrorCode::FlowControlError));
+            }
+            return Ok(());
+        }
+
+        if frame.stream > self.last_stream {
+            return Err(Http2Error::Connection(ErrorCode::ProtocolError));
+        }
+        if increment == 0 {
+            return self.reset_stream(frame.stream, ErrorCode::ProtocolError);
+        }
+
+        let window = match self.outgoing.iter_mut().find(|outgoing| outgoing.stream == frame.stream) {
+            Some(outgoing) => &mut outgoing.window,
+            None => match self.incoming.get_mut(&frame.stream) {
+                Some(incoming) => &mut incoming.window,
+                // its already closed, updates can still be on the way when that happens
+                None => return Ok(()),
+            },
+        };
+        *window += increment;
+        if *window > MAX_WINDOW {
+            return self.reset_stream(frame.stream, ErrorCode::FlowControlError);
+        }
+        Ok(())
+    }
+
+    // drops everything about the stream and tells the client
+    fn reset_stream(&mut self, stream: u32, code: ErrorCode) -> Result<(), Http2Error> {
+        self.incoming.remove(&stream);
+        self.outgoing.retain(|outgoing| outgoing.stream != stream);
+        self.send_rst(stream, code)
+    }
+
+    fn send_rst(&mut self, stream: u32, code: ErrorCode) -> Result<(), Http2Error> {
+        self.write_frame(RST_STREAM, 0, stream, &(code as u32).to_be_bytes())?;
+        Ok(())
+    }
+
+    fn send_goaway(&mut self, code: ErrorCode) -> io::Result<()> {
+        let mut payload = Vec::with_capacity(8);
+        payload.extend_from_slice(&self.last_stream.to_be_bytes());
+        payload.extend_from_slice(&(code as u32).to_be_bytes());
+        self.write_frame(GOAWAY, 0, 0, &payload)
+    }
+
+    fn write_frame(&mut self, kind: u8, flags: u8, stream: u32, payload: &[u8]) -> io::Result<()> {
+        let len = (payload.len() as u32).to_be_bytes();
+        let mut frame = Vec::with_capacity(9 + payload.len());
+        frame.extend_from_slice(&len[1..]);
+        frame.push(kind);
+        frame.push(flags);
+        frame.extend_from_slice(&stream.to_be_bytes());
+        frame.extend_from_slice(payload);
+        self.reader.get_mut().write_all(&frame)
+    }
+}
+
+// takes the padding off DATA and HEADERS frames
+fn strip_padding(payload: &[u8], flags: u8) -> Result<&[u8], Http2Error> {
+    if flags & PADDED == 0 {
+        return Ok(payload);
+    }
+
+    match payload.split_first() {
+        Some((&padding, rest)) if (padding as usize) <= rest.len() => Ok(&rest[..rest.len() - padding as usize]),
+        _ => Err(Http2Error::Connection(ErrorCode::ProtocolError)),
+    }
+}
+
+// splits the decoded fields into the pseudo headers and the normal ones
+// returns None for anything RFC 9113 section 8.1.1 calls malformed
+fn parse_fields(fields: Vec<(String, String)>) -> Option<(String, String, Headers)> {
+    let (mut method, mut scheme, mut path, mut authority) = (None, None, None, None);
+    let mut headers = Headers::new();
+    let mut cookies = Vec::new();
+    let mut seen_regular = false;
+
+    for (name, value) in fields {
+        if let Some(pseudo) = name.strip_prefix(':') {
+            // pseudo headers all have to come first
+            if seen_regular {
+                return None;
+            }
+            let slot = match pseudo {
+                "method" => &mut method,
+                "scheme" => &mut scheme,
+                "path" => &mut path,
+                "authority" => &mut authority,
+                _ => return None,
+            };
+            if slot.replace(value).is_some() {
+                return None;
+            }
+            continue;
+        }
+
+        seen_regular = true;
+        if name.is_empty() || name.bytes().any(|c| c.is_ascii_uppercase()) {
+            return None;
+        }
+        if CONNECTION_FIELDS.contains(&name.as_str()) || (name == "te" && value != "trailers") {
+            return None;
+        }
+        // cookies can be split up to compress better, they go back together with "; "
+        if name == "cookie" {
+            cookies.push(value);
+            continue;
+        }
+        headers.insert(&name, &value);
+    }
+
+    if !cookies.is_empty() {
+        headers.insert("cookie", &cookies.join("; "));
+    }
+
+    let (method, path) = match (method, scheme, path) {
+        (Some(method), Some(_), Some(path)) if !path.is_empty() => (method, path),
+        _ => return None,
+    };
+    // :authority stands in for Host which the rest of the server looks at
+    if let Some(authority) = authority {
+        if !headers.contains("host") {
+            headers.insert("host", &authority);
+        }
+    }
+
+    Some((method, path, headers))
+}
+
+// fills as much of buf as it can, only stopping short at the end of the reader
+fn read_up_to<R: Read + ?Sized>(reader: &mut R, buf: &mut [u8]) -> io::Result<usize> {
+    let mut filled = 0;
+    while filled < buf.len() {
+        match reader.read(&mut buf[filled..]) {
+            Ok(0) => break,
+            Ok(read) => filled += read,
+            Err(e) if e.kind() == ErrorKind::Interrupted => continue,
+            Err(e) => return Err(e),
+        }
+    }
+    Ok(filled)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::net::Ipv4Addr;
+    use crate::types::ContentType;
+
+    // hands out one chunk per read so the server sees them arrive seperately like off a socket
+    struct TestStream {
+        chunks: VecDeque<Vec<u8>>,
+        written: Vec<u8>,
+    }
+
+    impl Read for TestStream {
+        fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+            let chunk = match self.chunks.front_mut() {
+                Some(chunk) => chunk,
+                None => return Ok(0),
+            };
+            let len = chunk.len().min(buf.len());
+            buf[..len].copy_from_slice(&chunk[..len]);
+            chunk.drain(..len);
+            if chunk.is_empty() {
+                self.chunks.pop_front();
+            }
+            Ok(len)
+        }
+    }
+
+    impl Write for TestStream {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.written.extend_from_slice(buf);
+            Ok(buf.len())
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    fn frame(kind: u8, flags: u8, stream: u32, payload: &[u8]) -> Vec<u8> {
+        let mut frame = (payload.len() as u32).to_be_bytes()[1..].to_vec();
+        frame.push(kind);
+        frame.push(flags);
+        frame.extend_from_slice(&stream.to_be_bytes());
+        frame.extend_from_slice(payload);
+        frame
+    }
+
+    fn settings(list: &[(u16, u32)]) -> Vec<u8> {
+        let mut payload = Vec::new();
+        for (id, value) in list {
+            payload.extend_from_slice(&id.to_be_bytes());
+            payload.extend_from_slice(&value.to_be_bytes());
+        }
+        frame(SETTINGS, 0, 0, &payload)
+    }
+
+    fn request_block(path: &str) -> Vec<u8> {
+        hpack::encode(&[
+            (String::from(":method"), String::from("GET")),
+            (String::from(":scheme"), String::from("http")),
+            (String::from(":path"), String::from(path)),
+            (String::from(":authority"), String::from("localhost")),
+        ])
+    }
+
+    // runs a connection with the preface and the client settings in front of the first chunk
+    // and gives back every frame the server sent and the paths the handler was called with
+    fn run(mut chunks: Vec<Vec<u8>>, limits: RequestLimits, initial_window: u32, body: &[u8]) -> (Vec<Frame>, Vec<String>) {
+        let mut first = PREFACE.to_vec();
+        first.extend(settings(&[(SETTINGS_INITIAL_WINDOW_SIZE, initial_window)]));
+        first.append(&mut chunks[0]);
+        chunks[0] = first;
+
+        let stream = TestStream {
+            chunks: chunks.into(),
+            written: Vec::new(),
+        };
+        let mut reader = BufReader::new(stream);
+        let mut paths = Vec::new();
+        serve_connection(&mut reader, IpAddr::V4(Ipv4Addr::LOCALHOST), &TrustedProxies::loopback(), &limits, |request| {
+            paths.push(request.get_path().to_owned());
+            Response::new_ok(ContentType::PlainText, None, body.to_vec())
+        }).unwrap();
+
+        let mut frames = Vec::new();
+        let mut written = reader.get_ref().written.as_slice();
+        while !written.is_empty() {
+            let len = u32::from_be_bytes([0, written[0], written[1], written[2]]) as usize;
+            frames.push(Frame {
+                kind: written[3],
+                flags: written[4],
+                stream: u32::from_be_bytes([written[5], written[6], written[7], written[8]]),
+                payload: written[9..9 + len].to_vec(),
+            });
+            written = &written[9 + len..];
+        }
+        (frames, paths)
+    }
+
+    fn goaway_code(frames: &[Frame]) -> Option<u32> {
+        frames.iter().rev()
+            .find(|frame| frame.kind == GOAWAY)
+            .map(|frame| u32::from_be_bytes([frame.payload[4], frame.payload[5], frame.payload[6], frame.payload[7]]))
+    }
+
+    fn data(frames: &[Frame], stream: u32) -> Vec<&Frame> {
+        frames.iter().filter(|frame| frame.kind == DATA && frame.stream == stream).collect()
+    }
+
+    #[test]
+    fn request_gets_a_response() {
+        let (frames, paths) = run(vec![frame(HEADERS, END_HEADERS | END_STREAM, 1, &request_block("/hello"))], RequestLimits::default(), 65_535, b"hi there");
+        assert_eq!(paths, ["/hello"]);
+
+        // our settings, the ack for theirs, then the response
+        assert_eq!(frames[0].kind, SETTINGS);
+        assert!(frames.iter().any(|frame| frame.kind == SETTINGS && frame.flags & ACK != 0));
+        let headers = frames.iter().find(|frame| frame.kind == HEADERS).unwrap();
+        assert_eq!(headers.stream, 1);
+        assert_eq!(headers.flags & (END_HEADERS | END_STREAM), END_HEADERS);
+        let fields = Decoder::new(HEADER_TABLE_SIZE, usize::MAX).decode(&headers.payload).unwrap();
+        assert_eq!(fields[0], (String::from(":status"), String::from("200")));
+
+        let body = data(&frames, 1);
+        assert_eq!(body.len(), 1);
+        assert_eq!(body[0].payload, b"hi there");
+        assert_eq!(body[0].flags & END_STREAM, END_STREAM);
+        assert_eq!(goaway_code(&frames), Some(ErrorCode::NoError as u32));
+    }
+
+    #[test]
+    fn header_block_split_over_continuation() {
+        let block = request_block("/split/up/path");
+        let (first, rest) = block.split_at(5);
+        let (middle, last) = rest.split_at(5);
+        let chunk = [
+            frame(HEADERS, END_STREAM, 1, first),
+            frame(CONTINUATION, 0, 1, middle),
+            frame(CONTINUATION, END_HEADERS, 1, last),
+        ].concat();
+
+        let (frames, paths) = run(vec![chunk], RequestLimits::default(), 65_535, b"ok");
+        assert_eq!(paths, ["/split/up/path"]);
+        assert_eq!(data(&frames, 1).len(), 1);
+    }
+
+    #[test]
+    fn oversized_continuation_ends_the_connection() {
+        let limits = RequestLimits {
+            max_header_size: 64,
+            ..RequestLimits::default()
+        };
+        let mut chunk = frame(HEADERS, 0, 1, &request_block("/"));
+        // the client never stops sending more of the block
+        for _ in 0..10 {
+            chunk.extend(frame(CONTINUATION, 0, 1, &[0; 16]));
+        }
+
+        let (frames, paths) = run(vec![chunk], limits, 65_535, b"");
+        assert!(paths.is_empty());
+        assert!(!frames.iter().any(|frame| frame.kind == HEADERS));
+        assert_eq!(goaway_code(&frames), Some(ErrorCode::EnhanceYourCalm as u32));
+    }
+
+    #[test]
+    fn truncated_continuation() {
+        let block = request_block("/");
+        let (first, rest) = block.split_at(4);
+
+        // another frame turning up before END_HEADERS breaks the protocol
+        let chunk = [
+            frame(HEADERS, END_STREAM, 1, first),
+            frame(PING, 0, 0, &[0; 8]),
+            frame(CONTINUATION, END_HEADERS, 1, rest),
+        ].concat();
+        let (frames, paths) = run(vec![chunk], RequestLimits::default(), 65_535, b"");
+        assert!(paths.is_empty());
+        assert_eq!(goaway_code(&frames), Some(ErrorCode::ProtocolError as u32));
+
+        // so does a CONTINUATION for some other stream
+        let chunk = [
+            frame(HEADERS, END_STREAM, 1, first),
+            frame(CONTINUATION, END_HEADERS, 3, rest),
+        ].concat();
+        let (frames, paths) = run(vec![chunk], RequestLimits::default(), 65_535, b"");
+        assert!(paths.is_empty());
+        assert_eq!(goaway_code(&frames), Some(ErrorCode::ProtocolError as u32));
+
+        // the client going away half way through a block never reaches the handler
+        let (frames, paths) = run(vec![frame(HEADERS, END_STREAM, 1, first)], RequestLimits::default(), 65_535, b"");
+        assert!(paths.is_empty());
+        assert!(!frames.iter().any(|frame| frame.kind == HEADERS));
+
+        // and neither does one cut off in the middle of a frame
+        let mut cut = frame(CONTINUATION, END_HEADERS, 1, rest);
+        cut.truncate(cut.len() - 2);
+        let (frames, paths) = run(vec![[frame(HEADERS, END_STREAM, 1, first), cut].concat()], RequestLimits::default(), 65_535, b"");
+        assert!(paths.is_empty());
+        assert!(!frames.iter().any(|frame| frame.kind == HEADERS));
+    }
+
+    #[test]
+    fn header_block_that_decodes_too_big() {
+        // a 4000 byte value added to the table and then pointed at a hundred times with one byte each
+        let mut block = request_block("/");
+        block.extend_from_slice(&[0x40, 0x05]);
+        block.extend_from_slice(b"x-big");
+        block.extend_from_slice(&[0x7f, 0xa1, 0x1e]);
+        block.extend(vec![b'a'; 4000]);
+        block.extend(vec![0xbe; 100]);
+        assert!(block.len() < MAX_FRAME_SIZE);
+
+        let (frames, paths) = run(vec![frame(HEADERS, END_HEADERS | END_STREAM, 1, &block)], RequestLimits::default(), 65_535, b"");
+        assert!(paths.is_empty());
+        assert!(!frames.iter().any(|frame| frame.kind == HEADERS));
+        assert_eq!(goaway_code(&frames), Some(ErrorCode::EnhanceYourCalm as u32));
+    }
+
+    #[test]
+    fn buffered_bodies_are_capped() {
+        let limits = RequestLimits {
+            max_body_size: 100,
+            ..RequestLimits::default()
+        };
+        // two streams can fill the connection between them, a third one cant add anything
+        let chunk = [
+            frame(HEADERS, END_HEADERS, 1, &request_block("/one")),
+            frame(DATA, 0, 1, &[1; 100]),
+            frame(HEADERS, END_HEADERS, 3, &request_block("/two")),
+            frame(DATA, 0, 3, &[2; 100]),
+            frame(HEADERS, END_HEADERS, 5, &request_block("/three")),
+            frame(DATA, 0, 5, &[3; 1]),
+            frame(DATA, END_STREAM, 1, &[]),
+            frame(DATA, END_STREAM, 3, &[]),
+        ].concat();
+
+        let (frames, paths) = run(vec![chunk], limits, 65_535, b"done");
+        assert_eq!(paths, ["/one", "/two"]);
+        let reset = frames.iter().find(|frame| frame.kind == RST_STREAM).unwrap();
+        assert_eq!(reset.stream, 5);
+        assert_eq!(reset.payload, (ErrorCode::RefusedStream as u32).to_be_bytes());
+        assert_eq!(goaway_code(&frames), Some(ErrorCode::NoError as u32));
+    }
+
+    #[test]
+    fn body_waits_for_the_stream_window() {
+        let body = [7; 25];
+
+        // only the first 10 bytes fit and nothing more is allowed
+        let (frames, _) = run(vec![frame(HEADERS, END_HEADERS | END_STREAM, 1, &request_block("/"))], RequestLimits::default(), 10, &body);
+        let sent = data(&frames, 1);
+        assert_eq!(sent.len(), 1);
+        assert_eq!(sent[0].payload.len(), 10);
+        assert_eq!(sent[0].flags & END_STREAM, 0);
+
+        // the rest goes once the client opens the window back up
+        let (frames, _) = run(vec![
+            frame(HEADERS, END_HEADERS | END_STREAM, 1, &request_block("/")),
+            frame(WINDOW_UPDATE, 0, 1, &15u32.to_be_bytes()),
+        ], RequestLimits::default(), 10, &body);
+        let sent = data(&frames, 1);
+        assert_eq!(sent.len(), 2);
+        assert_eq!(sent[0].payload.len(), 10);
+        assert_eq!(sent[1].payload.len(), 15);
+        assert_eq!(sent[1].flags & END_STREAM, END_STREAM);
+    }
+
+    #[test]
+    fn connection_window_is_shared() {
+        // both streams are allowed plenty but the connection only has room for 65535 bytes between them
+        let body = vec![1; 40_000];
+        let chunk = [
+            settings(&[(SETTINGS_INITIAL_WINDOW_SIZE, 100_000)]),
+            frame(HEADERS, END_HEADERS | END_STREAM, 1, &request_block("/one")),
+            frame(HEADERS, END_HEADERS | END_STREAM, 3, &request_block("/two")),
+        ].concat();
+        let (frames, paths) = run(vec![chunk], RequestLimits::default(), 65_535, &body);
+        assert_eq!(paths, ["/one", "/two"]);
+
+        let sent: usize = frames.iter().filter(|frame| frame.kind == DATA).map(|frame| frame.payload.len()).sum();
+        assert_eq!(sent, DEFAULT_WINDOW as usize);
+        assert!(frames.iter().filter(|frame| frame.kind == DATA).all(|frame| frame.payload.len() <= MAX_FRAME_SIZE));
+    }
+
+    #[test]
+    fn window_overflow_is_an_error() {
+        let chunk = frame(WINDOW_UPDATE, 0, 0, &0x7fff_ffffu32.to_be_bytes());
+        let (frames, _) = run(vec![chunk], RequestLimits::default(), 65_535, b"");
+        assert_eq!(goaway_code(&frames), Some(ErrorCode::FlowControlError as u32));
+
+        let (frames, _) = run(vec![settings(&[(SETTINGS_INITIAL_WINDOW_SIZE, 0x8000_0000)])], RequestLimits::default(), 65_535, b"");
+        assert_eq!(goaway_code(&frames), Some(ErrorCode::FlowControlError as u32));
+    }
+
+    #[test]
+    fn frames_that_are_too_big() {
+        let chunk = frame(PING, 0, 0, &vec![0; MAX_FRAME_SIZE + 1]);
+        let (frames, _) = run(vec![chunk], RequestLimits::default(), 65_535, b"");
+        assert_eq!(goaway_code(&frames), Some(ErrorCode::FrameSizeError as u32));
+
+        let (frames, _) = run(vec![frame(PING, 0, 0, &[0; 7])], RequestLimits::default(), 65_535, b"");
+        assert_eq!(goaway_code(&frames), Some(ErrorCode::FrameSizeError as u32));
+    }
+}
@@ -1,9 +1,18 @@
 use std::collections::HashMap;
-use std::fmt::write;
-use std::net::{TcpStream, IpAddr};
-use std::time::{SystemTime, UNIX_EPOCH};
+use std::net::IpAddr;
+use std::time::{SystemTime, UNIX_EPOCH, Duration, Instant};
 use std::str::FromStr;
-use std::io::{BufReader, BufRead, Read};
+use std::io::{BufRead, Read};
+use crate::headers::{Headers, trim_line_ending};
+use crate::body::{BodyLength, read_body};
+use crate::client_ip::{TrustedProxies, resolve_client_ip};
+use crate::limits::{Deadline, RequestLimits};
+use crate::response_body::{ResponseBody, SendFile};
+use crate::cookies::{self, SetCookie};
+use crate::form::{Form, Multipart};
+use crate::json::Json;
+use crate::mime::MediaType;
+use crate::negotiate;
 
 #[derive(Debug)]
 pub enum RequestType {
@@ -13,15 +22,75 @@ pub enum RequestType {
 }
 
 fn make_code(code: u16) -> String {
+    format!("HTTP/1.1 {} {}", code, reason_phrase(code))
+}
+
+// the standard reason phrases from the IANA status code registry
+// codes that arent registered just get an empty phrase which HTTP/1.1 allows
+pub fn reason_phrase(code: u16) -> &'static str {
     match code {
-        200 => String::from("HTTP/1.1 200 OK"),
-        400 => String::from("HTTP/1.1 400 BAD REQUEST"),
-        404 => String::from("HTTP/1.1 404 NOT FOUND"),
-        405 => String::from("HTTP/1.1 405 METHOD NOT ALLOWED"),
-        415 => String::from("HTTP/1.1 415 UNSUPPORTED MEDIA TYPE"),
-        429 => String::from("HTTP/1.1 429 TOO MANY REQUESTS"),
-        500 => String::from("HTTP/1.1 500 INTERAL SERVER ERROR"),
-        _ => unimplemented!(),
+        100 => "Continue",
+        101 => "Switching Protocols",
+        102 => "Processing",
+        103 => "Early Hints",
+        200 => "OK",
+        201 => "Created",
+        202 => "Accepted",
+        203 => "Non-Authoritative Information",
+        204 => "No Content",
+        205 => "Reset Content",
+        206 => "Partial Content",
+        207 => "Multi-Status",
+        208 => "Already Reported",
+        226 => "IM Used",
+        300 => "Multiple Choices",
+        301 => "Moved Permanently",
+        302 => "Found",
+        303 => "See Other",
+        304 => "Not Modified",
+        305 => "Use Proxy",
+        307 => "Temporary Redirect",
+        308 => "Permanent Redirect",
+        400 => "Bad Request",
+        401 => "Unauthorized",
+        402 => "Payment Required",
+        403 => "Forbidden",
+        404 => "Not Found",
+        405 => "Method Not Allowed",
+        406 => "Not Acceptable",
+        407 => "Proxy Authentication Required",
+        408 => "Request Timeout",
+        409 => "Conflict",
+        410 => "Gone",
+        411 => "Length Required",
+        412 => "Precondition Failed",
+        413 => "Content Too Large",
+        414 => "URI Too Long",
+        415 => "Unsupported Media Type",
+        416 => "Range Not Satisfiable",
+        417 => "Expectation Failed",
+        418 => "I'm a teapot",
+        421 => "Misdirected Request",
+        422 => "Unprocessable Content",
+        423 => "Locked",
+        424 => "Failed Dependency",
+        425 => "Too Early",
+        426 => "Upgrade Required",
+        428 => "Precondition Required",
+        429 => "Too Many Requests",
+        431 => "Request Header Fields Too Large",
+        451 => "Unavailable For Legal Reasons",
+        500 => "Internal Server Error",
+        501 => "Not Implemented",
+        502 => "Bad Gateway",
+        503 => "Service Unavailable",
+        504 => "Gateway Timeout",
+        505 => "HTTP Version Not Supported",
+        506 => "Variant Also Negotiates",
+        507 => "Insufficient Storage",
+        508 => "Loop Detected",
+        511 => "Network Authentication Required",
+        _ => "",
     }
 }
 
@@ -31,113 +100,259 @@ pub struct Response {
     content_type: ContentType,
     modified_date: Option<SystemTime>,
     current_time: SystemTime,
-    allowed: Option<String>,
-    data: Vec<u8>,
+    headers: Headers,
+    body: ResponseBody,
 }
 
 impl Response {
-    pub fn new(code: u16, content_type: ContentType, modified_date: Option<SystemTime>, allowed: Option<String>, data: Vec<u8>) -> Self {
-        let current_time = SystemTime::now();
+    pub fn new(code: u16, content_type: ContentType, modified_date: Option<SystemTime>, data: Vec<u8>) -> Self {
+        Self::new_streamed(code, content_type, modified_date, ResponseBody::Bytes(data))
+    }
+
+    // for bodies that are written out a chunk at a time instead of being held in memory
+    //    let file = File::open("website/files/big.wasm")?;
+    //    Response::new_streamed(200, ContentType::Wasm, None, ResponseBody::file(file)?)
+    pub fn new_streamed(code: u16, content_type: ContentType, modified_date: Option<SystemTime>, body: ResponseBody) -> Self {
+        // anything outside 100-599 isnt a status code, better to send a 500 than crash the worker
+        let code = if (100..=599).contains(&code) {
+            code
+        } else {
+            println!("Error: invalid status code {}, occured at: {}", code, turn_system_time_to_http_date(SystemTime::now()));
+            500
+        };
+
         Self {
             code,
             content_type,
             modified_date,
-            current_time,
-            allowed,
-            data,
+            current_time: SystemTime::now(),
+            headers: Headers::new(),
+            body,
         }
     }
 
     pub fn new_ok(content_type: ContentType, modified_date: Option<SystemTime>, data: Vec<u8>) -> Self {
-        let current_time = SystemTime::now();
-        Self {
-            code: 200,
-            content_type,
-            modified_date,
-            current_time,
-            allowed: None,
-            data,
-        }
+        Self::new(200, content_type, modified_date, data)
+    }
+
+    // tells the client its cached copy is still good, this never has a body
+    pub fn not_modified(modified_date: Option<SystemTime>, etag: String) -> Self {
+        Self::new(304, ContentType::PlainText, modified_date, Vec::new())
+            .with_header("ETag", &etag)
+    }
+
+    // none of the requested ranges are inside the file, tells the client how long it actually is
+    pub fn range_not_satisfiable(total: u64) -> Self {
+        let data = String::from("Range Not Satisfiable").into_bytes();
+        Self::new(416, ContentType::PlainText, None, data)
+            .with_header("Content-Range", &format!("bytes */{}", total))
+            .with_header("Accept-Ranges", "bytes")
     }
 
     pub fn empty_404() -> Self {
         let data = String::from("NOT FOUND").into_bytes();
-        Self {
-            code: 404,
-            content_type: ContentType::PlainText,
-            modified_date: None,
-            current_time: SystemTime::now(),
-            allowed: None,
-            data,
-        }
+        Self::new(404, ContentType::PlainText, None, data)
     }
 
     pub fn empty_ok() -> Self {
         let data = String::from("OK").into_bytes();
-        Self {
-            code: 200,
-            content_type: ContentType::PlainText,
-            modified_date: None,
-            current_time: SystemTime::now(),
-            allowed: None,
-            data,
-        }
+        Self::new(200, ContentType::PlainText, None, data)
     }
 
     pub fn empty_500_error() -> Self {
         let data = String::from("Internal Server Error").into_bytes();
-        Self {
-            code: 500,
-            content_type: ContentType::PlainText,
-            modified_date: None,
-            current_time: SystemTime::now(),
-            allowed: None,
-            data,
-        }
+        Self::new(500, ContentType::PlainText, None, data)
+    }
+
+    // picks the status code that fits the error instead of always sending a 400
+    pub fn from_error(error: HTTPError) -> Self {
+        let data = format!("{}", error).into_bytes();
+        Self::new(error.get_code(), ContentType::PlainText, None, data)
     }
 
     pub fn new_400_error(error: HTTPError) -> Self {
         let data = format!("{}", error).into_bytes();
-        Self {
-            code: 400,
-            content_type: ContentType::PlainText,
-            modified_date: None,
-            current_time: SystemTime::now(),
-            allowed: None,
-            data,
-        }
+        Self::new(400, ContentType::PlainText, None, data)
+    }
+
+    // lists what could have been sent so the client can ask again
+    pub fn new_406_error(offered: &[ContentType]) -> Self {
+        let offered = offered.iter()
+            .map(ContentType::to_string)
+            .collect::<Vec<String>>()
+            .join(", ");
+        let data = format!("Not Acceptable, available types are: {}", offered).into_bytes();
+        Self::new(406, ContentType::PlainText, None, data)
+            .with_header("Vary", "Accept")
     }
 
-    pub fn new_405_error(accpected: &str) -> Self {
+    pub fn new_405_error(allowed: &str) -> Self {
         let data = String::from("Method Not Allowed").into_bytes();
-        Self {
-            code: 405,
-            content_type: ContentType::PlainText,
-            modified_date: None,
-            current_time: SystemTime::now(),
-            allowed: Some(accpected.into()),
-            data,
+        Self::new(405, ContentType::PlainText, None, data)
+            .with_header("Allow", allowed)
+    }
+
+    // location has to be percent-encoded already, 301 and 308 get cached by browsers so only use them when its for good
+    pub fn redirect(code: u16, location: &str) -> Self {
+        Self::new(code, ContentType::PlainText, None, Vec::new())
+            .with_header("Location", location)
+    }
+
+    // adds a header to the response, the same name can be added more than once for things like Set-Cookie
+    //    Response::new(301, ContentType::PlainText, None, Vec::new())
+    //        .with_header("Location", "/blog")
+    //        .with_header("Cache-Control", "max-age=3600")
+    pub fn with_header(mut self, name: &str, value: &str) -> Self {
+        self.add_header(name, value);
+        self
+    }
+
+    pub fn add_header(&mut self, name: &str, value: &str) {
+        if check_header(name, value) {
+            self.headers.insert(name, value);
         }
     }
 
-    pub fn into_bytes(self) -> Vec<u8> {
-        let header = format!("{}\r\nContent-type: {}\r\nContent-length: {}\r\n", make_code(self.code), self.content_type, self.data.len());
-        let modified_date = match self.modified_date {
-            None => String::new(),
-            Some(time) => format!("Last-Modified: {}\r\n", turn_system_time_to_http_date(time)),
-        };
+    // one Set-Cookie header per cookie, a cookie that isnt valid is left off like a bad header
+    pub fn with_cookie(mut self, cookie: &SetCookie) -> Self {
+        self.add_cookie(cookie);
+        self
+    }
 
-        let accpected = match self.allowed {
-            None => String::new(),
-            Some(s) => format!("Accpect: {}\r\n", s),
+    pub fn add_cookie(&mut self, cookie: &SetCookie) {
+        if cookie.is_valid() {
+            self.add_header("Set-Cookie", &cookie.to_string());
+        }
+    }
+
+    // replaces any value already set for the name
+    pub fn set_header(&mut self, name: &str, value: &str) {
+        if check_header(name, value) {
+            self.headers.set(name, value);
+        }
+    }
+
+    pub fn headers(&self) -> &Headers {
+        &self.headers
+    }
+
+    pub fn get_code(&self) -> u16 {
+        self.code
+    }
+
+    pub fn get_content_type(&self) -> ContentType {
+        self.content_type
+    }
+
+    // the body when its held in memory, None for files and readers
+    pub fn get_data(&self) -> Option<&[u8]> {
+        match &self.body {
+            ResponseBody::Bytes(data) => Some(data),
+            _ => None,
+        }
+    }
+
+    // swaps the body out and keeps everything else like the status and headers
+    pub fn set_data(&mut self, content_type: ContentType, data: Vec<u8>) {
+        self.content_type = content_type;
+        self.body = ResponseBody::Bytes(data);
+    }
+
+    // tells the client if the connection will stay open after this response
+    // a body without a known length only ends when the connection does so it always closes
+    pub fn set_keep_alive(&mut self, keep_alive: bool) {
+        let value = if keep_alive && self.body.len().is_some() { "keep-alive" } else { "close" };
+        self.headers.set("Connection", value);
+    }
+
+    pub fn keep_alive(&self) -> bool {
+        self.headers.has_token("Connection", "keep-alive")
+    }
+
+    // writes the header and then the body, head_only leaves the body off to answer a HEAD request
+    pub fn write_to<W: SendFile>(self, writer: &mut W, head_only: bool) -> std::io::Result<()> {
+        match self.into_head(head_only) {
+            // small enough to go out in one write with the header
+            (header, ResponseBody::Bytes(data)) => writer.write_all(&[header, data].concat()),
+            (header, body) => {
+                writer.write_all(&header)?;
+                body.write_to(writer)
+            },
+        }
+    }
+
+    // the header ready to be sent and the body that goes after it, for sending the body some other way
+    // the body is empty for head_only and codes that cant have one
+    pub fn into_head(self, head_only: bool) -> (Vec<u8>, ResponseBody) {
+        let header = self.header_bytes();
+        if head_only || !self.has_body() {
+            return (header, ResponseBody::Bytes(Vec::new()));
+        }
+        (header, self.body)
+    }
+
+    // 1xx, 204 and 304 never have a body so they cant have a length or type for one
+    // a 304 describes the cached body anyway
+    fn has_body(&self) -> bool {
+        !(self.code < 200 || self.code == 204 || self.code == 304)
+    }
+
+    // everything HTTP/2 needs to send the response itself, the body is empty for codes that cant have one
+    pub fn into_parts(self) -> (u16, Vec<(String, String)>, ResponseBody) {
+        let fields = self.fields();
+        let body = if self.has_body() {
+            self.body
+        } else {
+            ResponseBody::Bytes(Vec::new())
         };
+        (self.code, fields, body)
+    }
+
+    fn header_bytes(&self) -> Vec<u8> {
+        let mut line = format!("{}\r\n", make_code(self.code));
+        for (name, value) in self.fields() {
+            line.push_str(&format!("{}: {}\r\n", name, value));
+        }
+        line.push_str("\r\n");
+        line.into_bytes()
+    }
+
+    // every header field in the order they get sent
+    fn fields(&self) -> Vec<(String, String)> {
+        let mut fields = Vec::with_capacity(self.headers.len() + 4);
+        if self.has_body() {
+            fields.push((String::from("Content-type"), self.content_type.to_string()));
+            if let Some(len) = self.body.len() {
+                fields.push((String::from("Content-length"), len.to_string()));
+            }
+        }
+
+        if let Some(time) = self.modified_date {
+            fields.push((String::from("Last-Modified"), turn_system_time_to_http_date(time)));
+        }
+
+        for (name, value) in self.headers.iter() {
+            fields.push((name.to_owned(), value.to_owned()));
+        }
 
-        let date = format!("Date: {}\r\n\r\n", turn_system_time_to_http_date(self.current_time));
+        fields.push((String::from("Date"), turn_system_time_to_http_date(self.current_time)));
+        fields
+    }
+}
 
-        let line = header + &modified_date + &accpected + &date;
-        println!("{}", line);
-        [line.as_bytes(), &self.data].concat()
+// stops a handler from breaking the response with a bad name or a value with a newline in it
+// the headers the response works out itself cant be set by hand either
+fn check_header(name: &str, value: &str) -> bool {
+    let managed = ["Content-Type", "Content-Length", "Date", "Last-Modified"].iter()
+        .any(|managed| managed.eq_ignore_ascii_case(name));
+    let valid_name = !name.is_empty() && name.bytes().all(is_token_char);
+    let valid_value = !value.bytes().any(|c| c == b'\r' || c == b'\n' || c == b'\0');
+
+    if managed || !valid_name || !valid_value {
+        println!("Error: refused to set header {:?}: {:?}, occured at: {}", name, value, turn_system_time_to_http_date(SystemTime::now()));
+        return false;
     }
+    true
 }
 
 #[derive(Clone, Copy, Debug)]
@@ -151,6 +366,11 @@ pub enum ContentType {
     OctetStream, // should be raw binary
     Wasm,
     Wgsl,
+    EventStream,
+    Json,
+    FormUrlEncoded,
+    MultipartFormData,
+    MultipartByteRanges(u64), // the boundary, only ever sent back for multi range requests
 }
 
 #[derive(Clone, Copy, Debug)]
@@ -170,23 +390,36 @@ pub enum ImageType {
     XIcon,
 }
 
+// only looks at type/subtype, the parameters are kept on the request as a MediaType
+// a well formed type we dont know about is a 415 rather than a 400
 impl std::str::FromStr for ContentType {
     type Err = HTTPError;
     fn from_str(s: &str) -> Result<Self, Self::Err> {
-        println!("{s}");
-        match s {
-            "image/png" => Ok(Self::Image(ImageType::Png)),
-            "image/svg+xml" => Ok(Self::Image(ImageType::Svg)),
-            "image/x-icon" => Ok(Self::Image(ImageType::XIcon)),
-            "text/css" => Ok(Self::Css),
-            "text/javascript" => Ok(Self::JavaScript),
-            "text/html" => Ok(Self::Html),
-            "text/plain" => Ok(Self::PlainText),
-            "application/octet-stream" => Ok(Self::OctetStream),
-            "application/wasm" => Ok(Self::Wasm),
-            "text/wgsl" => Ok(Self::Wgsl),
-            _ => Err(HTTPError::InvalidContentType),
-        }
+        Self::from_media_type(&MediaType::from_str(s)?).ok_or(HTTPError::UnsupportedMediaType)
+    }
+}
+
+impl ContentType {
+    // None for types the server doesnt have a name for
+    pub fn from_media_type(media_type: &MediaType) -> Option<Self> {
+        let content_type = match media_type.essence().as_str() {
+            "image/png" => Self::Image(ImageType::Png),
+            "image/svg+xml" => Self::Image(ImageType::Svg),
+            "image/x-icon" => Self::Image(ImageType::XIcon),
+            "text/css" => Self::Css,
+            "text/javascript" => Self::JavaScript,
+            "text/html" => Self::Html,
+            "text/plain" => Self::PlainText,
+            "application/octet-stream" => Self::OctetStream,
+            "application/wasm" => Self::Wasm,
+            "text/wgsl" => Self::Wgsl,
+            "text/event-stream" => Self::EventStream,
+            "application/json" => Self::Json,
+            "application/x-www-form-urlencoded" => Self::FormUrlEncoded,
+            "multipart/form-data" => Self::MultipartFormData,
+            _ => return None,
+        };
+        Some(content_type)
     }
 }
 
@@ -209,58 +442,161 @@ impl std::fmt::Display for ContentType {
             Self::OctetStream => write!(f, "application/octet-stream"),
             Self::Wasm => write!(f, "application/wasm"),
             Self::Wgsl => write!(f, "text/wgsl"),
+            Self::EventStream => write!(f, "text/event-stream"),
+            Self::Json => write!(f, "application/json"),
+            Self::FormUrlEncoded => write!(f, "application/x-www-form-urlencoded"),
+            Self::MultipartFormData => write!(f, "multipart/form-data"),
+            Self::MultipartByteRanges(boundary) => write!(f, "multipart/byteranges; boundary={:016x}", boundary),
         }
     }
 }
 
 // used for API's to take a request either get or post without wierd jank,
+// methods without a body share GETRequest and methods with one share POSTRequest
 #[derive(Debug)]
 pub enum Request {
     GetRequest(GETRequest),
+    HeadRequest(GETRequest),
+    OptionsRequest(GETRequest),
     POSTRequest(POSTRequest),
+    PutRequest(POSTRequest),
+    DeleteRequest(POSTRequest),
+    PatchRequest(POSTRequest),
 }
 
 impl Request {
-    pub fn new(stream: &mut TcpStream) -> Result<Self, HTTPError> {
-        let mut buf_reader = BufReader::new(stream);
+    // the reader is kept by the caller between requests so any pipelined
+    // requests that got buffered early are not lost
+    // peer is whoever opened the connection, only a trusted proxy can say the client is someone else
+    // the client has limits.header_timeout from the first byte to get the whole header in
+    pub fn new<R: BufRead + Deadline>(buf_reader: &mut R, peer: IpAddr, trusted_proxies: &TrustedProxies, limits: &RequestLimits) -> Result<Self, HTTPError> {
+        Self::parse(buf_reader, peer, trusted_proxies, limits).map_err(|e| e.error)
+    }
+
+    // like new but a request that cant be read still gives back the path and Accept header
+    // if they got that far, so the error can be sent in a format the client takes
+    pub fn parse<R: BufRead + Deadline>(buf_reader: &mut R, peer: IpAddr, trusted_proxies: &TrustedProxies, limits: &RequestLimits) -> Result<Self, RequestError> {
+        buf_reader.set_deadline(Some(Instant::now() + limits.header_timeout));
+        let request = Self::read(buf_reader, peer, trusted_proxies, limits);
+        // back to the idle timeout while we wait for the next one
+        buf_reader.set_deadline(None);
+        request
+    }
 
+    fn read<R: BufRead + Deadline>(buf_reader: &mut R, peer: IpAddr, trusted_proxies: &TrustedProxies, limits: &RequestLimits) -> Result<Self, RequestError> {
         // should theoretically grab the 'GET path HTTP/1.1\r\n' 
-        let mut first_line_buffer = Vec::new();
-        let request_line_string = match buf_reader.read_until(b'\n', &mut first_line_buffer) {
-            Ok(_) => {
-                match String::from_utf8(first_line_buffer) {
-                    Ok(string) => string,
-                    Err(e) => {
-                        println!("Error: {}\n Occured at: {}", e, turn_system_time_to_http_date(SystemTime::now()));
-                        return Err(HTTPError::InvalidRequestLine);
-                    },
-                }
-            },
+        // RFC 9112 says to skip any empty lines sent before the request line
+        let first_line_buffer = read_request_line(buf_reader, limits.max_request_line)?;
+        let request_line_string = match String::from_utf8(first_line_buffer) {
+            Ok(string) => string,
             Err(e) => {
                 println!("Error: {}\n Occured at: {}", e, turn_system_time_to_http_date(SystemTime::now()));
-                return Err(HTTPError::InvalidRequestLine);
-            }
+                return Err(HTTPError::InvalidRequestLine.into());
+            },
         };
 
         let request_line = HTTPRequestLine::from_str(&request_line_string)?;
 
-        match request_line.get_kind() {
-            HTTPType::Get => Ok(Self::GetRequest(GETRequest::new(request_line, buf_reader)?)),
-            HTTPType::Post => Ok(Self::POSTRequest(POSTRequest::new(request_line, buf_reader)?)),
+        let path = request_line.path.clone();
+        let headers = Headers::read_from(buf_reader, limits.max_header_size)
+            .map_err(|error| RequestError { error, path: path.clone(), headers: Headers::new() })?;
+        // only what picking a format for the error needs is held onto
+        let mut accept = Headers::new();
+        headers.get_all("Accept").for_each(|value| accept.insert("Accept", value));
+
+        let request = match request_line.get_kind() {
+            HTTPType::Get => GETRequest::read_rest(request_line, headers, buf_reader, peer, trusted_proxies, limits).map(Self::GetRequest),
+            HTTPType::Head => GETRequest::read_rest(request_line, headers, buf_reader, peer, trusted_proxies, limits).map(Self::HeadRequest),
+            HTTPType::Options => GETRequest::read_rest(request_line, headers, buf_reader, peer, trusted_proxies, limits).map(Self::OptionsRequest),
+            HTTPType::Post => POSTRequest::read_rest(request_line, headers, buf_reader, peer, trusted_proxies, limits).map(Self::POSTRequest),
+            HTTPType::Put => POSTRequest::read_rest(request_line, headers, buf_reader, peer, trusted_proxies, limits).map(Self::PutRequest),
+            HTTPType::Delete => POSTRequest::read_rest(request_line, headers, buf_reader, peer, trusted_proxies, limits).map(Self::DeleteRequest),
+            HTTPType::Patch => POSTRequest::read_rest(request_line, headers, buf_reader, peer, trusted_proxies, limits).map(Self::PatchRequest),
+        };
+        request.map_err(|error| RequestError { error, path, headers: accept })
+    }
+
+    // builds a request out of pieces that were already read, HTTP/2 uses this as it has no request line
+    pub fn from_parts(line: HTTPRequestLine, headers: Headers, content: Vec<u8>, peer: IpAddr, trusted_proxies: &TrustedProxies) -> Result<Self, HTTPError> {
+        match line.get_kind() {
+            HTTPType::Get => Ok(Self::GetRequest(GETRequest::from_parts(line, headers, peer, trusted_proxies)?)),
+            HTTPType::Head => Ok(Self::HeadRequest(GETRequest::from_parts(line, headers, peer, trusted_proxies)?)),
+            HTTPType::Options => Ok(Self::OptionsRequest(GETRequest::from_parts(line, headers, peer, trusted_proxies)?)),
+            HTTPType::Post => Ok(Self::POSTRequest(POSTRequest::from_parts(line, headers, content, Headers::new(), peer, trusted_proxies)?)),
+            HTTPType::Put => Ok(Self::PutRequest(POSTRequest::from_parts(line, headers, content, Headers::new(), peer, trusted_proxies)?)),
+            HTTPType::Delete => Ok(Self::DeleteRequest(POSTRequest::from_parts(line, headers, content, Headers::new(), peer, trusted_proxies)?)),
+            HTTPType::Patch => Ok(Self::PatchRequest(POSTRequest::from_parts(line, headers, content, Headers::new(), peer, trusted_proxies)?)),
+        }
+    }
+
+    pub fn headers(&self) -> &Headers {
+        match self {
+            Request::GetRequest(r) | Request::HeadRequest(r) | Request::OptionsRequest(r) => &r.headers,
+            Request::POSTRequest(r) | Request::PutRequest(r) | Request::DeleteRequest(r) | Request::PatchRequest(r) => &r.headers,
+        }
+    }
+
+    // every cookie the client sent, in the order it sent them
+    pub fn cookies(&self) -> Vec<(String, String)> {
+        cookies::parse(self.headers())
+    }
+
+    pub fn get_cookie(&self, name: &str) -> Option<String> {
+        cookies::get(self.headers(), name)
+    }
+
+    // which of the offered types to answer with going by the Accept header, None should be a 406
+    pub fn negotiate(&self, offered: &[ContentType]) -> Option<ContentType> {
+        negotiate::negotiate(self.headers(), offered)
+    }
+
+    pub fn get_kind(&self) -> HTTPType {
+        match self {
+            Request::GetRequest(_) => HTTPType::Get,
+            Request::HeadRequest(_) => HTTPType::Head,
+            Request::OptionsRequest(_) => HTTPType::Options,
+            Request::POSTRequest(_) => HTTPType::Post,
+            Request::PutRequest(_) => HTTPType::Put,
+            Request::DeleteRequest(_) => HTTPType::Delete,
+            Request::PatchRequest(_) => HTTPType::Patch,
         }
     }
 
     pub fn get_path(&self) -> &str {
         match self {
-            Request::GetRequest(r) => &r.path,
-            Request::POSTRequest(r) => &r.path,
+            Request::GetRequest(r) | Request::HeadRequest(r) | Request::OptionsRequest(r) => &r.path,
+            Request::POSTRequest(r) | Request::PutRequest(r) | Request::DeleteRequest(r) | Request::PatchRequest(r) => &r.path,
+        }
+    }
+
+    // the Host header, or :authority for HTTP/2
+    pub fn get_host(&self) -> &str {
+        match self {
+            Request::GetRequest(r) | Request::HeadRequest(r) | Request::OptionsRequest(r) => r.get_host(),
+            Request::POSTRequest(r) | Request::PutRequest(r) | Request::DeleteRequest(r) | Request::PatchRequest(r) => r.get_host(),
+        }
+    }
+
+    // the query string exactly as it was sent, for passing it along to somewhere else
+    pub fn get_raw_query(&self) -> Option<&str> {
+        match self {
+            Request::GetRequest(r) | Request::HeadRequest(r) | Request::OptionsRequest(r) => r.raw_query.as_deref(),
+            Request::POSTRequest(r) | Request::PutRequest(r) | Request::DeleteRequest(r) | Request::PatchRequest(r) => r.raw_query.as_deref(),
         }
     }
 
     pub fn get_ip(&self) -> IpAddr {
         match self {
-            Request::GetRequest(r) => r.ip,
-            Request::POSTRequest(r) => r.ip,
+            Request::GetRequest(r) | Request::HeadRequest(r) | Request::OptionsRequest(r) => r.ip,
+            Request::POSTRequest(r) | Request::PutRequest(r) | Request::DeleteRequest(r) | Request::PatchRequest(r) => r.ip,
+        }
+    }
+
+    // whether the client wants the connection kept open after the response
+    pub fn keep_alive(&self) -> bool {
+        match self {
+            Request::GetRequest(r) | Request::HeadRequest(r) | Request::OptionsRequest(r) => r.keep_alive,
+            Request::POSTRequest(r) | Request::PutRequest(r) | Request::DeleteRequest(r) | Request::PatchRequest(r) => r.keep_alive,
         }
     }
 }
@@ -268,75 +604,74 @@ impl Request {
 #[derive(Debug)]
 pub struct POSTRequest {
     path: String,
-    query_string: HashMap<String, String>,
+    query_string: HashMap<String, Vec<String>>,
+    raw_query: Option<String>,
     host: String,
     ip: IpAddr,
     content_type: ContentType,
+    media_type: Option<MediaType>,
     content_length: usize,
     content: Vec<u8>,
+    headers: Headers,
+    trailers: Headers,
+    keep_alive: bool,
 }
 
 impl POSTRequest {
-    pub fn new(line: HTTPRequestLine, reader: BufReader<&mut TcpStream>) -> Result<Self, HTTPError>{
-        let (path, query_string) = match line.path.split_once("?") {
-            Some((left, right)) => {
-                let queries = process_query_string(right)?;
-                (left.to_owned(), queries)
-            },
-            None => (line.path, HashMap::new())
-        };
+    pub fn new<R: BufRead + Deadline>(line: HTTPRequestLine, reader: &mut R, peer: IpAddr, trusted_proxies: &TrustedProxies, limits: &RequestLimits) -> Result<Self, HTTPError>{
+        let headers = Headers::read_from(reader, limits.max_header_size)?;
+        Self::read_rest(line, headers, reader, peer, trusted_proxies, limits)
+    }
 
+    // the body once the header is in
+    fn read_rest<R: BufRead + Deadline>(line: HTTPRequestLine, headers: Headers, reader: &mut R, peer: IpAddr, trusted_proxies: &TrustedProxies, limits: &RequestLimits) -> Result<Self, HTTPError> {
+        // everything that can fail on the header is checked before the body gets read
+        let framing = Framing::from_headers(&headers, line.version)?;
+        let media_type = read_media_type(&headers)?;
 
-        let (header, mut reader) = split_header(reader)?;
-
-        let mut host = String::new();
-        let mut content_type = ContentType::PlainText;
-        let mut content_length = 0;
-        let mut ip_str = "";
-
-        for line in header.lines() {
-            if line.starts_with("Content-Type: ") {
-                content_type = ContentType::from_str(&line[14..])?;
-            } else if line.starts_with("Host: ") {
-                host.push_str(&line[6..]);
-            } else if line.starts_with("Content-Length: ") {
-                content_length = match line[16..].parse() {
-                    Err(_) => return Err(HTTPError::InvalidContentLength),
-                    Ok(num) => num,
-                };
-            } else if line.starts_with("X-Forwarded-For: ") {
-                ip_str = &line[17..];
-            }
-        }
+        let body = read_body(reader, framing.body_length, limits)?;
+        Self::build(line, headers, framing, media_type, body, peer, trusted_proxies)
+    }
 
-        let ip = match IpAddr::from_str(ip_str) {
-            Ok(ip) => ip,
-            Err(_) => Err(HTTPError::FailedToObtainIP)?,
+    // for a request whose header and body have already been read some other way, like off an HTTP/2 stream
+    pub fn from_parts(line: HTTPRequestLine, headers: Headers, content: Vec<u8>, trailers: Headers, peer: IpAddr, trusted_proxies: &TrustedProxies) -> Result<Self, HTTPError> {
+        let framing = Framing::from_headers(&headers, line.version)?;
+        let media_type = read_media_type(&headers)?;
+        Self::build(line, headers, framing, media_type, (content, trailers), peer, trusted_proxies)
+    }
+
+    fn build(line: HTTPRequestLine, headers: Headers, framing: Framing, media_type: Option<MediaType>, body: (Vec<u8>, Headers), peer: IpAddr, trusted_proxies: &TrustedProxies) -> Result<Self, HTTPError> {
+        let path = line.path;
+        let query_string = match &line.query {
+            Some(query) => process_query_string(query)?,
+            None => HashMap::new(),
         };
 
-        // read content length
-        let mut content: Vec<u8> = Vec::with_capacity(content_length);
-        let mut amount_read = 0;
-        while amount_read < content_length {
-            const BUFFER_SIZE: usize = 10;
-            let mut buffer = [0_u8; BUFFER_SIZE];
-            let amount_to_read = BUFFER_SIZE.min(content_length - amount_read);
-            match reader.read_exact(&mut buffer[..amount_to_read]) {
-                Err(_) => return Err(HTTPError::InvalidContent),
-                Ok(_) => {},
-            }
-            content.extend(&buffer[..amount_to_read]);
-            amount_read += BUFFER_SIZE;
-        }
+        let Framing { host, body_length } = framing;
+        let (content, trailers) = body;
+        // whether the body is a type it can take is up to the handler so anything well formed gets through,
+        // types without a ContentType of their own are just bytes as far as get_content_type goes
+        let content_type = match &media_type {
+            Some(media_type) => ContentType::from_media_type(media_type).unwrap_or(ContentType::OctetStream),
+            None => ContentType::PlainText,
+        };
+        let keep_alive = wants_keep_alive(line.version, &headers, body_length);
+        let ip = resolve_client_ip(peer, &headers, trusted_proxies);
+        let content_length = content.len();
 
         Ok(Self {
             path,
             host,
             query_string,
+            raw_query: line.query,
             ip,
             content_type,
+            media_type,
             content_length,
-            content
+            content,
+            headers,
+            trailers,
+            keep_alive,
         })
     }
 
@@ -348,6 +683,24 @@ impl POSTRequest {
         &self.host
     }
 
+    pub fn headers(&self) -> &Headers {
+        &self.headers
+    }
+
+    pub fn get_cookie(&self, name: &str) -> Option<String> {
+        cookies::get(&self.headers, name)
+    }
+
+    pub fn negotiate(&self, offered: &[ContentType]) -> Option<ContentType> {
+        negotiate::negotiate(&self.headers, offered)
+    }
+
+    // fields sent after a chunked body, empty for anything else
+    pub fn trailers(&self) -> &Headers {
+        &self.trailers
+    }
+
+    // the body with any chunked encoding already taken off
     pub fn get_data(&self) -> &[u8] {
         &self.content
     }
@@ -356,115 +709,322 @@ impl POSTRequest {
         self.content_length
     }
 
+    // application/octet-stream for types the server doesnt know, get_media_type has what was actually sent
     pub fn get_content_type(&self) -> ContentType {
         self.content_type
     }
 
+    // the Content-Type with its parameters like charset and boundary, None when it wasnt sent
+    pub fn get_media_type(&self) -> Option<&MediaType> {
+        self.media_type.as_ref()
+    }
+
+    // the body as an html form, sent either urlencoded or as multipart/form-data
+    // file parts of a multipart form are left out, use multipart() to get at those
+    pub fn form(&self) -> Result<Form, HTTPError> {
+        match self.content_type {
+            ContentType::FormUrlEncoded => Form::from_urlencoded(&self.content),
+            ContentType::MultipartFormData => {
+                let multipart = self.multipart()?;
+                let fields = multipart.parts().iter()
+                    .filter(|part| !part.is_file())
+                    .map(|part| Some((part.get_name(), part.text()?)))
+                    .collect::<Option<Vec<(&str, &str)>>>()
+                    .ok_or(HTTPError::InvalidForm)?;
+                Ok(Form::from_fields(&fields))
+            },
+            _ => Err(HTTPError::UnsupportedMediaType),
+        }
+    }
+
+    pub fn multipart(&self) -> Result<Multipart, HTTPError> {
+        match (self.content_type, &self.media_type) {
+            (ContentType::MultipartFormData, Some(media_type)) => Multipart::parse(&self.content, media_type),
+            _ => Err(HTTPError::UnsupportedMediaType),
+        }
+    }
+
+    pub fn json(&self) -> Result<Json, HTTPError> {
+        match self.content_type {
+            ContentType::Json => Json::parse(&self.content),
+            _ => Err(HTTPError::UnsupportedMediaType),
+        }
+    }
+
+    // the first value sent for the key, a key sent without a value gives an empty string
     pub fn get_query(&self, key: &str) -> Option<&String> {
-        self.query_string.get(key)
+        self.query_string.get(key).and_then(|values| values.first())
+    }
+
+    // every value sent for the key in order
+    pub fn get_query_all(&self, key: &str) -> &[String] {
+        match self.query_string.get(key) {
+            Some(values) => values,
+            None => &[],
+        }
+    }
+
+    pub fn has_query(&self, key: &str) -> bool {
+        self.query_string.contains_key(key)
     }
 }
 
-fn process_query_string(queries: &str) -> Result<HashMap<String, String>, HTTPError> {
-    let map = queries.split("&")
-        .map(|decleration| {
-            match decleration.split_once("=") {
-                None => Err(HTTPError::InvalidPath),
-                Some((l, r)) => Ok((l.to_owned(), r.to_owned()))
-            }
-        })
-        .collect::<Result<HashMap<String, String>, HTTPError>>();
+// a=1&a=2&debug&q=C%2B%2B+%26+Rust
+// keys can repeat and dont need a value, + is a space in here but not in the path
+fn process_query_string(queries: &str) -> Result<HashMap<String, Vec<String>>, HTTPError> {
+    let mut map: HashMap<String, Vec<String>> = HashMap::new();
 
-    map
+    for decleration in queries.split('&').filter(|d| !d.is_empty()) {
+        let (key, value) = decleration.split_once('=').unwrap_or((decleration, ""));
+        let key = percent_decode(key, true)?;
+        let value = percent_decode(value, true)?;
+        map.entry(key).or_default().push(value);
+    }
+
+    Ok(map)
 }
 
-fn split_header(mut reader: BufReader<&mut TcpStream>) -> Result<(String, BufReader<&mut TcpStream>), HTTPError> {
-        // some how split the body from the header
-    // this will be painfull and horrible
+// RFC 3986 percent-decoding, the decoded bytes have to be valid utf-8
+pub fn percent_decode(text: &str, plus_as_space: bool) -> Result<String, HTTPError> {
+    let bytes = text.as_bytes();
+    let mut decoded = Vec::with_capacity(bytes.len());
+
+    let mut i = 0;
+    while i < bytes.len() {
+        match bytes[i] {
+            b'%' => {
+                let byte = bytes.get(i + 1..i + 3).and_then(|hex| {
+                    let high = (hex[0] as char).to_digit(16)?;
+                    let low = (hex[1] as char).to_digit(16)?;
+                    Some((high * 16 + low) as u8)
+                });
+                match byte {
+                    Some(byte) => decoded.push(byte),
+                    None => return Err(HTTPError::InvalidEncoding),
+                }
+                i += 3;
+            },
+            b'+' if plus_as_space => {
+                decoded.push(b' ');
+                i += 1;
+            },
+            c => {
+                decoded.push(c);
+                i += 1;
+            },
+        }
+    }
+
+    match String::from_utf8(decoded) {
+        Ok(text) => Ok(text),
+        Err(_) => Err(HTTPError::InvalidEncoding),
+    }
+}
+
+// the other way from percent_decode for a path, anything that isnt allowed in a path segment gets encoded
+// slashes are left alone so the segments stay segments
+pub fn percent_encode_path(path: &str) -> String {
+    let mut encoded = String::with_capacity(path.len());
+    for c in path.bytes() {
+        if c.is_ascii_alphanumeric() || b"-._~!$&'()*+,;=:@/".contains(&c) {
+            encoded.push(c as char);
+        } else {
+            encoded.push_str(&format!("%{:02X}", c));
+        }
+    }
+    encoded
+}
+
+// reads the request line skipping any empty lines in front of it
+// a line that doesnt end within max_size bytes is almost always a giant path so its a 414
+fn read_request_line<R: BufRead>(reader: &mut R, max_size: usize) -> Result<Vec<u8>, HTTPError> {
     let mut buf = Vec::new();
     loop {
-        match reader.read_until(b'\r', &mut buf) {
-            Ok(_) => {},
-            Err(_) => return Err(HTTPError::InvalidHeader),
-        }
-        let mut minor_buf = [0_u8; 3];
-        match reader.read_exact(&mut minor_buf) {
-            Ok(_) => {},
-            Err(_) => return Err(HTTPError::InvalidHeader),
+        buf.clear();
+        let read = match reader.by_ref().take(max_size as u64).read_until(b'\n', &mut buf) {
+            Ok(read) => read,
+            Err(e) => {
+                println!("Error: {}\n Occured at: {}", e, turn_system_time_to_http_date(SystemTime::now()));
+                return Err(HTTPError::from_io(&e, HTTPError::InvalidRequestLine));
+            },
+        };
+        if read == max_size && !buf.ends_with(b"\n") {
+            return Err(HTTPError::UriTooLong);
         }
-        if minor_buf == [b'\n', b'\r', b'\n'] {
-            buf.extend(minor_buf);
-            break;
-        } else {
-            // failed to find the double newline but still need to extend the buffer
-            // as its still part from the header
-            buf.extend(minor_buf);
-            continue;
+        if read == 0 || !matches!(trim_line_ending(&buf), Ok([])) {
+            return Ok(buf);
         }
     }
+}
+
+// HTTP/1.1 connections stay open unless the client asks to close them
+// while HTTP/1.0 ones only stay open if the client asks for keep-alive
+fn wants_keep_alive(version: HTTPVersion, headers: &Headers, body_length: BodyLength) -> bool {
+    // HTTP/1.0 doesnt have chunked so RFC 9112 says the framing cant be trusted after it
+    if version == HTTPVersion::Http10 && body_length == BodyLength::Chunked {
+        return false;
+    }
 
-    let header_string = match String::from_utf8(buf) {
-        Ok(string) => string,
-        Err(_) => return Err(HTTPError::InvalidHeader),
-    };
+    match version {
+        HTTPVersion::Http11 => !headers.has_token("Connection", "close"),
+        HTTPVersion::Http10 => headers.has_token("Connection", "keep-alive"),
+        // streams dont own the connection so closing it is never up to one request
+        HTTPVersion::Http2 => true,
+    }
+}
+
+// HTTP/1.1 requests have to send exactly one Host
+// HTTP/2 ones use :authority instead which gets turned into a Host when its there
+fn get_host(headers: &Headers, version: HTTPVersion) -> Result<String, HTTPError> {
+    let mut hosts = headers.get_all("Host");
+    match (hosts.next(), hosts.next()) {
+        (Some(host), None) => Ok(host.to_owned()),
+        (None, None) if version != HTTPVersion::Http11 => Ok(String::new()),
+        _ => Err(HTTPError::InvalidHost),
+    }
+}
+
+// the Host and how the body is framed, both kinds of request work these out the same way
+// and do it once before any of the body is read
+struct Framing {
+    host: String,
+    body_length: BodyLength,
+}
 
-    Ok((header_string, reader))
+impl Framing {
+    fn from_headers(headers: &Headers, version: HTTPVersion) -> Result<Self, HTTPError> {
+        Ok(Self {
+            host: get_host(headers, version)?,
+            body_length: BodyLength::from_headers(headers)?,
+        })
+    }
+}
+
+// a Content-Type that cant be parsed is a 400, one we dont know is fine and left to the handler
+fn read_media_type(headers: &Headers) -> Result<Option<MediaType>, HTTPError> {
+    headers.get("Content-Type").map(MediaType::from_str).transpose()
 }
 
 #[derive(Debug)]
 pub struct GETRequest {
     pub path: String,
-    query_string: HashMap<String, String>,
+    query_string: HashMap<String, Vec<String>>,
+    raw_query: Option<String>,
+    host: String,
     ip: IpAddr,
+    headers: Headers,
+    keep_alive: bool,
 }
 
 impl GETRequest {
-    pub fn new(line: HTTPRequestLine, reader: BufReader<&mut TcpStream>) -> Result<Self, HTTPError> {
-        let (path, query_string) = match line.path.split_once("?") {
-            Some((left, right)) => {
-                let queries = process_query_string(right)?;
-                (left.to_owned(), queries)
-            },
-            None => (line.path, HashMap::new())
-        };
+    pub fn new<R: BufRead + Deadline>(line: HTTPRequestLine, reader: &mut R, peer: IpAddr, trusted_proxies: &TrustedProxies, limits: &RequestLimits) -> Result<Self, HTTPError> {
+        let headers = Headers::read_from(reader, limits.max_header_size)?;
+        Self::read_rest(line, headers, reader, peer, trusted_proxies, limits)
+    }
 
-        let (header, _) = split_header(reader)?;
+    // any body once the header is in
+    fn read_rest<R: BufRead + Deadline>(line: HTTPRequestLine, headers: Headers, reader: &mut R, peer: IpAddr, trusted_proxies: &TrustedProxies, limits: &RequestLimits) -> Result<Self, HTTPError> {
+        let framing = Framing::from_headers(&headers, line.version)?;
+        let body_length = framing.body_length;
+        let request = Self::build(line, headers, framing, peer, trusted_proxies)?;
 
-        let mut ip_str = "";
+        // nothing uses a body on these but it still has to come off the connection
+        // or it would be read as the next request
+        read_body(reader, body_length, limits)?;
 
-        for line in header.lines() {
-            if line.starts_with("X-Forwarded-For: ") {
-                ip_str = &line[17..];
-            }
-        }
+        Ok(request)
+    }
+
+    // for a request whose header has already been read some other way, like off an HTTP/2 stream
+    pub fn from_parts(line: HTTPRequestLine, headers: Headers, peer: IpAddr, trusted_proxies: &TrustedProxies) -> Result<Self, HTTPError> {
+        let framing = Framing::from_headers(&headers, line.version)?;
+        Self::build(line, headers, framing, peer, trusted_proxies)
+    }
 
-        let ip = match IpAddr::from_str(ip_str) {
-            Ok(ip) => ip,
-            Err(_) => Err(HTTPError::FailedToObtainIP)?,
+    fn build(line: HTTPRequestLine, headers: Headers, framing: Framing, peer: IpAddr, trusted_proxies: &TrustedProxies) -> Result<Self, HTTPError> {
+        let path = line.path;
+        let query_string = match &line.query {
+            Some(query) => process_query_string(query)?,
+            None => HashMap::new(),
         };
 
+        let Framing { host, body_length } = framing;
+        let keep_alive = wants_keep_alive(line.version, &headers, body_length);
+        let ip = resolve_client_ip(peer, &headers, trusted_proxies);
+
         Ok(Self {
             path,
             query_string,
+            raw_query: line.query,
+            host,
             ip,
+            headers,
+            keep_alive,
         })
     }
 
+    // empty for HTTP/1.0 requests that didnt send one
+    pub fn get_host(&self) -> &str {
+        &self.host
+    }
+
+    pub fn headers(&self) -> &Headers {
+        &self.headers
+    }
+
+    pub fn get_cookie(&self, name: &str) -> Option<String> {
+        cookies::get(&self.headers, name)
+    }
+
+    pub fn negotiate(&self, offered: &[ContentType]) -> Option<ContentType> {
+        negotiate::negotiate(&self.headers, offered)
+    }
+
+    // the first value sent for the key, a key sent without a value gives an empty string
     pub fn get_query(&self, key: &str) -> Option<&String> {
-        self.query_string.get(key)
+        self.query_string.get(key).and_then(|values| values.first())
+    }
+
+    // every value sent for the key in order
+    pub fn get_query_all(&self, key: &str) -> &[String] {
+        match self.query_string.get(key) {
+            Some(values) => values,
+            None => &[],
+        }
+    }
+
+    pub fn has_query(&self, key: &str) -> bool {
+        self.query_string.contains_key(key)
     }
 }
 
 #[derive(Debug)]
 pub struct HTTPRequestLine {
     kind: HTTPType,
-    pub path: String,
+    pub path: String, // already decoded
+    pub query: Option<String>, // still encoded as each piece gets decoded on its own
+    version: HTTPVersion,
 }
 
 impl HTTPRequestLine {
+    // for requests that dont come in as a line of text like HTTP/2 ones
+    pub fn new(kind: HTTPType, target: &str, version: HTTPVersion) -> Result<Self, HTTPError> {
+        let (path, query) = parse_target(kind, target)?;
+        Ok(Self {
+            kind,
+            path,
+            query,
+            version,
+        })
+    }
+
     pub fn get_kind(&self) -> HTTPType {
         self.kind
     }
+
+    pub fn get_version(&self) -> HTTPVersion {
+        self.version
+    }
 }
 
 impl std::str::FromStr for HTTPRequestLine {
@@ -474,47 +1034,110 @@ impl std::str::FromStr for HTTPRequestLine {
 
         let kind = match groups.next() {
             None => return Err(HTTPError::InvalidRequestType),
-            Some(kind) => {
-                match kind {
-                    "GET" => HTTPType::Get,
-                    "POST" => HTTPType::Post,
-                    _ => return Err(HTTPError::InvalidRequestType),
-                }
-            }
+            Some(kind) => HTTPType::from_str(kind)?,
         };
 
-        let path = match groups.next() {
+        let target = match groups.next() {
             None => return Err(HTTPError::InvalidPath),
-            Some(s) => s.replace("%20", " ")
+            Some(s) => s,
         };
+        let (path, query) = parse_target(kind, target)?;
 
-        // garuntees unwrap wont fail later
-        if !path.starts_with('/') {
-            return Err(HTTPError::InvalidPath);
-        }
-
-        // prevents people from theoretically escaping the website folder
-        // preventing them from accsessing any file on my PC!
-        if let Some(_) = path.matches("../").next() {
-            return Err(HTTPError::InvalidPath);
-        }
-
-        match groups.next() {
-            None => return Err(HTTPError::InvalidVersion),
-            Some(_) => {},
+        let version = match groups.next() {
+            Some("HTTP/1.1") => HTTPVersion::Http11,
+            Some("HTTP/1.0") => HTTPVersion::Http10,
+            _ => return Err(HTTPError::InvalidVersion),
         };
 
         Ok(Self {
             kind,
             path,
+            query,
+            version,
         })
     }
 }
 
-#[derive(Clone, Copy, Debug)]
+// splits the request target into the decoded path and the still encoded query
+fn parse_target(kind: HTTPType, target: &str) -> Result<(String, Option<String>), HTTPError> {
+    // the query is split off first so an encoded ? in the path stays part of the path
+    let (path, query) = match target.split_once('?') {
+        Some((path, query)) => (path, Some(query.to_owned())),
+        None => (target, None),
+    };
+    let path = percent_decode(path, false)?;
+
+    // garuntees unwrap wont fail later
+    // the only exception is 'OPTIONS *' which asks about the whole server
+    let asks_about_server = matches!(kind, HTTPType::Options) && path == "*";
+    if !path.starts_with('/') && !asks_about_server {
+        return Err(HTTPError::InvalidPath);
+    }
+
+    // prevents people from theoretically escaping the website folder
+    // preventing them from accsessing any file on my PC!
+    // this runs on the decoded path so %2e%2e%2f cant sneak past it
+    let escapes_folder = path.split('/').any(|segment| segment == "..");
+    if escapes_folder || path.contains('\\') || path.contains('\0') {
+        return Err(HTTPError::InvalidPath);
+    }
+
+    Ok((path, query))
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
 pub enum HTTPType {
     Post,
     Get,
+    Head,
+    Options,
+    Put,
+    Delete,
+    Patch,
+}
+
+impl std::str::FromStr for HTTPType {
+    type Err = HTTPError;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "GET" => Ok(Self::Get),
+            "HEAD" => Ok(Self::Head),
+            "OPTIONS" => Ok(Self::Options),
+            "POST" => Ok(Self::Post),
+            "PUT" => Ok(Self::Put),
+            "DELETE" => Ok(Self::Delete),
+            "PATCH" => Ok(Self::Patch),
+            // a well formed method we just dont know about is a 501 not a bad request
+            _ if !s.is_empty() && s.bytes().all(is_token_char) => Err(HTTPError::UnsupportedMethod),
+            _ => Err(HTTPError::InvalidRequestType),
+        }
+    }
+}
+
+impl std::fmt::Display for HTTPType {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Get => write!(f, "GET"),
+            Self::Head => write!(f, "HEAD"),
+            Self::Options => write!(f, "OPTIONS"),
+            Self::Post => write!(f, "POST"),
+            Self::Put => write!(f, "PUT"),
+            Self::Delete => write!(f, "DELETE"),
+            Self::Patch => write!(f, "PATCH"),
+        }
+    }
+}
+
+// the tchar rule from RFC 9110, methods and header names are made of these
+pub(crate) fn is_token_char(c: u8) -> bool {
+    c.is_ascii_alphanumeric() || b"!#$%&'*+-.^_`|~".contains(&c)
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum HTTPVersion {
+    Http10,
+    Http11,
+    Http2,
 }
 
 #[derive(Clone, Copy, Debug)]
@@ -527,7 +1150,44 @@ pub enum HTTPError {
     InvalidContentType,
     InvalidContentLength,
     InvalidContent,
-    FailedToObtainIP,
+    UnsupportedMethod,
+    InvalidHost,
+    InvalidTransferEncoding,
+    UnsupportedTransferEncoding,
+    InvalidChunk,
+    InvalidEncoding,
+    UriTooLong,
+    HeaderTooLarge,
+    ContentTooLarge,
+    RequestTimeout,
+    UnsupportedMediaType,
+    InvalidForm,
+    InvalidJson,
+    // every worker is busy and the queue is full
+    ServiceUnavailable,
+}
+
+impl HTTPError {
+    pub fn get_code(&self) -> u16 {
+        match self {
+            Self::UnsupportedMethod | Self::UnsupportedTransferEncoding => 501,
+            Self::UriTooLong => 414,
+            Self::HeaderTooLarge => 431,
+            Self::ContentTooLarge => 413,
+            Self::RequestTimeout => 408,
+            Self::UnsupportedMediaType => 415,
+            Self::ServiceUnavailable => 503,
+            _ => 400,
+        }
+    }
+
+    // a read that timed out becomes a RequestTimeout, any other io error is `otherwise`
+    pub fn from_io(error: &std::io::Error, otherwise: Self) -> Self {
+        match error.kind() {
+            std::io::ErrorKind::TimedOut | std::io::ErrorKind::WouldBlock => Self::RequestTimeout,
+            _ => otherwise,
+        }
+    }
 }
 
 impl std::fmt::Display for HTTPError {
@@ -541,11 +1201,151 @@ impl std::fmt::Display for HTTPError {
             Self::InvalidContentType => writeln!(f, "Invalid or missing Content-Type"),
             Self::InvalidContentLength => writeln!(f, "Invalid or missing Content-Length"),
             Self::InvalidContent => writeln!(f, "Content to short for Content-Length or invalid Content"),
-            Self::FailedToObtainIP => writeln!(f, "Unable to get IP address of the client"),
+            Self::UnsupportedMethod => writeln!(f, "Request method is not supported"),
+            Self::InvalidHost => writeln!(f, "Missing or repeated Host header"),
+            Self::InvalidTransferEncoding => writeln!(f, "Invalid Transfer-Encoding or sent with Content-Length"),
+            Self::UnsupportedTransferEncoding => writeln!(f, "Transfer-Encoding is not supported"),
+            Self::InvalidChunk => writeln!(f, "Invalid or incomplete chunked body"),
+            Self::InvalidEncoding => writeln!(f, "Invalid percent-encoding in path or query"),
+            Self::UriTooLong => writeln!(f, "Request line was too long"),
+            Self::HeaderTooLarge => writeln!(f, "Request header fields were too large"),
+            Self::ContentTooLarge => writeln!(f, "Request body was too large"),
+            Self::RequestTimeout => writeln!(f, "Client took too long to send the request"),
+            Self::UnsupportedMediaType => writeln!(f, "Content-Type is not supported"),
+            Self::InvalidForm => writeln!(f, "Invalid form data"),
+            Self::InvalidJson => writeln!(f, "Invalid JSON"),
+            Self::ServiceUnavailable => writeln!(f, "The server is too busy right now, try again soon"),
+        }
+    }
+}
+
+// a request that couldnt be read and what was known about it when it failed
+#[derive(Debug)]
+pub struct RequestError {
+    pub error: HTTPError,
+    // empty if the request line couldnt be read
+    pub path: String,
+    // just the Accept fields, empty if the header couldnt be read
+    pub headers: Headers,
+}
+
+impl RequestError {
+    // for a request that stopped part way through, the path and Accept fields are picked out of
+    // whatever of it arrived without checking any of it, lines that didnt finish arriving are left out
+    pub fn from_partial(error: HTTPError, buffer: &[u8]) -> Self {
+        let text = String::from_utf8_lossy(buffer);
+        let complete = match text.rfind('\n') {
+            Some(end) => &text[..end],
+            None => "",
+        };
+        let mut lines = complete.trim_start_matches(['\r', '\n']).split('\n');
+
+        let path = lines.next()
+            .and_then(|line| line.split_whitespace().nth(1))
+            .and_then(|target| target.split('?').next())
+            .unwrap_or_default()
+            .to_string();
+
+        let mut headers = Headers::new();
+        for line in lines {
+            if let Some((name, value)) = line.split_once(':') {
+                if name.trim().eq_ignore_ascii_case("Accept") {
+                    headers.insert("Accept", value.trim());
+                }
+            }
+        }
+
+        Self { error, path, headers }
+    }
+}
+
+impl From<HTTPError> for RequestError {
+    fn from(error: HTTPError) -> Self {
+        Self {
+            error,
+            path: String::new(),
+            headers: Headers::new(),
         }
     }
 }
 
+// reads any of the three date formats RFC 9110 says we have to accept
+// IMF-fixdate: Sun, 06 Nov 1994 08:49:37 GMT
+// RFC 850:     Sunday, 06-Nov-94 08:49:37 GMT
+// asctime:     Sun Nov  6 08:49:37 1994
+pub fn parse_http_date(date: &str) -> Option<SystemTime> {
+    let parts = date.split_whitespace().collect::<Vec<&str>>();
+
+    let (day, month, year, time) = match parts.as_slice() {
+        [week_day, day, month, year, time, "GMT"] if week_day.ends_with(',') => {
+            if year.len() != 4 {
+                return None;
+            }
+            (*day, *month, year.parse::<i64>().ok()?, *time)
+        },
+        [week_day, day_month_year, time, "GMT"] if week_day.ends_with(',') => {
+            let mut pieces = day_month_year.split('-');
+            let (day, month, year) = (pieces.next()?, pieces.next()?, pieces.next()?);
+            if pieces.next().is_some() || year.len() != 2 {
+                return None;
+            }
+            // two digit years are close enough to now to just split them at 70
+            let year = year.parse::<i64>().ok()?;
+            let year = if year < 70 { 2000 + year } else { 1900 + year };
+            (day, month, year, *time)
+        },
+        [_, month, day, time, year] => (*day, *month, year.parse::<i64>().ok()?, *time),
+        _ => return None,
+    };
+
+    if day.is_empty() || day.len() > 2 {
+        return None;
+    }
+    let day = day.parse::<i64>().ok()?;
+    let month = match month {
+        "Jan" => 1,
+        "Feb" => 2,
+        "Mar" => 3,
+        "Apr" => 4,
+        "May" => 5,
+        "Jun" => 6,
+        "Jul" => 7,
+        "Aug" => 8,
+        "Sep" => 9,
+        "Oct" => 10,
+        "Nov" => 11,
+        "Dec" => 12,
+        _ => return None,
+    };
+
+    let mut clock = time.split(':');
+    let (hour, min, sec) = (clock.next()?, clock.next()?, clock.next()?);
+    if clock.next().is_some() || [hour, min, sec].iter().any(|part| part.len() != 2) {
+        return None;
+    }
+    let (hour, min, sec) = (hour.parse::<u64>().ok()?, min.parse::<u64>().ok()?, sec.parse::<u64>().ok()?);
+
+    // 60 is a leap second
+    if !(1..=31).contains(&day) || hour > 23 || min > 59 || sec > 60 {
+        return None;
+    }
+
+    // days since the epoch from the civil date, the reverse of what turn_system_time_to_http_date does
+    let year = if month <= 2 { year - 1 } else { year };
+    let era = if year >= 0 { year } else { year - 399 } / 400;
+    let year_of_era = year - era * 400;
+    let day_of_year = (153 * ((month + 9) % 12) + 2) / 5 + day - 1;
+    let day_of_era = year_of_era * 365 + year_of_era / 4 - year_of_era / 100 + day_of_year;
+    let days = era * 146097 + day_of_era - 719468;
+
+    if days < 0 {
+        return None;
+    }
+
+    let seconds = days as u64 * 86400 + hour * 3600 + min * 60 + sec;
+    Some(UNIX_EPOCH + Duration::from_secs(seconds))
+}
+
 pub fn turn_system_time_to_http_date(time: SystemTime) -> String {
     let time_since_epoch = time.duration_since(UNIX_EPOCH).expect("Times should be after the epoch");
     let seconds_since_epoch = time_since_epoch.as_secs();
@@ -664,4 +1464,128 @@ pub fn turn_system_time_to_http_date(time: SystemTime) -> String {
     buf[24] = b'0' + (sec % 10) as u8;
 
     String::from_utf8_lossy(&buf).to_string()
-}
\ No newline at end of file
+}
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn percent_decoding() {
+        assert_eq!(percent_decode("/blog/my%20post", false).unwrap(), "/blog/my post");
+        assert_eq!(percent_decode("%2F%2f%41", false).unwrap(), "//A");
+        assert_eq!(percent_decode("caf%C3%A9", false).unwrap(), "café");
+        assert_eq!(percent_decode("nothing-to-do", false).unwrap(), "nothing-to-do");
+        assert_eq!(percent_decode("", false).unwrap(), "");
+
+        // + is only a space in a query
+        assert_eq!(percent_decode("C%2B%2B+rust", true).unwrap(), "C++ rust");
+        assert_eq!(percent_decode("C%2B%2B+rust", false).unwrap(), "C+++rust");
+    }
+
+    #[test]
+    fn bad_percent_encoding() {
+        // cut off, not hex, signs in front of the digits and bytes that arent utf-8
+        for text in ["%", "%4", "abc%", "%zz", "%+1", "%-1", "%C3", "%ff%fe", "%C3%28"] {
+            assert!(matches!(percent_decode(text, false), Err(HTTPError::InvalidEncoding)), "{}", text);
+        }
+    }
+
+    #[test]
+    fn query_strings() {
+        let map = process_query_string("a=1&a=2&debug&q=C%2B%2B+%26+Rust&&empty=").unwrap();
+        assert_eq!(map["a"], ["1", "2"]);
+        assert_eq!(map["debug"], [""]);
+        assert_eq!(map["q"], ["C++ & Rust"]);
+        assert_eq!(map["empty"], [""]);
+        assert_eq!(map.len(), 4);
+
+        // the key gets decoded too
+        let map = process_query_string("sort%20by=date").unwrap();
+        assert_eq!(map["sort by"], ["date"]);
+
+        assert!(matches!(process_query_string("a=%zz"), Err(HTTPError::InvalidEncoding)));
+    }
+
+    #[test]
+    fn request_targets() {
+        let (path, query) = parse_target(HTTPType::Get, "/files/a%20b.txt?x=%20").unwrap();
+        assert_eq!(path, "/files/a b.txt");
+        // the query is left encoded for process_query_string
+        assert_eq!(query.as_deref(), Some("x=%20"));
+
+        // an encoded ? belongs to the path
+        let (path, query) = parse_target(HTTPType::Get, "/what%3F?really").unwrap();
+        assert_eq!(path, "/what?");
+        assert_eq!(query.as_deref(), Some("really"));
+
+        assert_eq!(parse_target(HTTPType::Options, "*").unwrap().0, "*");
+        assert!(matches!(parse_target(HTTPType::Get, "*"), Err(HTTPError::InvalidPath)));
+    }
+
+    #[test]
+    fn encoded_escapes_are_still_caught() {
+        for target in ["/../secrets", "/%2e%2e/secrets", "/files/%2E%2E%2F%2E%2E%2Fsecrets", "/a%5Cb", "/a%00b", "no-slash"] {
+            assert!(matches!(parse_target(HTTPType::Get, target), Err(HTTPError::InvalidPath)), "{}", target);
+        }
+        assert!(matches!(parse_target(HTTPType::Get, "/bad%zz"), Err(HTTPError::InvalidEncoding)));
+    }
+
+    #[test]
+    fn paths_are_encoded_back() {
+        assert_eq!(percent_encode_path("/blog/my post?.html"), "/blog/my%20post%3F.html");
+        assert_eq!(percent_encode_path("/café"), "/caf%C3%A9");
+        assert_eq!(percent_encode_path("/a-b_c.d~e/f:g@h"), "/a-b_c.d~e/f:g@h");
+
+        let path = "/odd name/100%/ünïcode";
+        assert_eq!(percent_decode(&percent_encode_path(path), false).unwrap(), path);
+    }
+
+    #[test]
+    fn http_dates() {
+        let expected = UNIX_EPOCH + Duration::from_secs(784111777);
+        assert_eq!(parse_http_date("Sun, 06 Nov 1994 08:49:37 GMT"), Some(expected));
+        assert_eq!(parse_http_date("Sunday, 06-Nov-94 08:49:37 GMT"), Some(expected));
+        assert_eq!(parse_http_date("Sun Nov  6 08:49:37 1994"), Some(expected));
+        // RFC 850 years under 70 are this century
+        assert_eq!(parse_http_date("Thursday, 01-Jan-15 00:00:00 GMT"), parse_http_date("Thu, 01 Jan 2015 00:00:00 GMT"));
+        // what we send has to come back the same
+        assert_eq!(parse_http_date(&turn_system_time_to_http_date(expected)), Some(expected));
+    }
+
+    #[test]
+    fn bad_http_dates() {
+        for date in [
+            "",
+            "yesterday",
+            "Sun, 06 Nov 1994 08:49:37",
+            "Sun, 06 Nov 1994 08:49:37 UTC",
+            "Sun 06 Nov 1994 08:49:37 GMT",
+            "Sun, 06 Nov 94 08:49:37 GMT",
+            "Sun, 06 November 1994 08:49:37 GMT",
+            "Sun, 32 Nov 1994 08:49:37 GMT",
+            "Sun, 06 Nov 1994 24:00:00 GMT",
+            "Sun, 06 Nov 1994 8:49:37 GMT",
+            "Sun, 06 Nov 1994 08:49 GMT",
+            "Sunday, 06-Nov-1994 08:49:37 GMT",
+            "Sun Nov  6 08:49:37 94x",
+        ] {
+            assert_eq!(parse_http_date(date), None, "{date}");
+        }
+    }
+
+    #[test]
+    fn host_header() {
+        let mut headers = Headers::new();
+        // HTTP/1.1 has to say which host its for, older ones dont
+        assert!(matches!(get_host(&headers, HTTPVersion::Http11), Err(HTTPError::InvalidHost)));
+        assert_eq!(get_host(&headers, HTTPVersion::Http10).unwrap(), "");
+        assert_eq!(HTTPError::InvalidHost.get_code(), 400);
+
+        headers.insert("Host", "example.com:8080");
+        assert_eq!(get_host(&headers, HTTPVersion::Http11).unwrap(), "example.com:8080");
+        // two of them is as bad as none
+        headers.insert("host", "other.net");
+        assert!(matches!(get_host(&headers, HTTPVersion::Http11), Err(HTTPError::InvalidHost)));
+        assert!(matches!(get_host(&headers, HTTPVersion::Http10), Err(HTTPError::InvalidHost)));
+    }
+}
@@ -0,0 +1,450 @@
+use crate::types::HTTPError;
+
+// JSON, RFC 8259
+// small enough to not bother with a crate, objects keep their keys in the order they were sent
+
+// arrays and objects nested deeper than this are someone trying to blow the stack
+const MAX_DEPTH: usize = 64;
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Json {
+    Null,
+    Bool(bool),
+    Number(f64),
+    String(String),
+    Array(Vec<Json>),
+    Object(Vec<(String, Json)>),
+}
+
+impl Json {
+    // the whole input has to be one value, whitespace around it is fine
+    pub fn parse(data: &[u8]) -> Result<Self, HTTPError> {
+        let mut parser = Parser { data, pos: 0 };
+        let value = parser.value(0)?;
+        parser.skip_whitespace();
+        if parser.pos != data.len() {
+            return Err(HTTPError::InvalidJson);
+        }
+        Ok(value)
+    }
+
+    // the value for a key in an object, None for anything else
+    // when a key is sent twice the last one wins like most parsers do
+    pub fn get(&self, key: &str) -> Option<&Json> {
+        match self {
+            Self::Object(fields) => fields.iter().rev()
+                .find(|(name, _)| name == key)
+                .map(|(_, value)| value),
+            _ => None,
+        }
+    }
+
+    pub fn as_str(&self) -> Option<&str> {
+        match self {
+            Self::String(text) => Some(text),
+            _ => None,
+        }
+    }
+
+    pub fn as_f64(&self) -> Option<f64> {
+        match self {
+            Self::Number(number) => Some(*number),
+            _ => None,
+        }
+    }
+
+    // only for numbers that are whole and fit
+    pub fn as_u64(&self) -> Option<u64> {
+        match self {
+            Self::Number(number) if number.fract() == 0.0 && *number >= 0.0 && *number <= u64::MAX as f64 => Some(*number as u64),
+            _ => None,
+        }
+    }
+
+    pub fn as_bool(&self) -> Option<bool> {
+        match self {
+            Self::Bool(value) => Some(*value),
+            _ => None,
+        }
+    }
+
+    pub fn as_array(&self) -> Option<&[Json]> {
+        match self {
+            Self::Array(values) => Some(values),
+            _ => None,
+        }
+    }
+
+    pub fn is_null(&self) -> bool {
+        matches!(self, Self::Null)
+    }
+}
+
+impl From<&str> for Json {
+    fn from(text: &str) -> Self {
+        Self::String(text.to_string())
+    }
+}
+
+impl From<String> for Json {
+    fn from(text: String) -> Self {
+        Self::String(text)
+    }
+}
+
+impl From<f64> for Json {
+    fn from(number: f64) -> Self {
+        Self::Number(number)
+    }
+}
+
+impl From<u64> for Json {
+    fn from(number: u64) -> Self {
+        Self::Number(number as f64)
+    }
+}
+
+impl From<bool> for Json {
+    fn from(value: bool) -> Self {
+        Self::Bool(value)
+    }
+}
+
+// writes it out compact with no whitespace
+impl std::fmt::Display for Json {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Null => write!(f, "null"),
+            Self::Bool(value) => write!(f, "{}", value),
+            // JSON has no NaN or infinity
+            Self::Number(number) if !number.is_finite() => write!(f, "null"),
+            Self::Number(number) => write!(f, "{}", number),
+            Self::String(text) => write_string(f, text),
+            Self::Array(values) => {
+                write!(f, "[")?;
+                for (i, value) in values.iter().enumerate() {
+                    if i > 0 {
+                        write!(f, ",")?;
+                    }
+                    write!(f, "{}", value)?;
+                }
+                write!(f, "]")
+            },
+            Self::Object(fields) => {
+                write!(f, "{{")?;
+                for (i, (name, value)) in fields.iter().enumerate() {
+                    if i > 0 {
+                        write!(f, ",")?;
+                    }
+                    write_string(f, name)?;
+                    write!(f, ":{}", value)?;
+                }
+                write!(f, "}}")
+            },
+        }
+    }
+}
+
+fn write_string(f: &mut std::fmt::Formatter<'_>, text: &str) -> std::fmt::Result {
+    write!(f, "\"")?;
+    for c in text.chars() {
+        match c {
+            '"' => write!(f, "\\\"")?,
+            '\\' => write!(f, "\\\\")?,
+            '\n' => write!(f, "\\n")?,
+            '\r' => write!(f, "\\r")?,
+            '\t' => write!(f, "\\t")?,
+            // < is escaped so the output is safe to put inside a <script> tag
+            c if c < ' ' || c == '<' => write!(f, "\\u{:04x}", c as u32)?,
+            c => write!(f, "{}", c)?,
+        }
+    }
+    write!(f, "\"")
+}
+
+struct Parser<'a> {
+    data: &'a [u8],
+    pos: usize,
+}
+
+impl Parser<'_> {
+    fn value(&mut self, depth: usize) -> Result<Json, HTTPError> {
+        if depth > MAX_DEPTH {
+            return Err(HTTPError::InvalidJson);
+        }
+
+        self.skip_whitespace();
+        match self.peek() {
+            Some(b'{') => self.object(depth),
+            Some(b'[') => self.array(depth),
+            Some(b'"') => Ok(Json::String(self.string()?)),
+            Some(b't') => self.literal(b"true", Json::Bool(true)),
+            Some(b'f') => self.literal(b"false", Json::Bool(false)),
+            Some(b'n') => self.literal(b"null", Json::Null),
+            Some(b'-' | b'0'..=b'9') => self.number(),
+            _ => Err(HTTPError::InvalidJson),
+        }
+    }
+
+    fn object(&mut self, depth: usize) -> Result<Json, HTTPError> {
+        self.pos += 1;
+        let mut fields = Vec::new();
+
+        self.skip_whitespace();
+        if self.peek() == Some(b'}') {
+            self.pos += 1;
+            return Ok(Json::Object(fields));
+        }
+
+        loop {
+            self.skip_whitespace();
+            if self.peek() != Some(b'"') {
+                return Err(HTTPError::InvalidJson);
+            }
+            let name = self.string()?;
+
+            self.skip_whitespace();
+            self.expect(b':')?;
+            let value = self.value(depth + 1)?;
+            fields.push((name, value));
+
+            self.skip_whitespace();
+            match self.next() {
+                Some(b',') => continue,
+                Some(b'}') => return Ok(Json::Object(fields)),
+                _ => return Err(HTTPError::InvalidJson),
+            }
+        }
+    }
+
+    fn array(&mut self, depth: usize) -> Result<Json, HTTPError> {
+        self.pos += 1;
+        let mut values = Vec::new();
+
+        self.skip_whitespace();
+        if self.peek() == Some(b']') {
+            self.pos += 1;
+            return Ok(Json::Array(values));
+        }
+
+        loop {
+            values.push(self.value(depth + 1)?);
+
+            self.skip_whitespace();
+            match self.next() {
+                Some(b',') => continue,
+                Some(b']') => return Ok(Json::Array(values)),
+                _ => return Err(HTTPError::InvalidJson),
+            }
+        }
+    }
+
+    fn string(&mut self) -> Result<String, HTTPError> {
+        self.pos += 1;
+        let mut text = Vec::new();
+
+        loop {
+            match self.next() {
+                None => return Err(HTTPError::InvalidJson),
+                Some(b'"') => break,
+                Some(b'\\') => {
+                    let c = match self.next() {
+                        Some(b'"') => '"',
+                        Some(b'\\') => '\\',
+                        Some(b'/') => '/',
+                        Some(b'b') => '\u{8}',
+                        Some(b'f') => '\u{c}',
+                        Some(b'n') => '\n',
+                        Some(b'r') => '\r',
+                        Some(b't') => '\t',
+                        Some(b'u') => self.unicode_escape()?,
+                        _ => return Err(HTTPError::InvalidJson),
+                    };
+                    let mut buf = [0; 4];
+                    text.extend_from_slice(c.encode_utf8(&mut buf).as_bytes());
+                },
+                // control characters have to be escaped
+                Some(c) if c < 0x20 => return Err(HTTPError::InvalidJson),
+                Some(c) => text.push(c),
+            }
+        }
+
+        match String::from_utf8(text) {
+            Ok(text) => Ok(text),
+            Err(_) => Err(HTTPError::InvalidJson),
+        }
+    }
+
+    // \uXXXX, characters outside the BMP come as a surrogate pair of two of them
+    fn unicode_escape(&mut self) -> Result<char, HTTPError> {
+        let high = self.hex4()?;
+        if !(0xd800..0xdc00).contains(&high) {
+            return char::from_u32(high).ok_or(HTTPError::InvalidJson);
+        }
+
+        if self.next() != Some(b'\\') || self.next() != Some(b'u') {
+            return Err(HTTPError::InvalidJson);
+        }
+        let low = self.hex4()?;
+        if !(0xdc00..0xe000).contains(&low) {
+            return Err(HTTPError::InvalidJson);
+        }
+        char::from_u32(0x10000 + ((high - 0xd800) << 10) + (low - 0xdc00)).ok_or(HTTPError::InvalidJson)
+    }
+
+    fn hex4(&mut self) -> Result<u32, HTTPError> {
+        let digits = self.data.get(self.pos..self.pos + 4).ok_or(HTTPError::InvalidJson)?;
+        self.pos += 4;
+        let digits = std::str::from_utf8(digits).map_err(|_| HTTPError::InvalidJson)?;
+        // from_str_radix would let a + through
+        if !digits.bytes().all(|c| c.is_ascii_hexdigit()) {
+            return Err(HTTPError::InvalidJson);
+        }
+        u32::from_str_radix(digits, 16).map_err(|_| HTTPError::InvalidJson)
+    }
+
+    // -?(0|[1-9][0-9]*)(\.[0-9]+)?([eE][+-]?[0-9]+)?
+    fn number(&mut self) -> Result<Json, HTTPError> {
+        let start = self.pos;
+        if self.peek() == Some(b'-') {
+            self.pos += 1;
+        }
+
+        match self.peek() {
+            Some(b'0') => self.pos += 1,
+            Some(b'1'..=b'9') => self.digits(),
+            _ => return Err(HTTPError::InvalidJson),
+        }
+
+        if self.peek() == Some(b'.') {
+            self.pos += 1;
+            if !matches!(self.peek(), Some(b'0'..=b'9')) {
+                return Err(HTTPError::InvalidJson);
+            }
+            self.digits();
+        }
+
+        if matches!(self.peek(), Some(b'e' | b'E')) {
+            self.pos += 1;
+            if matches!(self.peek(), Some(b'+' | b'-')) {
+                self.pos += 1;
+            }
+            if !matches!(self.peek(), Some(b'0'..=b'9')) {
+                return Err(HTTPError::InvalidJson);
+            }
+            self.digits();
+        }
+
+        // only ascii got through so this cant fail
+        let text = std::str::from_utf8(&self.data[start..self.pos]).map_err(|_| HTTPError::InvalidJson)?;
+        match text.parse::<f64>() {
+            Ok(number) => Ok(Json::Number(number)),
+            Err(_) => Err(HTTPError::InvalidJson),
+        }
+    }
+
+    fn digits(&mut self) {
+        while matches!(self.peek(), Some(b'0'..=b'9')) {
+            self.pos += 1;
+        }
+    }
+
+    fn literal(&mut self, word: &[u8], value: Json) -> Result<Json, HTTPError> {
+        if !self.data[self.pos..].starts_with(word) {
+            return Err(HTTPError::InvalidJson);
+        }
+        self.pos += word.len();
+        Ok(value)
+    }
+
+    fn expect(&mut self, c: u8) -> Result<(), HTTPError> {
+        match self.next() {
+            Some(next) if next == c => Ok(()),
+            _ => Err(HTTPError::InvalidJson),
+        }
+    }
+
+    fn skip_whitespace(&mut self) {
+        while matches!(self.peek(), Some(b' ' | b'\t' | b'\n' | b'\r')) {
+            self.pos += 1;
+        }
+    }
+
+    fn peek(&self) -> Option<u8> {
+        self.data.get(self.pos).copied()
+    }
+
+    fn next(&mut self) -> Option<u8> {
+        let c = self.peek()?;
+        self.pos += 1;
+        Some(c)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse(text: &str) -> Result<Json, HTTPError> {
+        Json::parse(text.as_bytes())
+    }
+
+    #[test]
+    fn values() {
+        assert_eq!(parse(" null ").unwrap(), Json::Null);
+        assert_eq!(parse("true").unwrap(), Json::Bool(true));
+        assert_eq!(parse("-12.5e2").unwrap(), Json::Number(-1250.0));
+        assert_eq!(parse("\"hi\"").unwrap(), Json::from("hi"));
+        assert_eq!(parse("[1, [], {}]").unwrap(), Json::Array(vec![Json::from(1_u64), Json::Array(vec![]), Json::Object(vec![])]));
+
+        let object = parse("{\"a\": 1, \"b\": [true], \"a\": 2}").unwrap();
+        // the last one of a repeated key wins
+        assert_eq!(object.get("a").and_then(Json::as_u64), Some(2));
+        assert_eq!(object.get("b").and_then(Json::as_array), Some(&[Json::Bool(true)][..]));
+        assert_eq!(object.get("c"), None);
+    }
+
+    #[test]
+    fn malformed() {
+        for text in [
+            "", " ", "nul", "truee", "[1,]", "[1 2]", "{\"a\" 1}", "{\"a\":1,}", "{a:1}", "[", "{\"a\":",
+            "01", "1.", ".5", "-", "1e", "+1", "\"open", "\"tab\there\"", "1 2", "[1]]",
+        ] {
+            assert!(matches!(parse(text), Err(HTTPError::InvalidJson)), "{text:?}");
+        }
+    }
+
+    #[test]
+    fn escapes() {
+        assert_eq!(parse(r#""a\"b\\c\/d\n\t\u00e9""#).unwrap(), Json::from("a\"b\\c/d\n\té"));
+        // a surrogate pair is one character
+        assert_eq!(parse(r#""\ud83d\ude00""#).unwrap(), Json::from("😀"));
+
+        for text in [
+            r#""\x""#, r#""\u12""#, r#""\u12g4""#, r#""\u+123""#,
+            // a high surrogate has to be followed by a low one and a low one cant be on its own
+            r#""\ud83d""#, r#""\ud83dx""#, r#""\ud83d\u0041""#, r#""\ude00""#,
+        ] {
+            assert!(matches!(parse(text), Err(HTTPError::InvalidJson)), "{text}");
+        }
+        // the raw bytes have to be utf-8 as well
+        assert!(matches!(Json::parse(b"\"\xff\""), Err(HTTPError::InvalidJson)));
+    }
+
+    #[test]
+    fn nesting_is_limited() {
+        let nested = |depth: usize| format!("{}{}", "[".repeat(depth), "]".repeat(depth));
+        assert!(parse(&nested(MAX_DEPTH)).is_ok());
+        assert!(matches!(parse(&nested(MAX_DEPTH + 2)), Err(HTTPError::InvalidJson)));
+        // far too deep for the stack if it wasnt caught, and never closed
+        assert!(matches!(parse(&"[{\"a\":".repeat(100_000)), Err(HTTPError::InvalidJson)));
+    }
+
+    #[test]
+    fn writing() {
+        let value = parse("{\"text\":\"</script>\\n\\u0001\",\"list\":[1.5,null,false]}").unwrap();
+        assert_eq!(value.to_string(), "{\"text\":\"\\u003c/script>\\n\\u0001\",\"list\":[1.5,null,false]}");
+        assert_eq!(parse(&value.to_string()).unwrap(), value);
+        assert_eq!(Json::Number(f64::NAN).to_string(), "null");
+    }
+}
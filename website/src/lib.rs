@@ -1,4 +1,27 @@
 pub mod thread;
 pub mod apis;
 pub mod http_types;
+pub mod headers;
+pub mod body;
+pub mod conditional;
+pub mod range;
+pub mod client_ip;
+pub mod limits;
+pub mod response_body;
+pub mod tls;
+pub mod hpack;
+pub mod http2;
+pub mod websocket;
+pub mod sse;
+pub mod cookies;
+pub mod mime;
+pub mod form;
+pub mod json;
+pub mod negotiate;
+pub mod error_pages;
+pub mod redirects;
+pub mod vhost;
+pub mod shutdown;
+#[cfg(target_os = "linux")]
+pub mod reactor;
 pub use http_types as types;
\ No newline at end of file
@@ -0,0 +1,189 @@
+use std::io::{self, BufReader, ErrorKind, Read, Write};
+use std::net::Shutdown;
+use std::time::{Duration, Instant};
+use crate::tls::Stream;
+
+// caps on how big a request can be and how long the client gets to send it
+// without these one slow or greedy client can hold a worker forever
+#[derive(Debug, Clone, Copy)]
+pub struct RequestLimits {
+    // the request line including the line ending, too long is a 414
+    pub max_request_line: usize,
+    // every header field line together, too much is a 431
+    pub max_header_size: usize,
+    // the decoded body, too big is a 413
+    pub max_body_size: usize,
+    // time from the first byte of a request to the end of its header, going over is a 408
+    pub header_timeout: Duration,
+    // time to send the whole body once the header is in, also a 408
+    pub body_timeout: Duration,
+    // how long a write can wait for the client to take more of the response before the connection is dropped
+    // its per write and not for the whole response so a big download on a slow line still gets through
+    pub send_timeout: Duration,
+}
+
+impl Default for RequestLimits {
+    fn default() -> Self {
+        Self {
+            max_request_line: 8 * 1024,
+            max_header_size: 16 * 1024,
+            max_body_size: 8 * 1024 * 1024,
+            header_timeout: Duration::from_secs(10),
+            body_timeout: Duration::from_secs(30),
+            send_timeout: Duration::from_secs(30),
+        }
+    }
+}
+
+// how long a connection closed after an error response is read from and thrown away before its really closed
+pub const LINGER_TIMEOUT: Duration = Duration::from_secs(2);
+
+// something a request can be read from that can be told when to give up
+// a plain read timeout isnt enough as a client sending a byte every few seconds never trips it
+pub trait Deadline {
+    // None goes back to just waiting for the idle timeout
+    fn set_deadline(&mut self, deadline: Option<Instant>);
+}
+
+impl<T: Deadline> Deadline for BufReader<T> {
+    fn set_deadline(&mut self, deadline: Option<Instant>) {
+        self.get_mut().set_deadline(deadline);
+    }
+}
+
+impl<T: Deadline> Deadline for &mut T {
+    fn set_deadline(&mut self, deadline: Option<Instant>) {
+        (**self).set_deadline(deadline);
+    }
+}
+
+// a connection where every read waits at most untill the deadline,
+// with no deadline set a read waits for the idle timeout instead
+// every write waits at most the write timeout so a client that stops reading cant hold a worker either
+#[derive(Debug)]
+pub struct TimedStream {
+    stream: Stream,
+    idle_timeout: Duration,
+    write_timeout: Duration,
+    deadline: Option<Instant>,
+    // read off the connection by someone else already, handed out before anything else
+    buffered: Vec<u8>,
+    position: usize,
+}
+
+impl TimedStream {
+    pub fn new(stream: Stream, idle_timeout: Duration, write_timeout: Duration) -> Self {
+        Self::with_buffered(stream, idle_timeout, write_timeout, Vec::new())
+    }
+
+    // for a connection the reactor has already read a request off of
+    pub fn with_buffered(stream: Stream, idle_timeout: Duration, write_timeout: Duration, buffered: Vec<u8>) -> Self {
+        Self {
+            stream,
+            idle_timeout,
+            write_timeout,
+            deadline: None,
+            buffered,
+            position: 0,
+        }
+    }
+
+    // whether reading would still give back some of what was handed in without touching the connection
+    pub fn has_buffered(&self) -> bool {
+        self.position < self.buffered.len()
+    }
+
+    pub fn get_ref(&self) -> &Stream {
+        &self.stream
+    }
+
+    // for handing the connection on once its done with requests
+    pub fn into_inner(self) -> Stream {
+        self.stream
+    }
+
+    // the connection and whatever was handed in that hasnt been read yet
+    pub fn into_parts(mut self) -> (Stream, Vec<u8>) {
+        let rest = self.buffered.split_off(self.position);
+        (self.stream, rest)
+    }
+
+    // for connections that stop being request and response, like websockets
+    pub fn set_idle_timeout(&mut self, idle_timeout: Duration) {
+        self.idle_timeout = idle_timeout;
+    }
+
+    // closing with some of the request still unread resets the connection and the client can lose
+    // the error response before reading it, so the write side is shut first and whatever the client
+    // still sends is thrown away untill it closes too or the timeout runs out
+    pub fn linger(&mut self, timeout: Duration) {
+        let tcp = self.stream.tcp();
+        if tcp.shutdown(Shutdown::Write).is_err() {
+            return;
+        }
+
+        let deadline = Instant::now() + timeout;
+        let mut buffer = [0_u8; 4096];
+        loop {
+            let remaining = deadline.saturating_duration_since(Instant::now());
+            if remaining.is_zero() || tcp.set_read_timeout(Some(remaining)).is_err() {
+                return;
+            }
+            match (&mut &*tcp).read(&mut buffer) {
+                Ok(0) => return,
+                Ok(_) => {},
+                Err(e) if e.kind() == ErrorKind::Interrupted => {},
+                Err(_) => return,
+            }
+        }
+    }
+
+    // for anything that writes to the socket without going through write, like sendfile
+    pub(crate) fn apply_write_timeout(&self) -> io::Result<()> {
+        self.stream.tcp().set_write_timeout(Some(self.write_timeout))
+    }
+}
+
+impl Deadline for TimedStream {
+    fn set_deadline(&mut self, deadline: Option<Instant>) {
+        self.deadline = deadline;
+    }
+}
+
+impl Read for TimedStream {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if self.has_buffered() {
+            let read = (&self.buffered[self.position..]).read(buf)?;
+            self.position += read;
+            return Ok(read);
+        }
+
+        let timeout = match self.deadline {
+            Some(deadline) => {
+                let remaining = deadline.saturating_duration_since(Instant::now());
+                // a zero timeout means wait forever to set_read_timeout so it has to be caught here
+                if remaining.is_zero() {
+                    return Err(io::Error::from(ErrorKind::TimedOut));
+                }
+                remaining
+            },
+            None => self.idle_timeout,
+        };
+
+        self.stream.tcp().set_read_timeout(Some(timeout))?;
+        self.stream.read(buf)
+    }
+}
+
+impl Write for TimedStream {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.apply_write_timeout()?;
+        self.stream.write(buf)
+    }
+
+    // https can still have some of the last write to send
+    fn flush(&mut self) -> io::Result<()> {
+        self.apply_write_timeout()?;
+        self.stream.flush()
+    }
+}
@@ -1,31 +1,178 @@
 use std::{
-    net::{TcpListener, TcpStream},
-    io::{BufReader, Write, Read},
-    fs::{self, Metadata},
-    path::Path,
+    net::{TcpListener, TcpStream, IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr},
+    io::{self, BufReader, BufRead, Read, Write, ErrorKind},
+    fs::{self, File, Metadata},
+    path::{Path, PathBuf},
     ffi::OsStr,
-    sync::Arc,
+    sync::{Arc, Weak, atomic::{AtomicUsize, Ordering}},
+    panic::{self, AssertUnwindSafe},
     time::{SystemTime, Instant, Duration},
     env, thread,
 };
 use blog_cli::Cbmd;
-use website::{thread::ThreadPool, http_types::FontType};
+use website::{thread::{ThreadPool, PoolError, panic_message}, http_types::FontType};
 use website::apis::ApiRegister;
+use website::sse::EventStream;
+use website::headers::Headers;
+use website::conditional::{make_etag, is_not_modified, if_range_matches};
+use website::range::{get_ranges, read_ranges, RangeResult};
+use website::client_ip::TrustedProxies;
+use website::limits::{LINGER_TIMEOUT, RequestLimits, TimedStream};
+use website::response_body::ResponseBody;
+use website::tls::{CertStore, Stream};
+use website::http2;
+use website::json::Json;
+use website::error_pages::ErrorPages;
+use website::redirects::{Redirects, canonicalize};
+use website::vhost::{HostMatch, Site, UnknownHost, VirtualHosts};
+use website::shutdown::{self, Shutdown};
+#[cfg(target_os = "linux")]
+use website::reactor::{Reactor, Ready};
+use website::websocket::{self, CloseCode, WebSocket};
 use website::types::{
-    ContentType, RequestType,
+    ContentType, RequestType, HTTPType,
     Response, HTTPError,
-    turn_system_time_to_http_date,
-    Request, ImageType,
+    turn_system_time_to_http_date, percent_encode_path,
+    Request, RequestError, ImageType,
 };
 use lettre::{transport::smtp::authentication::Credentials, Message, message::Mailbox, Transport};
 use lettre::SmtpTransport;
 
+// what the server answers 'OPTIONS *' with
+const SERVER_METHODS: &str = "GET, HEAD, OPTIONS, POST, PUT, DELETE, PATCH";
+// static files can only be read
+const FILE_METHODS: &str = "GET, HEAD, OPTIONS";
+// what the blog apis can answer with, the first one is sent when the client doesnt care
+const BLOG_TYPES: &[ContentType] = &[ContentType::OctetStream, ContentType::Json];
+
+// settings read from the environment at startup and shared with every connection
+struct ServerConfig {
+    // how long an idle keep-alive connection is held open waiting for the next request
+    keep_alive_timeout: Duration,
+    // proxies whose X-Forwarded-For or Forwarded headers are believed
+    trusted_proxies: TrustedProxies,
+    // how big a request can get and how long the client has to send it
+    limits: RequestLimits,
+    // how long a websocket can go without hearing from the client before its pinged
+    websocket_idle_timeout: Duration,
+    // every open websocket gets a thread of its own, past this many new ones get a 503
+    max_websockets: usize,
+    open_websockets: Arc<AtomicUsize>,
+    // every HTTP/2 connection gets a thread of its own too, past this many new ones are closed
+    max_http2_connections: usize,
+    open_http2: Arc<AtomicUsize>,
+    // how often event stream subscribers get a heartbeat comment
+    event_heartbeat: Duration,
+    // how long requests that are already going get to finish once a shutdown starts
+    shutdown_timeout: Duration,
+    // started by SIGTERM or ctrl-c, connections close after the response they are on
+    shutdown: Shutdown,
+    // how many connections can wait for a worker before new ones get a 503
+    queue_depth: usize,
+    // what those 503s tell the client to wait before trying again
+    retry_after: Duration,
+}
+
+impl ServerConfig {
+    fn from_env() -> Self {
+        let keep_alive_timeout = Duration::from_secs(env_number("KEEP_ALIVE_TIMEOUT", 5));
+
+        // comma seperated CIDRs, a proxy running on the same machine is trusted by default
+        let trusted_proxies = match env::var("TRUSTED_PROXIES") {
+            Ok(list) => TrustedProxies::parse(&list).expect("TRUSTED_PROXIES should be a comma seperated list of CIDRs"),
+            Err(_) => TrustedProxies::loopback(),
+        };
+
+        let defaults = RequestLimits::default();
+        let limits = RequestLimits {
+            max_request_line: env_number("MAX_REQUEST_LINE", defaults.max_request_line as u64) as usize,
+            max_header_size: env_number("MAX_HEADER_SIZE", defaults.max_header_size as u64) as usize,
+            max_body_size: env_number("MAX_BODY_SIZE", defaults.max_body_size as u64) as usize,
+            header_timeout: Duration::from_secs(env_number("HEADER_TIMEOUT", defaults.header_timeout.as_secs())),
+            body_timeout: Duration::from_secs(env_number("BODY_TIMEOUT", defaults.body_timeout.as_secs())),
+            send_timeout: Duration::from_secs(env_number("SEND_TIMEOUT", defaults.send_timeout.as_secs())),
+        };
+
+        let websocket_idle_timeout = Duration::from_secs(env_number("WEBSOCKET_IDLE_TIMEOUT", 30));
+        let max_websockets = env_number("MAX_WEBSOCKETS", 64) as usize;
+        let max_http2_connections = env_number("MAX_HTTP2_CONNECTIONS", 64) as usize;
+        let event_heartbeat = Duration::from_secs(env_number("EVENT_HEARTBEAT", 15));
+        let shutdown_timeout = Duration::from_secs(env_number("SHUTDOWN_TIMEOUT", 30));
+        let queue_depth = env_number("QUEUE_DEPTH", 256) as usize;
+        let retry_after = Duration::from_secs(env_number("RETRY_AFTER", 2));
+
+        Self {
+            keep_alive_timeout,
+            trusted_proxies,
+            limits,
+            websocket_idle_timeout,
+            max_websockets,
+            open_websockets: Arc::new(AtomicUsize::new(0)),
+            max_http2_connections,
+            open_http2: Arc::new(AtomicUsize::new(0)),
+            event_heartbeat,
+            shutdown_timeout,
+            shutdown: Shutdown::new(),
+            queue_depth,
+            retry_after,
+        }
+    }
+}
+
+// website/ is the default site and SITE_HOSTS (comma seperated) are the names its served under
+// VIRTUAL_HOSTS adds static sites laid out the same way, like `blog.example.com=sites/blog, *.example.org=sites/org`
+// UNKNOWN_HOST says what any other host gets, `default` for the default site, `misdirected` for a 421 or a url to redirect to
+// without SITE_HOSTS every host is served by the default site like before
+fn load_virtual_hosts(main_site: Site) -> VirtualHosts {
+    let site_hosts = env::var("SITE_HOSTS").ok();
+
+    let unknown = match env::var("UNKNOWN_HOST") {
+        Ok(value) if value == "default" => UnknownHost::Default,
+        Ok(value) if value == "misdirected" => UnknownHost::Misdirected,
+        Ok(url) => UnknownHost::Redirect(url.trim_end_matches('/').to_string()),
+        Err(_) if site_hosts.is_none() => UnknownHost::Default,
+        Err(_) => UnknownHost::Misdirected,
+    };
+
+    let mut hosts = VirtualHosts::new(main_site, unknown);
+    if let Some(names) = site_hosts {
+        let names = names.split(',').map(str::trim).filter(|name| !name.is_empty()).collect::<Vec<&str>>();
+        hosts.add_default_hosts(&names);
+    }
+
+    if let Ok(list) = env::var("VIRTUAL_HOSTS") {
+        for entry in list.split(',').map(str::trim).filter(|entry| !entry.is_empty()) {
+            let (host, dir) = entry.split_once('=').expect("VIRTUAL_HOSTS should be host=folder pairs");
+            let site = Site::from_dir(Path::new(dir.trim()), ApiRegister::new()).expect("VIRTUAL_HOSTS site could not be loaded");
+            hosts.add(&[host.trim()], site);
+        }
+    }
+
+    hosts
+}
+
+// reads a number out of an env var or uses the default when it isnt set
+// all of these are sizes or timeouts so 0 would just break every request
+fn env_number(name: &str, default: u64) -> u64 {
+    match env::var(name) {
+        Ok(number) => {
+            let number = number.parse::<u64>().unwrap_or_else(|_| panic!("{} should be a number", name));
+            assert!(number > 0, "{} must be greater than 0", name);
+            number
+        },
+        Err(_) => default,
+    }
+}
+
 // creds should be filled like:
 // example@example.com
 // password
 const CREDS: &str = include_str!("../secrets");
 
 fn main() {
+    // before any threads are spawned so they all leave SIGTERM and ctrl-c to the main thread
+    shutdown::block_signals();
+
     let mut secrets = CREDS.lines();
     let username = secrets.next().unwrap();
     let password = secrets.next().unwrap();
@@ -46,28 +193,163 @@ fn main() {
     let port = env::var("PORT").expect("Need PORT env var");
     let addr = String::from("0.0.0.0:") + &port;
     let listener = TcpListener::bind(addr).unwrap();
+    let config = Arc::new(ServerConfig::from_env());
 
-    let pool = ThreadPool::new(8);
+    let pool = Arc::new(ThreadPool::with_queue_limit(8, config.queue_depth));
     let mut apis = ApiRegister::new();
-    apis.register_api("/api/test", Box::new(test_api), 6, 360);
-    apis.register_api("/api/mail", Box::new(email_api), 6, 360);
-    apis.register_api("/api/recentBlogPosts", Box::new(get_recent_blog_posts), 60, 360);
-    apis.register_api("/api/searchBlog", Box::new(search_blog_posts), 20, 360);
-    let apis = Arc::new(apis);
-
-    let register = Arc::clone(&apis);
-    let _cleaner = thread::spawn(|| {
+    apis.register_api("/api/test", &[HTTPType::Get, HTTPType::Post], Box::new(test_api), 6, 360);
+    apis.register_api("/api/mail", &[HTTPType::Post], Box::new(email_api), 6, 360);
+    apis.register_api("/api/recentBlogPosts", &[HTTPType::Get], Box::new(get_recent_blog_posts), 60, 360);
+    apis.register_api("/api/searchBlog", &[HTTPType::Get], Box::new(search_blog_posts), 20, 360);
+    apis.register_websocket("/api/echo", Box::new(echo_socket), 6, 360);
+    // weak so the pool isnt kept alive by its own jobs
+    let stats_pool = Arc::downgrade(&pool);
+    let pool_stats = move |r: Request| -> Response { pool_stats_api(r, &stats_pool) };
+    apis.register_api("/api/poolStats", &[HTTPType::Get], Box::new(pool_stats), 60, 360);
+
+    // tells anyone with the site open when a new blog post shows up
+    let blog_events = Arc::new(EventStream::new(100, 512));
+    apis.register_event_stream("/api/blogEvents", blog_events.clone(), 20, 360);
+    let heartbeat = blog_events.clone();
+    let interval = config.event_heartbeat;
+    let _heartbeat = thread::spawn(move || EventStream::run_heartbeat(heartbeat, interval));
+    let _blog_watcher = thread::spawn(move || watch_blog_posts(blog_events));
+
+    let site = Site::from_dir(Path::new("website"), apis).expect("website/redirects could not be loaded");
+    let hosts = Arc::new(load_virtual_hosts(site));
+
+    let (register, cleaner_config) = (Arc::clone(&hosts), config.clone());
+    let cleaner = thread::spawn(move || {
         // every 10mins will clear the registry of users (maybe should do it based on size?)
-        clean_api_register(register);
+        clean_api_register(register, &cleaner_config.shutdown);
+    });
+
+    let mut listeners = Vec::new();
+
+    // https is optional and turned on by setting TLS_PORT
+    if let Ok(tls_port) = env::var("TLS_PORT") {
+        let certs = env::var("TLS_CERTS").expect("TLS_PORT needs TLS_CERTS (cert.pem:key.pem, ...)");
+        let store = Arc::new(CertStore::parse(&certs).expect("TLS_CERTS could not be loaded"));
+
+        // picks up renewed certificates without a restart
+        let interval = Duration::from_secs(env_number("TLS_RELOAD_INTERVAL", 60));
+        let watched = store.clone();
+        let _watcher = thread::spawn(move || CertStore::watch(watched, interval));
+
+        let tls_listener = TcpListener::bind(String::from("0.0.0.0:") + &tls_port).unwrap();
+        let address = tls_listener.local_addr().unwrap();
+        let (pool, hosts, config) = (pool.clone(), hosts.clone(), config.clone());
+        listeners.push((address, thread::spawn(move || accept_connections(tls_listener, pool, hosts, config, Some(store)))));
+    }
+
+    let address = listener.local_addr().unwrap();
+    let (http_pool, http_config) = (pool.clone(), config.clone());
+    listeners.push((address, thread::spawn(move || serve_plain(listener, http_pool, hosts, http_config))));
+
+    let signal = shutdown::wait_for_signal();
+    println!("got {}, shutting down...", signal);
+    config.shutdown.start();
+
+    // a second signal means dont wait for anything
+    let _impatient = thread::spawn(|| {
+        let signal = shutdown::wait_for_signal();
+        println!("got {} again, exiting now", signal);
+        std::process::exit(1);
     });
 
+    for (address, accepting) in listeners {
+        wake_listener(address);
+        let _ = accepting.join();
+    }
+
+    if pool.shutdown(config.shutdown_timeout) {
+        println!("every request finished");
+    }
+    let _ = cleaner.join();
+    println!("shut down");
+}
+
+// accept blocks untill something connects so connecting to it is how the loop finds out about the shutdown
+fn wake_listener(address: SocketAddr) {
+    let ip = match address.ip() {
+        IpAddr::V4(ip) if ip.is_unspecified() => IpAddr::V4(Ipv4Addr::LOCALHOST),
+        IpAddr::V6(ip) if ip.is_unspecified() => IpAddr::V6(Ipv6Addr::LOCALHOST),
+        ip => ip,
+    };
+    if let Err(e) = TcpStream::connect_timeout(&SocketAddr::new(ip, address.port()), Duration::from_secs(1)) {
+        println!("Error: {}, occured at: {}", e, turn_system_time_to_http_date(SystemTime::now()));
+    }
+}
+
+// plain http connections wait on the reactor whenever they arent being handled so only requests take up workers
+#[cfg(target_os = "linux")]
+fn serve_plain(listener: TcpListener, pool: Arc<ThreadPool>, hosts: Arc<VirtualHosts>, config: Arc<ServerConfig>) {
+    let error_hosts = hosts.clone();
+    let error_response = Box::new(move |e: RequestError| {
+        default_error_page(&error_hosts, Response::from_error(e.error), &e.headers, &e.path)
+    });
+    let reactor = match Reactor::new(config.limits, config.keep_alive_timeout, error_response) {
+        Ok(reactor) => Arc::new(reactor),
+        Err(e) => {
+            println!("Error: {}, falling back to a worker per connection, occured at: {}", e, turn_system_time_to_http_date(SystemTime::now()));
+            return accept_connections(listener, pool, hosts, config, None);
+        }
+    };
+
+    let result = reactor.run(listener, &config.shutdown, |ready| {
+        // the request is already in so the 503 can be in a format it takes
+        if pool.is_full() {
+            return shed_connection(Some(ready.stream), &ready.buffered, &pool, &hosts, &config);
+        }
+        let spare = ready.stream.try_clone().ok();
+        let (job_hosts, job_config, job_reactor) = (hosts.clone(), config.clone(), reactor.clone());
+        let result = pool.execute(move || {
+            handle_ready_connection(ready, job_hosts, job_config, job_reactor)
+        });
+        match result {
+            Ok(()) => {},
+            Err(PoolError::Full) => shed_connection(spare, &[], &pool, &hosts, &config),
+            Err(e) => println!("Error: {}, occured at: {}", e, turn_system_time_to_http_date(SystemTime::now())),
+        }
+    });
+    if let Err(e) = result {
+        println!("Error: {}, occured at: {}", e, turn_system_time_to_http_date(SystemTime::now()));
+    }
+}
+
+// theres no epoll so every connection gets a worker for as long as its open
+#[cfg(not(target_os = "linux"))]
+fn serve_plain(listener: TcpListener, pool: Arc<ThreadPool>, hosts: Arc<VirtualHosts>, config: Arc<ServerConfig>) {
+    accept_connections(listener, pool, hosts, config, None);
+}
+
+// hands every connection off to the pool, tls is None for the plain http listener
+fn accept_connections(listener: TcpListener, pool: Arc<ThreadPool>, hosts: Arc<VirtualHosts>, config: Arc<ServerConfig>, tls: Option<Arc<CertStore>>) {
     for stream in listener.incoming() {
+        if config.shutdown.is_started() {
+            break;
+        }
+
         match stream {
             Ok(stream) => {
-                let apis = apis.clone();
-                pool.execute(move || {
-                    handle_connection(stream, apis)
+                // kept to answer with if the pool turns the connection away, https ones just get closed
+                // since a handshake is too slow to do on the accept thread
+                let spare = match tls {
+                    None => stream.try_clone().ok(),
+                    Some(_) => None,
+                };
+
+                let job_hosts = hosts.clone();
+                let job_config = config.clone();
+                let tls = tls.clone();
+                let result = pool.execute(move || {
+                    handle_connection(stream, job_hosts, job_config, tls)
                 });
+                match result {
+                    Ok(()) => {},
+                    Err(PoolError::Full) => shed_connection(spare, &[], &pool, &hosts, &config),
+                    Err(e) => println!("Error: {}, occured at: {}", e, turn_system_time_to_http_date(SystemTime::now())),
+                }
             }
             Err(e) => println!("Error: {}, \n occured at: {}", e, turn_system_time_to_http_date(SystemTime::now())),
         }
@@ -75,24 +357,509 @@ fn main() {
     }
 }
 
-fn handle_connection(mut stream: TcpStream, apis: Arc<ApiRegister>) {
-    let request = match Request::new(&mut stream) {
-        Ok(r) => r,
+// every worker is busy and the queue is full so the client is told to come back later
+// this runs on the accept or reactor thread so it never waits on the client
+// request is whatever of it has been read, nothing on the accept thread
+fn shed_connection(stream: Option<TcpStream>, request: &[u8], pool: &ThreadPool, hosts: &VirtualHosts, config: &ServerConfig) {
+    let stats = pool.stats();
+    println!("Error: {} connections queued and {} workers busy, turned one away, occured at: {}", stats.queued, stats.busy, turn_system_time_to_http_date(SystemTime::now()));
+
+    let mut stream = match stream {
+        Some(stream) => stream,
+        None => return,
+    };
+
+    // without any of the request it gets what a browser would
+    let context = RequestError::from_partial(HTTPError::ServiceUnavailable, request);
+    let response = Response::from_error(context.error)
+        .with_header("Retry-After", &config.retry_after.as_secs().to_string());
+    let response = default_error_page(hosts, response, &context.headers, &context.path);
+
+    let mut bytes = Vec::new();
+    if response.write_to(&mut bytes, false).is_err() {
+        return;
+    }
+    // a new connections send buffer has room for the whole page so it goes out in one write,
+    // if it doesnt the client just gets closed on
+    if let Err(e) = stream.set_nonblocking(true) {
+        println!("Error: {}, occured at: {}", e, turn_system_time_to_http_date(SystemTime::now()));
+        return;
+    }
+    match stream.write(&bytes) {
+        Ok(written) if written == bytes.len() => {},
+        Ok(_) => return,
+        Err(e) => {
+            println!("Error: {}, occured at: {}", e, turn_system_time_to_http_date(SystemTime::now()));
+            return;
+        }
+    }
+
+    // closing with the request still unread resets the connection and the client might never see the 503
+    let _ = stream.shutdown(std::net::Shutdown::Write);
+    // only what has already arrived is read, a client that keeps sending doesnt get to hold the thread
+    let mut buffer = [0_u8; 4096];
+    for _ in 0..16 {
+        if !matches!(stream.read(&mut buffer), Ok(read) if read > 0) {
+            break;
+        }
+    }
+}
+
+// an error page for a request that wasnt read or let in, the site isnt known yet so its the default sites
+// in whatever format the path and Accept header ask for, the connection closes after it
+fn default_error_page(hosts: &VirtualHosts, response: Response, headers: &Headers, path: &str) -> Response {
+    let format = ErrorPages::pick_format(headers, path);
+    let mut response = hosts.default_site().error_pages().render(response, format);
+    response.set_keep_alive(false);
+    response
+}
+
+// serves requests off the same connection untill the client asks to close it,
+// sends something we cant recover from or goes quiet for longer than the timeout
+// pipelined requests are just read in order as the reader holds onto anything extra
+fn handle_connection(stream: TcpStream, hosts: Arc<VirtualHosts>, config: Arc<ServerConfig>, tls: Option<Arc<CertStore>>) {
+    let peer = match stream.peer_addr() {
+        Ok(address) => address.ip(),
+        Err(e) => {
+            println!("Error: {}, occured at: {}", e, turn_system_time_to_http_date(SystemTime::now()));
+            return;
+        }
+    };
+
+    let stream = match tls {
+        None => Stream::Plain(stream),
+        Some(store) => match tls_handshake(stream, &store, config.limits.header_timeout) {
+            Ok(stream) => stream,
+            Err(e) => {
+                println!("Error: {}, occured at: {}", e, turn_system_time_to_http_date(SystemTime::now()));
+                return;
+            }
+        },
+    };
+
+    // reads wait for the keep-alive timeout between requests and the limits deadlines during them
+    let reader = BufReader::new(TimedStream::new(stream, config.keep_alive_timeout, config.limits.send_timeout));
+
+    if reader.get_ref().get_ref().is_http2() {
+        return spawn_http2(reader, peer, &hosts, &config);
+    }
+
+    serve_requests(reader, peer, &hosts, &config, None);
+}
+
+// a connection the reactor has read a whole request off of, it goes back to the reactor between requests
+#[cfg(target_os = "linux")]
+fn handle_ready_connection(ready: Ready, hosts: Arc<VirtualHosts>, config: Arc<ServerConfig>, reactor: Arc<Reactor>) {
+    let peer = match ready.stream.peer_addr() {
+        Ok(address) => address.ip(),
         Err(e) => {
-            println!("Error: {}, occured at: {:?}", e, turn_system_time_to_http_date(SystemTime::now()));
-            let response = Response::new_400_error(e).into_bytes();
-            stream.write_all(&response).unwrap_or_else(log_write_error);
+            println!("Error: {}, occured at: {}", e, turn_system_time_to_http_date(SystemTime::now()));
+            return;
+        }
+    };
+
+    let reader = BufReader::new(TimedStream::with_buffered(Stream::Plain(ready.stream), config.keep_alive_timeout, config.limits.send_timeout, ready.buffered));
+    let give_back = move |stream: TcpStream, buffered: Vec<u8>, output: Vec<u8>, file: Option<(File, u64, u64)>, keep_alive: bool| {
+        reactor.give_back(stream, buffered, output, file, keep_alive);
+    };
+    serve_requests(reader, peer, &hosts, &config, Some(&give_back));
+}
+
+// where a connection goes when the worker is done with it for now, with anything read past the request,
+// the response if it hasnt been written yet, a file body as (file, offset, len) to send after it
+// and whether to keep the connection open after that
+type GiveBack = dyn Fn(TcpStream, Vec<u8>, Vec<u8>, Option<(File, u64, u64)>, bool);
+
+// without give_back the worker holds onto the connection and waits for the next request itself
+fn serve_requests(mut reader: BufReader<TimedStream>, peer: IpAddr, hosts: &Arc<VirtualHosts>, config: &Arc<ServerConfig>, give_back: Option<&GiveBack>) {
+    let mut first_request = true;
+    loop {
+        // the next request isnt here yet so the reactor can wait for it instead
+        if let Some(give_back) = give_back {
+            if reader.buffer().is_empty() && !reader.get_ref().has_buffered() {
+                return hand_back(reader, give_back, Vec::new(), None, true);
+            }
+        }
+
+        // wait for the next request, an empty buffer means the client hung up
+        match reader.fill_buf() {
+            Ok([]) => return,
+            Ok(_) => {},
+            Err(e) if matches!(e.kind(), ErrorKind::WouldBlock | ErrorKind::TimedOut) => return,
+            Err(e) => {
+                println!("Error: {}, occured at: {}", e, turn_system_time_to_http_date(SystemTime::now()));
+                return;
+            }
+        }
+
+        // h2c with prior knowledge starts with the HTTP/2 preface instead of a request line
+        if first_request && reader.buffer().starts_with(b"PRI ") {
+            return spawn_http2(reader, peer, hosts, config);
+        }
+        first_request = false;
+
+        let request = match Request::parse(&mut reader, peer, &config.trusted_proxies, &config.limits) {
+            Ok(r) => r,
+            Err(e) => {
+                // no idea where the next request would start so the connection has to go
+                println!("Error: {}, occured at: {:?}", e.error, turn_system_time_to_http_date(SystemTime::now()));
+                // the format comes from whatever path and Accept header got read before it failed
+                let response = default_error_page(hosts, Response::from_error(e.error), &e.headers, &e.path);
+                // the rest of the request is still coming in so the close has to linger
+                if let Some(give_back) = give_back {
+                    let mut output = Vec::new();
+                    if let Err(e) = response.write_to(&mut output, false) {
+                        return log_write_error(e);
+                    }
+                    return hand_back(reader, give_back, output, None, false);
+                }
+                response.write_to(reader.get_mut(), false).unwrap_or_else(log_write_error);
+                reader.get_mut().linger(LINGER_TIMEOUT);
+                return;
+            }
+        };
+
+        let site = match pick_site(&request, hosts) {
+            Ok(site) => site,
+            Err(response) => {
+                if !write_response(&mut reader, response, &request) {
+                    return;
+                }
+                continue;
+            }
+        };
+
+        // websocket endpoints take over the connection once the handshake is done
+        if site.apis().get_websocket(request.get_path()).is_some() {
+            match websocket_request(reader, request, site, config) {
+                Some(back) => reader = back,
+                None => return,
+            }
+            continue;
+        }
+
+        // while shutting down every connection is closed after the response its waiting on
+        let keep_alive = request.keep_alive() && !config.shutdown.is_started();
+        let head_only = matches!(request, Request::HeadRequest(_));
+        let mut response = match site.apis().get_event_stream(request.get_path()) {
+            // the connection goes to the event stream so the worker can go back to the pool
+            Some(stream) => match check_event_stream(&request, site.apis()) {
+                Ok(last_event_id) => return subscribe(reader, stream.events(), last_event_id),
+                Err(response) => {
+                    let format = ErrorPages::pick_format(request.headers(), request.get_path());
+                    site.error_pages().render(response, format)
+                },
+            },
+            None => respond(request, &site),
+        };
+        response.set_keep_alive(keep_alive);
+        // a streamed body with no length can turn keep-alive off
+        let keep_alive = response.keep_alive();
+
+        // responses in memory or in a file are written by the reactor so a slow client doesnt hold the worker,
+        // other readers could block on something so they are still sent from here
+        let result = match give_back {
+            Some(give_back) => match response.into_head(head_only) {
+                (header, ResponseBody::Bytes(data)) => return hand_back(reader, give_back, [header, data].concat(), None, keep_alive),
+                (header, ResponseBody::File { file, offset, len }) => return hand_back(reader, give_back, header, Some((file, offset, len)), keep_alive),
+                (header, body) => reader.get_mut().write_all(&header).and_then(|_| body.write_to(reader.get_mut())),
+            },
+            None => response.write_to(reader.get_mut(), head_only),
+        };
+        if let Err(e) = result {
+            log_write_error(e);
+            return;
+        }
+
+        if !keep_alive {
             return;
         }
+    }
+}
+
+fn hand_back(reader: BufReader<TimedStream>, give_back: &GiveBack, output: Vec<u8>, file: Option<(File, u64, u64)>, keep_alive: bool) {
+    // the reader can have read past the end of the request and that belongs to the next one
+    let mut buffered = reader.buffer().to_vec();
+    let (stream, rest) = reader.into_inner().into_parts();
+    buffered.extend(rest);
+
+    if let Stream::Plain(stream) = stream {
+        give_back(stream, buffered, output, file, keep_alive);
+    }
+}
+
+// writes a response that didnt come from the site, returns whether the connection can carry on
+fn write_response(reader: &mut BufReader<TimedStream>, mut response: Response, request: &Request) -> bool {
+    response.set_keep_alive(request.keep_alive());
+    let keep_alive = response.keep_alive();
+    match response.write_to(reader.get_mut(), matches!(request, Request::HeadRequest(_))) {
+        Ok(()) => keep_alive,
+        Err(e) => {
+            log_write_error(e);
+            false
+        }
+    }
+}
+
+// the site the Host header is for, or what the client gets when there isnt one
+fn pick_site(request: &Request, hosts: &VirtualHosts) -> Result<Arc<Site>, Response> {
+    let unknown = match hosts.find(request.get_host()) {
+        HostMatch::Site(site) => return Ok(site),
+        HostMatch::Unknown(unknown) => unknown,
+    };
+
+    println!("Error: no site for host {:?}, occured at: {}", request.get_host(), turn_system_time_to_http_date(SystemTime::now()));
+    let response = match unknown {
+        UnknownHost::Redirect(url) => {
+            let mut location = url + &percent_encode_path(request.get_path());
+            if let Some(query) = request.get_raw_query() {
+                location.push('?');
+                location.push_str(query);
+            }
+            Response::redirect(308, &location)
+        },
+        _ => {
+            let data = String::from("This server doesnt serve that host").into_bytes();
+            Response::new(421, ContentType::PlainText, None, data)
+        },
+    };
+
+    let format = ErrorPages::pick_format(request.headers(), request.get_path());
+    Err(hosts.default_site().error_pages().render(response, format))
+}
+
+// the same handlers answer HTTP/1.1 and HTTP/2, only how the response gets written differs
+fn respond(request: Request, site: &Site) -> Response {
+    if let Some(response) = redirect(&request, site.redirects()) {
+        return response;
+    }
+
+    let format = ErrorPages::pick_format(request.headers(), request.get_path());
+    // a handler that panics gets the client a 500 instead of a dropped connection
+    let context = describe_request(&request);
+    let response = catch_panic(&context, || dispatch(request, site)).unwrap_or_else(Response::empty_500_error);
+    site.error_pages().render(response, format)
+}
+
+// runs f and logs what request it was for if it panics
+fn catch_panic<T>(context: &str, f: impl FnOnce() -> T) -> Option<T> {
+    match panic::catch_unwind(AssertUnwindSafe(f)) {
+        Ok(result) => Some(result),
+        Err(panic) => {
+            println!("Error: panicked while handling {}: {}, occured at: {}", context, panic_message(&*panic), turn_system_time_to_http_date(SystemTime::now()));
+            None
+        }
+    }
+}
+
+// enough to find the request that caused a panic in the logs, like `POST /api/mail on example.com from 1.2.3.4`
+fn describe_request(request: &Request) -> String {
+    format!("{} {} on {} from {}", request.get_kind(), request.get_path(), request.get_host(), request.get_ip())
+}
+
+// moved pages go where the redirect table says and everything else goes to its canonical url
+// both happen in one hop so /old-page/ doesnt bounce through /old-page first
+fn redirect(request: &Request, redirects: &Redirects) -> Option<Response> {
+    let path = request.get_path();
+    let canonical = canonicalize(path);
+    let lookup = canonical.as_deref().unwrap_or(path);
+
+    let (code, mut location) = match (redirects.find(lookup), canonical.as_deref()) {
+        (Some(moved), _) => moved,
+        // GET and HEAD can be sent on with a 301, anything else needs a 308 to keep its method and body
+        (None, Some(canonical)) => match request.get_kind() {
+            HTTPType::Get | HTTPType::Head => (301, percent_encode_path(canonical)),
+            _ => (308, percent_encode_path(canonical)),
+        },
+        (None, None) => return None,
     };
 
+    if let Some(query) = request.get_raw_query() {
+        if !location.contains('?') {
+            location.push('?');
+            location.push_str(query);
+        }
+    }
+    Some(Response::redirect(code, &location))
+}
+
+fn dispatch(request: Request, site: &Site) -> Response {
+    let apis = site.apis();
+    // HTTP/1.1 event streams never get here, HTTP/2 cant hand its connection over
+    // so those get whatever they missed and the retry field has them come back for more
+    if let Some(stream) = apis.get_event_stream(request.get_path()) {
+        return match check_event_stream(&request, apis) {
+            Ok(last_event_id) => Response::new(200, ContentType::EventStream, None, stream.events().replay(last_event_id))
+                .with_header("Cache-Control", "no-cache"),
+            Err(response) => response,
+        };
+    }
+
     match request {
-        Request::GetRequest(_) => process_get_request(request, apis, &mut stream),
-        Request::POSTRequest(_) => process_post_request(request, apis, &mut stream)
+        Request::GetRequest(_) => process_get_request(request, site),
+        // HEAD is handled exactly like GET and only the body gets dropped
+        Request::HeadRequest(r) => process_get_request(Request::GetRequest(r), site),
+        Request::OptionsRequest(_) => process_options_request(request, apis.clone()),
+        Request::POSTRequest(_)
+        | Request::PutRequest(_)
+        | Request::DeleteRequest(_)
+        | Request::PatchRequest(_) => process_post_request(request, apis.clone()),
+    }
+}
+
+// a connection can have many streams open for as long as it likes so like a websocket it gets a thread
+// of its own instead of holding a worker, past max_http2_connections the connection is just closed
+fn spawn_http2(mut reader: BufReader<TimedStream>, peer: IpAddr, hosts: &Arc<VirtualHosts>, config: &Arc<ServerConfig>) {
+    if !reserve_slot(&config.open_http2, config.max_http2_connections) {
+        println!("Error: Too many HTTP/2 connections are open, occured at: {}", turn_system_time_to_http_date(SystemTime::now()));
+        return;
+    }
+
+    let (hosts, config) = (hosts.clone(), config.clone());
+    let open_http2 = config.open_http2.clone();
+    let spawned = thread::Builder::new().spawn(move || {
+        serve_http2(&mut reader, peer, &hosts, &config);
+        release_slot(&config.open_http2);
+    });
+    if let Err(e) = spawned {
+        release_slot(&open_http2);
+        println!("Error: {}, occured at: {}", e, turn_system_time_to_http_date(SystemTime::now()));
+    }
+}
+
+fn serve_http2(reader: &mut BufReader<TimedStream>, peer: IpAddr, hosts: &VirtualHosts, config: &ServerConfig) {
+    let handler = |request: Request| match pick_site(&request, hosts) {
+        Ok(site) => respond(request, &site),
+        Err(response) => response,
+    };
+    if let Err(e) = http2::serve_connection(reader, peer, &config.trusted_proxies, &config.limits, handler) {
+        println!("Error: {}, occured at: {}", e, turn_system_time_to_http_date(SystemTime::now()));
+    }
+}
+
+// Ok is where the client wants to pick the stream back up from, Err is the response turning it down
+fn check_event_stream(request: &Request, apis: &ApiRegister) -> Result<Option<u64>, Response> {
+    if request.get_kind() != HTTPType::Get {
+        return Err(Response::new_405_error("GET, OPTIONS"));
+    }
+
+    if !apis.check_limit(&request.get_ip(), request.get_path()) {
+        let data = String::from("Too many requests").into_bytes();
+        return Err(Response::new(429, ContentType::PlainText, None, data));
+    }
+    apis.add_request(request.get_path(), request.get_ip());
+
+    // an id we never handed out just means starting fresh
+    Ok(request.headers().get("Last-Event-ID").and_then(|id| id.trim().parse::<u64>().ok()))
+}
+
+// sends the header of a response that never ends and gives the connection to the event stream
+fn subscribe(mut reader: BufReader<TimedStream>, events: &EventStream, last_event_id: Option<u64>) {
+    if events.is_full() {
+        let mut response = Response::new(503, ContentType::PlainText, None, String::from("Too many subscribers").into_bytes())
+            .with_header("Retry-After", "30");
+        response.set_keep_alive(false);
+        response.write_to(reader.get_mut(), false).unwrap_or_else(log_write_error);
+        return;
+    }
+
+    // no length so it ends when the connection does
+    let mut response = Response::new_streamed(200, ContentType::EventStream, None, ResponseBody::reader(io::empty(), None))
+        .with_header("Cache-Control", "no-cache");
+    response.set_keep_alive(false);
+    if let Err(e) = response.write_to(reader.get_mut(), true) {
+        log_write_error(e);
+        return;
+    }
+
+    events.subscribe(reader.into_inner().into_inner(), last_event_id);
+}
+
+// after the handshake the socket gets a thread of its own so a worker isnt held for as long as its open,
+// the connection is given back when the handshake failed and it can go on to the next request
+fn websocket_request(mut reader: BufReader<TimedStream>, request: Request, site: Arc<Site>, config: &ServerConfig) -> Option<BufReader<TimedStream>> {
+    let apis = site.apis();
+    let handshake = if !apis.check_limit(&request.get_ip(), request.get_path()) {
+        let data = String::from("Too many requests").into_bytes();
+        Err(Response::new(429, ContentType::PlainText, None, data))
+    } else if !reserve_slot(&config.open_websockets, config.max_websockets) {
+        let data = String::from("Too many websockets are open, try again soon").into_bytes();
+        Err(Response::new(503, ContentType::PlainText, None, data).with_header("Retry-After", &config.retry_after.as_secs().to_string()))
+    } else {
+        apis.add_request(request.get_path(), request.get_ip());
+        websocket::handshake(&request).inspect_err(|_| release_slot(&config.open_websockets))
+    };
+
+    let response = match handshake {
+        Ok(response) => response,
+        // still a normal HTTP connection so it can carry on like after any other error
+        Err(response) => {
+            let format = ErrorPages::pick_format(request.headers(), request.get_path());
+            let keep_alive = write_response(&mut reader, site.error_pages().render(response, format), &request);
+            return keep_alive.then_some(reader);
+        }
+    };
+
+    let open_websockets = config.open_websockets.clone();
+    if let Err(e) = response.write_to(reader.get_mut(), false) {
+        release_slot(&open_websockets);
+        log_write_error(e);
+        return None;
+    }
+
+    let (max_message_size, idle_timeout) = (config.limits.max_body_size, config.websocket_idle_timeout);
+    let spawned = thread::Builder::new().spawn(move || {
+        run_websocket(reader, request, &site, max_message_size, idle_timeout);
+        release_slot(&open_websockets);
+    });
+    if let Err(e) = spawned {
+        // the closure and the connection with it are dropped so the slot has to be given back here
+        release_slot(&config.open_websockets);
+        println!("Error: {}, occured at: {}", e, turn_system_time_to_http_date(SystemTime::now()));
+    }
+    None
+}
+
+fn run_websocket(mut reader: BufReader<TimedStream>, request: Request, site: &Site, max_message_size: usize, idle_timeout: Duration) {
+    let socket = match site.apis().get_websocket(request.get_path()) {
+        Some(socket) => socket,
+        None => return,
+    };
+
+    let mut websocket = WebSocket::new(&mut reader, max_message_size, idle_timeout);
+    let context = describe_request(&request);
+    // handlers that just return get a normal close sent for them
+    let code = match catch_panic(&context, || socket.run(request, &mut websocket)) {
+        Some(()) => CloseCode::Normal,
+        None => CloseCode::InternalError,
+    };
+    if let Err(e) = websocket.close(code, "") {
+        println!("Error: {}, occured at: {}", e, turn_system_time_to_http_date(SystemTime::now()));
     }
 }
 
-fn process_get_request(request: Request, apis: Arc<ApiRegister>, stream: &mut TcpStream) {
+// takes one of the spots for connections with a thread of their own, false when they are all in use
+fn reserve_slot(open: &AtomicUsize, max: usize) -> bool {
+    open.fetch_update(Ordering::SeqCst, Ordering::SeqCst, |count| (count < max).then_some(count + 1))
+        .is_ok()
+}
+
+fn release_slot(open: &AtomicUsize) {
+    open.fetch_sub(1, Ordering::SeqCst);
+}
+
+// the handshake gets as long as a request header would so a silent client cant hold the worker
+// after it TimedStream sets the timeouts before every read and write
+fn tls_handshake(stream: TcpStream, store: &CertStore, timeout: Duration) -> Result<Stream, String> {
+    stream.set_read_timeout(Some(timeout)).map_err(|e| e.to_string())?;
+    stream.set_write_timeout(Some(timeout)).map_err(|e| e.to_string())?;
+
+    let stream = store.accept(stream)?;
+
+    Ok(Stream::Tls(Box::new(stream)))
+}
+
+fn process_get_request(request: Request, site: &Site) -> Response {
     let path = request.get_path();
     println!("request_line: {:?}, {}", request, path == "/");
     let path = Path::new(path);
@@ -113,13 +880,31 @@ fn process_get_request(request: Request, apis: Arc<ApiRegister>, stream: &mut Tc
     println!("{:?}, {:?}", path, request_type);
 
     match request_type {
-        RequestType::Html => html_request(path, stream),
-        RequestType::OtherFile => file_request(path, stream),
-        RequestType::Api => api_request(apis , stream, request),
+        RequestType::Html => html_request(site.root(), path, request.headers()),
+        RequestType::OtherFile => file_request(site.root(), path, request.headers()),
+        RequestType::Api => api_request(site.apis().clone(), request),
     }
 }
 
-fn process_post_request(request: Request, apis: Arc<ApiRegister>, stream: &mut TcpStream) {
+fn process_options_request(request: Request, apis: Arc<ApiRegister>) -> Response {
+    let path = request.get_path();
+    let allowed = if path == "*" {
+        SERVER_METHODS.to_string()
+    } else if Path::new(path).parent().and_then(Path::to_str) == Some("/api") {
+        match apis.get_api(path) {
+            Some(api) => api.get_allowed_methods(),
+            None => return Response::empty_404(),
+        }
+    } else {
+        FILE_METHODS.to_string()
+    };
+
+    Response::new(204, ContentType::PlainText, None, Vec::new())
+        .with_header("Allow", &allowed)
+}
+
+// POST, PUT, DELETE and PATCH all end up here
+fn process_post_request(request: Request, apis: Arc<ApiRegister>) -> Response {
     println!("post!, {:?}", request);
     // should therortically just be an API request
 
@@ -127,72 +912,35 @@ fn process_post_request(request: Request, apis: Arc<ApiRegister>, stream: &mut T
         Some("/api") => {},
         Some(_) => {
             // honeslty not sure what error code belongs here
-            let response = Response::empty_404().into_bytes();
-            stream.write_all(&response).unwrap_or_else(log_write_error);
-            return;
+            return Response::empty_404();
         }
         None => {
             // errors
-            let response = Response::empty_404().into_bytes();
-            stream.write_all(&response).unwrap_or_else(log_write_error);
-            return;
+            return Response::empty_404();
         }
     };
 
-    api_request(apis, stream, request);
+    api_request(apis, request)
 }
 
-fn html_request(path: &Path, stream: &mut TcpStream) {
-    if path.as_os_str() == "/" {
-        let index_path = Path::new("website/files/index.html");
-        let data = fs::read(index_path).unwrap();
-        let last_modified = match index_path.metadata().and_then(into_modified) {
-            Ok(time) => Some(time),
-            Err(_) => None,
-        };
-        let response = Response::new_ok(ContentType::Html, last_modified, data)
-            .into_bytes();
-        stream.write_all(&response).unwrap_or_else(log_write_error);
-        return;
-    }
+// root is the folder the site is served out of, like website/files
+fn html_request(root: &Path, path: &Path, headers: &Headers) -> Response {
     // I Hate paths dear lord wtf is this garbage
-    let path = Path::new("website/files").join(path.strip_prefix("/").unwrap()).with_extension("html");
+    let path = if path.as_os_str() == "/" {
+        root.join("index.html")
+    } else {
+        root.join(path.strip_prefix("/").unwrap()).with_extension("html")
+    };
     println!("{:?}", path.as_path());
 
-    match fs::read(&path) {
-        Ok(data) => {
-            let last_modified = match path.metadata().and_then(into_modified) {
-                Ok(time) => Some(time),
-                Err(_) => None,
-            };
-            let response = Response::new_ok(ContentType::Html, last_modified, data)
-                .into_bytes();
-            stream.write_all(&response).unwrap_or_else(log_write_error);
-        },
-        Err(_) => {
-            let data = match fs::read("website/files/404.html") {
-                Ok(data) => data,
-                Err(e) => {
-                    println!("Error: {}\n Occured at: {}", e, turn_system_time_to_http_date(SystemTime::now()));
-                    let response = Response::empty_500_error().into_bytes();
-                    stream.write_all(&response).unwrap_or_else(log_write_error);
-                    return;
-                }
-            };
-
-            let modified_date = match Path::new("files/404.html").metadata().and_then(into_modified) {
-                Ok(m) => Some(m),
-                Err(_) => None,
-            };
-
-            let response = Response::new(404, ContentType::Html, modified_date, None, data)
-                .into_bytes();
-            stream.write_all(&response).unwrap_or_else(log_write_error)
-        }
+    // the error pages turn this into errors/404.html of the site for browsers
+    match path.metadata() {
+        Ok(metadata) => send_file(&path, &metadata, ContentType::Html, headers),
+        Err(_) => Response::empty_404(),
     }
 }
 
-fn file_request(path: &Path, stream: &mut TcpStream) {
+fn file_request(root: &Path, path: &Path, headers: &Headers) -> Response {
     let content_type = match path.extension().and_then(OsStr::to_str) {
         Some("css") => ContentType::Css,
         Some("js") => ContentType::JavaScript,
@@ -204,33 +952,68 @@ fn file_request(path: &Path, stream: &mut TcpStream) {
         Some("wgsl") => ContentType::Wgsl,
         ext => {
             println!("Unsuported extention: {:?}", ext);
-            let response = Response::new_400_error(HTTPError::InvalidPath).into_bytes();
-            stream.write_all(&response).unwrap_or_else(log_write_error);
-            return;
+            return Response::new_400_error(HTTPError::InvalidPath);
         }
     };
 
     // paths will single handly kill me
     // also we know path stripping wont fail bc we make sure it starts with one
-    let path = Path::new("website/files").join(path.strip_prefix("/").unwrap());
-    let modified_date = match path.metadata().and_then(into_modified) {
-        Ok(time) => Some(time),
-        Err(_) => None,
-    };
+    let path = root.join(path.strip_prefix("/").unwrap());
 
     println!("{:?}", path);
 
-    match fs::read(path) {
-        Ok(data) => {
-            let response = Response::new_ok(content_type, modified_date, data)
-                .into_bytes();
-            stream.write_all(&response).unwrap_or_else(log_write_error)
-        },
-        Err(_) => {
-            let response = Response::empty_404().into_bytes();
-            stream.write_all(&response).unwrap_or_else(log_write_error)
+    match path.metadata() {
+        Ok(metadata) => send_file(&path, &metadata, content_type, headers),
+        Err(_) => Response::empty_404(),
+    }
+}
+
+// sends the file unless the client already has this version of it cached
+// or just the parts of it that were asked for with Range
+fn send_file(path: &Path, metadata: &Metadata, content_type: ContentType, headers: &Headers) -> Response {
+    let last_modified = metadata.modified().ok();
+    let etag = make_etag(metadata);
+
+    if let Some(etag) = &etag {
+        if is_not_modified(headers, etag, last_modified) {
+            return Response::not_modified(last_modified, etag.clone());
         }
     }
+
+    let total = metadata.len();
+    let ranges = if if_range_matches(headers, etag.as_deref(), last_modified) {
+        get_ranges(headers, total)
+    } else {
+        RangeResult::Full
+    };
+
+    let mut response = match ranges {
+        RangeResult::Full => match File::open(path) {
+            Ok(file) => Response::new_streamed(200, content_type, last_modified, ResponseBody::file_range(file, 0, total)),
+            Err(_) => return Response::empty_404(),
+        },
+        RangeResult::Unsatisfiable => return Response::range_not_satisfiable(total),
+        RangeResult::Partial(ranges) => match read_ranges(path, &ranges, content_type, total) {
+            Ok((content_type, body)) => {
+                let mut response = Response::new_streamed(206, content_type, last_modified, body);
+                // multiple ranges have their Content-Range inside each part instead
+                if let [range] = ranges.as_slice() {
+                    response.add_header("Content-Range", &range.content_range(total));
+                }
+                response
+            },
+            Err(e) => {
+                println!("Error: {}\n Occured at: {}", e, turn_system_time_to_http_date(SystemTime::now()));
+                return Response::empty_404();
+            },
+        },
+    };
+
+    if let Some(etag) = etag {
+        response.add_header("ETag", &etag);
+    }
+    response.add_header("Accept-Ranges", "bytes");
+    response
 }
 
 fn log_write_error(error: std::io::Error) {
@@ -239,144 +1022,191 @@ fn log_write_error(error: std::io::Error) {
 }
 
 
-fn api_request(apis: Arc<ApiRegister>, stream: &mut TcpStream, request: Request) {
+fn api_request(apis: Arc<ApiRegister>, request: Request) -> Response {
     let path = request.get_path();
     // check if the user is over the limit
-    if !apis.user_exists(&request.get_ip()) {
-        apis.add_user(request.get_ip());
-    }
-
     if !apis.check_limit(&request.get_ip(), request.get_path()) {
         // too many requests
         let data = String::from("Too many requests").into_bytes();
-        let response = Response::new(429, ContentType::PlainText, None, None, data)
-            .into_bytes();
-
-        stream.write_all(&response).unwrap_or_else(log_write_error);
-        return;
+        return Response::new(429, ContentType::PlainText, None, data);
     }
 
     let api = apis.get_api(path);
-    let response = match api {
+    match api {
         None => {
             apis.add_gloabal_request(request.get_ip());
             Response::empty_404()
         },
+        Some(api) if !api.allows(request.get_kind()) => {
+            apis.add_request(request.get_path(), request.get_ip());
+            Response::new_405_error(&api.get_allowed_methods())
+        },
         Some(api) => {
             apis.add_request(request.get_path(), request.get_ip());
             api.run(request)
         },
-    };
-
-    stream.write_all(&response.into_bytes()).unwrap_or_else(log_write_error);
-}
-
-// made to use and_then on results for reading meta data to avoid unsessicary unwrap
-fn into_modified(metadata: Metadata) -> Result<SystemTime, std::io::Error> {
-    metadata.modified()
+    }
 }
 
-fn clean_api_register(register: Arc<ApiRegister>) -> ! {
-    loop {
-        thread::sleep(Duration::from_secs(1200));
+fn clean_api_register(hosts: Arc<VirtualHosts>, shutdown: &Shutdown) {
+    // wakes up early when the server is shutting down so it can be joined
+    while !shutdown.wait_timeout(Duration::from_secs(1200)) {
         println!("cleaning users...");
-        register.clean_recent_requests();
+        for site in hosts.sites() {
+            site.apis().clean_recent_requests();
+        }
         println!("done cleaning users!");
     }
 }
 
+// how loaded the thread pool is, only for someone on the same machine
+fn pool_stats_api(request: Request, pool: &Weak<ThreadPool>) -> Response {
+    if !request.get_ip().is_loopback() {
+        let data = String::from("Forbidden").into_bytes();
+        return Response::new(403, ContentType::PlainText, None, data);
+    }
+    let pool = match pool.upgrade() {
+        Some(pool) => pool,
+        None => return Response::empty_500_error(),
+    };
+
+    let stats = pool.stats();
+    let queue_limit = match stats.queue_limit {
+        Some(limit) => Json::from(limit as u64),
+        None => Json::Null,
+    };
+    let data = Json::Object(vec![
+        (String::from("workers"), Json::from(stats.workers as u64)),
+        (String::from("busy"), Json::from(stats.busy as u64)),
+        (String::from("queued"), Json::from(stats.queued as u64)),
+        (String::from("queueLimit"), queue_limit),
+        (String::from("peakQueued"), Json::from(stats.peak_queued as u64)),
+        (String::from("completed"), Json::from(stats.completed)),
+        (String::from("rejected"), Json::from(stats.rejected)),
+    ]).to_string().into_bytes();
+    Response::new_ok(ContentType::Json, None, data)
+}
+
 fn test_api(_: Request) -> Response {
     println!("Test Api!");
     let data = String::from("Test api!").into_bytes();
     Response::new_ok(ContentType::PlainText, None, data)
 }
 
+// checks for new .cbmd files every so often and sends out the title of each one it finds
+fn watch_blog_posts(events: Arc<EventStream>) -> ! {
+    let list_posts = || -> Vec<PathBuf> {
+        match fs::read_dir("website/files/blog") {
+            Ok(dir) => dir.filter_map(|f| f.ok())
+                .map(|f| f.path())
+                .filter(|path| path.extension() == Some(OsStr::new("cbmd")))
+                .collect(),
+            Err(_) => Vec::new(),
+        }
+    };
+
+    let mut known = list_posts();
+    loop {
+        thread::sleep(Duration::from_secs(30));
+        let posts = list_posts();
+        for post in posts.iter().filter(|post| !known.contains(post)) {
+            if let Ok(cbmd) = Cbmd::from_meta_file(post) {
+                events.publish(Some("blogPost"), cbmd.get_title());
+            }
+        }
+        known = posts;
+    }
+}
+
+// sends every message straight back, for trying websockets out from the examples
+fn echo_socket(_: Request, socket: &mut WebSocket) {
+    loop {
+        let message = match socket.recv() {
+            Ok(Some(message)) => message,
+            Ok(None) => return,
+            Err(e) => {
+                println!("Error: {}, occured at: {}", e, turn_system_time_to_http_date(SystemTime::now()));
+                return;
+            }
+        };
+
+        if let Err(e) = socket.send(&message) {
+            println!("Error: {}, occured at: {}", e, turn_system_time_to_http_date(SystemTime::now()));
+            return;
+        }
+    }
+}
+
 // takes ~1.6 seconds to send both emails and send a response
 // ~675ms per email so might async or do something to speed this up
 // maybe multithread each email (this is a joke)
 fn mail_api(request: Request, mailer: Arc<SmtpTransport>) -> Response {
     let request = match request {
-        Request::GetRequest(_) => {
-            let res = Response::new_405_error("POST");
+        Request::POSTRequest(r) => r,
+        _ => {
+            let res = Response::new_405_error("POST, OPTIONS");
             return res;
         }, //405 error
-        Request::POSTRequest(r) => r,
     };
 
     match request.get_content_type() {
         ContentType::OctetStream => {},
         _ => {
             let data = String::from("Unssuported Media Type").into_bytes();
-            return Response::new(415, ContentType::PlainText, None, None, data)
+            return Response::new(415, ContentType::PlainText, None, data)
         }
     }
 
     let mut data = BufReader::new(request.get_data());
     let mut email_len = [0_u8; 1];
 
-    match data.read_exact(&mut email_len) {
-        Err(_) => {
-            return Response::new(
-                400,
-                ContentType::PlainText,
-                None,
-                None,
-                String::from("Email Length Not Found").into_bytes()
-            );
-        }
-        Ok(_) => {},
+    if data.read_exact(&mut email_len).is_err() {
+        return Response::new(
+            400,
+            ContentType::PlainText,
+            None,
+            String::from("Email Length Not Found").into_bytes()
+        );
     }
 
     let mut email = vec![0_u8; email_len[0] as usize];
 
-    match data.read_exact(&mut email) {
-        Err(_) => {
-            return Response::new(
-                400,
-                ContentType::PlainText,
-                None,
-                None,
-                String::from("Email Not Found").into_bytes()
-            );
-        }
-        Ok(_) => {},
+    if data.read_exact(&mut email).is_err() {
+        return Response::new(
+            400,
+            ContentType::PlainText,
+            None,
+            String::from("Email Not Found").into_bytes()
+        );
     }
 
     let mut message_len = [0_u8; 2];
 
-    match data.read_exact(&mut message_len) {
-        Err(_) => {
-            return Response::new(
-                400,
-                ContentType::PlainText,
-                None,
-                None,
-                String::from("Message Length Not Found").into_bytes()
-            );
-        }
-        Ok(_) => {},
+    if data.read_exact(&mut message_len).is_err() {
+        return Response::new(
+            400,
+            ContentType::PlainText,
+            None,
+            String::from("Message Length Not Found").into_bytes()
+        );
     }
 
     let message_len = u16::from_le_bytes(message_len) as usize;
     let mut message = vec![0_u8; message_len];
-    match data.read_exact(&mut message) {
-        Err(_) => {
-            return Response::new(
-                400,
-                ContentType::PlainText,
-                None,
-                None,
-                String::from("Message Not Found").into_bytes()
-            );
-        }
-        Ok(_) => {},
+    if data.read_exact(&mut message).is_err() {
+        return Response::new(
+            400,
+            ContentType::PlainText,
+            None,
+            String::from("Message Not Found").into_bytes()
+        );
     }
 
     // send the email!
     let user_email = String::from_utf8_lossy(&email);
     let user_message = String::from_utf8_lossy(&message); 
 
-    if user_message.len() == 0 {
+    if user_message.is_empty() {
         return Response::new_400_error(HTTPError::InvalidContentLength);
     }
 
@@ -414,7 +1244,12 @@ fn mail_api(request: Request, mailer: Arc<SmtpTransport>) -> Response {
 fn get_recent_blog_posts(request: Request) -> Response {
     let request = match request {
         Request::GetRequest(r) => r,
-        Request::POSTRequest(_) => return Response::new_405_error("GET"),
+        _ => return Response::new_405_error("GET, HEAD, OPTIONS"),
+    };
+
+    let content_type = match request.negotiate(BLOG_TYPES) {
+        Some(content_type) => content_type,
+        None => return Response::new_406_error(BLOG_TYPES),
     };
 
     let skip = match request.get_query("skip") {
@@ -445,19 +1280,24 @@ fn get_recent_blog_posts(request: Request) -> Response {
         .filter(|f| f.path().extension() == Some(OsStr::new("cbmd")))
         .filter_map(|f| Cbmd::from_meta_file(&f.path()).ok())
         .collect::<Vec<Cbmd>>();
-    blog_data.sort_by(|a, b| b.get_timestamp().cmp(&a.get_timestamp()));
+    blog_data.sort_by_key(|b| std::cmp::Reverse(b.get_timestamp()));
     
-    send_blog_vec(blog_data, skip, max)
+    send_blog_vec(blog_data, skip, max, content_type)
 }
 
 fn search_blog_posts(request: Request) -> Response {
     let request = match request {
         Request::GetRequest(r) => r,
-        Request::POSTRequest(_) => return Response::new_405_error("GET"),
+        _ => return Response::new_405_error("GET, HEAD, OPTIONS"),
+    };
+
+    let content_type = match request.negotiate(BLOG_TYPES) {
+        Some(content_type) => content_type,
+        None => return Response::new_406_error(BLOG_TYPES),
     };
 
     let blog_title = match request.get_query("title") {
-        Some(t) => clean_url_spaces(t),
+        Some(t) => t,
         None => return Response::new_400_error(HTTPError::InvalidPath),
     };
 
@@ -466,13 +1306,30 @@ fn search_blog_posts(request: Request) -> Response {
     let blog_data = dir.filter_map(|f| f.ok())
         .filter(|f| f.path().extension() == Some(OsStr::new("cbmd")))
         .filter_map(|f| Cbmd::from_meta_file(&f.path()).ok())
-        .filter(|f| f.get_title().contains(&blog_title))
+        .filter(|f| f.get_title().contains(blog_title.as_str()))
         .collect::<Vec<Cbmd>>();
 
-    send_blog_vec(blog_data, 0, 8)
+    send_blog_vec(blog_data, 0, 8, content_type)
 }
 
-fn send_blog_vec(data: Vec<Cbmd>, skip: usize, max: usize) -> Response {
+// the binary format is what cbmd.js reads and anyone else can ask for JSON
+fn send_blog_vec(data: Vec<Cbmd>, skip: usize, max: usize, content_type: ContentType) -> Response {
+    if let ContentType::Json = content_type {
+        let posts = data.iter()
+            .skip(skip)
+            .take(max)
+            .map(|post| Json::Object(vec![
+                (String::from("title"), Json::from(post.get_title())),
+                (String::from("intro"), Json::from(post.get_intro_words())),
+                (String::from("path"), Json::from(post.get_path())),
+                (String::from("timestamp"), Json::from(post.get_timestamp())),
+            ]))
+            .collect::<Vec<Json>>();
+
+        return Response::new(200, ContentType::Json, None, Json::Array(posts).to_string().into_bytes())
+            .with_header("Vary", "Accept");
+    }
+
     let blog_data = data.into_iter()
         .skip(skip)
         .take(max)
@@ -492,9 +1349,6 @@ fn send_blog_vec(data: Vec<Cbmd>, skip: usize, max: usize) -> Response {
         data.extend_from_slice(&byte_array);
     }
 
-    Response::new(200, ContentType::OctetStream, None, None, data)
-}
-
-fn clean_url_spaces(text: &str) -> String {
-    text.replace("%20", " ")
+    Response::new(200, ContentType::OctetStream, None, data)
+        .with_header("Vary", "Accept")
 }
\ No newline at end of file
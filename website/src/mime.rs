@@ -0,0 +1,227 @@
+use crate::types::{HTTPError, is_token_char};
+
+// a media type like `text/plain; charset=utf-8` from a Content-Type header, RFC 9110 section 8.3.1
+// the type, subtype and parameter names dont care about case so they are kept lowercase,
+// parameter values are kept as they were sent (a boundary is case sensitive)
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct MediaType {
+    kind: String,
+    subtype: String,
+    params: Vec<(String, String)>,
+}
+
+impl MediaType {
+    pub fn new(kind: &str, subtype: &str) -> Self {
+        Self {
+            kind: kind.to_ascii_lowercase(),
+            subtype: subtype.to_ascii_lowercase(),
+            params: Vec::new(),
+        }
+    }
+
+    pub fn with_param(mut self, name: &str, value: &str) -> Self {
+        self.params.push((name.to_ascii_lowercase(), value.to_string()));
+        self
+    }
+
+    pub fn get_type(&self) -> &str {
+        &self.kind
+    }
+
+    pub fn get_subtype(&self) -> &str {
+        &self.subtype
+    }
+
+    // just type/subtype without any parameters
+    pub fn essence(&self) -> String {
+        format!("{}/{}", self.kind, self.subtype)
+    }
+
+    pub fn get_param(&self, name: &str) -> Option<&str> {
+        self.params.iter()
+            .find(|(key, _)| key.eq_ignore_ascii_case(name))
+            .map(|(_, value)| value.as_str())
+    }
+
+    pub fn params(&self) -> impl Iterator<Item = (&str, &str)> {
+        self.params.iter().map(|(key, value)| (key.as_str(), value.as_str()))
+    }
+
+    // lowercase since charset names dont care about case either
+    pub fn charset(&self) -> Option<String> {
+        self.get_param("charset").map(str::to_ascii_lowercase)
+    }
+
+    pub fn boundary(&self) -> Option<&str> {
+        self.get_param("boundary")
+    }
+}
+
+impl std::str::FromStr for MediaType {
+    type Err = HTTPError;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (essence, params) = match s.split_once(';') {
+            Some((essence, params)) => (essence, params),
+            None => (s, ""),
+        };
+
+        let (kind, subtype) = match essence.trim().split_once('/') {
+            Some((kind, subtype)) => (kind, subtype),
+            None => return Err(HTTPError::InvalidContentType),
+        };
+        let is_token = |text: &str| !text.is_empty() && text.bytes().all(is_token_char);
+        if !is_token(kind) || !is_token(subtype) {
+            return Err(HTTPError::InvalidContentType);
+        }
+
+        let mut media_type = Self::new(kind, subtype);
+        media_type.params = parse_params(params)?;
+        Ok(media_type)
+    }
+}
+
+impl std::fmt::Display for MediaType {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}/{}", self.kind, self.subtype)?;
+        for (name, value) in &self.params {
+            write!(f, "; {}={}", name, quote_if_needed(value))?;
+        }
+        Ok(())
+    }
+}
+
+// the `; name=value; name="quoted value"` list after a media type or in a Content-Disposition
+// names come back lowercase and quoted values come back unquoted
+pub fn parse_params(text: &str) -> Result<Vec<(String, String)>, HTTPError> {
+    let bytes = text.as_bytes();
+    let mut params = Vec::new();
+    let mut i = 0;
+
+    loop {
+        // skip the whitespace and semicolons between parameters
+        while i < bytes.len() && matches!(bytes[i], b' ' | b'\t' | b';') {
+            i += 1;
+        }
+        if i == bytes.len() {
+            return Ok(params);
+        }
+
+        let start = i;
+        while i < bytes.len() && is_token_char(bytes[i]) {
+            i += 1;
+        }
+        let name = &text[start..i];
+        if name.is_empty() || bytes.get(i) != Some(&b'=') {
+            return Err(HTTPError::InvalidContentType);
+        }
+        i += 1;
+
+        let value = if bytes.get(i) == Some(&b'"') {
+            let (value, read) = read_quoted(&bytes[i..])?;
+            i += read;
+            value
+        } else {
+            let start = i;
+            while i < bytes.len() && is_token_char(bytes[i]) {
+                i += 1;
+            }
+            if start == i {
+                return Err(HTTPError::InvalidContentType);
+            }
+            text[start..i].to_string()
+        };
+
+        // anything but whitespace or the next parameter after a value is junk
+        while i < bytes.len() && matches!(bytes[i], b' ' | b'\t') {
+            i += 1;
+        }
+        if i < bytes.len() && bytes[i] != b';' {
+            return Err(HTTPError::InvalidContentType);
+        }
+
+        params.push((name.to_ascii_lowercase(), value));
+    }
+}
+
+// a quoted-string starting at the opening quote, gives back the value and how many bytes it took up
+// a backslash lets the next character through as is
+fn read_quoted(bytes: &[u8]) -> Result<(String, usize), HTTPError> {
+    let mut value = Vec::new();
+    let mut i = 1;
+    loop {
+        match bytes.get(i) {
+            None => return Err(HTTPError::InvalidContentType),
+            Some(b'"') => break,
+            Some(b'\\') => {
+                match bytes.get(i + 1) {
+                    Some(&c) => value.push(c),
+                    None => return Err(HTTPError::InvalidContentType),
+                }
+                i += 2;
+            },
+            Some(&c) => {
+                value.push(c);
+                i += 1;
+            },
+        }
+    }
+
+    match String::from_utf8(value) {
+        Ok(value) => Ok((value, i + 1)),
+        Err(_) => Err(HTTPError::InvalidContentType),
+    }
+}
+
+fn quote_if_needed(value: &str) -> String {
+    if !value.is_empty() && value.bytes().all(is_token_char) {
+        return value.to_string();
+    }
+    format!("\"{}\"", value.replace('\\', "\\\\").replace('"', "\\\""))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parsing() {
+        let media_type = "Text/HTML; Charset=UTF-8".parse::<MediaType>().unwrap();
+        assert_eq!(media_type.essence(), "text/html");
+        assert_eq!(media_type.charset().as_deref(), Some("utf-8"));
+
+        // boundaries keep their case
+        let media_type = "multipart/form-data;boundary=AbC123 ".parse::<MediaType>().unwrap();
+        assert_eq!(media_type.boundary(), Some("AbC123"));
+
+        for text in ["text", "/html", "text/", "te xt/html", "text/html; charset", "text/html; =utf-8", "text/html; charset=", "text/html; a=b c"] {
+            assert!(matches!(text.parse::<MediaType>(), Err(HTTPError::InvalidContentType)), "{text}");
+        }
+    }
+
+    #[test]
+    fn quoted_params() {
+        let params = parse_params(r#"; a="with space"; b="semi;colon"; c="back\\slash \"quote\""; d="""#).unwrap();
+        assert_eq!(params, [
+            (String::from("a"), String::from("with space")),
+            (String::from("b"), String::from("semi;colon")),
+            (String::from("c"), String::from("back\\slash \"quote\"")),
+            (String::from("d"), String::new()),
+        ]);
+
+        // unclosed, ending on a backslash or with junk after the closing quote
+        for text in [r#"a="open"#, r#"a="ends\"#, r#"a="b"c"#] {
+            assert!(matches!(parse_params(text), Err(HTTPError::InvalidContentType)), "{text}");
+        }
+    }
+
+    #[test]
+    fn writing_quotes_what_needs_it() {
+        let media_type = MediaType::new("Multipart", "Form-Data")
+            .with_param("boundary", "simple")
+            .with_param("name", "a \"b\"\\c")
+            .with_param("empty", "");
+        let text = media_type.to_string();
+        assert_eq!(text, r#"multipart/form-data; boundary=simple; name="a \"b\"\\c"; empty="""#);
+        assert_eq!(text.parse::<MediaType>().unwrap(), media_type);
+    }
+}
@@ -0,0 +1,195 @@
+use std::str::FromStr;
+use crate::headers::Headers;
+use crate::mime::{MediaType, parse_params};
+use crate::types::ContentType;
+
+// proactive content negotiation with the Accept header, RFC 9110 section 12.5.1
+// Accept: application/json;q=0.9, text/*;q=0.5, */*;q=0.1
+
+// one media range from the Accept header and how much the client wants it (0 to 1000 so it can be compared exactly)
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct MediaRange {
+    media_type: MediaType,
+    quality: u16,
+}
+
+impl MediaRange {
+    // how closely the range names the type, the most specific range that matches is the one that counts
+    // None if it doesnt match at all
+    fn specificity(&self, offered: &MediaType) -> Option<usize> {
+        let kind = self.media_type.get_type();
+        let subtype = self.media_type.get_subtype();
+
+        if kind == "*" && subtype == "*" {
+            return Some(0);
+        }
+        if kind != offered.get_type() {
+            return None;
+        }
+        if subtype == "*" {
+            return Some(1);
+        }
+        if subtype != offered.get_subtype() {
+            return None;
+        }
+
+        // text/plain;charset=utf-8 beats text/plain but only if every parameter matches
+        let params_match = self.media_type.params()
+            .all(|(name, value)| offered.get_param(name).is_some_and(|offered| offered.eq_ignore_ascii_case(value)));
+        if !params_match {
+            return None;
+        }
+        Some(2 + self.media_type.params().count())
+    }
+}
+
+// every range in the Accept headers, anything that doesnt parse is skipped
+pub fn parse_accept(headers: &Headers) -> Vec<MediaRange> {
+    headers.get_list("Accept")
+        .filter_map(|element| {
+            let (essence, params) = element.split_once(';').unwrap_or((element, ""));
+            let essence = MediaType::from_str(essence).ok()?;
+            // */json isnt a thing
+            if essence.get_type() == "*" && essence.get_subtype() != "*" {
+                return None;
+            }
+
+            // the q parameter splits the media type parameters from accept extensions which get ignored
+            let mut media_type = MediaType::new(essence.get_type(), essence.get_subtype());
+            let mut quality = 1000;
+            for (name, value) in parse_params(params).ok()? {
+                if name == "q" {
+                    quality = parse_quality(&value)?;
+                    break;
+                }
+                media_type = media_type.with_param(&name, &value);
+            }
+            Some(MediaRange { media_type, quality })
+        })
+        .collect()
+}
+
+// picks the offered type the client wants most, ties go to whichever was offered first
+// no Accept header means anything goes, None means nothing offered is acceptable and it should be a 406
+pub fn negotiate(headers: &Headers, offered: &[ContentType]) -> Option<ContentType> {
+    let ranges = parse_accept(headers);
+    if ranges.is_empty() {
+        return offered.first().copied();
+    }
+
+    let mut best: Option<(ContentType, u16)> = None;
+    for content_type in offered {
+        let media_type = match MediaType::from_str(&content_type.to_string()) {
+            Ok(media_type) => media_type,
+            Err(_) => continue,
+        };
+
+        let quality = ranges.iter()
+            .filter_map(|range| Some((range.specificity(&media_type)?, range.quality)))
+            .max_by_key(|(specificity, _)| *specificity)
+            .map(|(_, quality)| quality)
+            .unwrap_or(0);
+
+        if quality > 0 && best.is_none_or(|(_, best_quality)| quality > best_quality) {
+            best = Some((*content_type, quality));
+        }
+    }
+
+    best.map(|(content_type, _)| content_type)
+}
+
+// a qvalue is 0 or 1 with up to three decimals
+fn parse_quality(text: &str) -> Option<u16> {
+    let (whole, decimals) = text.split_once('.').unwrap_or((text, ""));
+    if decimals.len() > 3 || !decimals.bytes().all(|c| c.is_ascii_digit()) {
+        return None;
+    }
+    let thousandths = format!("{:0<3}", decimals).parse::<u16>().ok()?;
+
+    match whole {
+        "0" => Some(thousandths),
+        "1" if thousandths == 0 => Some(1000),
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::types::Response;
+
+    const OFFERED: &[ContentType] = &[ContentType::OctetStream, ContentType::Json, ContentType::Html];
+
+    fn pick(accept: &[&str], offered: &[ContentType]) -> Option<String> {
+        let mut headers = Headers::new();
+        for value in accept {
+            headers.insert("Accept", value);
+        }
+        negotiate(&headers, offered).map(|content_type| content_type.to_string())
+    }
+
+    #[test]
+    fn no_preference_gets_the_first() {
+        assert_eq!(pick(&[], OFFERED).as_deref(), Some("application/octet-stream"));
+        assert_eq!(pick(&["*/*"], OFFERED).as_deref(), Some("application/octet-stream"));
+        // nothing in it we can read is the same as not sending it
+        assert_eq!(pick(&["garbage, */json"], OFFERED).as_deref(), Some("application/octet-stream"));
+        assert_eq!(pick(&[], &[]), None);
+    }
+
+    #[test]
+    fn highest_quality_wins() {
+        assert_eq!(pick(&["application/json"], OFFERED).as_deref(), Some("application/json"));
+        assert_eq!(pick(&["text/html;q=0.5, application/json;q=0.9"], OFFERED).as_deref(), Some("application/json"));
+        assert_eq!(pick(&["text/html;q=0.5", "application/json;q=0.4"], OFFERED).as_deref(), Some("text/html"));
+        // ties go to the first offered
+        assert_eq!(pick(&["text/html, application/json"], OFFERED).as_deref(), Some("application/json"));
+    }
+
+    #[test]
+    fn q_zero_is_never_sent() {
+        assert_eq!(pick(&["application/octet-stream;q=0, */*"], OFFERED).as_deref(), Some("application/json"));
+        assert_eq!(pick(&["*/*;q=0"], OFFERED), None);
+        assert_eq!(pick(&["application/json;q=0.000"], &[ContentType::Json]), None);
+    }
+
+    #[test]
+    fn most_specific_range_counts() {
+        // text/* would allow html but the more specific range turns it down
+        assert_eq!(pick(&["text/*, text/html;q=0"], OFFERED), None);
+        assert_eq!(pick(&["*/*;q=0.1, application/*;q=0.2, application/json"], OFFERED).as_deref(), Some("application/json"));
+        assert_eq!(pick(&["*/*;q=0.9, application/*;q=0.1"], OFFERED).as_deref(), Some("text/html"));
+        // a parameter we dont have means the range doesnt match at all
+        assert_eq!(pick(&["application/json;charset=ascii, */*;q=0.1"], OFFERED).as_deref(), Some("application/octet-stream"));
+    }
+
+    #[test]
+    fn nothing_acceptable_is_a_406() {
+        assert_eq!(pick(&["image/png"], OFFERED), None);
+        assert_eq!(pick(&["text/plain, image/*"], OFFERED), None);
+
+        let response = Response::new_406_error(OFFERED);
+        assert_eq!(response.get_code(), 406);
+        assert_eq!(response.headers().get("Vary"), Some("Accept"));
+    }
+
+    #[test]
+    fn quality_values() {
+        assert_eq!(parse_quality("1"), Some(1000));
+        assert_eq!(parse_quality("1.000"), Some(1000));
+        assert_eq!(parse_quality("0.5"), Some(500));
+        assert_eq!(parse_quality("0.05"), Some(50));
+        assert_eq!(parse_quality("0"), Some(0));
+        for text in ["", "1.001", "2", "0.1234", "-0.5", ".5", "0.x", "0.+1"] {
+            assert_eq!(parse_quality(text), None, "{text}");
+        }
+
+        // a broken q throws out the whole range
+        let mut headers = Headers::new();
+        headers.insert("Accept", "text/html;q=2, application/json;level=1;q=0.3;ext=x");
+        assert_eq!(parse_accept(&headers), [MediaRange {
+            media_type: MediaType::new("application", "json").with_param("level", "1"),
+            quality: 300,
+        }]);
+    }
+}
@@ -0,0 +1,308 @@
+use std::collections::hash_map::RandomState;
+use std::collections::VecDeque;
+use std::fs::File;
+use std::hash::{BuildHasher, Hasher};
+use std::io::{self, Read, Seek, SeekFrom};
+use std::path::Path;
+use crate::headers::Headers;
+use crate::response_body::ResponseBody;
+use crate::types::ContentType;
+
+// past this many ranges the request is treated like it asked for the whole file
+// stops someone from asking for the same bytes thousands of times over
+const MAX_RANGES: usize = 32;
+
+// both ends are inclusive just like in the header
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ByteRange {
+    pub start: u64,
+    pub end: u64,
+}
+
+impl ByteRange {
+    fn len(&self) -> u64 {
+        self.end - self.start + 1
+    }
+
+    // the value for a Content-Range header
+    pub fn content_range(&self, total: u64) -> String {
+        format!("bytes {}-{}/{}", self.start, self.end, total)
+    }
+}
+
+#[derive(Debug)]
+pub enum RangeResult {
+    Full,
+    Partial(Vec<ByteRange>),
+    Unsatisfiable,
+}
+
+// works out which bytes of a `total` byte long file the Range header asks for
+// a header we cant read is ignored like RFC 9110 says so the whole file gets sent
+pub fn get_ranges(headers: &Headers, total: u64) -> RangeResult {
+    let value = match headers.get("Range") {
+        Some(value) => value,
+        None => return RangeResult::Full,
+    };
+
+    match parse_range(value, total) {
+        None => RangeResult::Full,
+        Some(ranges) if ranges.is_empty() => RangeResult::Unsatisfiable,
+        Some(ranges) => RangeResult::Partial(ranges),
+    }
+}
+
+// Range: bytes=0-499, 1000-, -500
+// returns the satisfiable ranges merged together, an empty vec means none of them were
+fn parse_range(value: &str, total: u64) -> Option<Vec<ByteRange>> {
+    let value = value.trim();
+    // only bytes are supported, any other unit gets ignored
+    if value.len() < 6 || !value[..6].eq_ignore_ascii_case("bytes=") {
+        return None;
+    }
+
+    let specs = value[6..].split(',')
+        .map(str::trim)
+        .filter(|spec| !spec.is_empty())
+        .collect::<Vec<&str>>();
+
+    if specs.is_empty() || specs.len() > MAX_RANGES {
+        return None;
+    }
+
+    let parse_number = |number: &str| {
+        if number.is_empty() || !number.bytes().all(|c| c.is_ascii_digit()) {
+            return None;
+        }
+        number.parse::<u64>().ok()
+    };
+
+    let mut ranges = Vec::with_capacity(specs.len());
+    for spec in specs {
+        let (first, last) = spec.split_once('-')?;
+        let range = match (first, last) {
+            // the last n bytes
+            ("", suffix) => {
+                let suffix = parse_number(suffix)?;
+                if suffix == 0 || total == 0 {
+                    continue;
+                }
+                ByteRange { start: total.saturating_sub(suffix), end: total - 1 }
+            },
+            // from n to the end
+            (first, "") => {
+                let first = parse_number(first)?;
+                if first >= total {
+                    continue;
+                }
+                ByteRange { start: first, end: total - 1 }
+            },
+            (first, last) => {
+                let (first, last) = (parse_number(first)?, parse_number(last)?);
+                if last < first {
+                    return None;
+                }
+                if first >= total {
+                    continue;
+                }
+                ByteRange { start: first, end: last.min(total - 1) }
+            },
+        };
+        ranges.push(range);
+    }
+
+    // overlapping and touching ranges are merged, RFC 9110 lets us do that
+    ranges.sort_by_key(|range| range.start);
+    let mut merged: Vec<ByteRange> = Vec::with_capacity(ranges.len());
+    for range in ranges {
+        match merged.last_mut() {
+            Some(last) if range.start <= last.end.saturating_add(1) => {
+                last.end = last.end.max(range.end);
+            },
+            _ => merged.push(range),
+        }
+    }
+
+    Some(merged)
+}
+
+// a single range is sent straight out of the file
+// more than one range is sent as multipart/byteranges with each part labeled, the parts are read
+// from the file as they get written so asking for most of a big file doesnt put it all in memory
+pub fn read_ranges(path: &Path, ranges: &[ByteRange], content_type: ContentType, total: u64) -> io::Result<(ContentType, ResponseBody)> {
+    let file = File::open(path)?;
+
+    if let [range] = ranges {
+        return Ok((content_type, ResponseBody::file_range(file, range.start, range.len())));
+    }
+
+    let boundary = RandomState::new().build_hasher().finish();
+    let multipart = ContentType::MultipartByteRanges(boundary);
+
+    let mut parts = VecDeque::with_capacity(ranges.len() * 2 + 1);
+    for range in ranges {
+        let part_header = format!(
+            "\r\n--{:016x}\r\nContent-Type: {}\r\nContent-Range: {}\r\n\r\n",
+            boundary,
+            content_type,
+            range.content_range(total),
+        );
+        parts.push_back(Part::Text(io::Cursor::new(part_header.into_bytes())));
+        parts.push_back(Part::Range { position: range.start, remaining: range.len() });
+    }
+    parts.push_back(Part::Text(io::Cursor::new(format!("\r\n--{:016x}--\r\n", boundary).into_bytes())));
+
+    let len = parts.iter().map(Part::len).sum();
+    Ok((multipart, ResponseBody::reader(ByteRangeParts { file, parts }, Some(len))))
+}
+
+// the body of a multipart/byteranges response, one part after another
+struct ByteRangeParts {
+    file: File,
+    parts: VecDeque<Part>,
+}
+
+enum Part {
+    Text(io::Cursor<Vec<u8>>),
+    Range { position: u64, remaining: u64 },
+}
+
+impl Part {
+    fn len(&self) -> u64 {
+        match self {
+            Part::Text(text) => text.get_ref().len() as u64,
+            Part::Range { remaining, .. } => *remaining,
+        }
+    }
+}
+
+impl Read for ByteRangeParts {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if buf.is_empty() {
+            return Ok(0);
+        }
+        while let Some(part) = self.parts.front_mut() {
+            let read = match part {
+                Part::Text(text) => text.read(buf)?,
+                Part::Range { remaining: 0, .. } => 0,
+                Part::Range { position, remaining } => {
+                    self.file.seek(SeekFrom::Start(*position))?;
+                    let wanted = (*remaining).min(buf.len() as u64) as usize;
+                    let read = self.file.read(&mut buf[..wanted])?;
+                    // the file got shorter since we looked at it
+                    if read == 0 {
+                        return Err(io::Error::from(io::ErrorKind::UnexpectedEof));
+                    }
+                    *position += read as u64;
+                    *remaining -= read as u64;
+                    read
+                },
+            };
+
+            if read > 0 {
+                return Ok(read);
+            }
+            self.parts.pop_front();
+        }
+        Ok(0)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs;
+
+    fn range(start: u64, end: u64) -> ByteRange {
+        ByteRange { start, end }
+    }
+
+    #[test]
+    fn single_ranges() {
+        assert_eq!(parse_range("bytes=0-499", 1000), Some(vec![range(0, 499)]));
+        assert_eq!(parse_range("bytes=500-", 1000), Some(vec![range(500, 999)]));
+        assert_eq!(parse_range("bytes=-200", 1000), Some(vec![range(800, 999)]));
+        assert_eq!(parse_range(" Bytes=10-10 ", 1000), Some(vec![range(10, 10)]));
+        // ends past the file are cut down to it
+        assert_eq!(parse_range("bytes=900-5000", 1000), Some(vec![range(900, 999)]));
+        assert_eq!(parse_range("bytes=-5000", 1000), Some(vec![range(0, 999)]));
+    }
+
+    #[test]
+    fn several_ranges_are_sorted_and_merged() {
+        assert_eq!(parse_range("bytes=500-599, 0-99", 1000), Some(vec![range(0, 99), range(500, 599)]));
+        // overlapping and touching
+        assert_eq!(parse_range("bytes=0-99,50-150,151-200", 1000), Some(vec![range(0, 200)]));
+        assert_eq!(parse_range("bytes=0-0,-1", 1000), Some(vec![range(0, 0), range(999, 999)]));
+        // empty specs in the list are skipped
+        assert_eq!(parse_range("bytes=0-9,,20-29,", 1000), Some(vec![range(0, 9), range(20, 29)]));
+    }
+
+    #[test]
+    fn unsatisfiable_ranges() {
+        assert_eq!(parse_range("bytes=1000-", 1000), Some(vec![]));
+        assert_eq!(parse_range("bytes=2000-3000", 1000), Some(vec![]));
+        assert_eq!(parse_range("bytes=-0", 1000), Some(vec![]));
+        assert_eq!(parse_range("bytes=-10", 0), Some(vec![]));
+        // the ones that can be sent still are
+        assert_eq!(parse_range("bytes=2000-3000, 0-1", 1000), Some(vec![range(0, 1)]));
+    }
+
+    #[test]
+    fn bad_headers_are_ignored() {
+        for value in [
+            "", "bytes=", "bytes=,", "items=0-1", "bytes 0-1", "bytes=abc", "bytes=5-1",
+            "bytes=-", "bytes=+1-2", "bytes=1-2-3", "bytes=0x1-2", "bytes=99999999999999999999-",
+        ] {
+            assert_eq!(parse_range(value, 1000), None, "{}", value);
+        }
+
+        // too many ranges is treated like no ranges at all
+        let many = vec!["0-0"; MAX_RANGES + 1].join(",");
+        assert_eq!(parse_range(&format!("bytes={}", many), 1000), None);
+        let most = vec!["0-0"; MAX_RANGES].join(",");
+        assert_eq!(parse_range(&format!("bytes={}", most), 1000), Some(vec![range(0, 0)]));
+    }
+
+    #[test]
+    fn get_ranges_from_headers() {
+        let mut headers = Headers::new();
+        assert!(matches!(get_ranges(&headers, 100), RangeResult::Full));
+        headers.insert("Range", "bytes=200-");
+        assert!(matches!(get_ranges(&headers, 100), RangeResult::Unsatisfiable));
+
+        let mut headers = Headers::new();
+        headers.insert("Range", "bytes=10-19");
+        match get_ranges(&headers, 100) {
+            RangeResult::Partial(ranges) => assert_eq!(ranges[0].content_range(100), "bytes 10-19/100"),
+            other => panic!("{:?}", other),
+        }
+    }
+
+    #[test]
+    fn multipart_parts_are_read_from_the_file() {
+        let path = std::env::temp_dir().join(format!("range_test_{}", std::process::id()));
+        let contents = (0..=255).cycle().take(100_000).collect::<Vec<u8>>();
+        fs::write(&path, &contents).unwrap();
+
+        let ranges = [range(0, 9), range(50_000, 99_999)];
+        let (content_type, body) = read_ranges(&path, &ranges, ContentType::PlainText, 100_000).unwrap();
+        let len = body.len().unwrap();
+        let mut sent = Vec::new();
+        body.into_reader().unwrap().read_to_end(&mut sent).unwrap();
+        let _ = fs::remove_file(&path);
+
+        let boundary = match content_type {
+            ContentType::MultipartByteRanges(boundary) => format!("{:016x}", boundary),
+            other => panic!("{:?}", other),
+        };
+        let mut expected = format!("\r\n--{}\r\nContent-Type: text/plain\r\nContent-Range: bytes 0-9/100000\r\n\r\n", boundary).into_bytes();
+        expected.extend_from_slice(&contents[..10]);
+        expected.extend(format!("\r\n--{}\r\nContent-Type: text/plain\r\nContent-Range: bytes 50000-99999/100000\r\n\r\n", boundary).into_bytes());
+        expected.extend_from_slice(&contents[50_000..]);
+        expected.extend(format!("\r\n--{}--\r\n", boundary).into_bytes());
+
+        assert_eq!(len, sent.len() as u64);
+        assert!(sent == expected);
+    }
+}
@@ -0,0 +1,722 @@
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::{self, ErrorKind, Read, Seek, SeekFrom, Write};
+use std::net::{TcpListener, TcpStream};
+use std::os::fd::{AsRawFd, FromRawFd, OwnedFd, RawFd};
+use std::os::unix::fs::FileExt;
+use std::sync::Mutex;
+use std::time::{Duration, Instant, SystemTime};
+use crate::body::MAX_CHUNK_LINE;
+use crate::limits::{LINGER_TIMEOUT, RequestLimits};
+use crate::shutdown::Shutdown;
+use crate::thread::lock;
+use crate::types::{HTTPError, RequestError, Response, turn_system_time_to_http_date};
+
+// one thread watches every connection that isnt being handled right now with epoll,
+// so idle keep-alive connections and clients that send slowly dont each take up a worker
+// a connection only goes to a worker once a whole request has come in and comes back here
+// when the worker is done with it, along with the response if it was in memory or a file
+
+const LISTENER: u64 = u64::MAX;
+const WAKE: u64 = u64::MAX - 1;
+// how much is read off one connection at a time so a fast one cant starve the rest
+const READ_CHUNK: usize = 16 * 1024;
+// how often connections are checked for having gone over their timeouts
+const SWEEP_INTERVAL: Duration = Duration::from_millis(250);
+
+// makes the response for a request that couldnt be read, like one that took too long to arrive
+pub type ErrorResponse = dyn Fn(RequestError) -> Response + Send + Sync;
+
+// a connection with at least one whole request in buffered, the stream is blocking again
+#[derive(Debug)]
+pub struct Ready {
+    pub stream: TcpStream,
+    pub buffered: Vec<u8>,
+}
+
+// what a worker hands back once its done with a connection
+struct Returned {
+    stream: TcpStream,
+    buffered: Vec<u8>,
+    output: Vec<u8>,
+    file: Option<FilePart>,
+    keep_alive: bool,
+}
+
+// len bytes of the file from offset, sent with sendfile once the output is out
+struct FilePart {
+    file: File,
+    offset: u64,
+    len: u64,
+}
+
+pub struct Reactor {
+    epoll: OwnedFd,
+    // an eventfd that wakes epoll_wait up when a worker gives a connection back
+    wake: OwnedFd,
+    // None once the reactor has stopped so workers finish connections themselves
+    returned: Mutex<Option<Vec<Returned>>>,
+    limits: RequestLimits,
+    idle_timeout: Duration,
+    error_response: Box<ErrorResponse>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Phase {
+    // waiting for the next request, closed quietly after the idle timeout
+    Idle,
+    // part of the header is in, a 408 after the header timeout
+    Head,
+    // the header is in but not all of the body, a 408 after the body timeout
+    Body,
+    // writing out a response a worker gave back
+    Writing,
+    // the response is out and the write side shut, anything else the client sends is thrown away
+    // untill it closes too or the linger timeout runs out
+    Closing,
+}
+
+struct Connection {
+    stream: TcpStream,
+    buffer: Vec<u8>,
+    output: Vec<u8>,
+    written: usize,
+    file: Option<FilePart>,
+    keep_alive: bool,
+    phase: Phase,
+    deadline: Instant,
+    // how far into a chunked body has been checked so far
+    chunked: ChunkedProgress,
+    // the events epoll is watching for, None when it isnt registered
+    interest: Option<u32>,
+}
+
+impl Reactor {
+    // idle_timeout is how long a connection can sit between requests, the limits cover the rest
+    // error_response makes the 408 for connections that go over them part way through a request
+    pub fn new(limits: RequestLimits, idle_timeout: Duration, error_response: Box<ErrorResponse>) -> io::Result<Self> {
+        let epoll = check(unsafe { libc::epoll_create1(libc::EPOLL_CLOEXEC) })?;
+        let epoll = unsafe { OwnedFd::from_raw_fd(epoll) };
+        let wake = check(unsafe { libc::eventfd(0, libc::EFD_NONBLOCK | libc::EFD_CLOEXEC) })?;
+        let wake = unsafe { OwnedFd::from_raw_fd(wake) };
+
+        let reactor = Self {
+            epoll,
+            wake,
+            returned: Mutex::new(Some(Vec::new())),
+            limits,
+            idle_timeout,
+            error_response,
+        };
+        reactor.control(libc::EPOLL_CTL_ADD, reactor.wake.as_raw_fd(), libc::EPOLLIN as u32, WAKE)?;
+        Ok(reactor)
+    }
+
+    // for workers once they are done with a request, output and then file are written before the next request
+    // is waited for and the connection is closed after them if keep_alive is false
+    // file is (file, offset, len) for a response body that gets sent straight from disk
+    // buffered is anything already read off the connection that wasnt part of the request
+    pub fn give_back(&self, stream: TcpStream, buffered: Vec<u8>, output: Vec<u8>, file: Option<(File, u64, u64)>, keep_alive: bool) {
+        let file = file.map(|(file, offset, len)| FilePart { file, offset, len });
+        let connection = Returned { stream, buffered, output, file, keep_alive };
+
+        let mut returned = lock(&self.returned);
+        match returned.as_mut() {
+            Some(returned) => returned.push(connection),
+            // stopped so nothing is waiting for the next request, the response still has to get out though
+            None => {
+                drop(returned);
+                self.finish(connection.stream, &connection.output, connection.file);
+                return;
+            }
+        }
+        drop(returned);
+
+        let one = 1_u64.to_ne_bytes();
+        unsafe { libc::write(self.wake.as_raw_fd(), one.as_ptr() as *const libc::c_void, one.len()) };
+    }
+
+    // accepts connections and reads requests off them untill the shutdown starts,
+    // dispatch gets every connection that has a whole request in and is then responsible for it
+    // something has to connect to the listener once the shutdown starts to wake this up
+    pub fn run(&self, listener: TcpListener, shutdown: &Shutdown, mut dispatch: impl FnMut(Ready)) -> io::Result<()> {
+        listener.set_nonblocking(true)?;
+        self.control(libc::EPOLL_CTL_ADD, listener.as_raw_fd(), libc::EPOLLIN as u32, LISTENER)?;
+
+        // connections are keyed by their fd, it cant be reused while the connection is open
+        let mut connections: HashMap<u64, Connection> = HashMap::new();
+        let mut events = vec![libc::epoll_event { events: 0, u64: 0 }; 256];
+        let mut last_sweep = Instant::now();
+
+        while !shutdown.is_started() {
+            let timeout = SWEEP_INTERVAL.as_millis() as i32;
+            let count = unsafe { libc::epoll_wait(self.epoll.as_raw_fd(), events.as_mut_ptr(), events.len() as i32, timeout) };
+            if count < 0 {
+                let e = io::Error::last_os_error();
+                if e.kind() == ErrorKind::Interrupted {
+                    continue;
+                }
+                self.stop(connections);
+                return Err(e);
+            }
+
+            for event in &events[..count as usize] {
+                // epoll_event is packed so the fields are copied out instead of borrowed
+                let (token, flags) = (event.u64, event.events);
+                match token {
+                    LISTENER => self.accept(&listener, &mut connections, &mut dispatch),
+                    WAKE => self.take_returned(&mut connections, &mut dispatch),
+                    token => {
+                        if let Some(connection) = connections.remove(&token) {
+                            self.ready(connection, flags, &mut connections, &mut dispatch);
+                        }
+                    }
+                }
+            }
+
+            if last_sweep.elapsed() >= SWEEP_INTERVAL {
+                self.expire(&mut connections);
+                last_sweep = Instant::now();
+            }
+        }
+
+        self.stop(connections);
+        Ok(())
+    }
+
+    fn accept(&self, listener: &TcpListener, connections: &mut HashMap<u64, Connection>, dispatch: &mut impl FnMut(Ready)) {
+        loop {
+            match listener.accept() {
+                Ok((stream, _)) => {
+                    let connection = Returned { stream, buffered: Vec::new(), output: Vec::new(), file: None, keep_alive: true };
+                    self.add(connection, connections, dispatch);
+                },
+                Err(e) if e.kind() == ErrorKind::WouldBlock => return,
+                Err(e) if e.kind() == ErrorKind::Interrupted => continue,
+                Err(e) => {
+                    println!("Error: {}, occured at: {}", e, turn_system_time_to_http_date(SystemTime::now()));
+                    return;
+                }
+            }
+        }
+    }
+
+    fn take_returned(&self, connections: &mut HashMap<u64, Connection>, dispatch: &mut impl FnMut(Ready)) {
+        let mut count = [0_u8; 8];
+        unsafe { libc::read(self.wake.as_raw_fd(), count.as_mut_ptr() as *mut libc::c_void, count.len()) };
+
+        let returned = match lock(&self.returned).as_mut() {
+            Some(returned) => std::mem::take(returned),
+            None => return,
+        };
+        for connection in returned {
+            self.add(connection, connections, dispatch);
+        }
+    }
+
+    fn add(&self, returned: Returned, connections: &mut HashMap<u64, Connection>, dispatch: &mut impl FnMut(Ready)) {
+        if let Err(e) = returned.stream.set_nonblocking(true) {
+            println!("Error: {}, occured at: {}", e, turn_system_time_to_http_date(SystemTime::now()));
+            return;
+        }
+
+        let connection = Connection {
+            stream: returned.stream,
+            buffer: returned.buffered,
+            output: returned.output,
+            written: 0,
+            file: returned.file,
+            keep_alive: returned.keep_alive,
+            phase: Phase::Writing,
+            deadline: Instant::now() + self.limits.send_timeout,
+            chunked: ChunkedProgress::default(),
+            interest: None,
+        };
+        self.advance(connection, connections, dispatch);
+    }
+
+    // epoll said the connection can be read from or written to
+    fn ready(&self, mut connection: Connection, flags: u32, connections: &mut HashMap<u64, Connection>, dispatch: &mut impl FnMut(Ready)) {
+        if connection.phase == Phase::Closing {
+            return self.discard(connection, connections);
+        }
+
+        if connection.phase != Phase::Writing {
+            let mut chunk = vec![0_u8; READ_CHUNK];
+            match connection.stream.read(&mut chunk) {
+                // the client hung up, anything half sent is just dropped
+                Ok(0) => return self.close(connection),
+                Ok(read) => {
+                    if connection.phase == Phase::Idle {
+                        connection.phase = Phase::Head;
+                        connection.deadline = Instant::now() + self.limits.header_timeout;
+                    }
+                    connection.buffer.extend_from_slice(&chunk[..read]);
+                },
+                Err(e) if matches!(e.kind(), ErrorKind::WouldBlock | ErrorKind::Interrupted) => {},
+                Err(_) => return self.close(connection),
+            }
+        } else if flags & (libc::EPOLLERR | libc::EPOLLHUP) as u32 != 0 {
+            return self.close(connection);
+        }
+
+        self.advance(connection, connections, dispatch);
+    }
+
+    // moves the connection on as far as it can go without waiting,
+    // it either ends up back in connections waiting on epoll, with a worker or closed
+    fn advance(&self, mut connection: Connection, connections: &mut HashMap<u64, Connection>, dispatch: &mut impl FnMut(Ready)) {
+        if connection.phase == Phase::Writing {
+            loop {
+                while connection.written < connection.output.len() {
+                    match connection.stream.write(&connection.output[connection.written..]) {
+                        Ok(0) => return self.close(connection),
+                        Ok(written) => {
+                            connection.written += written;
+                            connection.deadline = Instant::now() + self.limits.send_timeout;
+                        },
+                        Err(e) if e.kind() == ErrorKind::Interrupted => continue,
+                        Err(e) if e.kind() == ErrorKind::WouldBlock => {
+                            return self.wait(connection, libc::EPOLLOUT as u32, connections);
+                        },
+                        Err(_) => return self.close(connection),
+                    }
+                }
+
+                match self.send_file(&mut connection) {
+                    Ok(true) => break,
+                    Ok(false) => continue,
+                    Err(e) if e.kind() == ErrorKind::WouldBlock => {
+                        return self.wait(connection, libc::EPOLLOUT as u32, connections);
+                    },
+                    Err(e) => {
+                        println!("Error: {}, occured at: {}", e, turn_system_time_to_http_date(SystemTime::now()));
+                        return self.close(connection);
+                    },
+                }
+            }
+
+            if !connection.keep_alive {
+                return self.linger(connection, connections);
+            }
+            connection.output = Vec::new();
+            connection.written = 0;
+            connection.phase = Phase::Idle;
+            connection.deadline = Instant::now() + self.idle_timeout;
+        }
+
+        let phase = match request_state(&connection.buffer, &self.limits, &mut connection.chunked) {
+            RequestState::Complete => {
+                self.unregister(&mut connection);
+                if let Err(e) = connection.stream.set_nonblocking(false) {
+                    println!("Error: {}, occured at: {}", e, turn_system_time_to_http_date(SystemTime::now()));
+                    return;
+                }
+                return dispatch(Ready {
+                    stream: connection.stream,
+                    buffered: connection.buffer,
+                });
+            },
+            RequestState::Empty => Phase::Idle,
+            RequestState::Head => Phase::Head,
+            RequestState::Body => Phase::Body,
+        };
+
+        // a buffer handed back can already have part of the next request in it
+        if phase != connection.phase {
+            connection.deadline = match phase {
+                Phase::Head => Instant::now() + self.limits.header_timeout,
+                Phase::Body => Instant::now() + self.limits.body_timeout,
+                _ => Instant::now() + self.idle_timeout,
+            };
+            connection.phase = phase;
+        }
+        self.wait(connection, (libc::EPOLLIN | libc::EPOLLRDHUP) as u32, connections);
+    }
+
+    // sends as much of the file as the socket takes, true once all of it has gone
+    // false means some of it was read into output instead and that has to be written first
+    fn send_file(&self, connection: &mut Connection) -> io::Result<bool> {
+        let socket = connection.stream.as_raw_fd();
+        let part = match connection.file.as_mut() {
+            Some(part) => part,
+            None => return Ok(true),
+        };
+
+        while part.len > 0 {
+            let mut position = part.offset as libc::off_t;
+            // the kernel caps a single call at a bit under 2GB anyway
+            let count = part.len.min(0x7fff_f000) as usize;
+            let result = unsafe { libc::sendfile(socket, part.file.as_raw_fd(), &mut position, count) };
+
+            match result {
+                // the file got shorter since we looked at it
+                0 => return Err(io::Error::from(ErrorKind::UnexpectedEof)),
+                sent if sent > 0 => {
+                    part.offset += sent as u64;
+                    part.len -= sent as u64;
+                    connection.deadline = Instant::now() + self.limits.send_timeout;
+                },
+                _ => {
+                    let error = io::Error::last_os_error();
+                    match error.raw_os_error() {
+                        Some(libc::EINTR) => continue,
+                        // some filesystems cant do it so a chunk is read in and written like any other output
+                        Some(libc::EINVAL) | Some(libc::ENOSYS) => {
+                            let mut chunk = vec![0_u8; part.len.min(READ_CHUNK as u64) as usize];
+                            let read = part.file.read_at(&mut chunk, part.offset)?;
+                            if read == 0 {
+                                return Err(io::Error::from(ErrorKind::UnexpectedEof));
+                            }
+                            chunk.truncate(read);
+                            part.offset += read as u64;
+                            part.len -= read as u64;
+                            connection.output = chunk;
+                            connection.written = 0;
+                            return Ok(false);
+                        },
+                        _ => return Err(error),
+                    }
+                },
+            }
+        }
+
+        connection.file = None;
+        Ok(true)
+    }
+
+    fn wait(&self, mut connection: Connection, interest: u32, connections: &mut HashMap<u64, Connection>) {
+        let fd = connection.stream.as_raw_fd();
+        let result = match connection.interest {
+            Some(current) if current == interest => Ok(()),
+            Some(_) => self.control(libc::EPOLL_CTL_MOD, fd, interest, fd as u64),
+            None => self.control(libc::EPOLL_CTL_ADD, fd, interest, fd as u64),
+        };
+
+        match result {
+            Ok(()) => {
+                connection.interest = Some(interest);
+                connections.insert(fd as u64, connection);
+            },
+            Err(e) => {
+                println!("Error: {}, occured at: {}", e, turn_system_time_to_http_date(SystemTime::now()));
+                self.close(connection);
+            }
+        }
+    }
+
+    // closes anything thats gone over its timeout, a client that started a request gets told why
+    fn expire(&self, connections: &mut HashMap<u64, Connection>) {
+        let now = Instant::now();
+        let expired = connections.iter()
+            .filter(|(_, connection)| connection.deadline <= now)
+            .map(|(token, _)| *token)
+            .collect::<Vec<u64>>();
+
+        for token in expired {
+            let mut connection = match connections.remove(&token) {
+                Some(connection) => connection,
+                None => continue,
+            };
+
+            if matches!(connection.phase, Phase::Head | Phase::Body) {
+                println!("Error: {}, occured at: {}", HTTPError::RequestTimeout, turn_system_time_to_http_date(SystemTime::now()));
+                let mut response = (self.error_response)(RequestError::from_partial(HTTPError::RequestTimeout, &connection.buffer));
+                response.set_keep_alive(false);
+                let mut output = Vec::new();
+                // best effort, the socket is still non-blocking so a client thats not reading just misses it
+                if response.write_to(&mut output, false).is_ok() {
+                    let _ = connection.stream.write(&output);
+                }
+                self.linger(connection, connections);
+                continue;
+            }
+            self.close(connection);
+        }
+    }
+
+    // any responses that were still being written are finished off, everything else is closed
+    fn stop(&self, connections: HashMap<u64, Connection>) {
+        let returned = lock(&self.returned).take().unwrap_or_default();
+
+        for mut connection in connections.into_values() {
+            self.unregister(&mut connection);
+            if connection.phase == Phase::Writing {
+                let _ = connection.stream.set_nonblocking(false);
+                self.finish(connection.stream, &connection.output[connection.written..], connection.file);
+            }
+        }
+        for connection in returned {
+            self.finish(connection.stream, &connection.output, connection.file);
+        }
+    }
+
+    // writes whats left of a response on the calling thread
+    fn finish(&self, mut stream: TcpStream, output: &[u8], file: Option<FilePart>) {
+        let _ = stream.set_write_timeout(Some(self.limits.send_timeout));
+        let result = stream.write_all(output).and_then(|_| match file {
+            Some(mut part) => {
+                part.file.seek(SeekFrom::Start(part.offset))?;
+                let sent = io::copy(&mut (&part.file).take(part.len), &mut stream)?;
+                if sent != part.len {
+                    return Err(io::Error::from(ErrorKind::UnexpectedEof));
+                }
+                Ok(())
+            },
+            None => Ok(()),
+        });
+        if let Err(e) = result {
+            println!("Error: {}, occured at: {}", e, turn_system_time_to_http_date(SystemTime::now()));
+        }
+    }
+
+    // the response has been written but the client could still be sending the rest of its request,
+    // closing now would reset the connection and it might lose the response
+    fn linger(&self, mut connection: Connection, connections: &mut HashMap<u64, Connection>) {
+        if connection.stream.shutdown(std::net::Shutdown::Write).is_err() {
+            return self.close(connection);
+        }
+        connection.buffer = Vec::new();
+        connection.phase = Phase::Closing;
+        connection.deadline = Instant::now() + LINGER_TIMEOUT;
+        self.discard(connection, connections);
+    }
+
+    // reads and throws away what the client sent, closing once it has closed its side too
+    fn discard(&self, mut connection: Connection, connections: &mut HashMap<u64, Connection>) {
+        let mut chunk = [0_u8; 4096];
+        // a bit at a time like reads so one connection cant starve the rest
+        for _ in 0..(READ_CHUNK / chunk.len()) {
+            match connection.stream.read(&mut chunk) {
+                Ok(0) => return self.close(connection),
+                Ok(_) => {},
+                Err(e) if e.kind() == ErrorKind::Interrupted => {},
+                Err(e) if e.kind() == ErrorKind::WouldBlock => break,
+                Err(_) => return self.close(connection),
+            }
+        }
+        self.wait(connection, (libc::EPOLLIN | libc::EPOLLRDHUP) as u32, connections);
+    }
+
+    fn close(&self, mut connection: Connection) {
+        self.unregister(&mut connection);
+    }
+
+    fn unregister(&self, connection: &mut Connection) {
+        if connection.interest.take().is_some() {
+            let _ = self.control(libc::EPOLL_CTL_DEL, connection.stream.as_raw_fd(), 0, 0);
+        }
+    }
+
+    fn control(&self, operation: libc::c_int, fd: RawFd, interest: u32, token: u64) -> io::Result<()> {
+        let mut event = libc::epoll_event { events: interest, u64: token };
+        check(unsafe { libc::epoll_ctl(self.epoll.as_raw_fd(), operation, fd, &mut event) })?;
+        Ok(())
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum RequestState {
+    Empty,
+    Head,
+    Body,
+    // or broken enough that the worker should be the one to answer it
+    Complete,
+}
+
+// how much of a request is in the buffer, this only has to find where it ends
+// the worker parses it properly and sends any errors
+fn request_state(buffer: &[u8], limits: &RequestLimits, chunked: &mut ChunkedProgress) -> RequestState {
+    // blank lines between requests get skipped by the parser, they cant count towards the blank line
+    // that ends the header either or a request that just started would look finished
+    let buffer = match buffer.iter().position(|c| *c != b'\r' && *c != b'\n') {
+        Some(start) => &buffer[start..],
+        None => return RequestState::Empty,
+    };
+
+    let end = match header_end(buffer) {
+        Some(end) => end,
+        // too long to ever be a header, the worker answers it with a 414 or 431
+        None if buffer.len() > limits.max_request_line + limits.max_header_size => return RequestState::Complete,
+        None => return RequestState::Head,
+    };
+
+    let mut length = None;
+    let mut transfer_encoding = None;
+    for line in buffer[..end].split(|c| *c == b'\n').skip(1) {
+        let (name, value) = match line.iter().position(|c| *c == b':') {
+            Some(colon) => (&line[..colon], &line[colon + 1..]),
+            None => continue,
+        };
+        if name.eq_ignore_ascii_case(b"transfer-encoding") {
+            transfer_encoding = Some(value);
+        }
+        if name.eq_ignore_ascii_case(b"content-length") {
+            match std::str::from_utf8(value).ok().and_then(|value| value.trim().parse::<usize>().ok()) {
+                Some(value) => length = Some(value),
+                None => return RequestState::Complete,
+            }
+        }
+    }
+
+    let body = &buffer[end..];
+    let done = match (transfer_encoding, length) {
+        // chunked has to be the last coding, anything else including both headers at once is for the worker to turn down
+        (Some(codings), None) if is_chunked(codings) => chunked_state(body, limits, chunked).unwrap_or(true),
+        (Some(_), _) => true,
+        // a 413 is sent without waiting for all of it
+        (None, Some(length)) => length > limits.max_body_size || body.len() >= length,
+        (None, None) => true,
+    };
+
+    if done {
+        RequestState::Complete
+    } else {
+        RequestState::Body
+    }
+}
+
+fn is_chunked(codings: &[u8]) -> bool {
+    let last = codings.rsplit(|c| *c == b',').next().unwrap_or(b"");
+    let last = last.split(|c| *c == b';').next().unwrap_or(b"");
+    last.trim_ascii().eq_ignore_ascii_case(b"chunked")
+}
+
+// where the next chunk size line starts and how much chunk data came before it,
+// kept between reads so a body sent in lots of tiny chunks isnt gone over again every time more arrives
+#[derive(Debug, Default, Clone, Copy)]
+struct ChunkedProgress {
+    next: usize,
+    decoded: usize,
+}
+
+// whether the whole chunked body and its trailers are in, None when its broken or too big
+// and the worker should be the one to answer it
+fn chunked_state(body: &[u8], limits: &RequestLimits, progress: &mut ChunkedProgress) -> Option<bool> {
+    loop {
+        let rest = &body[progress.next..];
+        let line_end = match rest.iter().position(|c| *c == b'\n') {
+            Some(line_end) => line_end,
+            None if rest.len() as u64 > MAX_CHUNK_LINE => return None,
+            None => return Some(false),
+        };
+
+        let digits = rest[..line_end].iter().take_while(|c| c.is_ascii_hexdigit()).count();
+        let size = std::str::from_utf8(&rest[..digits]).ok()
+            .and_then(|size| usize::from_str_radix(size, 16).ok())?;
+        let data_start = line_end + 1;
+
+        if size == 0 {
+            // the trailer section ends with a blank line, which is straight away when there arent any trailers
+            let trailers = &rest[data_start..];
+            return match trailers {
+                [b'\n', ..] | [b'\r', b'\n', ..] => Some(true),
+                _ if header_end(trailers).is_some() => Some(true),
+                _ if trailers.len() > limits.max_header_size => None,
+                _ => Some(false),
+            };
+        }
+
+        if size > limits.max_body_size - progress.decoded {
+            return None;
+        }
+        let after = match rest.get(data_start + size..) {
+            Some(after) => after,
+            None => return Some(false),
+        };
+        // every chunk ends with its own line ending
+        let ending = match after {
+            [b'\n', ..] => 1,
+            [b'\r', b'\n', ..] => 2,
+            [] | [b'\r'] => return Some(false),
+            _ => return None,
+        };
+
+        progress.next += data_start + size + ending;
+        progress.decoded += size;
+    }
+}
+
+// just past the blank line at the end of the header, lines can end with \r\n or just \n
+fn header_end(buffer: &[u8]) -> Option<usize> {
+    buffer.iter().enumerate()
+        .filter(|(_, c)| **c == b'\n')
+        .find_map(|(i, _)| match &buffer[i + 1..] {
+            [b'\n', ..] => Some(i + 2),
+            [b'\r', b'\n', ..] => Some(i + 3),
+            _ => None,
+        })
+}
+
+fn check(result: libc::c_int) -> io::Result<libc::c_int> {
+    if result < 0 {
+        Err(io::Error::last_os_error())
+    } else {
+        Ok(result)
+    }
+}
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn state(buffer: &[u8]) -> RequestState {
+        request_state(buffer, &RequestLimits::default(), &mut ChunkedProgress::default())
+    }
+
+    #[test]
+    fn fixed_length_bodies() {
+        assert_eq!(state(b""), RequestState::Empty);
+        assert_eq!(state(b"\r\n"), RequestState::Empty);
+        assert_eq!(state(b"GET / HTTP/1.1\r\nHost: a\r\n"), RequestState::Head);
+        assert_eq!(state(b"GET / HTTP/1.1\r\nHost: a\r\n\r\n"), RequestState::Complete);
+        assert_eq!(state(b"\r\n\r\nGET / HTTP/1.1\r\n"), RequestState::Head);
+        assert_eq!(state(b"POST / HTTP/1.1\r\nContent-Length: 5\r\n\r\nhel"), RequestState::Body);
+        assert_eq!(state(b"POST / HTTP/1.1\r\nContent-Length: 5\r\n\r\nhello"), RequestState::Complete);
+        // too big is answered straight away
+        assert_eq!(state(b"POST / HTTP/1.1\r\nContent-Length: 999999999999\r\n\r\n"), RequestState::Complete);
+    }
+
+    #[test]
+    fn chunked_bodies() {
+        let head = b"POST / HTTP/1.1\r\nTransfer-Encoding: chunked\r\n\r\n".to_vec();
+        let with = |body: &[u8]| state(&[head.as_slice(), body].concat());
+
+        assert_eq!(with(b""), RequestState::Body);
+        assert_eq!(with(b"5\r\nhel"), RequestState::Body);
+        assert_eq!(with(b"5\r\nhello\r\n"), RequestState::Body);
+        assert_eq!(with(b"5;ext=1\r\nhello\r\n0\r\n"), RequestState::Body);
+        assert_eq!(with(b"5\r\nhello\r\n0\r\n\r\n"), RequestState::Complete);
+        assert_eq!(with(b"5\nhello\n0\n\n"), RequestState::Complete);
+        // trailers have to end too
+        assert_eq!(with(b"0\r\nChecksum: abc\r\n"), RequestState::Body);
+        assert_eq!(with(b"0\r\nChecksum: abc\r\n\r\n"), RequestState::Complete);
+
+        // anything broken goes to the worker to answer
+        assert_eq!(with(b"zz\r\n"), RequestState::Complete);
+        assert_eq!(with(b"5\r\nhello world\r\n"), RequestState::Complete);
+        assert_eq!(with(b"fffffffffffffffffffff\r\n"), RequestState::Complete);
+        assert_eq!(with(&vec![b'1'; MAX_CHUNK_LINE as usize + 1]), RequestState::Complete);
+        assert_eq!(state(b"POST / HTTP/1.1\r\nTransfer-Encoding: gzip\r\n\r\n"), RequestState::Complete);
+        assert_eq!(state(b"POST / HTTP/1.1\r\nTransfer-Encoding: chunked\r\nContent-Length: 5\r\n\r\n"), RequestState::Complete);
+    }
+
+    #[test]
+    fn chunked_progress_carries_over() {
+        let limits = RequestLimits { max_body_size: 10, ..RequestLimits::default() };
+        let mut progress = ChunkedProgress::default();
+        let mut buffer = b"POST / HTTP/1.1\r\nTransfer-Encoding: gzip, chunked\r\n\r\n".to_vec();
+
+        for chunk in [&b"3\r\nabc\r\n"[..], b"3\r\ndef\r\n", b"2\r\n"] {
+            buffer.extend_from_slice(chunk);
+            assert_eq!(request_state(&buffer, &limits, &mut progress), RequestState::Body);
+        }
+        assert_eq!(progress.decoded, 6);
+        buffer.extend_from_slice(b"gh\r\n0\r\n\r\n");
+        assert_eq!(request_state(&buffer, &limits, &mut progress), RequestState::Complete);
+
+        // going over the body size is a 413 from the worker without waiting for the rest
+        let mut progress = ChunkedProgress::default();
+        let buffer = b"POST / HTTP/1.1\r\nTransfer-Encoding: chunked\r\n\r\n8\r\n12345678\r\n8\r\n".to_vec();
+        assert_eq!(request_state(&buffer, &limits, &mut progress), RequestState::Complete);
+    }
+}
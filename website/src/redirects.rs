@@ -0,0 +1,323 @@
+use std::collections::HashMap;
+use std::fs;
+use std::io::ErrorKind;
+use std::path::Path;
+use crate::types::{percent_decode, percent_encode_path};
+
+// a table of moved pages read from a file at startup, one rule per line
+//    # status  kind     from                  to
+//    301       exact    /old-page             /new-page
+//    308       prefix   /posts/               /blog/
+//    302       pattern  /blog/{year}/{slug}   /blog/{slug}
+//    301       pattern  /files/{*rest}        https://files.example.com/{rest}
+// exact matches the whole path, prefix swaps the start of the path for the target and keeps the rest,
+// pattern has {name} for one segment and {*name} at the end for everything left which can be used in the target
+// paths can be percent-encoded so they can have spaces in them, the first rule that matches wins
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum Segment {
+    Literal(String),
+    Capture(String),
+    Rest(String),
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum RuleKind {
+    Exact(String),
+    Prefix(String),
+    Pattern(Vec<Segment>),
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RedirectRule {
+    status: u16,
+    kind: RuleKind,
+    // kept encoded as it was written since it goes straight into the Location header
+    target: String,
+}
+
+impl RedirectRule {
+    // the target with any captures filled in, None if the path doesnt match
+    fn apply(&self, path: &str) -> Option<String> {
+        match &self.kind {
+            RuleKind::Exact(from) if from == path => Some(self.target.clone()),
+            RuleKind::Exact(_) => None,
+            RuleKind::Prefix(from) => {
+                let rest = path.strip_prefix(from.as_str())?;
+                Some(format!("{}{}", self.target, percent_encode_path(rest)))
+            },
+            RuleKind::Pattern(segments) => {
+                let captures = match_pattern(segments, path)?;
+                let mut target = self.target.clone();
+                for (name, value) in captures {
+                    target = target.replace(&format!("{{{}}}", name), &percent_encode_path(&value));
+                }
+                Some(target)
+            },
+        }
+    }
+}
+
+#[derive(Debug, Default, Clone)]
+pub struct Redirects {
+    rules: Vec<RedirectRule>,
+}
+
+impl Redirects {
+    // a missing file just means no redirects, a broken one is an error so a typo doesnt go unnoticed
+    pub fn load(path: &Path) -> Result<Self, String> {
+        match fs::read_to_string(path) {
+            Ok(text) => Self::parse(&text),
+            Err(e) if e.kind() == ErrorKind::NotFound => Ok(Self::default()),
+            Err(e) => Err(e.to_string()),
+        }
+    }
+
+    pub fn parse(text: &str) -> Result<Self, String> {
+        let mut rules = Vec::new();
+
+        for (number, line) in text.lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            let rule = parse_rule(line).map_err(|e| format!("line {}: {}", number + 1, e))?;
+            rules.push(rule);
+        }
+
+        Ok(Self { rules })
+    }
+
+    // the status and Location for a path that has moved, the path is the decoded one from the request
+    // a rule that would send the path back to itself is skipped so the browser doesnt go round in circles,
+    // and so is one where a capture turned the target into //host which browsers take as another site
+    pub fn find(&self, path: &str) -> Option<(u16, String)> {
+        let encoded = percent_encode_path(path);
+        self.rules.iter()
+            .filter_map(|rule| Some((rule.status, rule.apply(path)?)))
+            .find(|(_, location)| *location != encoded && !is_other_host(location))
+    }
+}
+
+fn parse_rule(line: &str) -> Result<RedirectRule, String> {
+    let fields = line.split_whitespace().collect::<Vec<&str>>();
+    let (status, kind, from, target) = match fields.as_slice() {
+        [status, kind, from, target] => (status, kind, from, target),
+        _ => return Err(String::from("should be `status kind from to`")),
+    };
+
+    let status = match status.parse::<u16>() {
+        Ok(status @ (301 | 302 | 303 | 307 | 308)) => status,
+        _ => return Err(format!("{} isnt a redirect status", status)),
+    };
+
+    if is_other_host(target) {
+        return Err(format!("{} would go to another host, write it with https: if thats what its for", target));
+    }
+
+    let from = percent_decode(from, false).map_err(|_| format!("{} has bad percent-encoding", from))?;
+    if !from.starts_with('/') {
+        return Err(format!("{} should start with /", from));
+    }
+
+    let kind = match *kind {
+        "exact" => RuleKind::Exact(from),
+        "prefix" => RuleKind::Prefix(from),
+        "pattern" => RuleKind::Pattern(parse_pattern(&from, target)?),
+        kind => return Err(format!("{} should be exact, prefix or pattern", kind)),
+    };
+
+    Ok(RedirectRule {
+        status,
+        kind,
+        target: target.to_string(),
+    })
+}
+
+// //host and /\host (browsers treat a backslash like a slash) are urls for another site without a scheme
+fn is_other_host(location: &str) -> bool {
+    location.starts_with("//") || location.starts_with("/\\")
+}
+
+fn parse_pattern(pattern: &str, target: &str) -> Result<Vec<Segment>, String> {
+    let parts = pattern[1..].split('/').collect::<Vec<&str>>();
+    let mut segments = Vec::with_capacity(parts.len());
+
+    for (i, part) in parts.iter().enumerate() {
+        let segment = match part.strip_prefix('{').and_then(|part| part.strip_suffix('}')) {
+            Some(name) => match name.strip_prefix('*') {
+                Some(_) if i != parts.len() - 1 => return Err(format!("{{{}}} can only be at the end", name)),
+                Some(name) => Segment::Rest(name.to_string()),
+                None => Segment::Capture(name.to_string()),
+            },
+            None => Segment::Literal(part.to_string()),
+        };
+        segments.push(segment);
+    }
+
+    // a capture the target never uses is fine, one the target uses that doesnt exist is a typo
+    for name in target.split('{').skip(1).filter_map(|part| part.split_once('}')).map(|(name, _)| name) {
+        let captured = segments.iter().any(|segment| matches!(segment, Segment::Capture(n) | Segment::Rest(n) if n == name));
+        if !captured {
+            return Err(format!("{{{}}} isnt captured by the pattern", name));
+        }
+    }
+
+    Ok(segments)
+}
+
+fn match_pattern(segments: &[Segment], path: &str) -> Option<HashMap<String, String>> {
+    let parts = path.strip_prefix('/')?.split('/').collect::<Vec<&str>>();
+    let mut captures = HashMap::new();
+
+    for (i, segment) in segments.iter().enumerate() {
+        match segment {
+            Segment::Rest(name) => {
+                let rest = parts.get(i..)?.join("/");
+                if rest.is_empty() {
+                    return None;
+                }
+                captures.insert(name.clone(), rest);
+                return Some(captures);
+            },
+            Segment::Capture(name) => {
+                let part = parts.get(i).filter(|part| !part.is_empty())?;
+                captures.insert(name.clone(), part.to_string());
+            },
+            Segment::Literal(literal) => {
+                if parts.get(i) != Some(&literal.as_str()) {
+                    return None;
+                }
+            },
+        }
+    }
+
+    // every part has to be used up unless it ended with a rest capture
+    if parts.len() != segments.len() {
+        return None;
+    }
+    Some(captures)
+}
+
+// the one url a page should be reached at, None if the path already is it
+// duplicate slashes are squashed everywhere, pages also lose a trailing slash and .html
+// and /index goes to / since thats where index.html is served from
+pub fn canonicalize(path: &str) -> Option<String> {
+    if !path.starts_with('/') {
+        return None;
+    }
+
+    let mut canonical = String::with_capacity(path.len());
+    for c in path.chars() {
+        if c == '/' && canonical.ends_with('/') {
+            continue;
+        }
+        canonical.push(c);
+    }
+
+    // apis are left as they are past the slashes, /api/ is where they get found
+    if !canonical.starts_with("/api/") {
+        while canonical.len() > 1 && canonical.ends_with('/') {
+            canonical.pop();
+        }
+        if let Some(stripped) = canonical.strip_suffix(".html") {
+            canonical = stripped.to_string();
+        }
+        if canonical == "/index" {
+            canonical = String::from("/");
+        }
+    }
+
+    if canonical == path {
+        None
+    } else {
+        Some(canonical)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn find(rules: &str, path: &str) -> Option<(u16, String)> {
+        Redirects::parse(rules).unwrap().find(path)
+    }
+
+    #[test]
+    fn exact_and_prefix() {
+        let rules = "# moved\n\n301 exact /old%20page /new-page\n308 prefix /posts/ /blog/\n";
+        assert_eq!(find(rules, "/old page"), Some((301, String::from("/new-page"))));
+        assert_eq!(find(rules, "/old page/more"), None);
+        assert_eq!(find(rules, "/posts/a b"), Some((308, String::from("/blog/a%20b"))));
+        assert_eq!(find(rules, "/post"), None);
+    }
+
+    #[test]
+    fn pattern_captures() {
+        let rules = "302 pattern /blog/{year}/{slug} /posts/{slug}?year={year}\n301 pattern /files/{*rest} https://files.example.com/{rest}\n";
+        assert_eq!(find(rules, "/blog/2020/hello world"), Some((302, String::from("/posts/hello%20world?year=2020"))));
+        assert_eq!(find(rules, "/files/a/b/c.txt"), Some((301, String::from("https://files.example.com/a/b/c.txt"))));
+        // segments have to be there and cant be empty, and nothing can be left over
+        assert_eq!(find(rules, "/blog/2020"), None);
+        assert_eq!(find(rules, "/blog//hello"), None);
+        assert_eq!(find(rules, "/blog/2020/hello/extra"), None);
+        assert_eq!(find(rules, "/files/"), None);
+    }
+
+    #[test]
+    fn first_match_wins() {
+        let rules = "301 exact /a /first\n302 prefix / /second/\n";
+        assert_eq!(find(rules, "/a"), Some((301, String::from("/first"))));
+        assert_eq!(find(rules, "/b"), Some((302, String::from("/second/b"))));
+    }
+
+    #[test]
+    fn redirects_dont_loop() {
+        // straight back to itself is skipped and the next rule gets a go
+        assert_eq!(find("301 exact /same /same\n302 exact /same /other\n", "/same"), Some((302, String::from("/other"))));
+        assert_eq!(find("301 pattern /{page} /{page}\n", "/a b"), None);
+        assert_eq!(find("301 prefix / /\n", "/page"), None);
+    }
+
+    #[test]
+    fn other_hosts_are_refused() {
+        assert!(Redirects::parse("301 exact /a //evil.com").is_err());
+        assert!(Redirects::parse("301 exact /a /\\evil.com").is_err());
+        assert!(Redirects::parse("301 exact /a https://example.com/").is_ok());
+        // a capture could still make one at the start of the target
+        assert_eq!(find("301 pattern /go/{*rest} /{rest}\n", "/go//evil.com"), None);
+        assert_eq!(find("301 pattern /go/{*rest} /{rest}\n", "/go/page"), Some((301, String::from("/page"))));
+    }
+
+    #[test]
+    fn broken_rules() {
+        for rules in [
+            "301 exact /a",
+            "301 exact /a /b /c",
+            "200 exact /a /b",
+            "301 sideways /a /b",
+            "301 exact a /b",
+            "301 exact /%zz /b",
+            "301 pattern /{*rest}/more /b",
+            "301 pattern /{slug} /{typo}",
+        ] {
+            assert!(Redirects::parse(rules).is_err(), "{rules}");
+        }
+        assert_eq!(Redirects::parse("\n301 exact /a /b\nnope\n").unwrap_err(), "line 3: should be `status kind from to`");
+    }
+
+    #[test]
+    fn canonical_urls() {
+        assert_eq!(canonicalize("/"), None);
+        assert_eq!(canonicalize("/about"), None);
+        assert_eq!(canonicalize("/about/").as_deref(), Some("/about"));
+        assert_eq!(canonicalize("//about.html").as_deref(), Some("/about"));
+        assert_eq!(canonicalize("/index.html").as_deref(), Some("/"));
+        assert_eq!(canonicalize("/index/").as_deref(), Some("/"));
+        // never anything starting with // so it cant point at another host
+        assert_eq!(canonicalize("//evil.com/").as_deref(), Some("/evil.com"));
+        // apis only lose the extra slashes
+        assert_eq!(canonicalize("/api//posts/").as_deref(), Some("/api/posts/"));
+        assert_eq!(canonicalize("/api/posts.html"), None);
+    }
+}
@@ -0,0 +1,162 @@
+use std::fs::File;
+use std::io::{self, ErrorKind, Read, Seek, SeekFrom, Write};
+use crate::limits::TimedStream;
+#[cfg(target_os = "linux")]
+use crate::tls::Stream;
+
+// how much gets copied at a time when a body cant be sent straight from the file
+const CHUNK_SIZE: usize = 64 * 1024;
+
+// what gets sent after the header of a response
+// only Bytes is held in memory, the others are read a chunk at a time as they get written
+pub enum ResponseBody {
+    Bytes(Vec<u8>),
+    // len bytes of the file starting at offset
+    File { file: File, offset: u64, len: u64 },
+    // a len of None means the body only ends when the reader does, so the connection has to close after it
+    Reader { reader: Box<dyn Read + Send>, len: Option<u64> },
+}
+
+impl ResponseBody {
+    // the whole file, the length is taken from the open handle so it matches what gets read
+    pub fn file(file: File) -> io::Result<Self> {
+        let len = file.metadata()?.len();
+        Ok(Self::File { file, offset: 0, len })
+    }
+
+    pub fn file_range(file: File, offset: u64, len: u64) -> Self {
+        Self::File { file, offset, len }
+    }
+
+    pub fn reader<R: Read + Send + 'static>(reader: R, len: Option<u64>) -> Self {
+        Self::Reader { reader: Box::new(reader), len }
+    }
+
+    // the Content-Length, None if it isnt known ahead of time
+    pub fn len(&self) -> Option<u64> {
+        match self {
+            Self::Bytes(data) => Some(data.len() as u64),
+            Self::File { len, .. } => Some(*len),
+            Self::Reader { len, .. } => *len,
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == Some(0)
+    }
+
+    // for sending the body a piece at a time, known lengths are cut off at len
+    pub fn into_reader(self) -> io::Result<Box<dyn Read + Send>> {
+        match self {
+            Self::Bytes(data) => Ok(Box::new(io::Cursor::new(data))),
+            Self::File { mut file, offset, len } => {
+                file.seek(SeekFrom::Start(offset))?;
+                Ok(Box::new(file.take(len)))
+            },
+            Self::Reader { reader, len: Some(len) } => Ok(Box::new(reader.take(len))),
+            Self::Reader { reader, len: None } => Ok(reader),
+        }
+    }
+
+    pub fn write_to<W: SendFile>(self, writer: &mut W) -> io::Result<()> {
+        match self {
+            Self::Bytes(data) => writer.write_all(&data),
+            Self::File { mut file, offset, len } => writer.send_file(&mut file, offset, len),
+            Self::Reader { reader, len: Some(len) } => copy_exact(reader.take(len), writer, len),
+            Self::Reader { mut reader, len: None } => copy_chunks(&mut reader, writer).map(|_| ()),
+        }
+    }
+}
+
+impl std::fmt::Debug for ResponseBody {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Bytes(data) => write!(f, "Bytes({} bytes)", data.len()),
+            Self::File { offset, len, .. } => write!(f, "File({} bytes at {})", len, offset),
+            Self::Reader { len, .. } => write!(f, "Reader({:?} bytes)", len),
+        }
+    }
+}
+
+// somewhere a response can be written to
+// send_file is there so a socket can skip copying the file through our memory
+pub trait SendFile: Write {
+    fn send_file(&mut self, file: &mut File, offset: u64, len: u64) -> io::Result<()> {
+        copy_file(file, offset, len, self)
+    }
+}
+
+impl SendFile for Vec<u8> {}
+
+#[cfg(not(target_os = "linux"))]
+impl SendFile for TimedStream {}
+
+// sendfile(2) hands the file straight from the page cache to the socket
+// https has to encrypt everything first so it gets copied like normal
+#[cfg(target_os = "linux")]
+impl SendFile for TimedStream {
+    fn send_file(&mut self, file: &mut File, offset: u64, len: u64) -> io::Result<()> {
+        use std::os::unix::io::AsRawFd;
+
+        let socket = match self.get_ref() {
+            Stream::Plain(stream) => stream.as_raw_fd(),
+            Stream::Tls(_) => return copy_file(file, offset, len, self),
+        };
+        // sendfile goes around write so the timeout has to be put on the socket here,
+        // a client that stops reading makes it fail with EAGAIN once it runs out
+        self.apply_write_timeout()?;
+        let mut sent = 0;
+        while sent < len {
+            let mut position = (offset + sent) as libc::off_t;
+            // the kernel caps a single call at a bit under 2GB anyway
+            let count = (len - sent).min(0x7fff_f000) as usize;
+            let result = unsafe { libc::sendfile(socket, file.as_raw_fd(), &mut position, count) };
+
+            match result {
+                // the file got shorter since we looked at it
+                0 => return Err(io::Error::from(ErrorKind::UnexpectedEof)),
+                written if written > 0 => sent += written as u64,
+                _ => {
+                    let error = io::Error::last_os_error();
+                    match error.raw_os_error() {
+                        Some(libc::EINTR) => continue,
+                        Some(libc::EAGAIN) => return Err(io::Error::from(ErrorKind::TimedOut)),
+                        // some filesystems cant do it, nothing has been sent yet so just copy it like normal
+                        Some(libc::EINVAL) | Some(libc::ENOSYS) if sent == 0 => return copy_file(file, offset, len, self),
+                        _ => return Err(error),
+                    }
+                },
+            }
+        }
+        Ok(())
+    }
+}
+
+fn copy_file<W: Write + ?Sized>(file: &mut File, offset: u64, len: u64, writer: &mut W) -> io::Result<()> {
+    file.seek(SeekFrom::Start(offset))?;
+    copy_exact(file.take(len), writer, len)
+}
+
+// copies everything and fails if there was less than promised in the Content-Length
+fn copy_exact<R: Read, W: Write + ?Sized>(mut reader: R, writer: &mut W, len: u64) -> io::Result<()> {
+    if copy_chunks(&mut reader, writer)? != len {
+        return Err(io::Error::from(ErrorKind::UnexpectedEof));
+    }
+    Ok(())
+}
+
+fn copy_chunks<R: Read + ?Sized, W: Write + ?Sized>(reader: &mut R, writer: &mut W) -> io::Result<u64> {
+    let mut buf = vec![0; CHUNK_SIZE];
+    let mut copied = 0;
+    loop {
+        let read = match reader.read(&mut buf) {
+            Ok(0) => return Ok(copied),
+            Ok(read) => read,
+            Err(e) if e.kind() == ErrorKind::Interrupted => continue,
+            Err(e) => return Err(e),
+        };
+        writer.write_all(&buf[..read])?;
+        writer.flush()?;
+        copied += read as u64;
+    }
+}
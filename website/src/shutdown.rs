@@ -0,0 +1,82 @@
+use std::sync::{Condvar, Mutex};
+use std::time::Duration;
+
+// graceful shutdown, SIGTERM or ctrl-c stops new connections from being taken
+// and gives the ones already going a chance to finish before the process exits
+
+// shared with every thread that has to stop when the server does
+#[derive(Debug, Default)]
+pub struct Shutdown {
+    started: Mutex<bool>,
+    changed: Condvar,
+}
+
+impl Shutdown {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn start(&self) {
+        *self.started.lock().unwrap() = true;
+        self.changed.notify_all();
+    }
+
+    pub fn is_started(&self) -> bool {
+        *self.started.lock().unwrap()
+    }
+
+    // sleeps for the timeout unless the shutdown starts first, returns whether it has
+    // for threads that do something every so often and would otherwise hold the exit up
+    pub fn wait_timeout(&self, timeout: Duration) -> bool {
+        let started = self.started.lock().unwrap();
+        let (started, _) = self.changed.wait_timeout_while(started, timeout, |started| !*started).unwrap();
+        *started
+    }
+}
+
+// blocks SIGTERM and SIGINT so they can be picked up by wait_for_signal instead of killing the process
+// threads get the mask of whoever spawned them so this has to happen before any are started
+#[cfg(target_os = "linux")]
+pub fn block_signals() {
+    let set = signal_set();
+    let result = unsafe { libc::pthread_sigmask(libc::SIG_BLOCK, &set, std::ptr::null_mut()) };
+    assert_eq!(result, 0, "SIGTERM and SIGINT could not be blocked");
+}
+
+// waits for the next SIGTERM or SIGINT and gives back its name
+#[cfg(target_os = "linux")]
+pub fn wait_for_signal() -> &'static str {
+    let set = signal_set();
+    let mut signal = 0;
+    loop {
+        if unsafe { libc::sigwait(&set, &mut signal) } == 0 {
+            break;
+        }
+    }
+    match signal {
+        libc::SIGTERM => "SIGTERM",
+        _ => "SIGINT",
+    }
+}
+
+#[cfg(target_os = "linux")]
+fn signal_set() -> libc::sigset_t {
+    unsafe {
+        let mut set = std::mem::zeroed::<libc::sigset_t>();
+        libc::sigemptyset(&mut set);
+        libc::sigaddset(&mut set, libc::SIGTERM);
+        libc::sigaddset(&mut set, libc::SIGINT);
+        set
+    }
+}
+
+// no libc anywhere else so signals just kill the process like they used to
+#[cfg(not(target_os = "linux"))]
+pub fn block_signals() {}
+
+#[cfg(not(target_os = "linux"))]
+pub fn wait_for_signal() -> &'static str {
+    loop {
+        std::thread::park();
+    }
+}
\ No newline at end of file
@@ -0,0 +1,172 @@
+use std::collections::VecDeque;
+use std::io::Write;
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, SystemTime};
+use crate::tls::Stream;
+use crate::types::turn_system_time_to_http_date;
+
+// Server-Sent Events, https://html.spec.whatwg.org/multipage/server-sent-events.html
+// a subscriber gets the response header and then its connection is handed over to the EventStream,
+// so the worker that served the request is free again and an idle subscriber costs a socket and nothing else
+
+// subscriber sockets are non-blocking so writing to them never waits while the lock is held,
+// one whose send buffer is full cant keep up and is dropped or one slow client would hold up everyone
+// what browsers are told to wait before reconnecting after the connection drops
+const RETRY_MILLIS: u64 = 3000;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Event {
+    pub id: u64,
+    // None is the default "message" event
+    pub name: Option<String>,
+    pub data: String,
+}
+
+impl Event {
+    // the wire format, every line of the data gets its own data field
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut text = format!("id: {}\n", self.id);
+        if let Some(name) = &self.name {
+            text.push_str(&format!("event: {}\n", name));
+        }
+        for line in self.data.lines() {
+            text.push_str(&format!("data: {}\n", line));
+        }
+        if self.data.is_empty() {
+            text.push_str("data\n");
+        }
+        text.push('\n');
+        text.into_bytes()
+    }
+}
+
+#[derive(Debug)]
+struct Inner {
+    next_id: u64,
+    // the last few events so a client that reconnects with Last-Event-ID doesnt miss anything
+    history: VecDeque<Event>,
+    subscribers: Vec<Stream>,
+}
+
+// one feed of events, clone the Arc to publish to it from anywhere in the server
+#[derive(Debug)]
+pub struct EventStream {
+    history_size: usize,
+    max_subscribers: usize,
+    inner: Mutex<Inner>,
+}
+
+impl EventStream {
+    pub fn new(history_size: usize, max_subscribers: usize) -> Self {
+        Self {
+            history_size,
+            max_subscribers,
+            inner: Mutex::new(Inner {
+                next_id: 1,
+                history: VecDeque::with_capacity(history_size),
+                subscribers: Vec::new(),
+            }),
+        }
+    }
+
+    // sends an event to every subscriber and returns its id
+    // subscribers whose connection is gone are dropped along the way
+    pub fn publish(&self, name: Option<&str>, data: &str) -> u64 {
+        let mut inner = self.lock();
+        let event = Event {
+            id: inner.next_id,
+            // a newline in the name would start a new field
+            name: name.map(|name| name.replace(['\r', '\n'], "")),
+            data: data.to_string(),
+        };
+        inner.next_id += 1;
+
+        broadcast(&mut inner.subscribers, &event.to_bytes());
+
+        if inner.history.len() == self.history_size {
+            inner.history.pop_front();
+        }
+        let id = event.id;
+        if self.history_size > 0 {
+            inner.history.push_back(event);
+        }
+        id
+    }
+
+    // takes over a connection that has already been sent the response header
+    // anything published after last_event_id that is still in the history gets sent first
+    pub fn subscribe(&self, mut stream: Stream, last_event_id: Option<u64>) {
+        let mut inner = self.lock();
+        // is_full should have turned it away already, this only happens when two race for the last spot
+        if inner.subscribers.len() >= self.max_subscribers {
+            return;
+        }
+
+        if let Err(e) = stream.tcp().set_nonblocking(true) {
+            println!("Error: {}, occured at: {}", e, turn_system_time_to_http_date(SystemTime::now()));
+            return;
+        }
+
+        let mut first = format!("retry: {}\n\n", RETRY_MILLIS).into_bytes();
+        first.extend(self.replay_locked(&inner, last_event_id));
+        // done while holding the lock so nothing can be published in between and get missed
+        if stream.write_all(&first).and_then(|_| stream.flush()).is_ok() {
+            inner.subscribers.push(stream);
+        }
+    }
+
+    // the events after last_event_id as they would be sent, for clients that cant hold a connection open
+    pub fn replay(&self, last_event_id: Option<u64>) -> Vec<u8> {
+        let inner = self.lock();
+        let mut data = format!("retry: {}\n\n", RETRY_MILLIS).into_bytes();
+        data.extend(self.replay_locked(&inner, last_event_id));
+        data
+    }
+
+    // a comment line keeps proxies from timing the connection out and finds subscribers that left
+    pub fn heartbeat(&self) {
+        let mut inner = self.lock();
+        broadcast(&mut inner.subscribers, b": heartbeat\n\n");
+    }
+
+    pub fn subscriber_count(&self) -> usize {
+        self.lock().subscribers.len()
+    }
+
+    pub fn is_full(&self) -> bool {
+        self.subscriber_count() >= self.max_subscribers
+    }
+
+    // sends a heartbeat every interval forever, meant to get its own thread
+    pub fn run_heartbeat(stream: Arc<Self>, interval: Duration) -> ! {
+        loop {
+            thread::sleep(interval);
+            stream.heartbeat();
+        }
+    }
+
+    fn replay_locked(&self, inner: &Inner, last_event_id: Option<u64>) -> Vec<u8> {
+        let last_event_id = match last_event_id {
+            Some(id) => id,
+            None => return Vec::new(),
+        };
+
+        inner.history.iter()
+            .filter(|event| event.id > last_event_id)
+            .flat_map(Event::to_bytes)
+            .collect()
+    }
+
+    fn lock(&self) -> std::sync::MutexGuard<'_, Inner> {
+        match self.inner.lock() {
+            Ok(inner) => inner,
+            Err(poisoned) => poisoned.into_inner(),
+        }
+    }
+}
+
+// a write that would block fails like any other so that subscriber goes too
+fn broadcast(subscribers: &mut Vec<Stream>, data: &[u8]) {
+    subscribers.retain_mut(|stream| stream.write_all(data).and_then(|_| stream.flush()).is_ok());
+}
@@ -1,63 +1,258 @@
+use std::any::Any;
+use std::fmt;
+use std::panic::{self, AssertUnwindSafe};
 use std::sync::mpsc::{self, Sender, Receiver};
-use std::sync::{Arc, Mutex};
+use std::sync::{Arc, Mutex, MutexGuard, PoisonError, TryLockError};
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
 use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant, SystemTime};
+use crate::types::turn_system_time_to_http_date;
 
 
-#[allow(dead_code)]
 pub struct ThreadPool {
-    workers: Vec<Worker>,
-    sender: Sender<Job>,
+    workers: Mutex<Vec<Worker>>,
+    // taken when the pool shuts down so nothing new can be queued
+    sender: Mutex<Option<Sender<Job>>>,
+    // kept so workers that died can be replaced
+    receiver: Arc<Mutex<Receiver<Job>>>,
+    // how many jobs can be waiting for a worker before execute turns new ones away, None for no limit
+    queue_limit: Option<usize>,
+    counters: Arc<Counters>,
+}
+
+// kept up to date by execute and the workers, read with stats
+#[derive(Debug, Default)]
+struct Counters {
+    queued: AtomicUsize,
+    peak_queued: AtomicUsize,
+    busy: AtomicUsize,
+    completed: AtomicU64,
+    rejected: AtomicU64,
+}
+
+// a snapshot of how loaded the pool is
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct PoolStats {
+    pub workers: usize,
+    // jobs waiting for a worker right now
+    pub queued: usize,
+    pub queue_limit: Option<usize>,
+    // the most that have been waiting at once since the pool started
+    pub peak_queued: usize,
+    // workers running a job right now
+    pub busy: usize,
+    pub completed: u64,
+    // jobs turned away because the queue was full
+    pub rejected: u64,
 }
 
 type Job = Box<dyn FnOnce() + Send + 'static>;
 
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PoolError {
+    // the pool has been shut down so the job was dropped without running
+    ShutDown,
+    // the queue is at its limit so the job was dropped without running
+    Full,
+}
+
+impl fmt::Display for PoolError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            PoolError::ShutDown => write!(f, "Thread pool is shut down"),
+            PoolError::Full => write!(f, "Thread pool queue is full"),
+        }
+    }
+}
+
 impl ThreadPool {
+    // jobs queue up without a limit untill a worker is free
     pub fn new(size: usize) -> Self {
+        Self::build(size, None)
+    }
+
+    // at most queue_limit jobs can be waiting, past that execute gives PoolError::Full
+    pub fn with_queue_limit(size: usize, queue_limit: usize) -> Self {
+        assert!(queue_limit > 0);
+        Self::build(size, Some(queue_limit))
+    }
+
+    fn build(size: usize, queue_limit: Option<usize>) -> Self {
         assert!(size > 0);
 
         let (sender, receiver) = mpsc::channel();
 
         let receiver = Arc::new(Mutex::new(receiver));
+        let counters = Arc::new(Counters::default());
 
         let mut workers = Vec::with_capacity(size);
 
-        (0..size).for_each(|id| workers.push(Worker::new(id, Arc::clone(&receiver))));
+        (0..size).for_each(|id| workers.push(Worker::new(id, Arc::clone(&receiver), Arc::clone(&counters))));
 
         ThreadPool {
-            workers,
-            sender,
+            workers: Mutex::new(workers),
+            sender: Mutex::new(Some(sender)),
+            receiver,
+            queue_limit,
+            counters,
         }
     }
 
-    pub fn execute<F>(&self, function: F)
+    pub fn execute<F>(&self, function: F) -> Result<(), PoolError>
     where
         F: FnOnce() + Send + 'static,
     {
         let job = Box::new(function);
 
-        self.sender.send(job).unwrap();
+        let sender = match lock(&self.sender).clone() {
+            Some(sender) => sender,
+            None => return Err(PoolError::ShutDown),
+        };
+        self.replace_dead_workers();
+
+        // the spot is taken before sending so two threads cant both get the last one
+        let limit = self.queue_limit.unwrap_or(usize::MAX);
+        let counters = &self.counters;
+        let queued = match counters.queued.fetch_update(Ordering::SeqCst, Ordering::SeqCst, |queued| (queued < limit).then_some(queued + 1)) {
+            Ok(queued) => queued + 1,
+            Err(_) => {
+                counters.rejected.fetch_add(1, Ordering::Relaxed);
+                return Err(PoolError::Full);
+            }
+        };
+        counters.peak_queued.fetch_max(queued, Ordering::Relaxed);
+
+        sender.send(job).map_err(|_| {
+            counters.queued.fetch_sub(1, Ordering::SeqCst);
+            PoolError::ShutDown
+        })
+    }
+
+    // whether execute would turn a job away right now
+    pub fn is_full(&self) -> bool {
+        self.queue_limit.is_some_and(|limit| self.counters.queued.load(Ordering::SeqCst) >= limit)
+    }
+
+    pub fn stats(&self) -> PoolStats {
+        let counters = &self.counters;
+        PoolStats {
+            workers: lock(&self.workers).len(),
+            queued: counters.queued.load(Ordering::SeqCst),
+            queue_limit: self.queue_limit,
+            peak_queued: counters.peak_queued.load(Ordering::Relaxed),
+            busy: counters.busy.load(Ordering::Relaxed),
+            completed: counters.completed.load(Ordering::Relaxed),
+            rejected: counters.rejected.load(Ordering::Relaxed),
+        }
+    }
+
+    // jobs that panic are caught so this should only happen if something outside a job does,
+    // either way the pool shouldnt slowly lose all its workers
+    fn replace_dead_workers(&self) {
+        // if they are locked something else is already replacing them or the pool is shutting down
+        let mut workers = match self.workers.try_lock() {
+            Ok(workers) => workers,
+            Err(TryLockError::Poisoned(e)) => e.into_inner(),
+            Err(TryLockError::WouldBlock) => return,
+        };
+        for worker in workers.iter_mut().filter(|worker| worker.thread.is_finished()) {
+            let id = worker.id;
+            let dead = std::mem::replace(worker, Worker::new(id, Arc::clone(&self.receiver), Arc::clone(&self.counters)));
+            dead.join();
+            println!("Worker {id} replaced.");
+        }
+    }
+
+    // stops taking new jobs and waits for the queued and running ones to finish,
+    // returns false if some were still going when the timeout ran out, those workers are left running
+    pub fn shutdown(&self, timeout: Duration) -> bool {
+        // once the sender is gone recv fails after the queue is empty which ends the worker loops
+        drop(lock(&self.sender).take());
+
+        let deadline = Instant::now() + timeout;
+        let mut workers = lock(&self.workers);
+        loop {
+            let (finished, running): (Vec<Worker>, Vec<Worker>) = workers.drain(..).partition(|worker| worker.thread.is_finished());
+            finished.into_iter().for_each(Worker::join);
+            *workers = running;
+
+            if workers.is_empty() {
+                return true;
+            }
+            if Instant::now() >= deadline {
+                println!("Error: {} workers still busy after {:?}, occured at: {}", workers.len(), timeout, turn_system_time_to_http_date(SystemTime::now()));
+                // dropping the handles detaches them so dropping the pool doesnt wait on them either
+                workers.clear();
+                return false;
+            }
+            thread::sleep(Duration::from_millis(10));
+        }
+    }
+}
+
+// dropping the pool waits for every job no matter how long they take, use shutdown for a deadline
+impl Drop for ThreadPool {
+    fn drop(&mut self) {
+        drop(lock(&self.sender).take());
+        lock(&self.workers).drain(..).for_each(Worker::join);
     }
 }
 
-#[allow(dead_code)]
 struct Worker {
     id: usize,
     thread: JoinHandle<()>,
 }
 
 impl Worker {
-    fn new(id: usize, receiver: Arc<Mutex<Receiver<Job>>>) -> Worker {
+    fn new(id: usize, receiver: Arc<Mutex<Receiver<Job>>>, counters: Arc<Counters>) -> Worker {
         let thread = thread::spawn(move || loop {
-            let job = receiver.lock().unwrap().recv().unwrap();
+            // the lock is let go of before the job runs so the others can pick up jobs
+            let message = lock(&receiver).recv();
+
+            match message {
+                Ok(job) => {
+                    println!("Worker {id} got a job; executing.");
+                    counters.queued.fetch_sub(1, Ordering::SeqCst);
+                    counters.busy.fetch_add(1, Ordering::Relaxed);
 
-            println!("Worker {id} got a job; executing.");
+                    // a panicking job only loses that job, the worker carries on with the next one
+                    if let Err(panic) = panic::catch_unwind(AssertUnwindSafe(job)) {
+                        println!("Error: job on worker {} panicked: {}, occured at: {}", id, panic_message(&*panic), turn_system_time_to_http_date(SystemTime::now()));
+                    }
 
-            job();
+                    counters.busy.fetch_sub(1, Ordering::Relaxed);
+                    counters.completed.fetch_add(1, Ordering::Relaxed);
+                },
+                Err(_) => {
+                    println!("Worker {id} shutting down.");
+                    break;
+                }
+            }
         });
 
         Worker {
-            id, 
+            id,
             thread,
         }
     }
+
+    fn join(self) {
+        if self.thread.join().is_err() {
+            println!("Error: worker {} panicked, occured at: {}", self.id, turn_system_time_to_http_date(SystemTime::now()));
+        }
+    }
+}
+
+// the text a panic was started with, panic!("...") gives a &str and panic!("{}", x) a String
+pub fn panic_message(panic: &(dyn Any + Send)) -> &str {
+    match panic.downcast_ref::<&str>() {
+        Some(message) => message,
+        None => panic.downcast_ref::<String>().map(String::as_str).unwrap_or("unknown panic"),
+    }
+}
+
+// a thread that panicked while holding one of these cant have left it half changed
+// (its a queue handle, a list of workers or connections to pick up) so the poison is ignored instead of spreading the panic
+pub(crate) fn lock<T>(mutex: &Mutex<T>) -> MutexGuard<'_, T> {
+    mutex.lock().unwrap_or_else(PoisonError::into_inner)
 }
\ No newline at end of file
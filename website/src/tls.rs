@@ -0,0 +1,246 @@
+use std::collections::HashMap;
+use std::fs;
+use std::io::{self, Read, Write};
+use std::net::TcpStream;
+use std::path::PathBuf;
+use std::str::FromStr;
+use std::sync::{Arc, Mutex, RwLock};
+use std::thread;
+use std::time::{Duration, SystemTime};
+use openssl::nid::Nid;
+use openssl::ssl::{select_next_proto, AlpnError, NameType, SniError, SslAcceptor, SslContext, SslFiletype, SslMethod, SslStream};
+use openssl::x509::X509;
+use crate::types::turn_system_time_to_http_date;
+
+// a connection as the rest of the server sees it, https ones are decrypted as they get read
+#[derive(Debug)]
+pub enum Stream {
+    Plain(TcpStream),
+    Tls(Box<SslStream<TcpStream>>),
+}
+
+impl Stream {
+    // the socket underneath, timeouts are set on this
+    pub fn tcp(&self) -> &TcpStream {
+        match self {
+            Self::Plain(stream) => stream,
+            Self::Tls(stream) => stream.get_ref(),
+        }
+    }
+
+    // whether the client picked HTTP/2 during the handshake
+    pub fn is_http2(&self) -> bool {
+        match self {
+            Self::Plain(_) => false,
+            Self::Tls(stream) => stream.ssl().selected_alpn_protocol() == Some(b"h2"),
+        }
+    }
+}
+
+impl Read for Stream {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        match self {
+            Self::Plain(stream) => stream.read(buf),
+            Self::Tls(stream) => stream.read(buf),
+        }
+    }
+}
+
+impl Write for Stream {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        match self {
+            Self::Plain(stream) => stream.write(buf),
+            Self::Tls(stream) => stream.write(buf),
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        match self {
+            Self::Plain(stream) => stream.flush(),
+            Self::Tls(stream) => stream.flush(),
+        }
+    }
+}
+
+// a PEM certificate chain and the private key that goes with it
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CertPaths {
+    pub cert: PathBuf,
+    pub key: PathBuf,
+}
+
+// cert.pem:key.pem
+impl FromStr for CertPaths {
+    type Err = String;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.trim().split_once(':') {
+            Some((cert, key)) if !cert.is_empty() && !key.is_empty() => Ok(Self {
+                cert: PathBuf::from(cert),
+                key: PathBuf::from(key),
+            }),
+            _ => Err(format!("{} should look like cert.pem:key.pem", s.trim())),
+        }
+    }
+}
+
+// every certificate the https listener can hand out
+// the first one is the default for clients that dont send SNI or ask for a name we dont have,
+// the rest are picked by the names in them
+pub struct CertStore {
+    paths: Vec<CertPaths>,
+    acceptor: RwLock<Arc<SslAcceptor>>,
+    modified: Mutex<Vec<Option<SystemTime>>>,
+}
+
+impl CertStore {
+    pub fn load(paths: Vec<CertPaths>) -> Result<Self, String> {
+        if paths.is_empty() {
+            return Err(String::from("at least one certificate is needed"));
+        }
+
+        let acceptor = build_acceptor(&paths)?;
+        let modified = modified_times(&paths);
+
+        Ok(Self {
+            paths,
+            acceptor: RwLock::new(Arc::new(acceptor)),
+            modified: Mutex::new(modified),
+        })
+    }
+
+    // a comma seperated list like the TLS_CERTS env var
+    pub fn parse(list: &str) -> Result<Self, String> {
+        let paths = list.split(',')
+            .filter(|pair| !pair.trim().is_empty())
+            .map(CertPaths::from_str)
+            .collect::<Result<Vec<CertPaths>, String>>()?;
+
+        Self::load(paths)
+    }
+
+    // does the TLS handshake, the caller should set a timeout on the stream first
+    pub fn accept(&self, stream: TcpStream) -> Result<SslStream<TcpStream>, String> {
+        let acceptor = match self.acceptor.read() {
+            Ok(acceptor) => acceptor.clone(),
+            Err(poisoned) => poisoned.into_inner().clone(),
+        };
+
+        acceptor.accept(stream).map_err(|e| e.to_string())
+    }
+
+    // loads the files again if any of them changed since last time
+    // if the new ones are broken the old certificates are kept so a half written file cant take the site down
+    pub fn reload_if_changed(&self) -> Result<bool, String> {
+        let mut modified = match self.modified.lock() {
+            Ok(modified) => modified,
+            Err(poisoned) => poisoned.into_inner(),
+        };
+
+        let current = modified_times(&self.paths);
+        if current == *modified {
+            return Ok(false);
+        }
+
+        let acceptor = build_acceptor(&self.paths)?;
+        match self.acceptor.write() {
+            Ok(mut old) => *old = Arc::new(acceptor),
+            Err(poisoned) => *poisoned.into_inner() = Arc::new(acceptor),
+        }
+        *modified = current;
+        Ok(true)
+    }
+
+    // checks the files every interval forever, meant to get its own thread
+    pub fn watch(store: Arc<Self>, interval: Duration) -> ! {
+        loop {
+            thread::sleep(interval);
+            match store.reload_if_changed() {
+                Ok(true) => println!("Reloaded TLS certificates at: {}", turn_system_time_to_http_date(SystemTime::now())),
+                Ok(false) => {},
+                Err(e) => println!("Error: {}, occured at: {}", e, turn_system_time_to_http_date(SystemTime::now())),
+            }
+        }
+    }
+}
+
+fn modified_times(paths: &[CertPaths]) -> Vec<Option<SystemTime>> {
+    paths.iter()
+        .flat_map(|pair| [&pair.cert, &pair.key])
+        .map(|path| fs::metadata(path).and_then(|metadata| metadata.modified()).ok())
+        .collect()
+}
+
+// the protocols offered over ALPN in the wire format, h2 first so clients that can do both get it
+const ALPN_PROTOCOLS: &[u8] = b"\x02h2\x08http/1.1";
+
+// the first pair is what gets served by default, the SNI callback swaps in the others by name
+fn build_acceptor(paths: &[CertPaths]) -> Result<SslAcceptor, String> {
+    let mut by_name = HashMap::new();
+    for pair in &paths[1..] {
+        let context = new_builder(pair)?.build().into_context();
+        for name in certificate_names(pair)? {
+            by_name.entry(name).or_insert_with(|| context.clone());
+        }
+    }
+
+    let mut builder = new_builder(&paths[0])?;
+    builder.set_servername_callback(move |ssl, _alert| {
+        let name = match ssl.servername(NameType::HOST_NAME) {
+            Some(name) => name.to_ascii_lowercase(),
+            None => return Ok(()),
+        };
+
+        match lookup_name(&by_name, &name) {
+            Some(context) => ssl.set_ssl_context(context).map_err(|_| SniError::ALERT_FATAL),
+            // a name we dont have still gets the default certificate
+            None => Ok(()),
+        }
+    });
+
+    Ok(builder.build())
+}
+
+fn new_builder(pair: &CertPaths) -> Result<openssl::ssl::SslAcceptorBuilder, String> {
+    let describe = |e: openssl::error::ErrorStack| format!("{}, {}: {}", pair.cert.display(), pair.key.display(), e);
+
+    let mut builder = SslAcceptor::mozilla_intermediate_v5(SslMethod::tls_server()).map_err(describe)?;
+    builder.set_certificate_chain_file(&pair.cert).map_err(describe)?;
+    builder.set_private_key_file(&pair.key, SslFiletype::PEM).map_err(describe)?;
+    builder.check_private_key().map_err(describe)?;
+    // every context needs this since the SNI callback swaps the whole context out
+    builder.set_alpn_select_callback(|_, client| {
+        // clients that dont offer either still get HTTP/1.1
+        select_next_proto(ALPN_PROTOCOLS, client).ok_or(AlpnError::NOACK)
+    });
+    Ok(builder)
+}
+
+// the dns names in the subject alt names, or the common name if there arent any
+fn certificate_names(pair: &CertPaths) -> Result<Vec<String>, String> {
+    let pem = fs::read(&pair.cert).map_err(|e| format!("{}: {}", pair.cert.display(), e))?;
+    let cert = X509::from_pem(&pem).map_err(|e| format!("{}: {}", pair.cert.display(), e))?;
+
+    let mut names = cert.subject_alt_names()
+        .map(|names| names.iter()
+            .filter_map(|name| name.dnsname().map(str::to_ascii_lowercase))
+            .collect::<Vec<String>>())
+        .unwrap_or_default();
+
+    if names.is_empty() {
+        names = cert.subject_name().entries_by_nid(Nid::COMMONNAME)
+            .map(|entry| String::from_utf8_lossy(entry.data().as_slice()).to_ascii_lowercase())
+            .collect();
+    }
+
+    Ok(names)
+}
+
+// exact names win over wildcards, *.example.com only covers one label like foo.example.com
+fn lookup_name<'a>(by_name: &'a HashMap<String, SslContext>, name: &str) -> Option<&'a SslContext> {
+    if let Some(context) = by_name.get(name) {
+        return Some(context);
+    }
+
+    let (_, parent) = name.split_once('.')?;
+    by_name.get(&format!("*.{}", parent))
+}
@@ -0,0 +1,247 @@
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use crate::apis::ApiRegister;
+use crate::error_pages::ErrorPages;
+use crate::redirects::Redirects;
+
+// serving more than one site out of the same process, picked by the Host header
+// a site is a folder laid out like website/ is:
+//    files/      what gets served, index.html is /
+//    errors/     the error page templates
+//    redirects   the redirect table, can be left out
+
+#[derive(Debug)]
+pub struct Site {
+    root: PathBuf,
+    apis: Arc<ApiRegister>,
+    error_pages: ErrorPages,
+    redirects: Redirects,
+}
+
+impl Site {
+    pub fn new(root: &Path, apis: Arc<ApiRegister>, error_pages: ErrorPages, redirects: Redirects) -> Self {
+        Self {
+            root: root.to_path_buf(),
+            apis,
+            error_pages,
+            redirects,
+        }
+    }
+
+    // a site made from a folder with the layout above
+    pub fn from_dir(dir: &Path, apis: ApiRegister) -> Result<Self, String> {
+        let redirects = Redirects::load(&dir.join("redirects"))
+            .map_err(|e| format!("{}: {}", dir.join("redirects").display(), e))?;
+        Ok(Self::new(&dir.join("files"), Arc::new(apis), ErrorPages::new(dir.join("errors")), redirects))
+    }
+
+    // where request paths are looked up
+    pub fn root(&self) -> &Path {
+        &self.root
+    }
+
+    pub fn apis(&self) -> &Arc<ApiRegister> {
+        &self.apis
+    }
+
+    pub fn error_pages(&self) -> &ErrorPages {
+        &self.error_pages
+    }
+
+    pub fn redirects(&self) -> &Redirects {
+        &self.redirects
+    }
+}
+
+// what a request for a host none of the sites are for gets
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum UnknownHost {
+    // served by the default site like the Host header wasnt there
+    Default,
+    // 421 Misdirected Request, the connection reached a server that isnt for that host
+    Misdirected,
+    // sent on to another url with a 308, like the main domain
+    Redirect(String),
+}
+
+#[derive(Debug)]
+pub struct VirtualHosts {
+    sites: HashMap<String, Arc<Site>>,
+    // *.example.com, the longest suffix that matches wins
+    wildcards: Vec<(String, Arc<Site>)>,
+    default: Arc<Site>,
+    unknown: UnknownHost,
+}
+
+// which site a request goes to, or what to do when there isnt one
+#[derive(Debug, Clone)]
+pub enum HostMatch {
+    Site(Arc<Site>),
+    Unknown(UnknownHost),
+}
+
+impl VirtualHosts {
+    // the default site also gets requests without a Host header (HTTP/1.0)
+    pub fn new(default: Site, unknown: UnknownHost) -> Self {
+        Self {
+            sites: HashMap::new(),
+            wildcards: Vec::new(),
+            default: Arc::new(default),
+            unknown,
+        }
+    }
+
+    // hosts are names without the port, "*.example.com" matches any subdomain of it
+    pub fn add(&mut self, hosts: &[&str], site: Site) {
+        let site = Arc::new(site);
+        for host in hosts {
+            let host = normalize_host(host);
+            match host.strip_prefix("*.") {
+                Some(suffix) => self.wildcards.push((format!(".{}", suffix), site.clone())),
+                None => {
+                    self.sites.insert(host, site.clone());
+                },
+            }
+        }
+        self.wildcards.sort_by_key(|(suffix, _)| std::cmp::Reverse(suffix.len()));
+    }
+
+    // names for the default site as well so they arent treated as unknown
+    pub fn add_default_hosts(&mut self, hosts: &[&str]) {
+        for host in hosts {
+            self.sites.insert(normalize_host(host), self.default.clone());
+        }
+    }
+
+    pub fn find(&self, host: &str) -> HostMatch {
+        let host = normalize_host(host);
+        if host.is_empty() {
+            return HostMatch::Site(self.default.clone());
+        }
+
+        if let Some(site) = self.sites.get(&host) {
+            return HostMatch::Site(site.clone());
+        }
+        let wildcard = self.wildcards.iter()
+            .find(|(suffix, _)| host.ends_with(suffix.as_str()));
+        if let Some((_, site)) = wildcard {
+            return HostMatch::Site(site.clone());
+        }
+
+        match &self.unknown {
+            UnknownHost::Default => HostMatch::Site(self.default.clone()),
+            unknown => HostMatch::Unknown(unknown.clone()),
+        }
+    }
+
+    pub fn default_site(&self) -> &Arc<Site> {
+        &self.default
+    }
+
+    // every site once, the default first
+    pub fn sites(&self) -> Vec<Arc<Site>> {
+        let mut sites = vec![self.default.clone()];
+        for site in self.sites.values().chain(self.wildcards.iter().map(|(_, site)| site)) {
+            if !sites.iter().any(|known| Arc::ptr_eq(known, site)) {
+                sites.push(site.clone());
+            }
+        }
+        sites
+    }
+}
+
+// Host is case insensitive and can have a port and a trailing dot, [::1]:8080 keeps its brackets
+fn normalize_host(host: &str) -> String {
+    let host = host.trim().to_ascii_lowercase();
+    let host = if host.starts_with('[') {
+        match host.find(']') {
+            Some(end) => host[..=end].to_string(),
+            None => host,
+        }
+    } else {
+        match host.rsplit_once(':') {
+            Some((name, port)) if port.bytes().all(|c| c.is_ascii_digit()) => name.to_string(),
+            _ => host,
+        }
+    };
+    host.trim_end_matches('.').to_string()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn site(name: &str) -> Site {
+        Site::new(Path::new(name), Arc::new(ApiRegister::new()), ErrorPages::new(name), Redirects::default())
+    }
+
+    fn hosts(unknown: UnknownHost) -> VirtualHosts {
+        let mut hosts = VirtualHosts::new(site("main"), unknown);
+        hosts.add_default_hosts(&["example.com", "www.example.com"]);
+        hosts.add(&["blog.example.com"], site("blog"));
+        hosts.add(&["*.example.org"], site("org"));
+        hosts.add(&["*.docs.example.org", "[::1]"], site("docs"));
+        hosts
+    }
+
+    // the folder of the site the host went to or what happened instead
+    fn served_by(hosts: &VirtualHosts, host: &str) -> Result<String, UnknownHost> {
+        match hosts.find(host) {
+            HostMatch::Site(site) => Ok(site.root().display().to_string()),
+            HostMatch::Unknown(unknown) => Err(unknown),
+        }
+    }
+
+    #[test]
+    fn hosts_pick_their_site() {
+        let hosts = hosts(UnknownHost::Misdirected);
+        assert_eq!(served_by(&hosts, "example.com"), Ok(String::from("main")));
+        assert_eq!(served_by(&hosts, "blog.example.com"), Ok(String::from("blog")));
+        // the port, case and a trailing dot dont matter
+        assert_eq!(served_by(&hosts, "Blog.Example.COM:8080"), Ok(String::from("blog")));
+        assert_eq!(served_by(&hosts, "www.example.com.:443"), Ok(String::from("main")));
+        assert_eq!(served_by(&hosts, "[::1]:8080"), Ok(String::from("docs")));
+    }
+
+    #[test]
+    fn wildcards() {
+        let hosts = hosts(UnknownHost::Misdirected);
+        assert_eq!(served_by(&hosts, "a.example.org"), Ok(String::from("org")));
+        assert_eq!(served_by(&hosts, "a.b.example.org"), Ok(String::from("org")));
+        // the longer suffix wins
+        assert_eq!(served_by(&hosts, "v2.docs.example.org"), Ok(String::from("docs")));
+        // the bare domain isnt a subdomain of itself
+        assert_eq!(served_by(&hosts, "example.org"), Err(UnknownHost::Misdirected));
+        assert_eq!(served_by(&hosts, "badexample.org"), Err(UnknownHost::Misdirected));
+    }
+
+    #[test]
+    fn unknown_hosts() {
+        assert_eq!(served_by(&hosts(UnknownHost::Default), "other.net"), Ok(String::from("main")));
+        assert_eq!(served_by(&hosts(UnknownHost::Misdirected), "other.net"), Err(UnknownHost::Misdirected));
+        let redirect = UnknownHost::Redirect(String::from("https://example.com"));
+        assert_eq!(served_by(&hosts(redirect.clone()), "other.net:80"), Err(redirect));
+        // no Host at all (HTTP/1.0) is always the default site
+        assert_eq!(served_by(&hosts(UnknownHost::Misdirected), ""), Ok(String::from("main")));
+    }
+
+    #[test]
+    fn every_site_once() {
+        let sites = hosts(UnknownHost::Default).sites();
+        let mut roots = sites.iter().map(|site| site.root().display().to_string()).collect::<Vec<String>>();
+        assert_eq!(roots[0], "main");
+        roots.sort();
+        assert_eq!(roots, ["blog", "docs", "main", "org"]);
+    }
+
+    #[test]
+    fn normalizing() {
+        assert_eq!(normalize_host(" Example.COM:8080 "), "example.com");
+        assert_eq!(normalize_host("example.com."), "example.com");
+        assert_eq!(normalize_host("[::1]:443"), "[::1]");
+        assert_eq!(normalize_host("[::1"), "[::1");
+        // not a port so its left alone
+        assert_eq!(normalize_host("example.com:http"), "example.com:http");
+    }
+}
@@ -0,0 +1,791 @@
+use std::io::{self, BufRead, BufReader, ErrorKind, Read, Write};
+use std::sync::mpsc::{self, Receiver, SyncSender, TrySendError};
+use std::time::{Duration, Instant};
+use openssl::base64;
+use openssl::sha::sha1;
+use crate::limits::{Deadline, TimedStream};
+use crate::types::{ContentType, HTTPType, Request, Response};
+
+// WebSocket, RFC 6455
+// the connection gets upgraded from a normal GET and then both sides send messages whenever they want
+// untill one of them sends a close frame
+
+// gets glued onto the client key for the accept header, its the same for every server
+const GUID: &str = "258EAFA5-E914-47DA-95CA-C5AB0DC85B11";
+
+// opcodes
+const CONTINUATION: u8 = 0x0;
+const TEXT: u8 = 0x1;
+const BINARY: u8 = 0x2;
+const CLOSE: u8 = 0x8;
+const PING: u8 = 0x9;
+const PONG: u8 = 0xa;
+
+// how long the client gets to answer our close frame before the connection is just dropped
+const CLOSE_TIMEOUT: Duration = Duration::from_secs(5);
+// how often recv stops waiting on the client to send what a WebSocketSender queued up
+const SEND_CHECK_INTERVAL: Duration = Duration::from_millis(50);
+// messages a WebSocketSender can have waiting before the client has to catch up
+const SEND_QUEUE: usize = 64;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Message {
+    Text(String),
+    Binary(Vec<u8>),
+}
+
+// why a connection was closed, the numbers are in RFC 6455 section 7.4.1
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CloseCode {
+    Normal,
+    GoingAway,
+    ProtocolError,
+    Unsupported,
+    InvalidData,
+    PolicyViolation,
+    TooBig,
+    InternalError,
+    Other(u16),
+}
+
+impl CloseCode {
+    pub fn code(self) -> u16 {
+        match self {
+            Self::Normal => 1000,
+            Self::GoingAway => 1001,
+            Self::ProtocolError => 1002,
+            Self::Unsupported => 1003,
+            Self::InvalidData => 1007,
+            Self::PolicyViolation => 1008,
+            Self::TooBig => 1009,
+            Self::InternalError => 1011,
+            Self::Other(code) => code,
+        }
+    }
+
+    // the codes a client is allowed to send, 1005 and 1006 are only for reporting and never go over the wire
+    fn is_valid(code: u16) -> bool {
+        matches!(code, 1000..=1003 | 1007..=1011 | 3000..=4999)
+    }
+}
+
+impl From<u16> for CloseCode {
+    fn from(code: u16) -> Self {
+        match code {
+            1000 => Self::Normal,
+            1001 => Self::GoingAway,
+            1002 => Self::ProtocolError,
+            1003 => Self::Unsupported,
+            1007 => Self::InvalidData,
+            1008 => Self::PolicyViolation,
+            1009 => Self::TooBig,
+            1011 => Self::InternalError,
+            code => Self::Other(code),
+        }
+    }
+}
+
+#[derive(Debug)]
+pub enum WebSocketError {
+    Io(io::Error),
+    // the client broke the protocol, the close frame with this code has already been sent
+    Protocol(CloseCode),
+    // the client never answered a ping
+    Timeout,
+    // nothing came in while waiting for a frame to start
+    Idle,
+    // a WebSocketSender has more queued up than the client is taking
+    Full,
+    // the connection is closing so nothing more can be sent
+    Closed,
+}
+
+impl std::fmt::Display for WebSocketError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Io(e) => write!(f, "websocket io error: {}", e),
+            Self::Protocol(code) => write!(f, "websocket closed with {}", code.code()),
+            Self::Timeout => write!(f, "websocket client stopped answering pings"),
+            Self::Idle => write!(f, "websocket client sent nothing for the idle timeout"),
+            Self::Full => write!(f, "websocket client isnt keeping up with what is being sent to it"),
+            Self::Closed => write!(f, "websocket is already closed"),
+        }
+    }
+}
+
+impl From<io::Error> for WebSocketError {
+    fn from(error: io::Error) -> Self {
+        Self::Io(error)
+    }
+}
+
+// whether the request is asking to be turned into a websocket
+pub fn is_upgrade(request: &Request) -> bool {
+    request.headers().has_token("Upgrade", "websocket")
+}
+
+// works out the 101 that accepts the upgrade, or the error to send if the handshake is wrong
+pub fn handshake(request: &Request) -> Result<Response, Response> {
+    let headers = request.headers();
+    if request.get_kind() != HTTPType::Get || !is_upgrade(request) || !headers.has_token("Connection", "upgrade") {
+        // everything else about the path only works as a websocket
+        return Err(upgrade_required("This endpoint only speaks WebSocket"));
+    }
+
+    // 13 is the only version there has ever been in a finished RFC
+    if headers.get("Sec-WebSocket-Version").map(str::trim) != Some("13") {
+        return Err(upgrade_required("Unsupported WebSocket version"));
+    }
+
+    let key = match headers.get("Sec-WebSocket-Key").map(str::trim) {
+        // the key has to be 16 random bytes in base64
+        Some(key) if base64::decode_block(key).is_ok_and(|bytes| bytes.len() == 16) => key,
+        _ => return Err(Response::new(400, ContentType::PlainText, None, b"Invalid Sec-WebSocket-Key".to_vec())),
+    };
+
+    let response = Response::new(101, ContentType::PlainText, None, Vec::new())
+        .with_header("Upgrade", "websocket")
+        .with_header("Connection", "Upgrade")
+        .with_header("Sec-WebSocket-Accept", &accept_key(key));
+    Ok(response)
+}
+
+// the Sec-WebSocket-Accept value, proves to the client we actually understood the handshake
+pub fn accept_key(key: &str) -> String {
+    base64::encode_block(&sha1(format!("{}{}", key, GUID).as_bytes()))
+}
+
+fn upgrade_required(message: &str) -> Response {
+    Response::new(426, ContentType::PlainText, None, message.as_bytes().to_vec())
+        .with_header("Upgrade", "websocket")
+        .with_header("Sec-WebSocket-Version", "13")
+}
+
+struct Frame {
+    fin: bool,
+    opcode: u8,
+    payload: Vec<u8>,
+}
+
+// sends on a websocket from anywhere else, like another thread, while the handler is waiting in recv
+// messages go out the next time the socket checks (at most SEND_CHECK_INTERVAL later while recv is waiting),
+// Closed means the connection is gone
+#[derive(Debug, Clone)]
+pub struct WebSocketSender {
+    queue: SyncSender<Message>,
+}
+
+impl WebSocketSender {
+    pub fn send(&self, message: Message) -> Result<(), WebSocketError> {
+        match self.queue.try_send(message) {
+            Ok(()) => Ok(()),
+            Err(TrySendError::Full(_)) => Err(WebSocketError::Full),
+            Err(TrySendError::Disconnected(_)) => Err(WebSocketError::Closed),
+        }
+    }
+
+    pub fn send_text(&self, text: &str) -> Result<(), WebSocketError> {
+        self.send(Message::Text(text.to_owned()))
+    }
+
+    pub fn send_binary(&self, data: &[u8]) -> Result<(), WebSocketError> {
+        self.send(Message::Binary(data.to_vec()))
+    }
+}
+
+// an upgraded connection, the handler uses this to talk to the client
+// pings and pongs are answered in here so the handler only ever sees messages
+pub struct WebSocket<'a> {
+    reader: &'a mut BufReader<TimedStream>,
+    max_message_size: usize,
+    idle_timeout: Duration,
+    // what senders have handed over, kept here so they can be given out after the handler has the socket
+    sender: SyncSender<Message>,
+    queued: Receiver<Message>,
+    // set after we ping a quiet client, if it stays quiet for another idle timeout its gone
+    awaiting_pong: bool,
+    close_sent: bool,
+    close_received: bool,
+}
+
+impl<'a> WebSocket<'a> {
+    // idle_timeout is how long recv waits before checking the client is still there with a ping
+    pub fn new(reader: &'a mut BufReader<TimedStream>, max_message_size: usize, idle_timeout: Duration) -> Self {
+        reader.get_mut().set_idle_timeout(idle_timeout);
+        let (sender, queued) = mpsc::sync_channel(SEND_QUEUE);
+        Self {
+            reader,
+            max_message_size,
+            idle_timeout,
+            sender,
+            queued,
+            awaiting_pong: false,
+            close_sent: false,
+            close_received: false,
+        }
+    }
+
+    // a handle for sending while recv is waiting, handing one to another thread lets the server
+    // push messages without waiting on the client to send something first
+    pub fn sender(&self) -> WebSocketSender {
+        WebSocketSender {
+            queue: self.sender.clone(),
+        }
+    }
+
+    // waits for the next whole message, None means the client closed the connection
+    // anything senders queue up is sent while this waits
+    pub fn recv(&mut self) -> Result<Option<Message>, WebSocketError> {
+        if self.close_received {
+            return Ok(None);
+        }
+
+        // the opcode and data of a message thats been split up into several frames
+        let mut partial: Option<(u8, Vec<u8>)> = None;
+        let mut quiet_since = Instant::now();
+        loop {
+            self.send_queued()?;
+
+            let frame = match self.next_frame() {
+                Ok(frame) => frame,
+                Err(WebSocketError::Idle) => {
+                    if quiet_since.elapsed() < self.idle_timeout {
+                        continue;
+                    }
+                    if self.awaiting_pong {
+                        let _ = self.send_close(CloseCode::GoingAway, "");
+                        return Err(WebSocketError::Timeout);
+                    }
+                    self.ping(b"")?;
+                    self.awaiting_pong = true;
+                    quiet_since = Instant::now();
+                    continue;
+                },
+                Err(e) => return Err(e),
+            };
+            // anything at all coming in shows the client is still there
+            self.awaiting_pong = false;
+            quiet_since = Instant::now();
+
+            match frame.opcode {
+                TEXT | BINARY => {
+                    if partial.is_some() {
+                        return Err(self.fail(CloseCode::ProtocolError));
+                    }
+                    if frame.fin {
+                        return self.finish_message(frame.opcode, frame.payload).map(Some);
+                    }
+                    partial = Some((frame.opcode, frame.payload));
+                },
+                CONTINUATION => {
+                    let (opcode, mut data) = match partial.take() {
+                        Some(partial) => partial,
+                        None => return Err(self.fail(CloseCode::ProtocolError)),
+                    };
+                    if frame.payload.len() > self.max_message_size - data.len() {
+                        return Err(self.fail(CloseCode::TooBig));
+                    }
+                    data.extend_from_slice(&frame.payload);
+                    if frame.fin {
+                        return self.finish_message(opcode, data).map(Some);
+                    }
+                    partial = Some((opcode, data));
+                },
+                CLOSE => {
+                    self.close_received = true;
+                    let code = match self.read_close(&frame.payload) {
+                        Ok(code) => code,
+                        Err(code) => return Err(self.fail(code)),
+                    };
+                    // the close gets sent back with the same code unless we already sent ours
+                    if !self.close_sent {
+                        self.send_close(code, "")?;
+                    }
+                    return Ok(None);
+                },
+                PING => self.write_frame(PONG, &frame.payload)?,
+                PONG => {},
+                _ => return Err(self.fail(CloseCode::ProtocolError)),
+            }
+        }
+    }
+
+    pub fn send(&mut self, message: &Message) -> Result<(), WebSocketError> {
+        match message {
+            Message::Text(text) => self.send_text(text),
+            Message::Binary(data) => self.send_binary(data),
+        }
+    }
+
+    pub fn send_text(&mut self, text: &str) -> Result<(), WebSocketError> {
+        self.send_frame(TEXT, text.as_bytes())
+    }
+
+    pub fn send_binary(&mut self, data: &[u8]) -> Result<(), WebSocketError> {
+        self.send_frame(BINARY, data)
+    }
+
+    pub fn ping(&mut self, data: &[u8]) -> Result<(), WebSocketError> {
+        if data.len() > 125 {
+            return Err(WebSocketError::Io(io::Error::new(ErrorKind::InvalidInput, "ping data can be at most 125 bytes")));
+        }
+        self.send_frame(PING, data)
+    }
+
+    // sends our close frame and waits a little for the client to send theirs back
+    // closing a socket thats already closed does nothing
+    pub fn close(&mut self, code: CloseCode, reason: &str) -> Result<(), WebSocketError> {
+        if !self.close_sent {
+            // whatever senders queued up still goes before the close
+            self.send_queued()?;
+            self.send_close(code, reason)?;
+        }
+        if self.close_received {
+            return Ok(());
+        }
+
+        self.reader.set_deadline(Some(Instant::now() + CLOSE_TIMEOUT));
+        // whatever the client was still sending doesnt matter anymore
+        while !self.close_received {
+            let frame = self.next_frame()?;
+            self.close_received = frame.opcode == CLOSE;
+        }
+        self.reader.set_deadline(None);
+        Ok(())
+    }
+
+    pub fn is_closed(&self) -> bool {
+        self.close_sent || self.close_received
+    }
+
+    fn send_queued(&mut self) -> Result<(), WebSocketError> {
+        while let Ok(message) = self.queued.try_recv() {
+            self.send(&message)?;
+        }
+        Ok(())
+    }
+
+    fn finish_message(&mut self, opcode: u8, data: Vec<u8>) -> Result<Message, WebSocketError> {
+        if opcode == BINARY {
+            return Ok(Message::Binary(data));
+        }
+        match String::from_utf8(data) {
+            Ok(text) => Ok(Message::Text(text)),
+            Err(_) => Err(self.fail(CloseCode::InvalidData)),
+        }
+    }
+
+    // the code and reason out of a close frame, an empty one counts as a normal close
+    fn read_close(&self, payload: &[u8]) -> Result<CloseCode, CloseCode> {
+        match payload {
+            [] => Ok(CloseCode::Normal),
+            [_] => Err(CloseCode::ProtocolError),
+            [high, low, reason @ ..] => {
+                let code = u16::from_be_bytes([*high, *low]);
+                if !CloseCode::is_valid(code) {
+                    return Err(CloseCode::ProtocolError);
+                }
+                if std::str::from_utf8(reason).is_err() {
+                    return Err(CloseCode::InvalidData);
+                }
+                Ok(CloseCode::from(code))
+            },
+        }
+    }
+
+    // closes the connection because of something the client did and hands back the error for it
+    fn fail(&mut self, code: CloseCode) -> WebSocketError {
+        if !self.close_sent {
+            // the connection is getting dropped anyway so a failed write doesnt change anything
+            let _ = self.send_close(code, "");
+        }
+        self.close_received = true;
+        WebSocketError::Protocol(code)
+    }
+
+    fn next_frame(&mut self) -> Result<Frame, WebSocketError> {
+        // waiting on the first byte is the only place the idle timeout should come from,
+        // a frame that stops half way through is just a broken connection
+        // the wait is cut into short pieces so recv can send what senders queued up in between
+        self.reader.get_mut().set_idle_timeout(SEND_CHECK_INTERVAL.min(self.idle_timeout));
+        let waited = self.reader.fill_buf().map(|buf| buf.is_empty());
+        self.reader.get_mut().set_idle_timeout(self.idle_timeout);
+        match waited {
+            Ok(true) => return Err(WebSocketError::Io(io::Error::from(ErrorKind::UnexpectedEof))),
+            Ok(false) => {},
+            Err(e) if matches!(e.kind(), ErrorKind::WouldBlock | ErrorKind::TimedOut) => return Err(WebSocketError::Idle),
+            Err(e) => return Err(e.into()),
+        }
+
+        let mut head = [0; 2];
+        self.read_exact(&mut head)?;
+        let fin = head[0] & 0x80 != 0;
+        let reserved = head[0] & 0x70;
+        let opcode = head[0] & 0x0f;
+        let masked = head[1] & 0x80 != 0;
+
+        // no extensions are agreed to so the reserved bits have to be 0, and clients always mask
+        if reserved != 0 || !masked {
+            return Err(self.fail(CloseCode::ProtocolError));
+        }
+
+        let len = match head[1] & 0x7f {
+            126 => {
+                let mut len = [0; 2];
+                self.read_exact(&mut len)?;
+                u16::from_be_bytes(len) as u64
+            },
+            127 => {
+                let mut len = [0; 8];
+                self.read_exact(&mut len)?;
+                u64::from_be_bytes(len)
+            },
+            len => len as u64,
+        };
+
+        // control frames cant be split up and have to fit in the short length
+        if opcode & 0x8 != 0 && (!fin || len > 125) {
+            return Err(self.fail(CloseCode::ProtocolError));
+        }
+        if len > self.max_message_size as u64 {
+            return Err(self.fail(CloseCode::TooBig));
+        }
+
+        let mut mask = [0; 4];
+        self.read_exact(&mut mask)?;
+        let mut payload = vec![0; len as usize];
+        self.read_exact(&mut payload)?;
+        for (i, byte) in payload.iter_mut().enumerate() {
+            *byte ^= mask[i % 4];
+        }
+
+        Ok(Frame { fin, opcode, payload })
+    }
+
+    // the rest of a frame thats already started, the bytes read so far are gone so a timeout here
+    // cant be waited out with a ping like one between frames, the connection is out of step and has to close
+    fn read_exact(&mut self, buf: &mut [u8]) -> Result<(), WebSocketError> {
+        match self.reader.read_exact(buf) {
+            Ok(()) => Ok(()),
+            Err(e) if matches!(e.kind(), ErrorKind::WouldBlock | ErrorKind::TimedOut) => Err(self.fail(CloseCode::ProtocolError)),
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    fn send_close(&mut self, code: CloseCode, reason: &str) -> Result<(), WebSocketError> {
+        let mut payload = code.code().to_be_bytes().to_vec();
+        // a close frame is a control frame so the reason has to fit in what the code leaves
+        let mut end = reason.len().min(123);
+        while !reason.is_char_boundary(end) {
+            end -= 1;
+        }
+        payload.extend_from_slice(&reason.as_bytes()[..end]);
+
+        self.write_frame(CLOSE, &payload)?;
+        self.close_sent = true;
+        Ok(())
+    }
+
+    fn send_frame(&mut self, opcode: u8, payload: &[u8]) -> Result<(), WebSocketError> {
+        if self.close_sent {
+            return Err(WebSocketError::Closed);
+        }
+        self.write_frame(opcode, payload)?;
+        Ok(())
+    }
+
+    // everything we send is one unmasked frame, servers are never allowed to mask
+    fn write_frame(&mut self, opcode: u8, payload: &[u8]) -> io::Result<()> {
+        let mut frame = Vec::with_capacity(payload.len() + 10);
+        frame.push(0x80 | opcode);
+        match payload.len() {
+            len if len < 126 => frame.push(len as u8),
+            len if len <= u16::MAX as usize => {
+                frame.push(126);
+                frame.extend_from_slice(&(len as u16).to_be_bytes());
+            },
+            len => {
+                frame.push(127);
+                frame.extend_from_slice(&(len as u64).to_be_bytes());
+            },
+        }
+        frame.extend_from_slice(payload);
+        self.reader.get_mut().write_all(&frame)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::net::{IpAddr, Ipv4Addr, TcpListener, TcpStream};
+    use std::thread;
+    use crate::client_ip::TrustedProxies;
+    use crate::limits::RequestLimits;
+    use crate::tls::Stream;
+
+    const MAX_MESSAGE: usize = 100_000;
+
+    // a real connection as TimedStream only wraps sockets, the client end is handed back to play the client
+    fn connect() -> (BufReader<TimedStream>, TcpStream) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let client = TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+        client.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+        let (server, _) = listener.accept().unwrap();
+        let stream = TimedStream::new(Stream::Plain(server), Duration::from_secs(5), Duration::from_secs(5));
+        (BufReader::new(stream), client)
+    }
+
+    // what a client sends, always masked
+    fn client_frame(fin: bool, opcode: u8, payload: &[u8]) -> Vec<u8> {
+        let mask = [0x37, 0xfa, 0x21, 0x3d];
+        let mut frame = vec![if fin { 0x80 | opcode } else { opcode }];
+        match payload.len() {
+            len if len < 126 => frame.push(0x80 | len as u8),
+            len if len <= u16::MAX as usize => {
+                frame.push(0x80 | 126);
+                frame.extend_from_slice(&(len as u16).to_be_bytes());
+            },
+            len => {
+                frame.push(0x80 | 127);
+                frame.extend_from_slice(&(len as u64).to_be_bytes());
+            },
+        }
+        frame.extend_from_slice(&mask);
+        frame.extend(payload.iter().enumerate().map(|(i, byte)| byte ^ mask[i % 4]));
+        frame
+    }
+
+    // the next frame the server sent as (first byte, payload)
+    fn server_frame(client: &mut TcpStream) -> (u8, Vec<u8>) {
+        let mut head = [0; 2];
+        client.read_exact(&mut head).unwrap();
+        // servers never mask
+        assert_eq!(head[1] & 0x80, 0);
+        let len = match head[1] {
+            126 => {
+                let mut len = [0; 2];
+                client.read_exact(&mut len).unwrap();
+                u16::from_be_bytes(len) as usize
+            },
+            127 => {
+                let mut len = [0; 8];
+                client.read_exact(&mut len).unwrap();
+                u64::from_be_bytes(len) as usize
+            },
+            len => len as usize,
+        };
+        let mut payload = vec![0; len];
+        client.read_exact(&mut payload).unwrap();
+        (head[0], payload)
+    }
+
+    fn close_code(payload: &[u8]) -> u16 {
+        u16::from_be_bytes([payload[0], payload[1]])
+    }
+
+    // sends the frames and gives back what recv made of them
+    fn receive(frames: &[Vec<u8>]) -> (Result<Option<Message>, WebSocketError>, TcpStream) {
+        let (mut reader, mut client) = connect();
+        client.write_all(&frames.concat()).unwrap();
+        let mut socket = WebSocket::new(&mut reader, MAX_MESSAGE, Duration::from_secs(5));
+        (socket.recv(), client)
+    }
+
+    // the client should have been sent a close frame with this code
+    fn assert_failed(frames: &[Vec<u8>], code: CloseCode) {
+        let (result, mut client) = receive(frames);
+        assert!(matches!(result, Err(WebSocketError::Protocol(c)) if c == code), "{:?}", result);
+        let (head, payload) = server_frame(&mut client);
+        assert_eq!(head, 0x80 | CLOSE);
+        assert_eq!(close_code(&payload), code.code());
+    }
+
+    #[test]
+    fn accept_key_from_the_rfc() {
+        assert_eq!(accept_key("dGhlIHNhbXBsZSBub25jZQ=="), "s3pPLMBiTxaQ9kYGzzhZRbK+xOo=");
+    }
+
+    #[test]
+    fn handshake_checks_the_request() {
+        let request = |extra: &str| {
+            let (mut reader, mut client) = connect();
+            client.write_all(format!("GET /api/echo HTTP/1.1\r\nHost: localhost\r\n{}\r\n", extra).as_bytes()).unwrap();
+            Request::new(&mut reader, IpAddr::V4(Ipv4Addr::LOCALHOST), &TrustedProxies::loopback(), &RequestLimits::default()).unwrap()
+        };
+
+        let good = "Upgrade: websocket\r\nConnection: keep-alive, Upgrade\r\nSec-WebSocket-Version: 13\r\nSec-WebSocket-Key: dGhlIHNhbXBsZSBub25jZQ==\r\n";
+        let response = handshake(&request(good)).unwrap();
+        assert_eq!(response.get_code(), 101);
+        assert_eq!(response.headers().get("Sec-WebSocket-Accept"), Some("s3pPLMBiTxaQ9kYGzzhZRbK+xOo="));
+
+        let no_upgrade = handshake(&request("")).unwrap_err();
+        assert_eq!(no_upgrade.get_code(), 426);
+        let old_version = handshake(&request(&good.replace("Version: 13", "Version: 8"))).unwrap_err();
+        assert_eq!(old_version.get_code(), 426);
+        let short_key = handshake(&request(&good.replace("dGhlIHNhbXBsZSBub25jZQ==", "c2hvcnQ="))).unwrap_err();
+        assert_eq!(short_key.get_code(), 400);
+    }
+
+    #[test]
+    fn masked_messages_are_read() {
+        let (result, _) = receive(&[client_frame(true, TEXT, "hello ☃".as_bytes())]);
+        assert_eq!(result.unwrap(), Some(Message::Text(String::from("hello ☃"))));
+
+        // the 16 and 64 bit lengths
+        let medium = vec![9; 300];
+        let (result, _) = receive(&[client_frame(true, BINARY, &medium)]);
+        assert_eq!(result.unwrap(), Some(Message::Binary(medium)));
+        let big = (0..70_000).map(|i| i as u8).collect::<Vec<u8>>();
+        let (result, _) = receive(&[client_frame(true, BINARY, &big)]);
+        assert_eq!(result.unwrap(), Some(Message::Binary(big)));
+    }
+
+    #[test]
+    fn fragments_with_a_ping_in_between() {
+        let (result, mut client) = receive(&[
+            client_frame(false, TEXT, b"split "),
+            client_frame(true, PING, b"still there?"),
+            client_frame(false, CONTINUATION, b"up "),
+            client_frame(true, CONTINUATION, b"message"),
+        ]);
+        assert_eq!(result.unwrap(), Some(Message::Text(String::from("split up message"))));
+        assert_eq!(server_frame(&mut client), (0x80 | PONG, b"still there?".to_vec()));
+    }
+
+    #[test]
+    fn quiet_between_frames_gets_a_ping() {
+        let (mut reader, mut client) = connect();
+        let mut socket = WebSocket::new(&mut reader, MAX_MESSAGE, Duration::from_millis(200));
+        let writer = thread::spawn(move || {
+            thread::sleep(Duration::from_millis(300));
+            client.write_all(&client_frame(true, TEXT, b"late")).unwrap();
+            client
+        });
+
+        assert_eq!(socket.recv().unwrap(), Some(Message::Text(String::from("late"))));
+        let mut client = writer.join().unwrap();
+        assert_eq!(server_frame(&mut client), (0x80 | PING, Vec::new()));
+    }
+
+    #[test]
+    fn stalling_inside_a_frame_closes() {
+        let (mut reader, mut client) = connect();
+        let mut socket = WebSocket::new(&mut reader, MAX_MESSAGE, Duration::from_millis(200));
+        // the header and mask go out but the payload is held back past the idle timeout
+        let frame = client_frame(true, TEXT, b"split by a pause");
+        client.write_all(&frame[..6]).unwrap();
+        let writer = thread::spawn(move || {
+            thread::sleep(Duration::from_millis(400));
+            // the server may have closed by now
+            let _ = client.write_all(&frame[6..]);
+            client
+        });
+
+        assert!(matches!(socket.recv(), Err(WebSocketError::Protocol(CloseCode::ProtocolError))));
+        // no ping was sent and the payload never gets read as the start of a frame
+        let mut client = writer.join().unwrap();
+        let (head, payload) = server_frame(&mut client);
+        assert_eq!(head, 0x80 | CLOSE);
+        assert_eq!(close_code(&payload), 1002);
+        assert_eq!(socket.recv().unwrap(), None);
+    }
+
+    #[test]
+    fn sender_pushes_while_recv_waits() {
+        let (mut reader, mut client) = connect();
+        let mut socket = WebSocket::new(&mut reader, MAX_MESSAGE, Duration::from_secs(5));
+        let sender = socket.sender();
+        let other = thread::spawn(move || {
+            sender.send_text("from the server").unwrap();
+            // the client only answers once it has the pushed message
+            assert_eq!(server_frame(&mut client), (0x80 | TEXT, b"from the server".to_vec()));
+            client.write_all(&client_frame(true, TEXT, b"got it")).unwrap();
+            client
+        });
+
+        assert_eq!(socket.recv().unwrap(), Some(Message::Text(String::from("got it"))));
+        other.join().unwrap();
+    }
+
+    #[test]
+    fn sender_after_the_socket_is_gone() {
+        let (mut reader, _client) = connect();
+        let socket = WebSocket::new(&mut reader, MAX_MESSAGE, Duration::from_secs(5));
+        let sender = socket.sender();
+
+        // nothing is reading the queue so it fills up
+        for _ in 0..SEND_QUEUE {
+            sender.send_binary(b"queued").unwrap();
+        }
+        assert!(matches!(sender.send_binary(b"one more"), Err(WebSocketError::Full)));
+
+        drop(socket);
+        assert!(matches!(sender.send_text("too late"), Err(WebSocketError::Closed)));
+    }
+
+    #[test]
+    fn close_is_sent_back() {
+        let mut payload = 1001u16.to_be_bytes().to_vec();
+        payload.extend_from_slice(b"bye");
+        let (result, mut client) = receive(&[client_frame(true, CLOSE, &payload)]);
+        assert_eq!(result.unwrap(), None);
+        let (head, payload) = server_frame(&mut client);
+        assert_eq!(head, 0x80 | CLOSE);
+        assert_eq!(close_code(&payload), 1001);
+
+        // an empty close is a normal one
+        let (result, mut client) = receive(&[client_frame(true, CLOSE, b"")]);
+        assert_eq!(result.unwrap(), None);
+        assert_eq!(close_code(&server_frame(&mut client).1), 1000);
+    }
+
+    #[test]
+    fn broken_frames() {
+        // not masked
+        assert_failed(&[vec![0x81, 0x02, b'h', b'i']], CloseCode::ProtocolError);
+        // a reserved bit set and an opcode that doesnt exist
+        assert_failed(&[{ let mut frame = client_frame(true, TEXT, b"hi"); frame[0] |= 0x40; frame }], CloseCode::ProtocolError);
+        assert_failed(&[client_frame(true, 0x3, b"hi")], CloseCode::ProtocolError);
+        // control frames that are split up or too long
+        assert_failed(&[client_frame(false, PING, b"hi")], CloseCode::ProtocolError);
+        assert_failed(&[client_frame(true, PING, &[0; 126])], CloseCode::ProtocolError);
+        // a continuation with nothing to continue and a new message before the last one finished
+        assert_failed(&[client_frame(true, CONTINUATION, b"hi")], CloseCode::ProtocolError);
+        assert_failed(&[client_frame(false, TEXT, b"hi"), client_frame(true, TEXT, b"there")], CloseCode::ProtocolError);
+    }
+
+    #[test]
+    fn bad_messages() {
+        assert_failed(&[client_frame(true, TEXT, &[0xff, 0xfe])], CloseCode::InvalidData);
+        // a frame thats too big on its own and fragments that only add up to too much
+        assert_failed(&[client_frame(true, BINARY, &vec![0; MAX_MESSAGE + 1])], CloseCode::TooBig);
+        let half = vec![0; MAX_MESSAGE / 2 + 1];
+        assert_failed(&[client_frame(false, BINARY, &half), client_frame(true, CONTINUATION, &half)], CloseCode::TooBig);
+        // close codes that cant be sent and reasons that arent utf-8
+        assert_failed(&[client_frame(true, CLOSE, &1005u16.to_be_bytes())], CloseCode::ProtocolError);
+        assert_failed(&[client_frame(true, CLOSE, &[0x03])], CloseCode::ProtocolError);
+        assert_failed(&[client_frame(true, CLOSE, &[0x03, 0xe8, 0xff])], CloseCode::InvalidData);
+    }
+
+    #[test]
+    fn sent_frames() {
+        let (mut reader, mut client) = connect();
+        let mut socket = WebSocket::new(&mut reader, MAX_MESSAGE, Duration::from_secs(5));
+
+        socket.send_text("hi").unwrap();
+        assert_eq!(server_frame(&mut client), (0x80 | TEXT, b"hi".to_vec()));
+        let big = vec![3; 70_000];
+        socket.send_binary(&big).unwrap();
+        assert_eq!(server_frame(&mut client), (0x80 | BINARY, big));
+        assert!(socket.ping(&[0; 126]).is_err());
+
+        // nothing goes out after our close
+        client.write_all(&client_frame(true, CLOSE, &1000u16.to_be_bytes())).unwrap();
+        socket.close(CloseCode::Normal, "done").unwrap();
+        let (head, payload) = server_frame(&mut client);
+        assert_eq!(head, 0x80 | CLOSE);
+        assert_eq!(&payload[2..], b"done");
+        assert!(socket.is_closed());
+        assert!(matches!(socket.send_text("too late"), Err(WebSocketError::Closed)));
+    }
+}
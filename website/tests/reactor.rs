@@ -0,0 +1,117 @@
+#![cfg(target_os = "linux")]
+
+use std::io::{Read, Write};
+use std::net::{TcpListener, TcpStream};
+use std::path::Path;
+use std::process::{Child, Command, Stdio};
+use std::thread;
+use std::time::{Duration, Instant};
+
+// kills the server when the test ends even if an assert fails
+struct Server {
+    child: Child,
+}
+
+impl Drop for Server {
+    fn drop(&mut self) {
+        let _ = self.child.kill();
+        let _ = self.child.wait();
+    }
+}
+
+fn start_server() -> (Server, u16) {
+    let port = TcpListener::bind("127.0.0.1:0").unwrap().local_addr().unwrap().port();
+    // the server looks for website/files from the workspace root
+    let root = Path::new(env!("CARGO_MANIFEST_DIR")).parent().unwrap();
+    let child = Command::new(env!("CARGO_BIN_EXE_website"))
+        .current_dir(root)
+        .env("PORT", port.to_string())
+        .env("HEADER_TIMEOUT", "1")
+        .env("BODY_TIMEOUT", "1")
+        .stdout(Stdio::null())
+        .spawn()
+        .unwrap();
+    let server = Server { child };
+
+    let started = Instant::now();
+    while TcpStream::connect(("127.0.0.1", port)).is_err() {
+        assert!(started.elapsed() < Duration::from_secs(10), "server never started listening");
+        thread::sleep(Duration::from_millis(50));
+    }
+
+    (server, port)
+}
+
+fn read_all(stream: &mut TcpStream) -> String {
+    stream.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+    let mut response = Vec::new();
+    let _ = stream.read_to_end(&mut response);
+    String::from_utf8_lossy(&response).into_owned()
+}
+
+#[test]
+fn idle_and_slow_connections_dont_hold_workers() {
+    let (_server, port) = start_server();
+
+    // far more than there are workers, most of them part way through a request, some of those
+    // with blank lines first that mustnt be taken for the end of the header and some part way through a chunked body
+    let mut waiting = Vec::new();
+    for i in 0..64 {
+        let mut stream = TcpStream::connect(("127.0.0.1", port)).unwrap();
+        match i % 4 {
+            0 => stream.write_all(b"GET / HTTP/1.1\r\nHo").unwrap(),
+            2 => stream.write_all(b"\r\n\r\nGET / HTTP/1.1\r\n").unwrap(),
+            3 => stream.write_all(b"POST / HTTP/1.1\r\nHost: localhost\r\nTransfer-Encoding: chunked\r\n\r\n5\r\nhello\r\n").unwrap(),
+            _ => {},
+        }
+        waiting.push(stream);
+    }
+
+    let started = Instant::now();
+    let mut stream = TcpStream::connect(("127.0.0.1", port)).unwrap();
+    stream.write_all(b"GET / HTTP/1.1\r\nHost: localhost\r\nConnection: close\r\n\r\n").unwrap();
+    let response = read_all(&mut stream);
+    assert!(response.starts_with("HTTP/1.1 200 OK\r\n"), "{response}");
+    assert!(started.elapsed() < Duration::from_secs(1));
+
+    // the ones that never finished their request get a 408 once the header or body timeout runs out
+    for i in [0, 2, 3] {
+        let response = read_all(&mut waiting[i]);
+        assert!(response.starts_with("HTTP/1.1 408 Request Timeout\r\n"), "{response}");
+    }
+}
+
+#[test]
+fn pipelined_requests_are_answered_in_order() {
+    let (_server, port) = start_server();
+
+    let mut stream = TcpStream::connect(("127.0.0.1", port)).unwrap();
+    stream.write_all(concat!(
+        "GET /css/index.css HTTP/1.1\r\nHost: localhost\r\n\r\n",
+        "GET /missing HTTP/1.1\r\nHost: localhost\r\n\r\n",
+        "HEAD / HTTP/1.1\r\nHost: localhost\r\nConnection: close\r\n\r\n",
+    ).as_bytes()).unwrap();
+    let response = read_all(&mut stream);
+
+    let statuses = response.match_indices("HTTP/1.1 ")
+        .map(|(i, _)| &response[i + 9..i + 12])
+        .collect::<Vec<&str>>();
+    assert_eq!(statuses, ["200", "404", "200"], "{response}");
+}
+#[test]
+fn error_responses_reach_clients_still_sending() {
+    let (_server, port) = start_server();
+
+    // too big to take so its turned down with a 413 while the body is still coming in
+    let mut stream = TcpStream::connect(("127.0.0.1", port)).unwrap();
+    stream.write_all(b"POST /api/echo HTTP/1.1\r\nHost: localhost\r\nContent-Length: 100000000\r\n\r\n").unwrap();
+    let chunk = vec![b'a'; 64 * 1024];
+    for _ in 0..16 {
+        // closing on unread input would reset the connection and these would start failing
+        stream.write_all(&chunk).unwrap();
+        thread::sleep(Duration::from_millis(20));
+    }
+
+    let response = read_all(&mut stream);
+    assert!(response.starts_with("HTTP/1.1 413 Content Too Large\r\n"), "{response}");
+}
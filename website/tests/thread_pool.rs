@@ -0,0 +1,85 @@
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{mpsc, Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+use website::thread::{PoolError, ThreadPool};
+
+#[test]
+fn shutdown_finishes_queued_jobs() {
+    let pool = ThreadPool::new(2);
+    let done = Arc::new(AtomicUsize::new(0));
+
+    // more jobs than workers so some are still queued when the shutdown starts
+    for _ in 0..6 {
+        let done = done.clone();
+        pool.execute(move || {
+            thread::sleep(Duration::from_millis(50));
+            done.fetch_add(1, Ordering::SeqCst);
+        }).unwrap();
+    }
+
+    assert!(pool.shutdown(Duration::from_secs(5)));
+    assert_eq!(done.load(Ordering::SeqCst), 6);
+
+    // nothing gets in after the shutdown
+    assert_eq!(pool.execute(|| {}), Err(PoolError::ShutDown));
+}
+
+#[test]
+fn shutdown_gives_up_at_the_deadline() {
+    let pool = ThreadPool::new(1);
+    pool.execute(|| thread::sleep(Duration::from_millis(500))).unwrap();
+    // give the worker time to pick it up
+    thread::sleep(Duration::from_millis(50));
+
+    let start = Instant::now();
+    assert!(!pool.shutdown(Duration::from_millis(100)));
+    assert!(start.elapsed() < Duration::from_secs(1));
+}
+
+#[test]
+fn panicking_jobs_dont_take_workers_down() {
+    let pool = ThreadPool::new(1);
+    let done = Arc::new(AtomicUsize::new(0));
+
+    // the only worker has to survive every one of these for the last job to run
+    for _ in 0..3 {
+        pool.execute(|| panic!("job failed")).unwrap();
+    }
+    let counter = done.clone();
+    pool.execute(move || {
+        counter.fetch_add(1, Ordering::SeqCst);
+    }).unwrap();
+
+    assert!(pool.shutdown(Duration::from_secs(5)));
+    assert_eq!(done.load(Ordering::SeqCst), 1);
+}
+
+
+#[test]
+fn full_queue_turns_jobs_away() {
+    let pool = ThreadPool::with_queue_limit(1, 2);
+    let (release, wait) = mpsc::channel::<()>();
+    let wait = Arc::new(Mutex::new(wait));
+
+    // one job holds the only worker and two more fill the queue
+    for _ in 0..3 {
+        let wait = wait.clone();
+        pool.execute(move || {
+            let _ = wait.lock().unwrap().recv();
+        }).unwrap();
+        thread::sleep(Duration::from_millis(20));
+    }
+
+    assert!(pool.is_full());
+    assert_eq!(pool.execute(|| {}), Err(PoolError::Full));
+
+    let stats = pool.stats();
+    assert_eq!((stats.busy, stats.queued, stats.peak_queued, stats.rejected), (1, 2, 2, 1));
+
+    for _ in 0..3 {
+        release.send(()).unwrap();
+    }
+    assert!(pool.shutdown(Duration::from_secs(5)));
+    assert_eq!(pool.stats().completed, 3);
+}
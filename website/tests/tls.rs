@@ -0,0 +1,160 @@
+use std::fs;
+use std::io::{Read, Write};
+use std::net::{TcpListener, TcpStream};
+use std::path::{Path, PathBuf};
+use std::process::{Child, Command, Stdio};
+use std::thread;
+use std::time::{Duration, Instant};
+use openssl::asn1::Asn1Time;
+use openssl::bn::BigNum;
+use openssl::ec::{EcGroup, EcKey};
+use openssl::hash::MessageDigest;
+use openssl::nid::Nid;
+use openssl::pkey::{PKey, Private};
+use openssl::ssl::{SslConnector, SslMethod, SslVerifyMode};
+use openssl::x509::extension::SubjectAlternativeName;
+use openssl::x509::{X509, X509NameBuilder};
+
+// kills the server when the test ends even if an assert fails
+struct Server {
+    child: Child,
+    dir: PathBuf,
+}
+
+impl Drop for Server {
+    fn drop(&mut self) {
+        let _ = self.child.kill();
+        let _ = self.child.wait();
+        let _ = fs::remove_dir_all(&self.dir);
+    }
+}
+
+fn self_signed(name: &str, serial: u32) -> (X509, PKey<Private>) {
+    let group = EcGroup::from_curve_name(Nid::X9_62_PRIME256V1).unwrap();
+    let key = PKey::from_ec_key(EcKey::generate(&group).unwrap()).unwrap();
+
+    let mut subject = X509NameBuilder::new().unwrap();
+    subject.append_entry_by_nid(Nid::COMMONNAME, name).unwrap();
+    let subject = subject.build();
+
+    let mut cert = X509::builder().unwrap();
+    cert.set_version(2).unwrap();
+    cert.set_serial_number(&BigNum::from_u32(serial).unwrap().to_asn1_integer().unwrap()).unwrap();
+    cert.set_subject_name(&subject).unwrap();
+    cert.set_issuer_name(&subject).unwrap();
+    cert.set_pubkey(&key).unwrap();
+    cert.set_not_before(&Asn1Time::days_from_now(0).unwrap()).unwrap();
+    cert.set_not_after(&Asn1Time::days_from_now(1).unwrap()).unwrap();
+    let san = SubjectAlternativeName::new().dns(name).build(&cert.x509v3_context(None, None)).unwrap();
+    cert.append_extension(san).unwrap();
+    cert.sign(&key, MessageDigest::sha256()).unwrap();
+
+    (cert.build(), key)
+}
+
+fn write_pem(dir: &Path, file: &str, cert: &X509, key: &PKey<Private>) -> String {
+    let cert_path = dir.join(format!("{file}.pem"));
+    let key_path = dir.join(format!("{file}-key.pem"));
+    // key first so a reload never sees the new cert with the old key
+    fs::write(&key_path, key.private_key_to_pem_pkcs8().unwrap()).unwrap();
+    fs::write(&cert_path, cert.to_pem().unwrap()).unwrap();
+    format!("{}:{}", cert_path.display(), key_path.display())
+}
+
+fn free_port() -> u16 {
+    TcpListener::bind("127.0.0.1:0").unwrap().local_addr().unwrap().port()
+}
+
+fn start_server(dir: &Path, certs: &str) -> (Server, u16) {
+    let (port, tls_port) = (free_port(), free_port());
+    // the server looks for website/files from the workspace root
+    let root = Path::new(env!("CARGO_MANIFEST_DIR")).parent().unwrap();
+    let child = Command::new(env!("CARGO_BIN_EXE_website"))
+        .current_dir(root)
+        .env("PORT", port.to_string())
+        .env("TLS_PORT", tls_port.to_string())
+        .env("TLS_CERTS", certs)
+        .env("TLS_RELOAD_INTERVAL", "1")
+        .stdout(Stdio::null())
+        .spawn()
+        .unwrap();
+    let server = Server { child, dir: dir.to_path_buf() };
+
+    let started = Instant::now();
+    while TcpStream::connect(("127.0.0.1", tls_port)).is_err() {
+        assert!(started.elapsed() < Duration::from_secs(10), "server never started listening");
+        thread::sleep(Duration::from_millis(50));
+    }
+
+    (server, tls_port)
+}
+
+// sends a GET over https using `name` for SNI and returns the response and the certificate we got
+// with no trusted certificate the one the server sends isnt checked
+fn get(port: u16, name: &str, trusted: Option<&X509>) -> (String, X509) {
+    let mut connector = SslConnector::builder(SslMethod::tls_client()).unwrap();
+    match trusted {
+        Some(cert) => connector.cert_store_mut().add_cert(cert.clone()).unwrap(),
+        None => connector.set_verify(SslVerifyMode::NONE),
+    }
+    let connector = connector.build();
+
+    let stream = TcpStream::connect(("127.0.0.1", port)).unwrap();
+    stream.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+    let mut stream = connector.connect(name, stream).unwrap();
+
+    let request = format!("GET / HTTP/1.1\r\nHost: {name}\r\nConnection: close\r\n\r\n");
+    stream.write_all(request.as_bytes()).unwrap();
+    let mut response = Vec::new();
+    let _ = stream.read_to_end(&mut response);
+
+    let cert = stream.ssl().peer_certificate().unwrap();
+    (String::from_utf8_lossy(&response).into_owned(), cert)
+}
+
+fn serial(cert: &X509) -> u32 {
+    cert.serial_number().to_bn().unwrap().to_dec_str().unwrap().parse().unwrap()
+}
+
+#[test]
+fn serves_https_picks_certificates_by_sni_and_reloads_them() {
+    let dir = std::env::temp_dir().join(format!("bottomless-site-tls-{}", std::process::id()));
+    fs::create_dir_all(&dir).unwrap();
+
+    let (localhost, localhost_key) = self_signed("localhost", 1);
+    let (other, other_key) = self_signed("other.test", 2);
+    let certs = format!(
+        "{},{}",
+        write_pem(&dir, "localhost", &localhost, &localhost_key),
+        write_pem(&dir, "other", &other, &other_key),
+    );
+
+    let (_server, port) = start_server(&dir, &certs);
+
+    let (response, cert) = get(port, "localhost", Some(&localhost));
+    assert!(response.starts_with("HTTP/1.1 200 OK\r\n"), "{response}");
+    assert_eq!(serial(&cert), 1);
+
+    let (response, cert) = get(port, "other.test", Some(&other));
+    assert!(response.starts_with("HTTP/1.1 200 OK\r\n"), "{response}");
+    assert_eq!(serial(&cert), 2);
+
+    // swapping the files out should be picked up without a restart
+    let (renewed, renewed_key) = self_signed("localhost", 3);
+    write_pem(&dir, "localhost", &renewed, &renewed_key);
+
+    // it could be either certificate untill the reload happens so neither is checked here
+    let started = Instant::now();
+    loop {
+        let (response, cert) = get(port, "localhost", None);
+        assert!(response.starts_with("HTTP/1.1 200 OK\r\n"), "{response}");
+        if serial(&cert) == 3 {
+            break;
+        }
+        assert!(started.elapsed() < Duration::from_secs(10), "certificate was never reloaded");
+        thread::sleep(Duration::from_millis(200));
+    }
+
+    let (response, _) = get(port, "localhost", Some(&renewed));
+    assert!(response.starts_with("HTTP/1.1 200 OK\r\n"), "{response}");
+}